@@ -16,4 +16,5 @@ fn main () {
     // rebuild if new kernel
     println!("cargo:rerun-if-changed={}", Path::new(&kernel_path).join("libkernel.a").to_string_lossy());
     println!("cargo:rerun-if-env-changed=KERNEL_PATH");
+    println!("cargo:rerun-if-env-changed=KERNEL_CMDLINE");
 }
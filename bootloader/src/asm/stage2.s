@@ -71,6 +71,8 @@ mm_handle_entry2:
     jz mm_skip_entry # if length of entry is 0 skip it
     inc bp
     add di, 24
+    cmp bp, 168 # the kernel's map holds 0xFF0/24 (170) entries, 2 are left for the ones it adds when splitting
+    jae mm_finish
 
 mm_skip_entry:
     test ebx, ebx
@@ -90,12 +92,19 @@ vesa_get_info:
     mov si, [vbe_info_structure_video_mode_ptr] # offset
     mov ax, [vbe_info_structure_video_mode_ptr+2] # segment
     mov fs, ax
+    # store linear address of mode list (segment*16 + offset) so it can be passed to the kernel
+    movzx eax, ax
+    shl eax, 4
+    movzx ebx, si
+    add eax, ebx
+    mov [vbe_mode_list_addr], eax
     sub si, 2
+# enumerate modes looking for the biggest resolution within the target one
 vesa_search_mode:
     add si, 2
     mov cx, fs:[si]
     cmp cx, 0xFFFF
-    je error_vbe_mode_not_found
+    je vesa_search_finished
 vesa_get_mode_info:
     push esi
     mov ax, 0x4F01
@@ -103,24 +112,46 @@ vesa_get_mode_info:
     int 0x10
     pop esi
     cmp ax, 0x4F
-    jne error_vbe
+    jne vesa_search_mode # skip modes whose info can't be retrieved
 vesa_check_mode:
-    # check if bpp of mode is 24 FIXME: make configurable
-    cmp byte ptr [vbe_mode_info_structure_bpp], 24
-    jne vesa_search_mode
-    # check if width of mode is 800 FIXME: make configurable
-    cmp byte ptr [vbe_mode_info_structure_width], 800
-    jne vesa_search_mode
-    # check if height of mode is 600 FIXME: make configurable
-    cmp byte ptr [vbe_mode_info_structure_height], 600
-    jne vesa_search_mode
-    # check memory model is direct color
-    cmp byte ptr [vbe_mode_info_structure_memory_model], 6
-    jne vesa_search_mode
-    # check if linear framebuffer bit is set
-    mov ax, [vbe_mode_info_structure_attributes]
-    test ax, 0x80
+    call vesa_check_mode_attributes
+    test ax, ax
     jz vesa_search_mode
+    # check if resolution of mode is within 800x600 FIXME: make configurable
+    mov ax, [vbe_mode_info_structure_width]
+    cmp ax, 800
+    ja vesa_search_mode
+    mov bx, [vbe_mode_info_structure_height]
+    cmp bx, 600
+    ja vesa_search_mode
+    # check if resolution of mode is bigger than the best one found so far
+    cmp ax, [vbe_best_mode_width]
+    jb vesa_search_mode
+    ja vesa_new_best_mode
+    cmp bx, [vbe_best_mode_height]
+    jbe vesa_search_mode
+vesa_new_best_mode:
+    mov [vbe_best_mode_width], ax
+    mov [vbe_best_mode_height], bx
+    mov [vbe_mode_number], cx
+    jmp vesa_search_mode
+vesa_search_finished:
+    mov cx, [vbe_mode_number]
+    cmp cx, 0xFFFF
+    jne vesa_get_chosen_mode_info
+    # if enumeration found no suitable mode fall back to standard 800x600 24bpp mode
+    mov cx, 0x115
+    mov [vbe_mode_number], cx
+vesa_get_chosen_mode_info:
+    # mode info structure holds the last mode enumerated, retrieve the chosen one's again
+    mov ax, 0x4F01
+    mov di, offset vbe_mode_info_structure
+    int 0x10
+    cmp ax, 0x4F
+    jne error_vbe_mode_not_found
+    call vesa_check_mode_attributes
+    test ax, ax
+    jz error_vbe_mode_not_found
 vesa_set_mode:
     mov bx, cx
     or bx, 0x4000 # enable linear framebuffer
@@ -299,6 +330,24 @@ a20_line_fast_enable_ret:
     ret
 
 
+# vesa routines:
+# checks if mode in vbe_mode_info_structure is usable, returns 1 on ax if so or 0 otherwise
+vesa_check_mode_attributes:
+    xor ax, ax
+    # check if bpp of mode is 24 FIXME: make configurable
+    cmp byte ptr [vbe_mode_info_structure_bpp], 24
+    jne vesa_check_mode_attributes_ret
+    # check memory model is direct color
+    cmp byte ptr [vbe_mode_info_structure_memory_model], 6
+    jne vesa_check_mode_attributes_ret
+    # check if linear framebuffer bit is set
+    test word ptr [vbe_mode_info_structure_attributes], 0x80
+    jz vesa_check_mode_attributes_ret
+    mov ax, 1
+vesa_check_mode_attributes_ret:
+    ret
+
+
 # errors routines:
 error_enable_a20:
     mov bx, offset error_enable_a20_string
@@ -344,6 +393,12 @@ vbe_mode_info_structure:
     vbe_mode_info_structure_framebuffer_addr: .long 0
     .skip 212, 0 # not used here
 
+# VESA chosen mode and list of modes
+vbe_mode_number: .word 0xFFFF # 0xFFFF (end of mode list) while no mode was chosen
+vbe_mode_list_addr: .long 0
+vbe_best_mode_width: .word 0
+vbe_best_mode_height: .word 0
+
 
 # strings:
 second_stage_string: .asciz "Booting second stage..."
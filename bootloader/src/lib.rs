@@ -13,6 +13,8 @@ pub mod kernel_loader;
 pub struct BootloaderInfo {
     pub drive_code: u8,
     pub vesa_mode_info_addr: u64,
+    pub vesa_mode_number: u64,
+    pub vesa_mode_list_addr: u64, // list of mode numbers supported terminated by 0xFFFF
     pub memory_map_addr: u64,
     pub vga_bitmap_font_addr: u64,
     pub rsdp_addr: u64,
@@ -24,7 +26,9 @@ pub struct BootloaderInfo {
         Start of conventional mem not used by bootloader.
         Used by kernel for allocating tables to map physical memory
     */
-    pub conventional_mem_addr: u64
+    pub conventional_mem_addr: u64,
+    pub cmdline_addr: u64, // kernel command line, not null terminated
+    pub cmdline_len: u64
 }
 
 #[repr(C, packed)]
@@ -22,6 +22,8 @@ extern {
     // from stage1/2.s
     static drive_code: u8;
     static vbe_mode_info_structure: [u8; 256];
+    static vbe_mode_number: u16;
+    static vbe_mode_list_addr: u32;
     // from bootloader.ld
     static memory_map: ();
     static vga_bitmap_font: [[u8; 16]; 256];
@@ -31,9 +33,17 @@ extern {
     static kernel_addr: ();
 }
 
+// set by the "KERNEL_CMDLINE" env variable when building
+const KERNEL_CMDLINE: &str = match option_env!("KERNEL_CMDLINE") {
+    Some(cmdline) => cmdline,
+    None => ""
+};
+
 static mut BOOTLOADER_INFO: BootloaderInfo = BootloaderInfo {
     drive_code: 0,
     vesa_mode_info_addr: 0,
+    vesa_mode_number: 0,
+    vesa_mode_list_addr: 0,
     memory_map_addr: 0,
     vga_bitmap_font_addr: 0,
     rsdp_addr: 0,
@@ -41,7 +51,9 @@ static mut BOOTLOADER_INFO: BootloaderInfo = BootloaderInfo {
     kernel_elf_size: 0,
     bss_start_addr: 0,
     bss_size: 0,
-    conventional_mem_addr: 0
+    conventional_mem_addr: 0,
+    cmdline_addr: 0,
+    cmdline_len: 0
 };
 
 const GDT64_DESCRIPTOR: Gdt64Descriptor = Gdt64Descriptor {
@@ -76,6 +88,8 @@ unsafe fn stage3_start() -> ! {
     // fill up bootloader info structure
     BOOTLOADER_INFO.drive_code = drive_code;
     BOOTLOADER_INFO.vesa_mode_info_addr = &vbe_mode_info_structure as *const _ as u64;
+    BOOTLOADER_INFO.vesa_mode_number = vbe_mode_number as u64;
+    BOOTLOADER_INFO.vesa_mode_list_addr = vbe_mode_list_addr as u64;
     BOOTLOADER_INFO.memory_map_addr = &memory_map as *const _ as u64;
     BOOTLOADER_INFO.vga_bitmap_font_addr = &vga_bitmap_font as *const _ as u64;
     BOOTLOADER_INFO.rsdp_addr = bootloader::get_rsdp();
@@ -85,6 +99,8 @@ unsafe fn stage3_start() -> ! {
     BOOTLOADER_INFO.bss_start_addr = bss_start_addr;
     BOOTLOADER_INFO.bss_size = bss_size;
     BOOTLOADER_INFO.conventional_mem_addr = &conventional_mem_addr as *const _ as u64;
+    BOOTLOADER_INFO.cmdline_addr = KERNEL_CMDLINE.as_ptr() as u64;
+    BOOTLOADER_INFO.cmdline_len = KERNEL_CMDLINE.len() as u64;
 
     // these panic if not supported
     bootloader::detect_cpuid();
@@ -66,4 +66,6 @@ fn main() {
     println!("cargo:rerun-if-changed={}", Path::new(&kernel_path).join("Cargo.toml").to_string_lossy());
     println!("cargo:rerun-if-changed={}", Path::new(&bootloader_path).join("src").to_string_lossy());
     println!("cargo:rerun-if-changed={}", Path::new(&bootloader_path).join("Cargo.toml").to_string_lossy());
+    // kernel command line embedded by the bootloader
+    println!("cargo:rerun-if-env-changed=KERNEL_CMDLINE");
 }
@@ -0,0 +1,50 @@
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use crate::{memory::address::PhysAddr, utils::lazy_static::LazyStatic};
+
+
+static CMDLINE: LazyStatic<BTreeMap<&'static str, &'static str>> = LazyStatic::new();
+
+
+/*
+ * Parses the kernel command line passed by the bootloader, it's copied to the heap first
+ * since the bootloader's memory can be reused after setup. Requires the heap.
+ */
+pub fn init(cmdline_addr: PhysAddr, cmdline_len: usize) {
+    let cmdline = if cmdline_len == 0 {
+        ""
+    }
+    else {
+        let bytes = unsafe { core::slice::from_raw_parts(cmdline_addr.to_virtual().as_ptr::<u8>(), cmdline_len) };
+        core::str::from_utf8(bytes).unwrap_or_else(|_| {
+            crate::warn!("WARNING: Kernel command line isn't valid UTF-8, ignoring it.");
+            ""
+        })
+    };
+    let cmdline: &'static str = Box::leak(Box::from(cmdline));
+    CMDLINE.init(parse(cmdline));
+}
+
+/**
+ * Splits the command line on whitespace into "key=value" pairs, or just "key" for flags
+ * (which map to an empty value). Pairs with an empty key are ignored and a repeated key
+ * keeps the last value.
+ */
+pub fn parse(cmdline: &str) -> BTreeMap<&str, &str> {
+    let mut pairs = BTreeMap::new();
+    for pair in cmdline.split_whitespace() {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if !key.is_empty() {
+            pairs.insert(key, value);
+        }
+    }
+    pairs
+}
+
+// Value of key, empty if the key is a flag
+pub fn get(key: &str) -> Option<&'static str> {
+    CMDLINE.try_get().and_then(|cmdline| cmdline.get(key).copied())
+}
+pub fn has(key: &str) -> bool {
+    get(key).is_some()
+}
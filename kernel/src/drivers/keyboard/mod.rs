@@ -9,8 +9,6 @@ pub mod scancode;
 
 
 const SCANCODE_QUEUE_SIZE: usize = 100;
-const PS2_CONTROLLER_DATA_PORT: u16 = 0x60;
-const PS2_CONTROLLER_STATUS_PORT: u16 = 0x64;
 const PS2_CONTROLLER_STATUS_SCANCODE_FULL: u8 = 0x1;
 
 
@@ -19,22 +17,17 @@ static mut HALTED_TASK_ID: Option<TaskId> = None;
 
 
 pub fn init() {
-    use x86_64::{interrupts::{self, apic::io_apic}, structures::idt::{Index, Flags}};
+    use x86_64::{interrupts::{self, Irq}, structures::idt::Index};
 
     // init keyboard scancode queue
     let scancode_queue = atomic::ArrayQueue::<u8>::new(SCANCODE_QUEUE_SIZE)
                                             .expect("Unsufficient memory for keyboard driver");
     unsafe { SCANCODE_QUEUE.init(scancode_queue); }
 
-    // set handler for keyboard interrupt
-    interrupts::set_idt_entry(
-        Index::KEYBOARD, keyboard_handler.get_addr(), 0x8, Flags::BASE, 0
-    );
-
-    // enable keyboard interrupt
-    io_apic::enable_keyboard(Index::KEYBOARD);
+    // set handler for keyboard interrupt and enable it
+    interrupts::enable_irq(Irq::KEYBOARD, Index::KEYBOARD, keyboard_handler.get_addr());
     // flush output buffer
-    crate::x86_64::cpu::instructions::inb(PS2_CONTROLLER_DATA_PORT);
+    x86_64::port::PS2_CONTROLLER_DATA.read();
 }
 
 pub fn retrieve_scancode() -> u8 {
@@ -63,25 +56,25 @@ pub fn retrieve_scancode() -> u8 {
 }
 
 
-def_interrupt_handler!(keyboard_handler,
+def_interrupt_handler!(keyboard_handler, x86_64::structures::idt::Index::KEYBOARD,
     fn keyboard_handler_fn(_stack_frame: &StackFrame) {
-        use x86_64::interrupts::apic;
+        use x86_64::interrupts::{self, Irq, deferred::{self, DeferredWork}};
 
-        let scancode_status = x86_64::cpu::instructions::inb(PS2_CONTROLLER_STATUS_PORT) & 1;
+        let scancode_status = x86_64::port::PS2_CONTROLLER_STATUS.read() & 1;
         if scancode_status == PS2_CONTROLLER_STATUS_SCANCODE_FULL {
-            let scancode = x86_64::cpu::instructions::inb(PS2_CONTROLLER_DATA_PORT);
+            let scancode = x86_64::port::PS2_CONTROLLER_DATA.read();
             unsafe {
                 if let Ok(_) = SCANCODE_QUEUE.push(scancode) {
                     if let Some(task_id) = HALTED_TASK_ID.take() {
-                        scheduler::wake_up_task(task_id);
+                        deferred::defer(DeferredWork::WakeUpTask(task_id));
                     }
                 }
                 else {
-                    crate::println_color!(crate::video::color::SAFETY_YELLOW, "\nWARNING: Failed to push scancode to queue, keypress dropped."); // FIXME
+                    crate::warn!("\nWARNING: Failed to push scancode to queue, keypress dropped.");
                 }
             }
         }
 
-        apic::lapic::eoi();
+        interrupts::end_of_irq(Irq::KEYBOARD);
     }
 );
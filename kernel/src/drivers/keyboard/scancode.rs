@@ -77,3 +77,93 @@ impl TryFrom<u8> for IbmXt {
         }
     }
 }
+
+
+const PAUSE_SEQUENCE: [u8; 6] = [0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5];
+const EXTENDED_PRINT_SCREEN: u8 = 0x37;
+// sent around PrintScreen and other extended keys depending on the shift and num lock state
+const EXTENDED_FAKE_SHIFTS: [u8; 4] = [0x2A, 0xAA, 0x36, 0xB6];
+
+
+// A key read from one or more scancodes
+#[derive(Clone, Copy)]
+pub enum Key {
+    Standard(IbmXt),
+    Extended(u8), // the byte after ExtendedByte (arrows, right ctrl and alt, keypad enter...), KEY_RELEASED set on release
+    PrintScreen,
+    PrintScreenR,
+    Pause // there's no release
+}
+impl Key {
+    pub fn to_char(&self) -> Option<&'static str> {
+        match self {
+            Key::Standard(key) => key.to_char(),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum DecoderState {
+    Idle,
+    Extended,      // after ExtendedByte
+    Pause(usize)   // number of bytes of PAUSE_SEQUENCE matched so far
+}
+
+/*
+ * Turns scancodes into keys, consuming the bytes of multi-byte sequences so each becomes a
+ * single key instead of being read as separate ones (e.g. PrintScreen, 0xE0 0x2A 0xE0 0x37,
+ * would be a left shift and a keypad asterisk). Unknown scancodes and sequences cut short are
+ * dropped, in the latter case the byte that broke the sequence is decoded from scratch.
+ */
+pub struct ScancodeDecoder {
+    state: DecoderState
+}
+impl ScancodeDecoder {
+    pub const fn new() -> ScancodeDecoder {
+        ScancodeDecoder { state: DecoderState::Idle }
+    }
+
+    // Returns the key once scancode completes one
+    pub fn feed(&mut self, scancode: u8) -> Option<Key> {
+        match self.state {
+            DecoderState::Idle => self.feed_first(scancode),
+            DecoderState::Extended => {
+                self.state = DecoderState::Idle;
+                match scancode {
+                    _ if EXTENDED_FAKE_SHIFTS.contains(&scancode) => None,
+                    EXTENDED_PRINT_SCREEN => Some(Key::PrintScreen),
+                    _ if scancode == EXTENDED_PRINT_SCREEN | KEY_RELEASED => Some(Key::PrintScreenR),
+                    _ if scancode == IbmXt::ExtendedByte as u8 || scancode == PAUSE_SEQUENCE[0] => self.feed_first(scancode),
+                    _ => Some(Key::Extended(scancode))
+                }
+            }
+            DecoderState::Pause(matched) => {
+                if scancode != PAUSE_SEQUENCE[matched] {
+                    self.state = DecoderState::Idle;
+                    return self.feed_first(scancode);
+                }
+                if matched + 1 == PAUSE_SEQUENCE.len() {
+                    self.state = DecoderState::Idle;
+                    return Some(Key::Pause);
+                }
+                self.state = DecoderState::Pause(matched + 1);
+                None
+            }
+        }
+    }
+
+    fn feed_first(&mut self, scancode: u8) -> Option<Key> {
+        if scancode == IbmXt::ExtendedByte as u8 {
+            self.state = DecoderState::Extended;
+            None
+        }
+        else if scancode == PAUSE_SEQUENCE[0] {
+            self.state = DecoderState::Pause(1);
+            None
+        }
+        else {
+            IbmXt::try_from(scancode).ok().map(Key::Standard)
+        }
+    }
+}
@@ -1 +1,4 @@
 pub mod keyboard;
+pub mod registry;
+
+pub use registry::{register, init_all};
@@ -0,0 +1,72 @@
+use core::mem;
+use alloc::boxed::Box;
+
+use crate::{locks::spinlock::Spinlock, utils::stack_vec::StackVec};
+
+
+const MAX_DRIVERS: usize = 16;
+
+
+static DRIVERS: Spinlock<StackVec<Driver, MAX_DRIVERS>> = Spinlock::new(StackVec::new());
+
+
+type DriverInitFn = Box<dyn FnOnce() -> Result<(), &'static str> + Send>;
+
+struct Driver {
+    name: &'static str,
+    dependencies: &'static [&'static str], // names of the drivers initialized before it
+    init: DriverInitFn
+}
+
+
+/**
+ * Registers a driver for "init_all", init only runs once every driver in dependencies has been
+ * initialized. Closures that don't capture anything aren't allocated.
+ */
+pub fn register<F>(name: &'static str, dependencies: &'static [&'static str], init: F) -> Result<(), &'static str>
+    where F: FnOnce() -> Result<(), &'static str> + Send + 'static
+{
+    let mut drivers = DRIVERS.lock();
+    if drivers.iter().any(|driver| driver.name == name) {
+        return Err("Driver is already registered");
+    }
+    drivers.push(Driver { name, dependencies, init: Box::new(init) })
+        .map_err(|_| "Too many drivers registered")
+}
+
+/**
+ * Initializes the registered drivers, each after its dependencies and otherwise in the order
+ * they were registered. Nothing is initialized if a dependency isn't registered, stops at the
+ * first driver that fails or if the ones left depend on each other.
+ */
+pub fn init_all() -> Result<(), &'static str> {
+    let mut pending = mem::replace(&mut *DRIVERS.lock(), StackVec::new());
+
+    for driver in &pending {
+        if let Some(dependency) = driver.dependencies.iter().find(|d| !pending.iter().any(|p| p.name == **d)) {
+            crate::error!("Driver \"{}\" depends on \"{}\", which isn't registered", driver.name, dependency);
+            return Err("Driver depends on one that isn't registered");
+        }
+    }
+
+    let mut initialized = StackVec::<&'static str, MAX_DRIVERS>::new();
+    while !pending.is_empty() {
+        let is_ready = |driver: &Driver| driver.dependencies.iter().all(|d| initialized.contains(d));
+        let Some(index) = pending.iter().position(is_ready) else {
+            for driver in &pending {
+                crate::error!("Driver \"{}\" is waiting on {:?}", driver.name, driver.dependencies);
+            }
+            return Err("Drivers have circular dependencies");
+        };
+
+        let driver = pending.remove(index);
+        if let Err(err) = (driver.init)() {
+            crate::error!("Failed to initialize driver \"{}\": {}", driver.name, err);
+            return Err(err);
+        }
+        crate::debug!("Initialized driver \"{}\"", driver.name);
+        // can't be full, it has the same capacity as pending
+        let _ = initialized.push(driver.name);
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use core::fmt;
+
+use crate::{
+    no_enable_irq_print_color, processor, time,
+    video::color,
+    x86_64::{cpu::{self, smp}, interrupts::apic::lapic, structures::idt::Index}
+};
+
+
+/**
+ * Kernel assertions, only checked with the "kassert" feature (on by default). On failure they
+ * print the condition along with the executing task, processor and runtime, then halt every
+ * processor (like the panic handler, without clearing the screen).
+ */
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr) => {
+        if cfg!(feature = "kassert") && !($cond) {
+            $crate::kassert::_kassert_failed(format_args!("{}", stringify!($cond)), file!(), line!());
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if cfg!(feature = "kassert") && !($cond) {
+            $crate::kassert::_kassert_failed(
+                format_args!("{} ({})", stringify!($cond), format_args!($($arg)+)), file!(), line!()
+            );
+        }
+    };
+}
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr) => {
+        if cfg!(feature = "kassert") {
+            match (&$left, &$right) {
+                (left, right) => if !(*left == *right) {
+                    $crate::kassert::_kassert_failed(
+                        format_args!("{} == {} (left: {:?}, right: {:?})", stringify!($left), stringify!($right), left, right),
+                        file!(), line!()
+                    );
+                }
+            }
+        }
+    };
+}
+
+#[cold]
+pub fn _kassert_failed(condition: fmt::Arguments, file: &str, line: u32) -> ! {
+    cpu::instructions::cli();
+
+    if smp::is_init() {
+        // nothing else to do if it doesn't go through, this processor halts anyway
+        let _ = lapic::broadcast_ipi(Index::HALT);
+    }
+
+    no_enable_irq_print_color!(color::RED, "\nASSERTION FAILED at {}:{}: {}\n", file, line, condition);
+    // processor isn't registered this early in setup
+    if let Some(processor) = processor::try_get() {
+        let task_id = processor.scheduler().try_get_executing_task_id();
+        no_enable_irq_print_color!(color::RED,
+            "Task: {:?}, LAPIC id: {}, runtime: {}\n", task_id, lapic::get_id(), time::uptime().pretty()
+        );
+    }
+
+    loop { cpu::instructions::hlt(); }
+}
@@ -1,8 +1,11 @@
-#![no_std]
+// std only for the unit tests, which run on the host (build-std has to build std for them too):
+//   cargo test --lib --target x86_64-unknown-linux-gnu --config 'unstable.build-std=["std"]'
+#![cfg_attr(not(test), no_std)]
 #![feature(core_intrinsics)]
 #![feature(abi_x86_interrupt)]
-#![feature(alloc_error_handler)]
+#![cfg_attr(not(test), feature(alloc_error_handler))]
 #![feature(const_mut_refs)]
+#![feature(allocator_api)]
 
 extern crate alloc;
 
@@ -16,12 +19,18 @@ pub mod video;
 pub mod processor;
 pub mod time;
 pub mod scheduler;
+pub mod cmdline;
+pub mod log;
+pub mod kassert;
+pub mod trace;
 
 
 // Needs to be the exact same as the struct in ../../bootloader/src/lib.rs
 pub struct BootloaderInfo {
     pub drive_code: u8,
     pub vesa_mode_info_addr: u64,
+    pub vesa_mode_number: u64,
+    pub vesa_mode_list_addr: u64, // list of mode numbers supported terminated by 0xFFFF
     pub memory_map_addr: u64,
     pub vga_bitmap_font_addr: u64,
     pub rsdp_addr: u64,
@@ -33,7 +42,9 @@ pub struct BootloaderInfo {
         Start of conventional mem not used by bootloader.
         Used by kernel for allocating tables to map physical memory
     */
-    pub conventional_mem_addr: u64
+    pub conventional_mem_addr: u64,
+    pub cmdline_addr: u64, // kernel command line, not null terminated
+    pub cmdline_len: u64
 }
 
 
@@ -44,7 +55,7 @@ pub fn setup(bootloader_info: &mut &mut BootloaderInfo) -> Result<(), &'static s
         FrameSize, FrameAllocator, address::PhysAddr,
         e820_memory_map::{self, MemoryMap}, kalloc
     };
-    use video::{vesa::VBEModeInfo, color, logger};
+    use video::{vesa::{self, VBEModeInfo}, color, logger, text_grid};
 
     // memsets the bss section to 0
     zero_out_bss(bootloader_info);
@@ -71,11 +82,15 @@ pub fn setup(bootloader_info: &mut &mut BootloaderInfo) -> Result<(), &'static s
     // initialize vbe mode info struct
     let vbe_mode_info_addr = PhysAddr::new(bootloader_info.vesa_mode_info_addr as usize).to_virtual();
     let vbe_mode_info = unsafe { &*vbe_mode_info_addr.as_ptr::<VBEModeInfo>() };
+    // the framebuffer is mapped write combining, which takes the PAT entry set up here
+    cpu::registers::pat::init();
     // map framebuffer to virtual memory at set offset
     map_framebuffer(vbe_mode_info, &mut frame_allocator)?;
 
     // initialize color builder
     color::init(vbe_mode_info);
+    // initialize framebuffer shared by the logger and terminal
+    vesa::init(vbe_mode_info);
     // initialize logger
     let vga_bitmap_font_addr = PhysAddr::new(bootloader_info.vga_bitmap_font_addr as usize).to_virtual();
     logger::init(vbe_mode_info, vga_bitmap_font_addr, color::GREY);
@@ -83,31 +98,95 @@ pub fn setup(bootloader_info: &mut &mut BootloaderInfo) -> Result<(), &'static s
     // initialize and load gdt
     gdt::init();
     gdt::load();
+    // before any task switch, which writes the FS base
+    cpu::registers::fs_base::init();
+
+    // log video mode chosen by the bootloader
+    let vbe_mode_list = vesa::VBEModeListIterator::new(PhysAddr::new(bootloader_info.vesa_mode_list_addr as usize));
+    no_enable_irq_print!(
+        "VBE mode {:#x} ({} available): {}\n",
+        bootloader_info.vesa_mode_number, vbe_mode_list.count(), vbe_mode_info.describe()
+    );
+
+    // check there's enough memory left for the tables mapping physical memory and for the heap
+    let frames_needed = count_physical_memory_tables(memory_map) + kalloc::frames_needed();
+    let frames_remaining = frame_allocator.remaining_estimate();
+    if frames_needed > frames_remaining {
+        no_enable_irq_print_color!(color::RED,
+            "Need ~{} frames for page tables and heap, have {}\n", frames_needed, frames_remaining
+        );
+        return Err("Insufficient physical memory for mapping physical memory and heap");
+    }
 
     // have to use this macro to print here since interrupts aren't setup yet
     no_enable_irq_print!("Mapping physical memory: ");
     // map physical memory past first 2MB detected by the e820 memory map structure to virtual memory at set offset
     map_physical_memory(memory_map, &mut frame_allocator)?;
     no_enable_irq_print_color!(color::DARK_GREEN, "DONE.\n");
+    for region in &memory_map.reserved_regions() {
+        no_enable_irq_print!("Reserved memory: {:#x}-{:#x}\n", region.base(), region.base() + region.length());
+    }
 
     no_enable_irq_print!("Initializing heap: ");
     // initialize heap
     kalloc::init_heap(&mut frame_allocator)?;
     no_enable_irq_print_color!(color::DARK_GREEN, "DONE.\n");
 
+    // parse kernel command line embedded by the bootloader
+    cmdline::init(PhysAddr::new(bootloader_info.cmdline_addr as usize), bootloader_info.cmdline_len as usize);
+    // set log level from the command line
+    log::init();
+    // set the font and its scale from the command line, the logger was already drawing with the VGA one at 1
+    text_grid::init();
+    logger::init_font();
+    // set watchdog threshold and timeslice from the command line
+    scheduler::watchdog::init();
+    scheduler::init();
+
+    // check the physical memory mapping if asked to, reads from every 2MB of RAM
+    if cmdline::has("verify_physmap") {
+        no_enable_irq_print!("Verifying physical memory mapping: ");
+        memory::verify_physical_mapping(memory_map)?;
+        no_enable_irq_print_color!(color::DARK_GREEN, "DONE.\n");
+    }
+
+    // make kernel code and read-only data read-only, before the APs share the tables
+    memory::kernel_sections::protect(PhysAddr::new(bootloader_info.kernel_load_addr as usize))?;
+
+    // seed the global random number generator
+    utils::rng::init();
+
     // retrieve and validate system description pointer and table
     let rsdp_addr = PhysAddr::new(bootloader_info.rsdp_addr as usize).to_virtual();
     acpi::init_rsdp_and_rsdt(rsdp_addr)?;
-    acpi::init_madt()?;
-    let madt = acpi::get_madt();
-    // map apic MMIO addresses retrieved from MADT
-    map_apic_registers(madt.get_lapic_addr(), madt.get_io_apic_addr_base_0()?, &mut frame_allocator)?;
+    if interrupts::should_use_apic() {
+        acpi::init_madt()?;
+        let madt = acpi::get_madt();
+        // map apic MMIO addresses retrieved from MADT
+        map_apic_registers(madt.get_lapic_addr(), &madt.get_io_apic_addrs(), &mut frame_allocator)?;
+    }
+    else {
+        no_enable_irq_print_color!(color::SAFETY_YELLOW, "APIC unavailable or disabled, using the PIC without SMP\n");
+    }
+
+    // keep the frame allocator around for frames needed from here on (e.g. task stacks)
+    memory::init_frame_allocator(frame_allocator);
+    // track frames in a bitmap instead if asked to, which can reuse any frame given back
+    if cmdline::has("bitmap_frames") {
+        memory::switch_to_bitmap_frame_allocator()?;
+    }
+    // tasks can get their own address space from here on
+    memory::address_space::init()?;
 
     // initialize hardware interrupts
     interrupts::init_hardware_interrupts()?;
 
     // register bootstrap processor struct
     processor::register_bsp();
+    processor::cache_current_id();
+    // switch to the bsp's own gdt with its tss, needed to enter the kernel from ring 3
+    processor::get().load_gdt_and_tss();
+    x86_64::syscall::init();
 
     // fill bsp idt with exception handlers and load it
     interrupts::fill_and_load_idt();
@@ -115,20 +194,71 @@ pub fn setup(bootloader_info: &mut &mut BootloaderInfo) -> Result<(), &'static s
     // enable interrupts
     cpu::instructions::sti();
 
-    // initialize bootstrap processor lapic and timer
+    // initialize bootstrap processor lapic and timer, without the APIC the timer is driven by the PIT
     let bsp = processor::get();
-    bsp.lapic().enable();
-    bsp.timer().init();
+    if interrupts::apic::is_enabled() {
+        bsp.lapic().enable();
+    }
+    bsp.timer().init().expect("Failed to initialize the BSP timer");
+
+    // initialize smp unless disabled in the command line, up to "maxcpus" processors (including the BSP)
+    if !cmdline::has("nosmp") && interrupts::apic::is_enabled() {
+        let max_cpus = match cmdline::get("maxcpus") {
+            Some(value) => value.parse::<usize>().map_err(|_| "Invalid maxcpus in kernel command line")?,
+            None => usize::MAX
+        };
+        cpu::smp::init(max_cpus);
+    }
+
+    /*
+     * the AP trampoline is only needed while there are APs left to start with "smp::start_ap",
+     * its frame is given back unless bootloader data still in use shares it
+     */
+    let trampoline_region = memory::MemoryRegion::new(
+        cpu::smp::TRAMPOLINE_ADDR as usize, cpu::smp::TRAMPOLINE_FRAME_SIZE
+    );
+    let are_all_processors_online = !interrupts::apic::is_enabled()
+        || processor::count() == acpi::get_madt().processor_lapic_iter().count();
+    let should_release_trampoline = are_all_processors_online
+        && bootloader_regions_in_use(bootloader_info).iter().all(|r| !trampoline_region.overlaps(r));
 
-    // initialize smp
-    cpu::smp::init();
+    // remove first 2mb identity mapping, the trampoline has to stay identity mapped for the APs
+    remove_first_2mb_identity_mapping(!should_release_trampoline);
 
-    // remove first 2mb identity mapping
-    remove_first_2mb_identity_mapping();
+    if should_release_trampoline {
+        memory::free_frame(cpu::smp::release_trampoline())?;
+    }
+
+    // W^X, only kernel code is executable (the trampoline writes to itself so it's left alone)
+    let wx_exceptions = if should_release_trampoline { &[][..] } else { core::slice::from_ref(&trampoline_region) };
+    if let Some(page) = memory::paging::find_writable_executable(wx_exceptions) {
+        crate::error!("Page at {:#x} is writable and executable", page.as_usize());
+        return Err("Found mapping that is both writable and executable");
+    }
+
+    // check the tables for mapping bugs if asked to, walks every table
+    if cmdline::has("check_paging") {
+        memory::paging::check_invariants(memory_map)?;
+    }
 
     Ok(())
 }
 
+// Memory left by the bootloader that is still accessed after setup
+fn bootloader_regions_in_use(bootloader_info: &BootloaderInfo) -> [memory::MemoryRegion; 4] {
+    use core::mem;
+    use memory::{MemoryRegion, address::{VirtualAddress, VirtAddr}, e820_memory_map::MemoryMap};
+    use video::vesa::VBEModeInfo;
+
+    let bootloader_info_addr = unsafe { VirtAddr::new(bootloader_info as *const _ as usize).to_phys_direct() };
+    [
+        MemoryRegion::new(bootloader_info_addr.as_usize(), mem::size_of::<BootloaderInfo>()),
+        MemoryRegion::new(bootloader_info.vesa_mode_info_addr as usize, mem::size_of::<VBEModeInfo>()),
+        MemoryRegion::new(bootloader_info.vga_bitmap_font_addr as usize, mem::size_of::<[[u8; 16]; 256]>()),
+        MemoryRegion::new(bootloader_info.memory_map_addr as usize, mem::size_of::<MemoryMap>())
+    ]
+}
+
 fn zero_out_bss(bootloader_info: &BootloaderInfo) {
     use core::intrinsics::volatile_set_memory;
     let ptr = bootloader_info.bss_start_addr as *mut u8;
@@ -138,7 +268,6 @@ fn zero_out_bss(bootloader_info: &BootloaderInfo) {
 // Maps first 2mb to virtual memory at set offset
 fn map_first_2mb(bootloader_info: &mut BootloaderInfo) {
     use core::intrinsics::volatile_set_memory;
-    use x86_64::cpu::registers;
     use memory::{
         address::{PhysAddr, VirtualAddress, VirtAddr, MutVirtAddr},
         paging::{Table, TableLevel, Flags}
@@ -149,7 +278,7 @@ fn map_first_2mb(bootloader_info: &mut BootloaderInfo) {
     // map first 2MB
     unsafe {
         let virt_base = memory::address::PHYS_MEM_VIRT_ADDR;
-        let mut table4 = Table::new(VirtAddr::new(registers::cr3::read() as usize), TableLevel::Four);
+        let mut table4 = Table::new(VirtAddr::new(memory::address_space::current_table4_addr().as_usize()), TableLevel::Four);
 
         volatile_set_memory(next_table_addr.as_ptr::<u8>(), 0, 0x1000);
         let t3_addr: PhysAddr = next_table_addr.as_usize().into();
@@ -167,11 +296,15 @@ fn map_first_2mb(bootloader_info: &mut BootloaderInfo) {
         bootloader_info.conventional_mem_addr = next_table_addr.as_usize() as u64;
 
         let first_frame = PhysAddr::new(0x0);
-        table2.set_entry(first_frame, Flags::PRESENT | Flags::WRITABLE | Flags::HUGE, 0)
+        table2.set_entry(first_frame, Flags::PRESENT | Flags::WRITABLE | Flags::HUGE | Flags::NO_EXECUTE, 0)
     }
 }
 
-fn map_physical_region(memory_region: memory::MemoryRegion,
+/*
+    Maps the 2MB frames of the region at the physical memory mapping with the memory type flags
+    (the huge page ones), frames that are already mapped keep their mapping and memory type.
+*/
+fn map_physical_region(memory_region: memory::MemoryRegion, memory_type: u64,
     frame_allocator: &mut memory::FrameAllocator) -> Result<(), ()>
 {
     use memory::{
@@ -197,9 +330,13 @@ fn map_physical_region(memory_region: memory::MemoryRegion,
             }
         }
 
-        // map with huge page (2MB per entry)
+        // map with huge page (2MB per entry), nothing is executed through this mapping
         let t2_entry = virt_addr.get_entry(TableLevel::Two);
-        table.set_entry(PhysAddr::new(frame), Flags::PRESENT | Flags::WRITABLE | Flags::HUGE, t2_entry);
+        if table.get_entry(t2_entry).is_some() {
+            continue;
+        }
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::HUGE | Flags::NO_EXECUTE | memory_type;
+        table.set_entry(PhysAddr::new(frame), flags, t2_entry);
     }
 
     Ok(())
@@ -208,11 +345,16 @@ fn map_physical_region(memory_region: memory::MemoryRegion,
 fn map_framebuffer(vbe_mode_info: &video::vesa::VBEModeInfo,
     frame_allocator: &mut memory::FrameAllocator) -> Result<(), &'static str>
 {
-    use memory::MemoryRegion;
+    use memory::{MemoryRegion, paging::Flags};
+    use x86_64::cpu::registers::pat;
+
+    // write combining makes pixel writes much faster, the framebuffer has to be mapped before
+    // the rest of physical memory for the memory type to stick (it could be in an e820 entry)
+    let memory_type = if pat::is_supported() { Flags::HUGE_PAT } else { Flags::NO_CACHE | Flags::WRITE_THROUGH };
 
     let length = vbe_mode_info.length();
     let memory_region = MemoryRegion::new(vbe_mode_info.framebuffer_addr().as_usize(), length);
-    if let Err(_) = map_physical_region(memory_region, frame_allocator) {
+    if let Err(_) = map_physical_region(memory_region, memory_type, frame_allocator) {
         return Err("Insufficient physical memory for mapping framebuffer");
     }
     Ok(())
@@ -235,36 +377,65 @@ fn map_physical_memory(memory_map: &memory::e820_memory_map::MemoryMap,
         let length = entry.length as usize;
         let memory_region = MemoryRegion::new(base, length);
 
-        if let Err(_) = map_physical_region(memory_region, frame_allocator) {
+        if let Err(_) = map_physical_region(memory_region, 0, frame_allocator) {
             return Err("Insufficient physical memory for mapping physical memory");
         }
     }
     Ok(())
 }
 
-fn map_apic_registers(lapic_base_addr: memory::address::PhysAddr, io_apic_base_addr: memory::address::PhysAddr,
+/*
+    Upper bound of the frames "map_physical_memory" takes for tables: one level 2 table
+    per GB and one level 3 table per 512GB with an entry of the memory map (which is sorted).
+*/
+fn count_physical_memory_tables(memory_map: &memory::e820_memory_map::MemoryMap) -> usize {
+    use memory::{FrameSize, MemoryRegion, address::PhysAddr};
+
+    let mut count = 0;
+    let (mut last_gb, mut last_512gb) = (None, None);
+    for entry in memory_map {
+        let memory_region = MemoryRegion::new(entry.base as usize, entry.length as usize);
+        for frame in memory_region.iter(FrameSize::TwoMb) {
+            let virt_addr: usize = PhysAddr::new(frame).to_virtual().into();
+            let (gb, gb_512) = (virt_addr >> 30, virt_addr >> 39);
+            if last_gb != Some(gb) {
+                last_gb = Some(gb);
+                count += 1;
+            }
+            if last_512gb != Some(gb_512) {
+                last_512gb = Some(gb_512);
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+fn map_apic_registers(lapic_base_addr: memory::address::PhysAddr, io_apic_base_addrs: &[memory::address::PhysAddr],
     frame_allocator: &mut memory::FrameAllocator) -> Result<(), &'static str>
 {
     use memory::MemoryRegion;
 
     let memory_region = MemoryRegion::new(lapic_base_addr.as_usize(), 0x1000);
-    if let Err(_) = map_physical_region(memory_region, frame_allocator) {
+    if let Err(_) = map_physical_region(memory_region, 0, frame_allocator) {
         return Err("Insufficient physical memory for mapping apic registers");
     }
 
-    // this is probably already by mapped by the above function call but just to be sure
-    let memory_region = MemoryRegion::new(io_apic_base_addr.as_usize(), 0x1000);
-    if let Err(_) = map_physical_region(memory_region, frame_allocator) {
-        return Err("Insufficient physical memory for mapping apic registers");
+    // these are probably already by mapped by the above function call but just to be sure
+    for io_apic_base_addr in io_apic_base_addrs {
+        let memory_region = MemoryRegion::new(io_apic_base_addr.as_usize(), 0x1000);
+        if let Err(_) = map_physical_region(memory_region, 0, frame_allocator) {
+            return Err("Insufficient physical memory for mapping apic registers");
+        }
     }
 
     Ok(())
 }
 
 // Remove first 2mb identity mapping
-fn remove_first_2mb_identity_mapping() {
+fn remove_first_2mb_identity_mapping(should_keep_trampoline: bool) {
     use x86_64::cpu::registers;
-    use memory::paging::{Table, TableEntry};
+    use memory::paging::{Table, TableEntry, Flags};
 
     let table4 = Table::table4();
     let table3 = if let Some(TableEntry::Table { table, .. }) = table4.get_entry(0) {
@@ -285,8 +456,17 @@ fn remove_first_2mb_identity_mapping() {
     else {
         unreachable!();
     };
-    // removes all mappings except 0x1000-0x8000 because of stack
+    // removes all mappings except 0x1000-0x8000 because of stack, which isn't executed
+    for i in 0..8 {
+        if let Some(TableEntry::Frame { address, flags }) = table1.get_entry(i) {
+            table1.set_entry(address, flags | Flags::NO_EXECUTE, i);
+        }
+    }
+    let trampoline_entry = x86_64::cpu::smp::TRAMPOLINE_ADDR as usize / 0x1000;
     for i in 8..512 {
+        if should_keep_trampoline && i == trampoline_entry {
+            continue;
+        }
         table1.remove_entry(i);
     }
 
@@ -296,26 +476,36 @@ fn remove_first_2mb_identity_mapping() {
 
 
 // This function is called on alloc error.
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("Allocation error: {:?}", layout)
 }
 
 
+#[cfg(not(test))]
 use core::panic::PanicInfo;
 
 // This function is called on panic.
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    use x86_64::{cpu::{self, smp}, interrupts::apic::lapic, structures::idt::Index};
+    use x86_64::{cpu::{self, smp, backtrace::{self, Registers}}, interrupts::apic::lapic, structures::idt::Index};
+
+    let registers = Registers::capture();
 
     cpu::instructions::cli();
 
     if smp::is_init() {
-        lapic::broadcast_ipi(Index::HALT);
+        // nothing else to do if it doesn't go through, this processor halts anyway
+        let _ = lapic::broadcast_ipi(Index::HALT);
     }
 
-    crate::video::logger::LOGGER.lock().clear_screen();
-    no_enable_irq_print_color!(video::color::RED, "{info}\n");
+    // the panic could come from inside a print on this processor, which still holds the locks
+    video::logger::emergency_clear_screen();
+    emergency_print_color!(video::color::RED, "{info}\n");
+    emergency_print!("\n{}\n", registers);
+    emergency_print!("\nBacktrace:\n");
+    backtrace::walk_stack(registers.rbp, |return_addr| emergency_print!("  {:#018x}\n", return_addr));
     loop { x86_64::cpu::instructions::hlt(); }
 }
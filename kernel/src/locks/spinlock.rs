@@ -5,8 +5,10 @@ use core::{
     ops::{Deref, DerefMut}
 };
 
+use crate::utils::backoff::Backoff;
 
-pub struct Spinlock<T> {
+
+pub struct Spinlock<T: ?Sized> {
     locked: AtomicBool,
     value: UnsafeCell<T>
 }
@@ -14,10 +16,14 @@ impl<T> Spinlock<T> {
     pub const fn new(value: T) -> Spinlock<T> {
         Spinlock { locked: AtomicBool::new(false), value: UnsafeCell::new(value) }
     }
-
+}
+// Can hold unsized values like trait objects, a &Spinlock<T> coerces to &Spinlock<dyn Trait>
+impl<T: ?Sized> Spinlock<T> {
+    // backs off while waiting, see Backoff::snooze
     pub fn lock(&self) -> SpinlockGuard<T> {
+        let mut backoff = Backoff::new();
         while self.locked.swap(true, Ordering::Acquire) {
-            spin_loop()
+            backoff.snooze();
         }
         SpinlockGuard::new(self)
     }
@@ -29,14 +35,46 @@ impl<T> Spinlock<T> {
         );
         SpinlockGuard::new(self)
     }
+
+    // doesn't wait, for code that can't block like interrupt handlers
+    pub fn try_lock(&self) -> Option<SpinlockGuard<T>> {
+        self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinlockGuard::new(self))
+    }
+
+    // only a hint, can have changed by the time it's used
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    // Pointer to the value, only safe to use while holding a guard that can't be reached (see vesa::lock_for_emergency)
+    pub fn as_ptr(&self) -> *mut T {
+        self.value.get()
+    }
+
+    /**
+     * Like "lock" but gives up waiting after max_tries and takes the lock anyway, only for crash
+     * paths: the holder can be the code interrupted on this same processor, which never gets to
+     * release it, and getting the message out matters more than the value being consistent.
+     */
+    pub unsafe fn lock_or_steal(&self, max_tries: usize) -> SpinlockGuard<T> {
+        for _ in 0..max_tries {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            spin_loop();
+        }
+        SpinlockGuard::new(self)
+    }
 }
 // The spinlock will guarantee only one thread can access the value at a time
-unsafe impl<T> Sync for Spinlock<T> where T: Send {}
+unsafe impl<T: ?Sized> Sync for Spinlock<T> where T: Send {}
 
-pub struct SpinlockGuard<'a, T> {
+pub struct SpinlockGuard<'a, T: ?Sized> {
     spinlock: &'a Spinlock<T>,
 }
-impl<T> SpinlockGuard<'_, T> {
+impl<T: ?Sized> SpinlockGuard<'_, T> {
     fn new(spinlock: &Spinlock<T>) -> SpinlockGuard<'_, T> {
         SpinlockGuard { spinlock }
     }
@@ -46,18 +84,18 @@ impl<T> SpinlockGuard<'_, T> {
     }
 }
 // Only one instance of SpinlockGuard can exist at a time, making these references safe
-impl<T> Deref for SpinlockGuard<'_, T> {
+impl<T: ?Sized> Deref for SpinlockGuard<'_, T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
         unsafe { &*self.spinlock.value.get() }
     }
 }
-impl<T> DerefMut for SpinlockGuard<'_, T> {
+impl<T: ?Sized> DerefMut for SpinlockGuard<'_, T> {
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.spinlock.value.get() }
     }
 }
-impl<T> Drop for SpinlockGuard<'_, T> {
+impl<T: ?Sized> Drop for SpinlockGuard<'_, T> {
     fn drop(&mut self) {
         self.spinlock.locked.store(false, Ordering::Release);
     }
@@ -0,0 +1,90 @@
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    cell::UnsafeCell,
+    hint::spin_loop,
+    ops::{Deref, DerefMut}
+};
+
+
+/*
+ * Spinlock handed out in the order it was asked for: each locker takes the next ticket and
+ * waits until it's the one being served, so no processor can keep winning the race to the lock
+ * like with the test-and-set in Spinlock. Costs an extra atomic and every waiter polls the same
+ * counter, Spinlock is still the better choice for locks that are rarely contended.
+ */
+pub struct TicketSpinlock<T> {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+    value: UnsafeCell<T>
+}
+impl<T> TicketSpinlock<T> {
+    pub const fn new(value: T) -> TicketSpinlock<T> {
+        TicketSpinlock {
+            next_ticket: AtomicUsize::new(0), now_serving: AtomicUsize::new(0), value: UnsafeCell::new(value)
+        }
+    }
+
+    pub fn lock(&self) -> TicketSpinlockGuard<T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop()
+        }
+        TicketSpinlockGuard::new(self)
+    }
+
+    // halts while waiting
+    pub fn lock_hlt(&self) -> TicketSpinlockGuard<T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        crate::x86_64::interrupts::hlt_wait(
+            || { self.now_serving.load(Ordering::Acquire) == ticket }
+        );
+        TicketSpinlockGuard::new(self)
+    }
+
+    // doesn't wait, only takes a ticket if it would be served right away
+    pub fn try_lock(&self) -> Option<TicketSpinlockGuard<T>> {
+        let now_serving = self.now_serving.load(Ordering::Relaxed);
+        self.next_ticket.compare_exchange(now_serving, now_serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| TicketSpinlockGuard::new(self))
+    }
+
+    // only a hint, can have changed by the time it's used
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+}
+// The spinlock will guarantee only one thread can access the value at a time
+unsafe impl<T> Sync for TicketSpinlock<T> where T: Send {}
+
+pub struct TicketSpinlockGuard<'a, T> {
+    spinlock: &'a TicketSpinlock<T>,
+}
+impl<T> TicketSpinlockGuard<'_, T> {
+    fn new(spinlock: &TicketSpinlock<T>) -> TicketSpinlockGuard<'_, T> {
+        TicketSpinlockGuard { spinlock }
+    }
+
+    pub fn unlock(self) {
+        drop(self);
+    }
+}
+// Only one instance of TicketSpinlockGuard can exist at a time, making these references safe
+impl<T> Deref for TicketSpinlockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.spinlock.value.get() }
+    }
+}
+impl<T> DerefMut for TicketSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.spinlock.value.get() }
+    }
+}
+// Serves the next ticket, only the holder writes now_serving
+impl<T> Drop for TicketSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        let now_serving = self.spinlock.now_serving.load(Ordering::Relaxed);
+        self.spinlock.now_serving.store(now_serving.wrapping_add(1), Ordering::Release);
+    }
+}
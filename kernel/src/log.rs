@@ -0,0 +1,109 @@
+use core::{fmt, sync::atomic::{AtomicU8, Ordering}};
+
+use crate::{cmdline, video::{color, logger}};
+
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+impl Level {
+    fn from_u8(value: u8) -> Option<Level> {
+        match value {
+            0 => Some(Level::Error),
+            1 => Some(Level::Warn),
+            2 => Some(Level::Info),
+            3 => Some(Level::Debug),
+            4 => Some(Level::Trace),
+            _ => None
+        }
+    }
+
+    // Accepts the level's name or number
+    fn parse(value: &str) -> Option<Level> {
+        match value {
+            "error" => Some(Level::Error),
+            "warn"  => Some(Level::Warn),
+            "info"  => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => value.parse::<u8>().ok().and_then(Level::from_u8)
+        }
+    }
+
+    fn color(&self) -> color::Color {
+        match self {
+            Level::Error => color::RED,
+            Level::Warn  => color::SAFETY_YELLOW,
+            _ => color::GREY
+        }
+    }
+}
+
+
+// Sets the level from "loglevel" in the kernel command line (Info by default)
+pub fn init() {
+    if let Some(value) = cmdline::get("loglevel") {
+        if let Some(level) = Level::parse(value) {
+            set_level(level);
+        }
+        else {
+            crate::warn!("WARNING: Invalid loglevel \"{}\" in kernel command line, ignoring it.", value);
+        }
+    }
+}
+
+// Messages less severe than level are suppressed
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed)).unwrap()
+}
+#[inline]
+pub fn is_enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn _log(level: Level, args: fmt::Arguments) {
+    logger::_print_color(level.color(), format_args!("{}\n", args));
+}
+
+
+// Log macros, the message is only formatted if its level is enabled:
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::is_enabled($level) {
+            $crate::log::_log($level, format_args!($($arg)*));
+        }
+    };
+}
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
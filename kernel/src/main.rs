@@ -3,7 +3,7 @@
 
 
 use kernel::{
-    BootloaderInfo, x86_64,
+    BootloaderInfo, drivers, x86_64,
     memory::address::PhysAddr,
     scheduler::{self, task::Task}
 };
@@ -19,13 +19,24 @@ fn _start() -> ! {
         panic!("Panicked during setup: {}", str);
     }
 
-    kernel::drivers::keyboard::init();
     let vbe_mode_info_addr = PhysAddr::new(bootloader_info.vesa_mode_info_addr as usize).to_virtual();
     let vbe_mode_info =  unsafe { &*vbe_mode_info_addr.as_ptr::<kernel::video::vesa::VBEModeInfo>() };
     let vga_bitmap_font_addr = PhysAddr::new(bootloader_info.vga_bitmap_font_addr as usize).to_virtual();
-    kernel::video::terminal::init(vbe_mode_info, vga_bitmap_font_addr, 100);
 
-    let terminal_task = Task::new(32768, kernel::video::terminal::terminal_task, None);
+    // the terminal reads its input from the keyboard
+    drivers::register("keyboard", &[], || { drivers::keyboard::init(); Ok(()) })
+        .expect("Failed to register keyboard driver");
+    drivers::register("terminal", &["keyboard"], move || {
+        kernel::video::terminal::init(vbe_mode_info, vga_bitmap_font_addr, 100, 1000);
+        Ok(())
+    }).expect("Failed to register terminal driver");
+    if let Err(str) = drivers::init_all() {
+        panic!("Panicked initializing drivers: {}", str);
+    }
+
+    let mut terminal_task = Task::new(32768, kernel::video::terminal::terminal_task, None);
+    // woken up by the keyboard handler, which runs where the keyboard IRQ is routed (the BSP)
+    terminal_task.set_affinity(Some(kernel::processor::bsp_id()));
     scheduler::add_task(terminal_task);
 
     scheduler::enable_preemption();
@@ -3,7 +3,7 @@ use core::{
     mem, fmt::Debug
 };
 
-use super::paging::{Table, TableEntry, TableLevel};
+use super::paging::{Table, TableEntry, TableLevel, Translation, TranslationStep, TranslationEnd};
 
 
 // virtual memory offset where physical memory is stored
@@ -81,6 +81,41 @@ pub trait VirtualAddress {
         table
     }
 
+    // Same walk as "to_phys" keeping every entry on the way, tables are in the physical memory mapping
+    fn translate(&self) -> Translation {
+        let mut translation = Translation {
+            steps: [None; 4], end: TranslationEnd::Unmapped(TableLevel::Four), phys_addr: None, present_entries: None
+        };
+
+        let mut table = Table::table4();
+        for step in translation.steps.iter_mut() {
+            let level = table.level;
+            let index = self.get_entry(level);
+            let entry_addr = unsafe { table.address.to_phys_direct() } + index*core::mem::size_of::<u64>();
+
+            match table.get_entry(index) {
+                None => {
+                    translation.end = TranslationEnd::Unmapped(level);
+                    translation.present_entries = Some(table.iter_present().count());
+                    break;
+                },
+                Some(TableEntry::Table { table: next_table, flags }) => {
+                    let address = unsafe { next_table.address.to_phys_direct() };
+                    *step = Some(TranslationStep { level, index, entry_addr, address, flags });
+                    table = next_table;
+                },
+                Some(TableEntry::Frame { address, flags }) => {
+                    *step = Some(TranslationStep { level, index, entry_addr, address, flags });
+                    translation.end = if level == TableLevel::One { TranslationEnd::Frame } else { TranslationEnd::HugePage(level) };
+                    translation.phys_addr = Some(address + self.get_offset(level));
+                    break;
+                }
+            }
+        }
+
+        translation
+    }
+
     fn to_phys(&self) -> Option<PhysAddr> {
         let table = self.get_table();
         let entry = self.get_entry(table.level);
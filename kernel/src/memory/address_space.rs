@@ -0,0 +1,314 @@
+use core::{
+    intrinsics::{volatile_copy_memory, volatile_set_memory}, mem,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering}
+};
+use alloc::vec::Vec;
+
+use super::{
+    FrameSize, dma, user,
+    address::{PhysAddr, VirtAddr, VirtualAddress},
+    paging::{self, Table, TableEntry, TableLevel}
+};
+use crate::{
+    locks::spinlock::Spinlock, processor, scheduler::task, utils::lazy_static::LazyStatic,
+    x86_64::{cpu::{instructions::{self, InvpcidKind}, registers::{cr3, cr4}}, interrupts::interrupts_disabled}
+};
+
+
+const TABLE4_ENTRY_LENGTH: usize = 0x80_00000000; // 512GB
+const CR3_ADDRESS_BITMASK: u64 = 0xFFFFFFFFFF000;
+const CR3_PCID_BITMASK: u64 = 0xFFF;
+
+const CPUID_FUNC_GET_MAX: u32 = 0;
+const CPUID_FUNC_GET_FEATURES: u32 = 1;
+const CPUID_FUNC_GET_EXTENDED_FEATURES: u32 = 7;
+const CPUID_GET_FEATURES_ECX_PCID_BIT: u32 = 1<<17;
+const CPUID_GET_EXTENDED_FEATURES_EBX_INVPCID_BIT: u32 = 1<<10;
+
+
+// CR3 of the tables set up by the bootloader and setup, tasks without their own address space run in them
+static KERNEL_CR3: LazyStatic<u64> = LazyStatic::new();
+
+/*
+ * With PCIDs the TLB keeps the entries of every address space switched to, tagged with its
+ * PCID, so switching back doesn't flush them. As long as they aren't unmapped the user pages
+ * an address space had in the TLB of a processor are still right when it's switched back to.
+ */
+static IS_PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static PCID_ALLOCATOR: Spinlock<PcidAllocator> = Spinlock::new(PcidAllocator::new());
+// Bumped when PCIDs of dropped address spaces are recycled, see "switch_to"
+static PCID_GENERATION: AtomicU64 = AtomicU64::new(0);
+const MAX_PCID: u16 = 0xFFF;
+
+
+/*
+ * Hands out PCIDs 1 to MAX_PCID, then the ones given back by dropped address spaces. Other
+ * processors could still have TLB entries tagged with those, so they're only handed out again
+ * after bumping PCID_GENERATION, which makes every processor flush them before switching.
+ * Address spaces created while none are left get PCID 0 (none), which is always flushed.
+ */
+struct PcidAllocator {
+    next: u16,
+    recycled: Vec<u16>, // free, processors flush them before they can be switched to
+    freed: Vec<u16> // since the last recycle, processors could still have them in the TLB
+}
+impl PcidAllocator {
+    const fn new() -> PcidAllocator {
+        PcidAllocator { next: 1, recycled: Vec::new(), freed: Vec::new() }
+    }
+
+    fn alloc(&mut self) -> u16 {
+        if self.next <= MAX_PCID {
+            self.next += 1;
+            return self.next - 1;
+        }
+
+        if self.recycled.is_empty() && !self.freed.is_empty() {
+            mem::swap(&mut self.recycled, &mut self.freed);
+            PCID_GENERATION.fetch_add(1, Ordering::Release);
+        }
+        self.recycled.pop().unwrap_or(0)
+    }
+
+    fn free(&mut self, pcid: u16) {
+        if pcid != 0 {
+            self.freed.push(pcid);
+        }
+    }
+}
+
+
+/**
+ * Has to be called before any address space is created, from the kernel's address space.
+ * Everything outside the user region is shared by referencing the same level 3 tables,
+ * so the level 4 entries of the kernel regions mapped in later (task stacks and DMA) are
+ * allocated up front, otherwise they'd only end up in the tables they were mapped from.
+ */
+pub fn init() -> Result<(), &'static str> {
+    init_pcid();
+    KERNEL_CR3.init(current_table4_addr().as_usize() as u64 | alloc_pcid() as u64);
+
+    paging::allocate_table4_entry(VirtAddr::new(task::STACKS_BASE))?;
+    for region_addr in (dma::DMA_BASE..dma::DMA_BASE + dma::DMA_LENGTH).step_by(TABLE4_ENTRY_LENGTH) {
+        paging::allocate_table4_entry(VirtAddr::new(region_addr))?;
+    }
+    Ok(())
+}
+
+/*
+ * Enables PCIDs on the current processor if it supports them, along with INVPCID which
+ * is needed to flush kernel pages from the TLB entries of every PCID when they're unmapped.
+ * CR4.PCIDE can only be set with PCID 0 loaded, which the tables were loaded with so far.
+ */
+pub fn init_pcid() {
+    if is_pcid_supported() {
+        cr3::write(cr3::read() & CR3_ADDRESS_BITMASK);
+        cr4::write(cr4::read() | cr4::FLAG_PCID);
+        IS_PCID_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+fn is_pcid_supported() -> bool {
+    use instructions::{cpuid, cpuid_subleaf};
+
+    if cpuid(CPUID_FUNC_GET_FEATURES).ecx & CPUID_GET_FEATURES_ECX_PCID_BIT == 0
+        || cpuid(CPUID_FUNC_GET_MAX).eax < CPUID_FUNC_GET_EXTENDED_FEATURES
+    {
+        return false;
+    }
+    cpuid_subleaf(CPUID_FUNC_GET_EXTENDED_FEATURES, 0).ebx & CPUID_GET_EXTENDED_FEATURES_EBX_INVPCID_BIT != 0
+}
+
+// 0 if PCIDs aren't enabled or ran out
+fn alloc_pcid() -> u16 {
+    if !IS_PCID_ENABLED.load(Ordering::Relaxed) {
+        return 0;
+    }
+    let mut pcid = 0;
+    interrupts_disabled(|| pcid = PCID_ALLOCATOR.lock().alloc());
+    pcid
+}
+fn free_pcid(pcid: u16) {
+    interrupts_disabled(|| PCID_ALLOCATOR.lock().free(pcid));
+}
+
+pub fn kernel_cr3() -> u64 {
+    *KERNEL_CR3
+}
+
+// Address of the level 4 table the current processor is using
+pub fn current_table4_addr() -> PhysAddr {
+    PhysAddr::new((cr3::read() & CR3_ADDRESS_BITMASK) as usize)
+}
+
+/*
+ * Loads the tables (and PCID) of cr3 unless they're already the current ones, so switching
+ * between tasks in the same address space doesn't touch the TLB. Switching to another one only
+ * flushes the TLB without PCIDs (or if it has none, see PcidAllocator).
+ */
+pub fn switch_to(cr3: u64) {
+    if cr3 & CR3_ADDRESS_BITMASK == current_table4_addr().as_usize() as u64 {
+        return;
+    }
+
+    // recycled PCIDs can still have entries of the address spaces they belonged to
+    let pcid_generation = PCID_GENERATION.load(Ordering::Acquire);
+    let processor_pcid_generation = processor::get().pcid_generation();
+    if *processor_pcid_generation != pcid_generation {
+        instructions::invpcid(InvpcidKind::ALL_CONTEXTS, 0, 0);
+        *processor_pcid_generation = pcid_generation;
+    }
+
+    if cr3 & CR3_PCID_BITMASK != 0 {
+        cr3::write(cr3 | cr3::FLAG_NO_FLUSH);
+    }
+    else {
+        cr3::write(cr3);
+    }
+}
+
+/*
+ * Invalidates the TLB entries of the pages of a kernel region after they're unmapped, on the
+ * current processor. They're the same in every address space so every PCID can have them.
+ */
+pub fn flush_kernel_pages(pages: impl Iterator<Item = usize>) {
+    if IS_PCID_ENABLED.load(Ordering::Relaxed) {
+        instructions::invpcid(InvpcidKind::ALL_CONTEXTS, 0, 0);
+    }
+    else {
+        pages.for_each(instructions::invlpg);
+    }
+}
+
+
+/**
+ * Tables of their own for the user region, the rest of the address space is the kernel's.
+ * Created by forking another one (or the kernel's), which copies every page mapped in the
+ * user region to a new frame. Nothing is shared so there's no copy-on-write to track.
+ * The user region's virtual addresses are handed out globally (see memory::user), so
+ * mappings made after the fork don't overlap between address spaces.
+ */
+pub struct AddressSpace {
+    table4_addr: PhysAddr,
+    pcid: u16
+}
+impl AddressSpace {
+    // Copies the user mappings of the kernel's tables, the ones shared by every task without its own
+    pub fn fork_kernel() -> Result<AddressSpace, &'static str> {
+        Self::fork_from(PhysAddr::new((kernel_cr3() & CR3_ADDRESS_BITMASK) as usize))
+    }
+    pub fn fork(&self) -> Result<AddressSpace, &'static str> {
+        Self::fork_from(self.table4_addr)
+    }
+
+    fn fork_from(src_table4_addr: PhysAddr) -> Result<AddressSpace, &'static str> {
+        let table4_addr = super::alloc_frame().ok_or("Insufficient physical memory for address space")?;
+        unsafe {
+            volatile_copy_memory(
+                table4_addr.to_mut_virtual().as_ptr::<u8>(), src_table4_addr.to_virtual().as_ptr::<u8>(),
+                FrameSize::FourKb.to_bytes()
+            );
+        }
+
+        let src_table4 = Table::new(src_table4_addr.to_virtual(), TableLevel::Four);
+        let mut table4 = Table::new(table4_addr.to_virtual(), TableLevel::Four);
+        for entry in user_table4_entries() {
+            table4.remove_entry(entry);
+        }
+        // from here on dropping it frees whatever was copied
+        let address_space = AddressSpace { table4_addr, pcid: alloc_pcid() };
+
+        for entry in user_table4_entries() {
+            if let Some(TableEntry::Table { table, flags }) = src_table4.get_entry(entry) {
+                let table_copy_addr = copy_table(&table)?;
+                table4.set_entry(table_copy_addr, flags, entry);
+            }
+        }
+
+        Ok(address_space)
+    }
+
+    pub fn table4_addr(&self) -> PhysAddr {
+        self.table4_addr
+    }
+    // Value CR3 is loaded with while a task in the address space executes
+    pub fn cr3(&self) -> u64 {
+        self.table4_addr.as_usize() as u64 | self.pcid as u64
+    }
+}
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        crate::kassert!(current_table4_addr() != self.table4_addr, "Dropped the address space in use");
+
+        let mut table4 = Table::new(self.table4_addr.to_virtual(), TableLevel::Four);
+        for entry in user_table4_entries() {
+            if let Some(TableEntry::Table { table, .. }) = table4.get_entry(entry) {
+                free_table(table);
+                table4.remove_entry(entry);
+            }
+        }
+        let _ = super::free_frame(self.table4_addr);
+        free_pcid(self.pcid);
+    }
+}
+impl core::fmt::Debug for AddressSpace {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "AddressSpace({:#x}, PCID {})", self.table4_addr.as_usize(), self.pcid)
+    }
+}
+
+
+fn user_table4_entries() -> core::ops::RangeInclusive<usize> {
+    let first = user::USER_BASE / TABLE4_ENTRY_LENGTH;
+    let last = (user::USER_BASE + user::USER_LENGTH - 1) / TABLE4_ENTRY_LENGTH;
+    first..=last
+}
+
+// Copies the table, the tables under it and the pages they map to new frames, on failure frees the copy
+fn copy_table(src: &Table) -> Result<PhysAddr, &'static str> {
+    let copy_addr = super::alloc_frame().ok_or("Insufficient physical memory for table copy")?;
+    let mut copy = Table::new(copy_addr.to_virtual(), src.level);
+    unsafe { volatile_set_memory(copy_addr.to_mut_virtual().as_ptr::<u8>(), 0, FrameSize::FourKb.to_bytes()); }
+
+    for entry in 0..512 {
+        let result = match src.get_entry(entry) {
+            None => Ok(()),
+            Some(TableEntry::Table { table, flags }) => copy_table(&table)
+                .map(|table_copy_addr| copy.set_entry(table_copy_addr, flags, entry)),
+            Some(TableEntry::Frame { address, flags }) => copy_frame(src.level, address)
+                .map(|frame_copy_addr| copy.set_entry(frame_copy_addr, flags, entry))
+        };
+        if let Err(err) = result {
+            free_table(copy);
+            return Err(err);
+        }
+    }
+
+    Ok(copy_addr)
+}
+// Only 4KB pages are mapped in the user region
+fn copy_frame(level: TableLevel, frame_addr: PhysAddr) -> Result<PhysAddr, &'static str> {
+    if level != TableLevel::One {
+        return Err("Huge page in the user region can't be copied");
+    }
+
+    let copy_addr = super::alloc_frame().ok_or("Insufficient physical memory for page copy")?;
+    unsafe {
+        volatile_copy_memory(
+            copy_addr.to_mut_virtual().as_ptr::<u8>(), frame_addr.to_virtual().as_ptr::<u8>(),
+            FrameSize::FourKb.to_bytes()
+        );
+    }
+    Ok(copy_addr)
+}
+
+// Gives back the table, the tables under it and the pages they map
+fn free_table(table: Table) {
+    for entry in 0..512 {
+        match table.get_entry(entry) {
+            Some(TableEntry::Table { table, .. }) => free_table(table),
+            Some(TableEntry::Frame { address, .. }) => { let _ = super::free_frame(address); },
+            None => {}
+        }
+    }
+    let _ = super::free_frame(unsafe { table.address.to_phys_direct() });
+}
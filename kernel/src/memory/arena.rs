@@ -0,0 +1,75 @@
+use core::{alloc::{AllocError, Allocator, Layout}, cell::Cell, ptr::NonNull};
+use alloc::alloc::{alloc, dealloc};
+
+
+const ARENA_ALIGN: usize = 16;
+
+
+/**
+ * Allocator for short lived data (e.g. while parsing a table) that hands out memory from a
+ * single heap block by bumping an offset, everything is freed at once with "reset" or when
+ * the arena is dropped. Only freeing the last allocation gives its space back before that.
+ * Used through the allocator API, e.g. Vec::new_in(&arena).
+ */
+pub struct BumpArena {
+    buffer: NonNull<u8>,
+    capacity: usize,
+    offset: Cell<usize> // from the start of the buffer to the free space
+}
+impl BumpArena {
+    pub fn new(capacity: usize) -> Result<BumpArena, &'static str> {
+        assert!(capacity > 0, "Attempted to create an empty arena");
+
+        let layout = Self::buffer_layout(capacity)?;
+        let buffer = NonNull::new(unsafe { alloc(layout) }).ok_or("Insufficient memory for arena")?;
+        Ok(BumpArena { buffer, capacity, offset: Cell::new(0) })
+    }
+
+    /*
+     * Frees every allocation, taking it mutably makes sure nothing allocated
+     * from it (which borrows the arena) is still around
+     */
+    pub fn reset(&mut self) {
+        self.offset.set(0);
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    pub fn used(&self) -> usize {
+        self.offset.get()
+    }
+
+    fn buffer_layout(capacity: usize) -> Result<Layout, &'static str> {
+        Layout::from_size_align(capacity, ARENA_ALIGN).map_err(|_| "Invalid arena capacity")
+    }
+}
+impl Drop for BumpArena {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.buffer.as_ptr(), Self::buffer_layout(self.capacity).unwrap()); }
+    }
+}
+unsafe impl Allocator for BumpArena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let buffer_addr = self.buffer.as_ptr() as usize;
+
+        // aligned by address since allocations can be more aligned than the buffer
+        let start_addr = super::align_up(buffer_addr + self.offset.get(), layout.align());
+        let end_offset = (start_addr - buffer_addr).checked_add(layout.size()).ok_or(AllocError)?;
+        if end_offset > self.capacity {
+            return Err(AllocError);
+        }
+        self.offset.set(end_offset);
+
+        let ptr = unsafe { self.buffer.as_ptr().add(start_addr - buffer_addr) };
+        Ok(NonNull::slice_from_raw_parts(unsafe { NonNull::new_unchecked(ptr) }, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        // the last allocation can be given back right away, the rest waits for reset
+        let end_offset = ptr.as_ptr() as usize + layout.size() - self.buffer.as_ptr() as usize;
+        if end_offset == self.offset.get() {
+            self.offset.set(end_offset - layout.size());
+        }
+    }
+}
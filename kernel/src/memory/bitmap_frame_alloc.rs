@@ -0,0 +1,154 @@
+use alloc::vec::Vec;
+
+use super::{FrameAllocator, FrameSize, address::PhysAddr, align_down, align_up, is_aligned, e820_memory_map::MemoryMap};
+
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+
+/**
+ * Frame allocator keeping a bit per 4KB frame (set if in use) from the first to the last
+ * usable frame of the memory map, frames in between that aren't usable are always set.
+ * Unlike the linear allocator any frame given back can be handed out again and runs of
+ * consecutive frames are found by scanning, so contiguous allocations keep working once
+ * frames are freed out of order. The bitmap is on the heap, see "from_linear".
+ */
+pub struct BitmapFrameAllocator {
+    base: usize, // address of the frame of the first bit
+    frame_count: usize,
+    bitmap: Vec<u64>,
+    next_word: usize // where the search for a single frame starts
+}
+impl BitmapFrameAllocator {
+    // Every frame starts in use, bits past frame_count stay that way
+    fn new(memory_map: &MemoryMap) -> Result<BitmapFrameAllocator, &'static str> {
+        let frame_size = FrameSize::FourKb.to_bytes();
+
+        let base = memory_map.iter_usable()
+            .map(|entry| align_down(entry.base as usize, frame_size))
+            .min()
+            .ok_or("Memory map has no usable memory")?;
+        let end = memory_map.iter_usable()
+            .map(|entry| align_up((entry.base + entry.length) as usize, frame_size))
+            .max()
+            .unwrap();
+        let frame_count = (end - base) / frame_size;
+
+        let word_count = align_up(frame_count, BITS_PER_WORD) / BITS_PER_WORD;
+        let mut bitmap = Vec::new();
+        bitmap.try_reserve_exact(word_count).map_err(|_| "Insufficient heap memory for the frame bitmap")?;
+        bitmap.resize(word_count, u64::MAX);
+
+        Ok(BitmapFrameAllocator { base, frame_count, bitmap, next_word: 0 })
+    }
+
+    /**
+     * Takes over from the linear allocator: the usable frames it hasn't reached yet and the
+     * ones in its free list are free, everything it went past is assumed in use.
+     */
+    pub fn from_linear(linear: &FrameAllocator) -> Result<BitmapFrameAllocator, &'static str> {
+        let frame_size = FrameSize::FourKb.to_bytes();
+        assert_eq!(linear.frame_size.to_bytes(), frame_size, "Bitmap frame allocator only takes over 4KB frames");
+
+        let mut allocator = Self::new(linear.memory_map)?;
+
+        let next_frame_addr = linear.next_frame_addr.as_usize();
+        for entry in linear.memory_map.iter_usable() {
+            let mut frame_addr = core::cmp::max(align_up(entry.base as usize, frame_size), next_frame_addr);
+            let end = align_down((entry.base + entry.length) as usize, frame_size);
+            while frame_addr < end {
+                allocator.set_in_use(allocator.index_of(frame_addr), false);
+                frame_addr += frame_size;
+            }
+        }
+        for frame_addr in &linear.free_frames[..linear.free_frames_len] {
+            allocator.free(*frame_addr)?;
+        }
+
+        Ok(allocator)
+    }
+
+    pub fn alloc(&mut self) -> Option<PhysAddr> {
+        let word_count = self.bitmap.len();
+        for i in 0..word_count {
+            let word_index = (self.next_word + i) % word_count;
+            let word = self.bitmap[word_index];
+            if word != u64::MAX {
+                let index = word_index*BITS_PER_WORD + word.trailing_ones() as usize;
+                self.set_in_use(index, true);
+                self.next_word = word_index;
+                return Some(self.frame_addr(index));
+            }
+        }
+
+        None
+    }
+
+    // Takes count consecutive frames starting at a multiple of align (at least the frame size)
+    pub fn alloc_contiguous(&mut self, count: usize, align: usize) -> Option<PhysAddr> {
+        let align = core::cmp::max(align, FrameSize::FourKb.to_bytes());
+        if count == 0 {
+            return None;
+        }
+
+        let mut index = 0;
+        loop {
+            index = self.index_of(align_up(self.frame_addr(index).as_usize(), align));
+            if index + count > self.frame_count {
+                return None;
+            }
+
+            // checking from the end of the run lets the search skip past the last frame in use
+            match (index..index + count).rev().find(|&i| self.is_in_use(i)) {
+                Some(in_use_index) => index = in_use_index + 1,
+                None => {
+                    for i in index..index + count {
+                        self.set_in_use(i, true);
+                    }
+                    return Some(self.frame_addr(index));
+                }
+            }
+        }
+    }
+
+    pub fn free(&mut self, frame_addr: PhysAddr) -> Result<(), &'static str> {
+        assert!(is_aligned(frame_addr.as_usize(), FrameSize::FourKb.to_bytes()), "Freed frame isn't aligned to the frame size");
+
+        if frame_addr.as_usize() < self.base || self.index_of(frame_addr.as_usize()) >= self.frame_count {
+            return Err("Freed frame is outside of usable memory");
+        }
+        let index = self.index_of(frame_addr.as_usize());
+        if !self.is_in_use(index) {
+            return Err("Freed frame isn't in use");
+        }
+
+        self.set_in_use(index, false);
+        self.next_word = core::cmp::min(self.next_word, index / BITS_PER_WORD);
+        Ok(())
+    }
+
+    // Frames not in use, counts every bit so it takes a while with a lot of memory
+    pub fn free_count(&self) -> usize {
+        self.bitmap.iter().map(|word| word.count_zeros() as usize).sum()
+    }
+
+    fn index_of(&self, frame_addr: usize) -> usize {
+        (frame_addr - self.base) / FrameSize::FourKb.to_bytes()
+    }
+    fn frame_addr(&self, index: usize) -> PhysAddr {
+        PhysAddr::new(self.base + index*FrameSize::FourKb.to_bytes())
+    }
+
+    fn is_in_use(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+    fn set_in_use(&mut self, index: usize, is_in_use: bool) {
+        let bit = 1 << (index % BITS_PER_WORD);
+        if is_in_use {
+            self.bitmap[index / BITS_PER_WORD] |= bit;
+        }
+        else {
+            self.bitmap[index / BITS_PER_WORD] &= !bit;
+        }
+    }
+}
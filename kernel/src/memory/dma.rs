@@ -0,0 +1,69 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{
+    FrameSize, MemoryRegion, align_up,
+    address::{PhysAddr, VirtAddr},
+    paging::{self, Flags}
+};
+use crate::x86_64::cpu::registers::pat;
+
+
+pub const DMA_BASE: usize = 0x1300_00000000;
+pub const DMA_LENGTH: usize = 0x100_00000000;
+
+
+// Address space isn't reused after a buffer is freed, there's plenty of it
+static NEXT_DMA_ADDR: AtomicUsize = AtomicUsize::new(DMA_BASE);
+
+
+/**
+ * Memory type of a buffer's mapping, devices that don't snoop the caches need UNCACHED (or
+ * WRITE_COMBINING for buffers only written by the processor, see registers::pat, which is
+ * uncached if there's no PAT).
+ * The frames stay write back in the physical memory mapping, which must not be used for them.
+ */
+pub struct CacheMode;
+impl CacheMode {
+    pub const WRITE_BACK: u64 = 0;
+    pub const WRITE_THROUGH: u64 = Flags::WRITE_THROUGH;
+    pub const UNCACHED: u64 = Flags::NO_CACHE | Flags::WRITE_THROUGH;
+    pub const WRITE_COMBINING: u64 = Flags::PAT;
+}
+
+
+/**
+ * Allocates a buffer of physically consecutive frames starting at a multiple of align
+ * and maps it with the cache mode, returns its virtual and physical addresses.
+ * The size is rounded up to whole pages.
+ */
+pub fn alloc_contiguous(size: usize, align: usize, cache_mode: u64) -> Result<(VirtAddr, PhysAddr), &'static str> {
+    assert!(size > 0, "Attempted to allocate an empty DMA buffer");
+
+    let page_size = FrameSize::FourKb.to_bytes();
+    let length = align_up(size, page_size);
+
+    let virt_base = NEXT_DMA_ADDR.fetch_add(length, Ordering::Relaxed);
+    if virt_base + length > DMA_BASE + DMA_LENGTH {
+        return Err("DMA address space exhausted");
+    }
+
+    let phys_base = super::alloc_contiguous_frames(length / page_size, align)
+        .ok_or("Insufficient contiguous physical memory for DMA buffer")?;
+
+    // the PAT flag is reserved without a PAT
+    let cache_mode = if cache_mode == CacheMode::WRITE_COMBINING && !pat::is_supported() { CacheMode::UNCACHED } else { cache_mode };
+
+    // on failure the frames are given back
+    paging::map_region_to(
+        &MemoryRegion::new(virt_base, length), phys_base,
+        Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE | cache_mode
+    )?;
+
+    Ok((VirtAddr::new(virt_base), phys_base))
+}
+
+// Unmaps a buffer from "alloc_contiguous" and gives its frames back, the device must be done with it
+pub fn free(virt_addr: VirtAddr, size: usize) {
+    let length = align_up(size, FrameSize::FourKb.to_bytes());
+    paging::unmap_region(&MemoryRegion::new(virt_addr.as_usize(), length));
+}
@@ -1,15 +1,21 @@
 use core::mem;
 
-use super::address::PhysAddr;
+use crate::utils::stack_vec::StackVec;
+use super::{address::PhysAddr, MemoryRegion};
+
+
+pub const MAX_NUM_OF_RESERVED_REGIONS: usize = 32;
+// As many as fit in the page the bootloader stores the memory map in, after the size
+pub const MAX_NUM_OF_ENTRIES: usize = 0xFF0/mem::size_of::<MemoryMapEntry>();
 
 
 // Creates reserved entry for kernel map, sorts entries and align RAM entries to 4KB
 pub fn init(memory_map: &mut MemoryMap, kernel_base: usize, kernel_len: usize) -> Result<(), &'static str> {
     use crate::memory;
-    use super::{FrameSize, MemoryRegion};
+    use super::FrameSize;
 
     // get memory map entry that contains kernel elf
-    let mut kernel_entry_index = memory_map.size as usize;
+    let mut kernel_entry_index = memory_map.len();
     for (i, entry) in memory_map.iter().enumerate() {
         let entry_region = MemoryRegion::from_e820_entry(entry);
         if entry_region.is_within(kernel_base, kernel_len) {
@@ -17,7 +23,7 @@ pub fn init(memory_map: &mut MemoryMap, kernel_base: usize, kernel_len: usize) -
             break;
         }
     }
-    if kernel_entry_index == memory_map.size as usize {
+    if kernel_entry_index == memory_map.len() {
         return Err("Error with E820 Memory Map, perhaps lack of memory?");
     }
 
@@ -29,7 +35,8 @@ pub fn init(memory_map: &mut MemoryMap, kernel_base: usize, kernel_len: usize) -
             (entry.base as usize).into(), kernel_base as u64 - entry.base,
             MemoryMapRegionType::Ram
         );
-        memory_map.add_entry(prev_entry, kernel_entry_index);
+        memory_map.add_entry(prev_entry, kernel_entry_index)
+            .map_err(|_| "E820 Memory Map has no space left to split the kernel entry")?;
         kernel_entry_index += 1;
     }
     // entry after kernel (if exists)
@@ -39,7 +46,8 @@ pub fn init(memory_map: &mut MemoryMap, kernel_base: usize, kernel_len: usize) -
             (kernel_base+kernel_len).into(), entry.base+entry.length-(kernel_base+kernel_len) as u64,
             MemoryMapRegionType::Ram
         );
-        memory_map.add_entry(next_entry, kernel_entry_index+1);
+        memory_map.add_entry(next_entry, kernel_entry_index+1)
+            .map_err(|_| "E820 Memory Map has no space left to split the kernel entry")?;
     }
     // kernel entry
     let entry = &mut memory_map.entries[kernel_entry_index];
@@ -64,10 +72,18 @@ pub fn init(memory_map: &mut MemoryMap, kernel_base: usize, kernel_len: usize) -
 #[repr(C, packed)]
 pub struct MemoryMap {
     size: u32,
-    entries: [MemoryMapEntry; 0xFF0/(mem::size_of::<u64>()*3)]
+    entries: [MemoryMapEntry; MAX_NUM_OF_ENTRIES]
 }
 impl MemoryMap {
-    pub fn add_entry(&mut self, entry: MemoryMapEntry, index: usize) {
+    // Inserts the entry at index, shifting the ones after it
+    pub fn add_entry(&mut self, entry: MemoryMapEntry, index: usize) -> Result<(), &'static str> {
+        if self.len() >= MAX_NUM_OF_ENTRIES {
+            return Err("Memory map is full");
+        }
+        if index > self.len() {
+            return Err("Memory map index out of bounds");
+        }
+
         let mut prev_entry = entry;
         for entry in self.iter_mut().skip(index) {
             let temp = *entry;
@@ -76,6 +92,14 @@ impl MemoryMap {
         }
         self.entries[self.size as usize] = prev_entry;
         self.size += 1;
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.size as usize
+    }
+    pub fn get(&self, index: usize) -> Option<&MemoryMapEntry> {
+        self.entries[..self.len()].get(index)
     }
 
     // Sorts entries in ascending order of base address
@@ -88,14 +112,25 @@ impl MemoryMap {
     }
     pub fn iter_usable(&self) -> impl Iterator<Item = &MemoryMapEntry> {
         let iter = MemoryMapIterator { memory_map: self, index: 0 };
-        iter.filter(|e| (*e).region_type == MemoryMapRegionType::Ram as u32)
+        iter.filter(|e| e.is_usable())
     }
     pub fn iter_mut(&mut self) -> MemoryMapMutIterator {
         MemoryMapMutIterator { memory_map: self, index: 0 }
     }
     pub fn iter_mut_usable(&mut self) -> impl Iterator<Item = &mut MemoryMapEntry>{
         let iter = MemoryMapMutIterator { memory_map: self, index: 0 };
-        iter.filter(|e| (*e).region_type == MemoryMapRegionType::Ram as u32)
+        iter.filter(|e| e.is_usable())
+    }
+
+    // Returns regions not usable as RAM (includes kernel elf), doesn't require the heap
+    pub fn reserved_regions(&self) -> StackVec<MemoryRegion, MAX_NUM_OF_RESERVED_REGIONS> {
+        let mut reserved_regions = StackVec::new();
+        for entry in self.iter().filter(|e| (*e).region_type != MemoryMapRegionType::Ram as u32) {
+            if let Err(_) = reserved_regions.push(MemoryRegion::from_e820_entry(entry)) {
+                break;
+            }
+        }
+        reserved_regions
     }
 }
 impl<'a> IntoIterator for &'a MemoryMap {
@@ -155,6 +190,13 @@ pub enum MemoryMapRegionType {
     AcpiNvs,
     Unusable
 }
+// ACPI 3.0 extended attributes, the bootloader sets ENABLED for BIOSes that only return 20 bytes
+pub struct ExtendedAttributes;
+impl ExtendedAttributes {
+    pub const ENABLED: u32 = 1<<0; // the entry should be ignored if clear
+    pub const NON_VOLATILE: u32 = 1<<1;
+}
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct MemoryMapEntry {
@@ -165,6 +207,20 @@ pub struct MemoryMapEntry {
 }
 impl MemoryMapEntry {
     pub fn new(base: PhysAddr, length: u64, region_type: MemoryMapRegionType) -> MemoryMapEntry {
-        MemoryMapEntry { base: base.as_usize() as u64, length, region_type: region_type as u32, extended_attributes: 1 }
+        MemoryMapEntry {
+            base: base.as_usize() as u64, length, region_type: region_type as u32,
+            extended_attributes: ExtendedAttributes::ENABLED
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.extended_attributes & ExtendedAttributes::ENABLED != 0
+    }
+    pub fn is_non_volatile(&self) -> bool {
+        self.extended_attributes & ExtendedAttributes::NON_VOLATILE != 0
+    }
+    // RAM that isn't marked to be ignored
+    pub fn is_usable(&self) -> bool {
+        self.region_type == MemoryMapRegionType::Ram as u32 && self.is_enabled()
     }
 }
@@ -9,6 +9,14 @@ pub const HEAP_BASE: usize = 0x1100_00000000;
 pub const HEAP_LENGTH: usize = 0xA00000; // 10 MBs
 
 
+// Upper bound of the frames taken by "init_heap", for the heap and the tables mapping it
+pub fn frames_needed() -> usize {
+    let frames = HEAP_LENGTH / FrameSize::FourKb.to_bytes();
+    let level1_tables = super::align_up(HEAP_LENGTH, FrameSize::TwoMb.to_bytes()) / FrameSize::TwoMb.to_bytes();
+    let level2_tables = super::align_up(HEAP_LENGTH, FrameSize::OneGb.to_bytes()) / FrameSize::OneGb.to_bytes();
+    frames + level1_tables + level2_tables + 1 // and a level 3 table
+}
+
 pub fn init_heap(frame_allocator: &mut FrameAllocator) -> Result<(), &'static str> {
     // allocate tables for heap
     let memory_region = MemoryRegion::new(HEAP_BASE, HEAP_LENGTH);
@@ -33,7 +41,7 @@ pub fn init_heap(frame_allocator: &mut FrameAllocator) -> Result<(), &'static st
             else {
                 return Err("Insufficient physical memory for heap");
             };
-            table.set_entry(phys_frame_addr, Flags::PRESENT | Flags::WRITABLE, virt_addr.get_entry(table.level))
+            table.set_entry(phys_frame_addr, Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE, virt_addr.get_entry(table.level))
         }
     }
 
@@ -47,7 +55,8 @@ pub fn init_heap(frame_allocator: &mut FrameAllocator) -> Result<(), &'static st
 use crate::locks::spinlock::Spinlock;
 use self::fixed_size_block_alloc::FixedSizeBlockAllocator;
 
-#[global_allocator]
+// the host's in unit tests
+#[cfg_attr(not(test), global_allocator)]
 static ALLOCATOR: Spinlock<FixedSizeBlockAllocator> = Spinlock::new(FixedSizeBlockAllocator::new());
 
 
@@ -77,10 +86,30 @@ pub mod fixed_size_block_alloc {
             self.fallback.init(heap_base, heap_length);
         }
 
+        /*
+         * Blocks are aligned to their size, so a block at least as big as both the size and
+         * the alignment satisfies both, anything aligned to more than the largest block
+         * goes to the fallback allocator
+         */
         fn get_index(layout: Layout) -> Option<usize> {
             let required_block_size = layout.size().max(layout.align());
             BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
         }
+        fn block_layout(index: usize) -> Layout {
+            Layout::from_size_align(BLOCK_SIZES[index], BLOCK_SIZES[index]).unwrap()
+        }
+
+        // Allocates a new block for the size at index with the fallback
+        unsafe fn alloc_block(&mut self, index: usize) -> *mut u8 {
+            let ret = self.fallback.alloc(FixedSizeBlockAllocator::block_layout(index));
+
+            // since the smallest region the fallback can allocate is 16 bytes separate 8 byte blocks in 2
+            assert!(mem::size_of::<ListNode>() == 16 && mem::size_of::<BlockNode>() == 8);
+            if ret != ptr::null_mut() && BLOCK_SIZES[index] == 8 {
+                self.add_block_node((ret as *mut BlockNode).add(1), index);
+            }
+            ret
+        }
 
         // if possible scraps blocks to make space for the layout
         fn scrap_free_blocks(&mut self, layout: Layout) -> Result<(), ()>{
@@ -118,17 +147,7 @@ pub mod fixed_size_block_alloc {
                     ret = node as *mut BlockNode as *mut u8;
                 }
                 else {
-                    // allocate a block for this size with fallback
-                    let block_size = BLOCK_SIZES[index];
-                    // align will be updated by fallback allocator
-                    let layout = Layout::from_size_align(block_size, 1).unwrap();
-                    ret = allocator.fallback.alloc(layout);
-
-                    // since the smallest region the fallback can allocate is 16 bytes separate 8 byte blocks in 2
-                    assert!(mem::size_of::<ListNode>() == 16 && mem::size_of::<BlockNode>() == 8);
-                    if ret != ptr::null_mut() && BLOCK_SIZES[index] == 8 {
-                        allocator.add_block_node((ret as *mut BlockNode).add(1), index);
-                    }
+                    ret = allocator.alloc_block(index);
                 }
             }
             else {
@@ -137,9 +156,12 @@ pub mod fixed_size_block_alloc {
 
             // if alloc failed
             if ret == ptr::null_mut() {
-                // try to scrap free blocks and alloc again
+                // try to scrap free blocks and alloc again, as a block if it's freed as one
                 if let Ok(_) = allocator.scrap_free_blocks(layout) {
-                    return allocator.fallback.alloc(layout);
+                    return match FixedSizeBlockAllocator::get_index(layout) {
+                        Some(index) => allocator.alloc_block(index),
+                        None => allocator.fallback.alloc(layout)
+                    };
                 }
             }
 
@@ -160,6 +182,30 @@ pub mod fixed_size_block_alloc {
                 allocator.fallback.dealloc(ptr, layout);
             }
         }
+
+        /*
+         * Keeps the allocation where it is if it stays in the same block size, or if neither
+         * size fits a block and the fallback can resize it in place, otherwise moves it
+         */
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+            let index = FixedSizeBlockAllocator::get_index(layout);
+            let new_index = FixedSizeBlockAllocator::get_index(new_layout);
+            if index.is_some() && index == new_index {
+                return ptr;
+            }
+            if index.is_none() && new_index.is_none() && self.lock().fallback.resize_in_place(ptr, layout, new_layout) {
+                return ptr;
+            }
+
+            let new_ptr = self.alloc(new_layout);
+            if new_ptr != ptr::null_mut() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            new_ptr
+        }
     }
 
 
@@ -238,9 +284,18 @@ pub mod fixed_size_block_alloc {
             None
         }
 
+        /*
+         * The space left before the aligned start is given back as its own region,
+         * so it has to be either empty or able to hold a Node
+         */
         fn alloc_from_region(region: &ListNode, length: usize, align: usize) -> Result<MutVirtAddr, ()>
         {
-            let alloc_start_addr: MutVirtAddr = memory::align_up(region.start_addr().as_usize(), align).into();
+            let region_start_addr = region.start_addr().as_usize();
+            let mut alloc_start_addr = memory::align_up(region_start_addr, align);
+            if alloc_start_addr != region_start_addr && alloc_start_addr - region_start_addr < mem::size_of::<ListNode>() {
+                alloc_start_addr = memory::align_up(region_start_addr + mem::size_of::<ListNode>(), align);
+            }
+            let alloc_start_addr: MutVirtAddr = alloc_start_addr.into();
             let alloc_end_addr: VirtAddr = alloc_start_addr.as_usize().checked_add(length).expect("Overflow").into();
 
             // if region is too small
@@ -265,11 +320,19 @@ pub mod fixed_size_block_alloc {
             let size = layout.size();
             let align = layout.align();
             if let Some((region, alloc_start_addr)) = self.find_region(size, align) {
+                let region_start_addr = region.start_addr().as_usize();
+                let region_end_addr = region.end_addr().as_usize();
+
                 let alloc_end_addr: MutVirtAddr = alloc_start_addr.as_usize().checked_add(size).unwrap().into();
-                let excess_size = region.end_addr().as_usize() - alloc_end_addr;
+                let excess_size = region_end_addr - alloc_end_addr;
                 if excess_size > 0 {
                     self.add_free_region(alloc_end_addr, excess_size);
                 }
+                // the node is overwritten here, so only after reading it
+                let padding_size = alloc_start_addr.as_usize() - region_start_addr;
+                if padding_size > 0 {
+                    self.add_free_region(MutVirtAddr::new(region_start_addr), padding_size);
+                }
                 alloc_start_addr.as_ptr::<u8>()
             } else {
                 ptr::null_mut()
@@ -280,5 +343,52 @@ pub mod fixed_size_block_alloc {
             let layout = LinkedListAllocator::adjust_layout(layout);
             self.add_free_region(MutVirtAddr::new(ptr as usize), layout.size());
         }
+
+        /**
+         * Shrinks the allocation giving back its end or grows it into the free region right
+         * after it, returns false if it can't without leaving a gap too small to hold a Node
+         */
+        pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_layout: Layout) -> bool {
+            let size = LinkedListAllocator::adjust_layout(layout).size();
+            let new_size = LinkedListAllocator::adjust_layout(new_layout).size();
+            let end_addr = ptr as usize + size;
+            let new_end_addr = ptr as usize + new_size;
+
+            if new_size <= size {
+                if new_size == size {
+                    return true;
+                }
+                if size - new_size < mem::size_of::<ListNode>() {
+                    return false;
+                }
+                self.add_free_region(MutVirtAddr::new(new_end_addr), size - new_size);
+                return true;
+            }
+
+            let mut current = &mut self.head;
+            while let Some(ref mut region) = current.next {
+                if region.start_addr().as_usize() != end_addr {
+                    current = current.next.as_mut().unwrap();
+                    continue;
+                }
+
+                let region_end_addr = region.end_addr().as_usize();
+                if region_end_addr < new_end_addr
+                    || (region_end_addr != new_end_addr && region_end_addr - new_end_addr < mem::size_of::<ListNode>())
+                {
+                    return false;
+                }
+
+                // take the region out of the list and give back what isn't needed
+                let next = region.next.take();
+                current.next = next;
+                if region_end_addr > new_end_addr {
+                    self.add_free_region(MutVirtAddr::new(new_end_addr), region_end_addr - new_end_addr);
+                }
+                return true;
+            }
+
+            false
+        }
     }
 }
@@ -0,0 +1,97 @@
+use super::{
+    FrameSize, MemoryRegion,
+    address::{PhysAddr, VirtAddr, VirtualAddress},
+    paging::{Flags, TableEntry, TableLevel}
+};
+use crate::x86_64::cpu::registers;
+
+
+// ELF section header flags
+struct SectionFlags;
+impl SectionFlags {
+    const WRITE: u64 = 1;
+    const ALLOC: u64 = 2;
+    const EXECINSTR: u64 = 4;
+}
+
+#[derive(Clone, Copy)]
+struct Section {
+    addr: usize,
+    size: usize,
+    flags: u64
+}
+impl Section {
+    fn region(&self) -> MemoryRegion {
+        MemoryRegion::new(self.addr, self.size)
+    }
+}
+
+
+/**
+ * Goes over the sections of the kernel ELF (still where the bootloader loaded it) and makes
+ * the pages of the ones that aren't writable read-only in the active tables, executable only
+ * if the section is code. The bootloader maps by segment, which can put read-only data in
+ * pages writable at runtime.
+ * A page shared with a writable section is left as is, as is anything mapped with a huge page
+ * since it could hold anything. The TLB is only flushed on the current processor.
+ */
+pub fn protect(kernel_elf_addr: PhysAddr) -> Result<(), &'static str> {
+    let kernel_elf = kernel_elf_addr.to_virtual();
+    if unsafe { *kernel_elf.as_ptr::<u32>() } != 0x464C457F {
+        return Err("Kernel ELF invalid");
+    }
+
+    for section in sections(kernel_elf).filter(|s| s.flags & SectionFlags::WRITE == 0) {
+        for page in section.region().iter(FrameSize::FourKb) {
+            let page_region = MemoryRegion::new(page, FrameSize::FourKb.to_bytes());
+
+            let (mut is_writable, mut is_executable) = (false, false);
+            for other in sections(kernel_elf).filter(|s| s.region().overlaps(&page_region)) {
+                is_writable |= other.flags & SectionFlags::WRITE != 0;
+                is_executable |= other.flags & SectionFlags::EXECINSTR != 0;
+            }
+            if !is_writable {
+                set_read_only(VirtAddr::new(page), is_executable);
+            }
+        }
+    }
+
+    registers::cr3::flush_tlb();
+    Ok(())
+}
+
+// Allocated sections (the ones in memory at runtime) of the kernel ELF
+fn sections(kernel_elf: VirtAddr) -> impl Iterator<Item = Section> {
+    let (e_shoff, e_shentsize, e_shnum) = unsafe {(
+        *kernel_elf.as_ptr::<u64>().add(5) as usize,
+        *kernel_elf.as_ptr::<u16>().add(29) as usize,
+        *kernel_elf.as_ptr::<u16>().add(30) as usize
+    )};
+
+    (0..e_shnum)
+        .map(move |i| unsafe {
+            let sheader = kernel_elf.offset::<u8>(e_shoff + i*e_shentsize).as_ptr::<u64>();
+            Section {
+                flags: *sheader.add(1),
+                addr: *sheader.add(2) as usize,
+                size: *sheader.add(4) as usize
+            }
+        })
+        .filter(|s| s.flags & SectionFlags::ALLOC != 0 && s.size != 0)
+}
+
+fn set_read_only(virt_addr: VirtAddr, is_executable: bool) {
+    let mut table = virt_addr.get_table();
+    if table.level != TableLevel::One {
+        return;
+    }
+
+    let entry = virt_addr.get_entry(TableLevel::One);
+    if let Some(TableEntry::Frame { address, flags }) = table.get_entry(entry) {
+        let mut flags = flags & !(Flags::WRITABLE | Flags::NO_EXECUTE);
+        if !is_executable {
+            flags |= Flags::NO_EXECUTE;
+        }
+        table.set_entry(address, flags, entry);
+    }
+}
@@ -1,11 +1,31 @@
 use address::PhysAddr;
 use e820_memory_map::MemoryMap;
+use bitmap_frame_alloc::BitmapFrameAllocator;
+
+use crate::{locks::spinlock::Spinlock, utils::lazy_static::LazyStatic, x86_64::interrupts::interrupts_disabled};
 
 
 pub mod address;
 pub mod e820_memory_map;
 pub mod paging;
 pub mod kalloc;
+pub mod arena;
+pub mod dma;
+pub mod slab;
+pub mod kernel_sections;
+pub mod bitmap_frame_alloc;
+pub mod user;
+pub mod usercopy;
+pub mod address_space;
+
+
+const FREE_FRAMES_CAPACITY: usize = 512; // frames freed past it are lost
+
+
+// Allocator left over from setup, used for frames needed after it
+static FRAME_ALLOCATOR: LazyStatic<Spinlock<FrameAllocator<'static>>> = LazyStatic::new();
+// Takes over from the one above if switched to with "switch_to_bitmap_frame_allocator"
+static BITMAP_FRAME_ALLOCATOR: LazyStatic<Spinlock<BitmapFrameAllocator>> = LazyStatic::new();
 
 
 // Aligns value down to bytes
@@ -25,6 +45,74 @@ pub fn align_up(mut value: usize, bytes: usize) -> usize {
 }
 
 
+pub fn init_frame_allocator(frame_allocator: FrameAllocator<'static>) {
+    FRAME_ALLOCATOR.init(Spinlock::new(frame_allocator));
+}
+/*
+ * Moves frame allocation over to a bitmap built from the state of the frame allocator,
+ * needs the heap. Has to be called before the APs are started since it isn't synchronized
+ * with allocations from the frame allocator going on at the same time.
+ */
+pub fn switch_to_bitmap_frame_allocator() -> Result<(), &'static str> {
+    let mut result = Ok(());
+    interrupts_disabled(|| {
+        let frame_allocator = FRAME_ALLOCATOR.lock();
+        result = BitmapFrameAllocator::from_linear(&frame_allocator)
+            .map(|bitmap_frame_allocator| BITMAP_FRAME_ALLOCATOR.init(Spinlock::new(bitmap_frame_allocator)));
+    });
+    result
+}
+pub fn alloc_frame() -> Option<PhysAddr> {
+    let mut frame = None;
+    interrupts_disabled(|| frame = match BITMAP_FRAME_ALLOCATOR.try_get() {
+        Some(bitmap_frame_allocator) => bitmap_frame_allocator.lock().alloc(),
+        None => FRAME_ALLOCATOR.lock().get_next_frame()
+    });
+    frame
+}
+pub fn free_frame(frame_addr: PhysAddr) -> Result<(), &'static str> {
+    let mut result = Ok(());
+    interrupts_disabled(|| result = match BITMAP_FRAME_ALLOCATOR.try_get() {
+        Some(bitmap_frame_allocator) => bitmap_frame_allocator.lock().free(frame_addr),
+        None => FRAME_ALLOCATOR.lock().free_frame(frame_addr)
+    });
+    result
+}
+/**
+ * Checks that the physical memory mapping covers every usable region by reading the first
+ * byte of each 2MB frame in it (or of the region if it doesn't start on one) through the
+ * mapping and translating the virtual address back. Touches a page per 2MB of RAM.
+ */
+pub fn verify_physical_mapping(memory_map: &MemoryMap) -> Result<(), &'static str> {
+    use address::VirtualAddress;
+
+    for entry in memory_map.iter_usable() {
+        let region = MemoryRegion::from_e820_entry(entry);
+        for frame in region.iter(FrameSize::TwoMb) {
+            let phys_addr = PhysAddr::new(core::cmp::max(frame, region.base()));
+            let virt_addr = phys_addr.to_virtual();
+
+            if virt_addr.to_phys() != Some(phys_addr) {
+                return Err("Physical memory mapping doesn't translate back to the same address");
+            }
+            unsafe { core::ptr::read_volatile(virt_addr.as_ptr::<u8>()); }
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the first of count physically consecutive frames, aligned to align
+pub fn alloc_contiguous_frames(count: usize, align: usize) -> Option<PhysAddr> {
+    let mut frame = None;
+    interrupts_disabled(|| frame = match BITMAP_FRAME_ALLOCATOR.try_get() {
+        Some(bitmap_frame_allocator) => bitmap_frame_allocator.lock().alloc_contiguous(count, align),
+        None => FRAME_ALLOCATOR.lock().get_contiguous_frames(count, align)
+    });
+    frame
+}
+
+
 #[derive(Clone, Copy)]
 pub enum FrameSize {
     FourKb,
@@ -54,10 +142,20 @@ impl MemoryRegion {
         MemoryRegion::new(entry.base as usize, entry.length as usize)
     }
 
+    pub fn base(&self) -> usize {
+        self.base
+    }
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
     // Whether given region is within self
     pub fn is_within(&self, base: usize, length: usize) -> bool {
         base >= self.base && base + length <= self.base + self.length
     }
+    pub fn overlaps(&self, other: &MemoryRegion) -> bool {
+        self.base < other.base + other.length && other.base < self.base + self.length
+    }
 
     pub fn iter(&self, frame_size: FrameSize) -> MemoryRegionIterator {
         MemoryRegionIterator::new(self.base, self.length, frame_size, 0)
@@ -103,12 +201,17 @@ impl Iterator for MemoryRegionIterator {
 }
 
 
-// Simple allocator that takes frames linearly from RAM memory map entries
+/**
+ * Simple allocator that takes frames linearly from RAM memory map entries,
+ * frames given back are kept in a small free list and handed out first.
+ */
 pub struct FrameAllocator<'a> {
     memory_map: &'a MemoryMap,
     next_frame_addr: address::PhysAddr,
     frame_size: FrameSize,
-    cur_entry: usize
+    cur_entry: usize,
+    free_frames: [PhysAddr; FREE_FRAMES_CAPACITY],
+    free_frames_len: usize
 }
 impl<'a> FrameAllocator<'a> {
     pub fn new(memory_map: &'a MemoryMap, next_frame_addr: PhysAddr, frame_size: FrameSize) -> FrameAllocator<'a> {
@@ -121,10 +224,18 @@ impl<'a> FrameAllocator<'a> {
             }
         }
 
-        FrameAllocator { memory_map, next_frame_addr, frame_size, cur_entry }
+        FrameAllocator {
+            memory_map, next_frame_addr, frame_size, cur_entry,
+            free_frames: [PhysAddr::new(0); FREE_FRAMES_CAPACITY], free_frames_len: 0
+        }
     }
 
     pub fn get_next_frame(&mut self) -> Option<PhysAddr> {
+        if self.free_frames_len > 0 {
+            self.free_frames_len -= 1;
+            return Some(self.free_frames[self.free_frames_len]);
+        }
+
         for (i, entry) in self.memory_map.iter_usable().enumerate().skip(self.cur_entry) {
             if self.next_frame_addr < entry.base as usize {
                 self.next_frame_addr = (entry.base as usize).into();
@@ -141,4 +252,73 @@ impl<'a> FrameAllocator<'a> {
 
         None
     }
+
+    /**
+     * Takes count consecutive frames starting at a multiple of align (at least the frame size),
+     * always from the memory map since the free list isn't sorted. Frames passed over to get
+     * there are put in the free list, as many as fit.
+     */
+    pub fn get_contiguous_frames(&mut self, count: usize, align: usize) -> Option<PhysAddr> {
+        let frame_size = self.frame_size.to_bytes();
+        let align = core::cmp::max(align, frame_size);
+        let length = count.checked_mul(frame_size)?;
+
+        for (i, entry) in self.memory_map.iter_usable().enumerate().skip(self.cur_entry) {
+            let entry_base = align_up(entry.base as usize, frame_size);
+            let entry_end = align_down((entry.base + entry.length) as usize, frame_size);
+            let search_start = core::cmp::max(self.next_frame_addr.as_usize(), entry_base);
+            let start = align_up(search_start, align);
+
+            if start >= entry_end || entry_end - start < length {
+                continue;
+            }
+
+            // whatever is left of the entries passed over, then the gap left by the alignment
+            let memory_map = self.memory_map;
+            for passed_entry in memory_map.iter_usable().skip(self.cur_entry).take(i - self.cur_entry) {
+                let passed_start = core::cmp::max(self.next_frame_addr.as_usize(), passed_entry.base as usize);
+                self.free_frames_between(passed_start, (passed_entry.base + passed_entry.length) as usize);
+            }
+            self.free_frames_between(search_start, start);
+
+            self.next_frame_addr = PhysAddr::new(start + length);
+            self.cur_entry = i;
+            return Some(PhysAddr::new(start));
+        }
+
+        None
+    }
+    fn free_frames_between(&mut self, start: usize, end: usize) {
+        let frame_size = self.frame_size.to_bytes();
+        let mut frame_addr = align_up(start, frame_size);
+        while frame_addr + frame_size <= end && self.free_frame(PhysAddr::new(frame_addr)).is_ok() {
+            frame_addr += frame_size;
+        }
+    }
+
+    // Frame must be of the allocator's frame size and not in use anymore
+    pub fn free_frame(&mut self, frame_addr: PhysAddr) -> Result<(), &'static str> {
+        assert!(is_aligned(frame_addr.as_usize(), self.frame_size.to_bytes()), "Freed frame isn't aligned to the frame size");
+
+        if self.free_frames_len == FREE_FRAMES_CAPACITY {
+            return Err("Frame allocator free list is full");
+        }
+        self.free_frames[self.free_frames_len] = frame_addr;
+        self.free_frames_len += 1;
+        Ok(())
+    }
+
+    // Estimate of the frames left, entries after the current one are counted as if aligned to the frame size
+    pub fn remaining_estimate(&self) -> usize {
+        let frame_size = self.frame_size.to_bytes();
+        let next_frame_addr: usize = self.next_frame_addr.into();
+
+        self.memory_map.iter_usable().skip(self.cur_entry)
+            .map(|entry| {
+                let start = core::cmp::max(entry.base as usize, next_frame_addr);
+                let end = (entry.base + entry.length) as usize;
+                end.saturating_sub(start) / frame_size
+            })
+            .sum::<usize>() + self.free_frames_len
+    }
 }
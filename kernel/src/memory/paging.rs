@@ -1,7 +1,24 @@
 use super::{
-    FrameSize, MemoryRegion, FrameAllocator,
+    FrameSize, MemoryRegion, FrameAllocator, e820_memory_map::MemoryMap,
     address::{PhysAddr, VirtualAddress, VirtAddr, MutVirtAddr},
 };
+use crate::{locks::spinlock::Spinlock, x86_64::{cpu::instructions, interrupts::interrupts_disabled}};
+
+
+// Taken while walking and changing the tables, the kernel's part of them is shared by every processor
+static TABLES_LOCK: Spinlock<()> = Spinlock::new(());
+
+// Executes given closure with the tables locked and interrupts disabled to avoid deadlock
+fn with_tables_locked<F, R>(closure: F) -> R
+    where F: FnOnce() -> R
+{
+    let mut result = None;
+    interrupts_disabled(|| {
+        let _guard = TABLES_LOCK.lock();
+        result = Some(closure());
+    });
+    result.unwrap()
+}
 
 
 // Allocates tables for virtual memory region // FIXME: ONLY FOR 4KB FOR NOW
@@ -35,6 +52,263 @@ pub fn allocate_tables(frame_allocator: &mut FrameAllocator, memory_region: &Mem
 }
 
 
+/**
+ * Maps every 4KB page of the region to a new frame taken from the global frame allocator,
+ * along with any table missing on the way. Fails without mapping anything if a page is
+ * already mapped, and unmaps what it mapped if it runs out of frames.
+ */
+pub fn map_region(memory_region: &MemoryRegion, flags: u64) -> Result<(), &'static str> {
+    with_tables_locked(|| map_region_locked(memory_region, None, flags))
+}
+/*
+ * Same as "map_region" but maps the pages to the consecutive frames starting at phys_addr,
+ * which are given back to the frame allocator by "unmap_region" like any other.
+ * On failure all of them are given back.
+ */
+pub fn map_region_to(memory_region: &MemoryRegion, phys_addr: PhysAddr, flags: u64) -> Result<(), &'static str> {
+    with_tables_locked(|| map_region_locked(memory_region, Some(phys_addr), flags))
+}
+fn map_region_locked(memory_region: &MemoryRegion, phys_addr: Option<PhysAddr>, flags: u64) -> Result<(), &'static str> {
+    if memory_region.iter(FrameSize::FourKb).any(|page| VirtAddr::new(page).to_phys().is_some()) {
+        return Err("Page in region already mapped");
+    }
+
+    for (i, page) in memory_region.iter(FrameSize::FourKb).enumerate() {
+        let frame_addr = phys_addr.map(|phys_addr| phys_addr + i*FrameSize::FourKb.to_bytes());
+        if let Err(err) = map_page(VirtAddr::new(page), frame_addr, flags) {
+            unmap_region_locked(&MemoryRegion::new(memory_region.base(), i*FrameSize::FourKb.to_bytes()));
+            if let Some(frame_addr) = frame_addr {
+                for unmapped_frame in MemoryRegion::new(frame_addr.as_usize(), memory_region.length() - i*FrameSize::FourKb.to_bytes()).iter(FrameSize::FourKb) {
+                    let _ = super::free_frame(PhysAddr::new(unmapped_frame));
+                }
+            }
+            return Err(err);
+        }
+    }
+
+    Ok(())
+}
+/*
+ * Maps the page to frame_addr or, if None, to a new frame.
+ * With the USER flag every table on the way to the page allows user access too,
+ * the pages under them keep whatever they had.
+ */
+fn map_page(virt_addr: VirtAddr, frame_addr: Option<PhysAddr>, flags: u64) -> Result<(), &'static str> {
+    if flags & Flags::USER != 0 {
+        allow_user_access_to_tables(virt_addr);
+    }
+
+    let mut table = virt_addr.get_table();
+    while table.level != TableLevel::One {
+        let entry = virt_addr.get_entry(table.level);
+        if let Some(TableEntry::Frame { .. }) = table.get_entry(entry) {
+            return Err("Page in region is part of a huge page");
+        }
+
+        let phys_frame_addr = super::alloc_frame().ok_or("Insufficient physical memory for table allocation")?;
+        unsafe {
+            table.map_table_at(phys_frame_addr.to_mut_virtual(), Flags::PRESENT | Flags::WRITABLE | flags & Flags::USER, entry);
+        }
+        table = Table::new(phys_frame_addr.to_virtual(), table.level.get_next_level().unwrap());
+    }
+
+    let phys_frame_addr = match frame_addr {
+        Some(frame_addr) => frame_addr,
+        None => super::alloc_frame().ok_or("Insufficient physical memory for region")?
+    };
+    table.set_entry(phys_frame_addr, flags, virt_addr.get_entry(TableLevel::One));
+    Ok(())
+}
+
+// Allocates the level 3 table of the level 4 entry virt_addr is under, unless it's there already
+pub fn allocate_table4_entry(virt_addr: VirtAddr) -> Result<(), &'static str> {
+    with_tables_locked(|| allocate_table4_entry_locked(virt_addr))
+}
+fn allocate_table4_entry_locked(virt_addr: VirtAddr) -> Result<(), &'static str> {
+    let mut table4 = Table::table4();
+    let entry = virt_addr.get_entry(TableLevel::Four);
+    if table4.get_entry(entry).is_some() {
+        return Ok(());
+    }
+
+    let phys_frame_addr = super::alloc_frame().ok_or("Insufficient physical memory for table allocation")?;
+    unsafe {
+        table4.map_table_at(phys_frame_addr.to_mut_virtual(), Flags::PRESENT | Flags::WRITABLE, entry);
+    }
+    Ok(())
+}
+
+fn allow_user_access_to_tables(virt_addr: VirtAddr) {
+    let mut table = Table::table4();
+    loop {
+        let entry = virt_addr.get_entry(table.level);
+        let Some(TableEntry::Table { table: next_table, flags }) = table.get_entry(entry) else {
+            break;
+        };
+        if flags & Flags::USER == 0 {
+            let next_table_addr = unsafe { next_table.address.to_phys_direct() };
+            table.set_entry(next_table_addr, flags | Flags::USER, entry);
+        }
+        table = next_table;
+    }
+}
+
+/*
+ * Unmaps the 4KB pages of the region mapped with "map_region" and gives their frames back,
+ * tables are kept. The TLB is only flushed on the current processor, for the current address
+ * space if the region is in the user one or for all of them otherwise.
+ */
+pub fn unmap_region(memory_region: &MemoryRegion) {
+    with_tables_locked(|| unmap_region_locked(memory_region));
+}
+fn unmap_region_locked(memory_region: &MemoryRegion) {
+    let is_user_region = super::user::is_user_range(memory_region.base(), memory_region.length());
+
+    for page in memory_region.iter(FrameSize::FourKb) {
+        let virt_addr = VirtAddr::new(page);
+        let mut table = virt_addr.get_table();
+        if table.level != TableLevel::One {
+            continue;
+        }
+
+        let entry = virt_addr.get_entry(TableLevel::One);
+        if let Some(TableEntry::Frame { address, .. }) = table.get_entry(entry) {
+            table.remove_entry(entry);
+            if is_user_region {
+                instructions::invlpg(page);
+            }
+            // the free list only holds so many, the rest is lost until reclaim is smarter
+            let _ = super::free_frame(address);
+        }
+    }
+
+    if !is_user_region {
+        super::address_space::flush_kernel_pages(memory_region.iter(FrameSize::FourKb));
+    }
+}
+
+/**
+ * Walks every mapping of the current tables looking for a page that is both writable and
+ * executable. The entries on the way count too: a page is only writable if all of them are
+ * and only executable if none has NO_EXECUTE (EFER.NXE is set by the bootloader).
+ * Pages overlapping one of the exceptions are skipped. Returns the first one found.
+ */
+pub fn find_writable_executable(exceptions: &[MemoryRegion]) -> Option<VirtAddr> {
+    find_writable_executable_in(&Table::table4(), 0, Flags::WRITABLE, exceptions)
+}
+fn find_writable_executable_in(table: &Table, table_base: usize, inherited_flags: u64, exceptions: &[MemoryRegion])
+    -> Option<VirtAddr>
+{
+    for (i, entry) in table.iter_present() {
+        let address = entry_virt_addr(table, table_base, i);
+
+        let (next_table, flags) = match entry {
+            TableEntry::Table { table, flags } => (Some(table), flags),
+            TableEntry::Frame { flags, .. } => (None, flags)
+        };
+        let flags = (inherited_flags & flags & Flags::WRITABLE) | ((inherited_flags | flags) & Flags::NO_EXECUTE);
+
+        let found = match next_table {
+            Some(next_table) => find_writable_executable_in(&next_table, address, flags, exceptions),
+            None => {
+                let page = MemoryRegion::new(address, entry_length(table.level));
+                let is_exception = exceptions.iter().any(|exception| exception.overlaps(&page));
+                if flags == Flags::WRITABLE && !is_exception { Some(VirtAddr::new(address)) } else { None }
+            }
+        };
+        if found.is_some() {
+            return found;
+        }
+    }
+
+    None
+}
+
+/**
+ * Walks every present entry of the current tables checking what mapping bugs would break:
+ * tables are in memory the memory map knows about (frames aren't checked, they can be MMIO),
+ * only level 3 and 2 entries are huge pages and reserved bits are clear, which are the address
+ * bits past the processor's physical address width and the low address bits of huge pages.
+ * Every violation is logged, fails if there was any.
+ */
+pub fn check_invariants(memory_map: &MemoryMap) -> Result<(), &'static str> {
+    let reserved_addr_bits = Table::ADRESS_BITMASK & !((1u64 << max_phys_addr_bits()) - 1);
+    match check_invariants_in(&Table::table4(), 0, memory_map, reserved_addr_bits) {
+        0 => Ok(()),
+        _ => Err("Page tables break invariants")
+    }
+}
+// Returns the number of violations found
+fn check_invariants_in(table: &Table, table_base: usize, memory_map: &MemoryMap, reserved_addr_bits: u64) -> usize {
+    let mut violations = 0;
+    let mut nested_violations = 0;
+    let mut report = |i: usize, violation: &str| {
+        crate::error!("{:?} table at {:#x}, entry {} (mapping {:#x}): {}",
+            table.level, unsafe { table.address.to_phys_direct().as_usize() }, i,
+            entry_virt_addr(table, table_base, i), violation);
+        violations += 1;
+    };
+
+    for (i, entry) in table.iter_present() {
+        if entry.address().as_usize() as u64 & reserved_addr_bits != 0 {
+            report(i, "address past the physical address width");
+        }
+
+        match entry {
+            TableEntry::Table { flags, .. } if flags & Flags::HUGE != 0 => report(i, "huge page at level 4"),
+            TableEntry::Table { table: next_table, .. } => {
+                let table_addr = unsafe { next_table.address.to_phys_direct() }.as_usize();
+                let is_in_memory_map = memory_map.iter()
+                    .any(|entry| MemoryRegion::from_e820_entry(entry).is_within(table_addr, FrameSize::FourKb.to_bytes()));
+                if !is_in_memory_map {
+                    report(i, "table outside of the memory map");
+                    continue;
+                }
+                let next_table_base = entry_virt_addr(table, table_base, i);
+                nested_violations += check_invariants_in(&next_table, next_table_base, memory_map, reserved_addr_bits);
+            },
+            TableEntry::Frame { address, .. } if table.level != TableLevel::One => {
+                // bit 12 is the PAT bit of huge pages
+                let reserved_low_bits = (entry_length(table.level) as u64 - 1) & !0x1FFF;
+                if address.as_usize() as u64 & reserved_low_bits != 0 {
+                    report(i, "huge page address isn't aligned");
+                }
+            },
+            TableEntry::Frame { .. } => {}
+        }
+    }
+
+    violations + nested_violations
+}
+
+// MAXPHYADDR, 36 if the processor doesn't report it
+fn max_phys_addr_bits() -> u32 {
+    use crate::x86_64::cpu::instructions::cpuid;
+
+    const CPUID_FUNC_GET_MAX_EXTENDED: u32 = 0x80000000;
+    const CPUID_FUNC_GET_ADDRESS_SIZES: u32 = 0x80000008;
+
+    if cpuid(CPUID_FUNC_GET_MAX_EXTENDED).eax < CPUID_FUNC_GET_ADDRESS_SIZES {
+        return 36;
+    }
+    cpuid(CPUID_FUNC_GET_ADDRESS_SIZES).eax & 0xFF
+}
+
+// Bytes of the address space mapped by each entry of a table of level
+fn entry_length(level: TableLevel) -> usize {
+    match level.get_frame_size() {
+        Some(frame_size) => frame_size.to_bytes(),
+        None => 512*FrameSize::OneGb.to_bytes()
+    }
+}
+// Virtual address mapped by the entry of a table mapping from table_base
+fn entry_virt_addr(table: &Table, table_base: usize, entry: usize) -> usize {
+    let address = table_base + entry*entry_length(table.level);
+    // upper half addresses are sign extended
+    if table.level == TableLevel::Four && entry >= 256 { address | 0xFFFF_0000_00000000 } else { address }
+}
+
+
 #[non_exhaustive]
 pub struct Flags;
 impl Flags {
@@ -46,11 +320,13 @@ impl Flags {
     pub const ACCESSED: u64 = 32;
     pub const DIRTY: u64 = 64;
     pub const HUGE: u64 = 128;
+    pub const PAT: u64 = 128; // in level 1 entries, which can't be huge
+    pub const HUGE_PAT: u64 = 0x1000; // in level 2 and 3 entries that are huge pages
     pub const GLOBAL: u64 = 256;
     pub const NO_EXECUTE: u64 = 0x8000000000000000;
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum TableLevel {
     Four,
     Three,
@@ -77,10 +353,63 @@ impl TableLevel {
     }
 }
 
+/**
+ * Path taken translating a virtual address, for diagnostics: the entry read at each level
+ * (in order from level 4, until translation stopped) and where it stopped.
+ */
+pub struct Translation {
+    pub steps: [Option<TranslationStep>; 4],
+    pub end: TranslationEnd,
+    pub phys_addr: Option<PhysAddr>,
+    // in the table translation stopped at if unmapped there, tells an empty table from a sparse one
+    pub present_entries: Option<usize>
+}
+#[derive(Clone, Copy)]
+pub struct TranslationStep {
+    pub level: TableLevel,
+    pub index: usize,
+    pub entry_addr: PhysAddr, // of the entry itself
+    pub address: PhysAddr, // of the next table or the frame
+    pub flags: u64
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranslationEnd {
+    Unmapped(TableLevel), // at the entry of the level
+    HugePage(TableLevel),
+    Frame
+}
+impl core::fmt::Display for Translation {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for step in self.steps.iter().flatten() {
+            writeln!(f, "{:?}[{}] at {:#x}: {:#x} flags {:#x}",
+                step.level, step.index, step.entry_addr.as_usize(), step.address.as_usize(), step.flags)?;
+        }
+        match (self.phys_addr, self.present_entries) {
+            (Some(phys_addr), _) => write!(f, "{:?} -> {:#x}", self.end, phys_addr.as_usize()),
+            (None, Some(present_entries)) => write!(f, "{:?} ({} present entries in the table)", self.end, present_entries),
+            (None, None) => write!(f, "{:?}", self.end)
+        }
+    }
+}
+
 pub enum TableEntry {
     Table{ table: Table, flags: u64 },
     Frame{ address: PhysAddr, flags: u64 }
 }
+impl TableEntry {
+    // Of the next table or the frame
+    pub fn address(&self) -> PhysAddr {
+        match self {
+            TableEntry::Table { table, .. } => unsafe { table.address.to_phys_direct() },
+            TableEntry::Frame { address, .. } => *address
+        }
+    }
+    pub fn flags(&self) -> u64 {
+        match self {
+            TableEntry::Table { flags, .. } | TableEntry::Frame { flags, .. } => *flags
+        }
+    }
+}
 
 pub struct Table {
     pub address: VirtAddr,
@@ -94,10 +423,8 @@ impl Table {
         Table { address, level }
     }
     pub fn table4() -> Table {
-        use crate::x86_64::cpu::registers;
-
         Table {
-            address: PhysAddr::new(registers::cr3::read() as usize).to_virtual(),
+            address: super::address_space::current_table4_addr().to_virtual(),
             level: TableLevel::Four
         }
     }
@@ -119,14 +446,24 @@ impl Table {
                 let next_level = self.level.get_next_level().unwrap();
                 Some(TableEntry::Table { table: Table::new(address.to_virtual(), next_level), flags })
             }
-            // if table has huge page bit on
+            // if table has huge page bit on, whose PAT bit is in the address bits
             else {
-                Some(TableEntry::Frame { address, flags })
+                let pat_flag = address.as_usize() as u64 & Flags::HUGE_PAT;
+                let address = PhysAddr::new(address.as_usize() & !(Flags::HUGE_PAT as usize));
+                Some(TableEntry::Frame { address, flags: flags | pat_flag })
             }
         }
     }
+    // Index and entry of every present entry in order, entries that are set but not present are left out
+    pub fn iter_present(&self) -> impl Iterator<Item = (usize, TableEntry)> + '_ {
+        (0..512).filter_map(|i| match self.get_entry(i) {
+            Some(entry) if entry.flags() & Flags::PRESENT != 0 => Some((i, entry)),
+            _ => None
+        })
+    }
+
     fn get_entry_raw(&self, entry: usize) -> (PhysAddr, u64) {
-        let entry_value = unsafe { *self.address.as_ptr::<u64>().add(entry) };
+        let entry_value = unsafe { self.address.as_ptr::<u64>().add(entry).read_volatile() };
         let address = (entry_value & Self::ADRESS_BITMASK) as usize;
         let flags = entry_value & Self::FLAGS_BITMASK;
 
@@ -0,0 +1,131 @@
+use core::{
+    marker::PhantomData, mem, ops::{Deref, DerefMut}, ptr::{self, NonNull}
+};
+
+use crate::{locks::spinlock::Spinlock, x86_64::interrupts::interrupts_disabled};
+use super::FrameSize;
+
+
+const SLAB_LENGTH: usize = 4096;
+
+
+/**
+ * Cache of T sized slots carved out of whole frames, for objects created and destroyed often
+ * so they don't fragment the heap. Freed slots go in a free list threaded through them and
+ * are handed out first, frames are never given back so it only grows to the most objects
+ * alive at once. Meant to be a static, with "alloc" returning an owning pointer to the slot.
+ */
+pub struct SlabCache<T> {
+    inner: Spinlock<SlabCacheInner>,
+    phantom: PhantomData<T>
+}
+impl<T> SlabCache<T> {
+    // slots have to fit a free list link and keep T aligned one after the other
+    const SLOT_ALIGN: usize = if mem::align_of::<T>() > mem::align_of::<FreeSlot>() { mem::align_of::<T>() }
+                              else { mem::align_of::<FreeSlot>() };
+    const SLOT_SIZE: usize = {
+        let size = if mem::size_of::<T>() > mem::size_of::<FreeSlot>() { mem::size_of::<T>() }
+                   else { mem::size_of::<FreeSlot>() };
+        (size + Self::SLOT_ALIGN - 1) / Self::SLOT_ALIGN * Self::SLOT_ALIGN
+    };
+
+    pub const fn new() -> SlabCache<T> {
+        SlabCache {
+            inner: Spinlock::new(SlabCacheInner { free_list: ptr::null_mut(), slab_count: 0 }),
+            phantom: PhantomData
+        }
+    }
+
+    pub fn alloc(&'static self, value: T) -> Result<SlabBox<T>, &'static str> {
+        assert!(Self::SLOT_SIZE <= SLAB_LENGTH, "Slab cache type doesn't fit in a slab");
+
+        let mut slot = ptr::null_mut();
+        let mut result = Ok(());
+        interrupts_disabled(|| {
+            let mut inner = self.inner.lock();
+            if inner.free_list.is_null() {
+                result = inner.add_slab(Self::SLOT_SIZE);
+            }
+            if result.is_ok() {
+                slot = inner.free_list;
+                inner.free_list = unsafe { (*slot).next };
+            }
+        });
+        result?;
+
+        let slot_ptr = slot as *mut T;
+        unsafe { slot_ptr.write(value); }
+        Ok(SlabBox { ptr: unsafe { NonNull::new_unchecked(slot_ptr) }, cache: self })
+    }
+
+    // Number of frames taken so far
+    pub fn slab_count(&self) -> usize {
+        let mut slab_count = 0;
+        interrupts_disabled(|| slab_count = self.inner.lock().slab_count);
+        slab_count
+    }
+
+    // Value must have been dropped already
+    fn free(&self, slot_ptr: *mut T) {
+        let slot = slot_ptr as *mut FreeSlot;
+        interrupts_disabled(|| {
+            let mut inner = self.inner.lock();
+            unsafe { slot.write(FreeSlot { next: inner.free_list }); }
+            inner.free_list = slot;
+        });
+    }
+}
+
+
+struct FreeSlot {
+    next: *mut FreeSlot
+}
+
+struct SlabCacheInner {
+    free_list: *mut FreeSlot,
+    slab_count: usize
+}
+// the slots are only reached through the lock
+unsafe impl Send for SlabCacheInner {}
+impl SlabCacheInner {
+    // Takes a frame and puts all its slots in the free list
+    fn add_slab(&mut self, slot_size: usize) -> Result<(), &'static str> {
+        let frame_addr = super::alloc_frame().ok_or("Insufficient physical memory for slab")?;
+        assert!(FrameSize::FourKb.to_bytes() == SLAB_LENGTH);
+
+        let slab_addr = frame_addr.to_mut_virtual().as_usize();
+        for slot_addr in (slab_addr..slab_addr + SLAB_LENGTH - slot_size + 1).step_by(slot_size).rev() {
+            let slot = slot_addr as *mut FreeSlot;
+            unsafe { slot.write(FreeSlot { next: self.free_list }); }
+            self.free_list = slot;
+        }
+        self.slab_count += 1;
+        Ok(())
+    }
+}
+
+
+// Owning pointer to a value in a slot of a SlabCache, like a Box
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>
+}
+unsafe impl<T> Send for SlabBox<T> where T: Send {}
+unsafe impl<T> Sync for SlabBox<T> where T: Sync {}
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe { ptr::drop_in_place(self.ptr.as_ptr()); }
+        self.cache.free(self.ptr.as_ptr());
+    }
+}
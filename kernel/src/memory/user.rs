@@ -0,0 +1,67 @@
+use core::{intrinsics::{volatile_copy_memory, volatile_set_memory}, sync::atomic::{AtomicUsize, Ordering}};
+
+use super::{
+    FrameSize, MemoryRegion, align_up,
+    address::VirtAddr,
+    paging::{self, Flags}
+};
+
+
+// The only part of the address space mapped accessible from ring 3
+pub const USER_BASE: usize = 0x1400_00000000;
+pub const USER_LENGTH: usize = 0x100_00000000;
+
+
+// Addresses aren't reused, what's mapped here isn't owned by a task and stays mapped after it exits
+static NEXT_USER_ADDR: AtomicUsize = AtomicUsize::new(USER_BASE);
+
+
+// Whether the whole range is in the user region, for pointers passed in by user tasks
+pub fn is_user_range(addr: usize, length: usize) -> bool {
+    match addr.checked_add(length) {
+        Some(end) => addr >= USER_BASE && end <= USER_BASE + USER_LENGTH,
+        None => false
+    }
+}
+
+/**
+ * Maps a copy of code in the user region, readable and executable from ring 3 but not
+ * writable. It's copied through the physical memory mapping before being mapped.
+ */
+pub fn map_code(code: &[u8]) -> Result<VirtAddr, &'static str> {
+    assert!(!code.is_empty(), "Attempted to map empty user code");
+
+    let page_size = FrameSize::FourKb.to_bytes();
+    let length = align_up(code.len(), page_size);
+
+    let phys_base = super::alloc_contiguous_frames(length / page_size, page_size)
+        .ok_or("Insufficient contiguous physical memory for user code")?;
+    unsafe {
+        let dst = phys_base.to_mut_virtual().as_ptr::<u8>();
+        volatile_set_memory(dst, 0, length);
+        volatile_copy_memory(dst, code.as_ptr(), code.len());
+    }
+
+    let virt_base = reserve(length)?;
+    paging::map_region_to(&MemoryRegion::new(virt_base, length), phys_base, Flags::PRESENT | Flags::USER)?;
+    Ok(VirtAddr::new(virt_base))
+}
+
+// Maps a stack of length (rounded up to 4KB pages) with an unmapped guard page below, returns its top
+pub fn map_stack(length: usize) -> Result<VirtAddr, &'static str> {
+    let page_size = FrameSize::FourKb.to_bytes();
+    let length = align_up(length, page_size);
+
+    let guard_page_addr = reserve(page_size + length)?;
+    let body_region = MemoryRegion::new(guard_page_addr + page_size, length);
+    paging::map_region(&body_region, Flags::PRESENT | Flags::WRITABLE | Flags::USER | Flags::NO_EXECUTE)?;
+    Ok(VirtAddr::new(body_region.base() + length))
+}
+
+fn reserve(length: usize) -> Result<usize, &'static str> {
+    let virt_base = NEXT_USER_ADDR.fetch_add(length, Ordering::Relaxed);
+    if virt_base + length > USER_BASE + USER_LENGTH {
+        return Err("User address space exhausted");
+    }
+    Ok(virt_base)
+}
@@ -0,0 +1,92 @@
+use core::arch::global_asm;
+
+use crate::processor;
+use super::{FrameSize, MemoryRegion, user, address::{VirtAddr, VirtualAddress}};
+
+
+/*
+ * Copies RDX bytes from RSI to RDI and returns 0. The only instruction that touches user
+ * memory is the one at "usercopy_copy", if it page faults the handler resumes execution at
+ * "usercopy_fixup" (see "fixup_page_fault") which returns the number of bytes left instead.
+ */
+global_asm!(r#"
+usercopy_bytes:
+    mov rcx, rdx
+usercopy_copy:
+    rep movsb
+    xor eax, eax
+    ret
+usercopy_fixup:
+    mov rax, rcx
+    ret
+"#);
+
+#[allow(improper_ctypes)]
+extern {
+    static usercopy_copy: ();
+    static usercopy_fixup: ();
+}
+extern "sysv64" {
+    fn usercopy_bytes(dst: *mut u8, src: *const u8, length: usize) -> usize;
+}
+
+
+// Copies length bytes at user_addr, a pointer from a user task, to the start of dst
+pub fn copy_from_user(dst: &mut [u8], user_addr: usize, length: usize) -> Result<(), &'static str> {
+    if length > dst.len() {
+        return Err("Destination is smaller than the user buffer");
+    }
+    check_user_range(user_addr, length)?;
+
+    match unsafe { usercopy_bytes(dst.as_mut_ptr(), user_addr as *const u8, length) } {
+        0 => Ok(()),
+        _ => Err("Fault accessing user memory")
+    }
+}
+// Copies src to user_addr, a pointer from a user task
+pub fn copy_to_user(user_addr: usize, src: &[u8]) -> Result<(), &'static str> {
+    check_user_range(user_addr, src.len())?;
+
+    match unsafe { usercopy_bytes(user_addr as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err("Fault accessing user memory")
+    }
+}
+
+/*
+ * Catches what can be checked up front, pages can still be unmapped (or read-only when
+ * copying to them) by the time they're accessed, which the fixup takes care of
+ */
+fn check_user_range(user_addr: usize, length: usize) -> Result<(), &'static str> {
+    if !user::is_user_range(user_addr, length) {
+        return Err("Pointer is outside of user memory");
+    }
+    let region = MemoryRegion::new(user_addr, length);
+    if region.iter(FrameSize::FourKb).any(|page| VirtAddr::new(page).to_phys().is_none()) {
+        return Err("User buffer isn't mapped");
+    }
+    Ok(())
+}
+
+/**
+ * Called by the page fault handler, if the fault was on user memory accessed by the copy
+ * in "usercopy_bytes" makes the interrupted code resume at its fixup and returns true.
+ * The state to change is the one saved when the interrupt came in, so faults in nested
+ * interrupts aren't fixed up (user memory isn't copied from interrupt handlers).
+ */
+pub fn fixup_page_fault(fault_addr: usize) -> bool {
+    let Some(processor) = processor::try_get() else { return false; };
+
+    let saved_state = *processor.curr_interrupt_saved_state();
+    if *processor.active_interrupt_count() != 1 || saved_state.is_null() || !user::is_user_range(fault_addr, 1) {
+        return false;
+    }
+
+    unsafe {
+        if (*saved_state).stack_frame.rip != &usercopy_copy as *const _ as u64 {
+            return false;
+        }
+        (*saved_state).stack_frame.rip = &usercopy_fixup as *const _ as u64;
+    }
+    true
+}
@@ -1,33 +1,76 @@
-use core::{cell::UnsafeCell, ptr};
-use alloc::collections::BTreeMap;
+use core::{cell::UnsafeCell, ptr, sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering}};
+use alloc::{boxed::Box, collections::VecDeque};
 
 use crate::{
-    time::timer::Timer, utils::lazy_static::LazyStatic, scheduler::Scheduler,
-    x86_64::{interrupts::{apic::lapic::{self, Lapic}, handler}, structures::idt::Idt}
+    locks::spinlock::Spinlock, memory::address::VirtAddr,
+    time::timer::Timer, utils::{lazy_static::LazyStatic, atomic::ArrayQueue},
+    scheduler::{Scheduler, idle::IdleWakeup, task::Task, watchdog::Watchdog},
+    x86_64::{
+        cpu::registers::tsc_aux, interrupts::{apic::lapic::{self, Lapic}, handler, deferred::{self, DeferredWork}},
+        structures::{idt::Idt, gdt::{self, Gdt}, tss::Tss}, syscall
+    }
 };
 
 
-static mut PROCESSORS: BTreeMap<u32, Processor> = BTreeMap::new();
+pub mod topology;
+
+use topology::Topology;
+
+
+// xAPIC ids are 8 bits
+const MAX_PROCESSORS: usize = 256;
+// marks the id cached in IA32_TSC_AUX by "cache_current_id", so the MSR's reset value (0) isn't taken as one
+const CACHED_ID_BIT: u32 = 1<<31;
+
+
+// Indexed by LAPIC id, never moved once registered
+static PROCESSORS: [AtomicPtr<Processor>; MAX_PROCESSORS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_PROCESSORS];
+static PROCESSOR_COUNT: AtomicUsize = AtomicUsize::new(0);
 static BSP_LAPIC_ID: LazyStatic<u32> = LazyStatic::new();
 
 
 pub struct Processor {
     idt: UnsafeCell<Idt>,
+    gdt: UnsafeCell<Gdt>,
+    tss: UnsafeCell<Tss>,
+    syscall_entry_state: UnsafeCell<syscall::EntryState>,
     lapic: UnsafeCell<Lapic>,
     timer: UnsafeCell<Timer>,
     active_interrupt_count: UnsafeCell<u64>, // number of interrupts currently being handled
     curr_interrupt_saved_state: UnsafeCell<*mut handler::SavedState>,
-    scheduler: UnsafeCell<Scheduler>
+    pcid_generation: UnsafeCell<u64>, // of the PCIDs the TLB was last flushed for, see address_space
+    scheduler: UnsafeCell<Scheduler>,
+    idle_wakeup: IdleWakeup, // what the idle task waits on
+    incoming_tasks: Spinlock<VecDeque<Task>>, // pinned to it by other processors, see scheduler::add_task
+    deferred_work: ArrayQueue<DeferredWork>, // work queued by interrupt handlers
+    watchdog: Watchdog, // read by every processor
+    topology: Topology,
+    #[cfg(feature = "trace")]
+    trace_buffer: crate::trace::TraceBuffer, // read by every processor
+    interrupt_counts: [AtomicU64; 256] // number of times each vector was handled
 }
 impl Processor {
-    pub fn new() -> Processor {
+    pub fn new(topology: Topology) -> Processor {
         Processor{
             idt: UnsafeCell::new(Idt::new()),
+            gdt: UnsafeCell::new(Gdt::new()),
+            tss: UnsafeCell::new(Tss::new()),
+            syscall_entry_state: UnsafeCell::new(syscall::EntryState::new()),
             lapic: UnsafeCell::new(Lapic::new()),
             timer: UnsafeCell::new(Timer::new()),
             active_interrupt_count: UnsafeCell::new(0),
             curr_interrupt_saved_state: UnsafeCell::new(ptr::null_mut()),
-            scheduler: UnsafeCell::new(Scheduler::new())
+            pcid_generation: UnsafeCell::new(0),
+            scheduler: UnsafeCell::new(Scheduler::new()),
+            idle_wakeup: IdleWakeup::new(),
+            incoming_tasks: Spinlock::new(VecDeque::new()),
+            deferred_work: ArrayQueue::new(deferred::DEFERRED_WORK_QUEUE_SIZE)
+                                      .expect("Insufficient memory for deferred work queue"),
+            watchdog: Watchdog::new(),
+            topology,
+            #[cfg(feature = "trace")]
+            trace_buffer: crate::trace::TraceBuffer::new(),
+            interrupt_counts: [const { AtomicU64::new(0) }; 256]
         }
     }
 
@@ -50,27 +93,133 @@ impl Processor {
     pub fn curr_interrupt_saved_state(&self) -> &mut *mut handler::SavedState {
         unsafe { &mut *self.curr_interrupt_saved_state.get() }
     }
+    pub fn pcid_generation(&self) -> &mut u64 {
+        unsafe { &mut *self.pcid_generation.get() }
+    }
+    pub fn syscall_entry_state(&self) -> &mut syscall::EntryState {
+        unsafe { &mut *self.syscall_entry_state.get() }
+    }
     pub fn scheduler(&self) -> &mut Scheduler {
         unsafe { &mut *self.scheduler.get() }
     }
+    pub fn idle_wakeup(&self) -> &IdleWakeup {
+        &self.idle_wakeup
+    }
+    pub fn incoming_tasks(&self) -> &Spinlock<VecDeque<Task>> {
+        &self.incoming_tasks
+    }
+    pub fn deferred_work(&self) -> &ArrayQueue<DeferredWork> {
+        &self.deferred_work
+    }
+    pub fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+    pub fn topology(&self) -> Topology {
+        self.topology
+    }
+    #[cfg(feature = "trace")]
+    pub fn trace_buffer(&self) -> &crate::trace::TraceBuffer {
+        &self.trace_buffer
+    }
+
+    // Switches to the processor's own GDT, which has an entry for its TSS, and loads the TSS
+    pub fn load_gdt_and_tss(&'static self) {
+        unsafe { gdt::load_with_tss(&mut *self.gdt.get(), &*self.tss.get()); }
+    }
+    // Stack the processor enters the kernel on from ring 3, through interrupts or syscalls
+    pub fn set_kernel_stack(&self, stack_top: VirtAddr) {
+        unsafe { (*self.tss.get()).set_kernel_stack(stack_top); }
+        self.syscall_entry_state().set_kernel_stack(stack_top);
+    }
+
+    // Only incremented by this processor so it doesn't contend with others
+    #[inline]
+    pub fn count_interrupt(&self, vector: u8) {
+        self.interrupt_counts[vector as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn interrupt_count(&self, vector: u8) -> u64 {
+        self.interrupt_counts[vector as usize].load(Ordering::Relaxed)
+    }
+}
+
+
+/**
+ * Caches the LAPIC id of the current processor for "current_id", in IA32_TSC_AUX since
+ * it's per processor and cheap to read. Called by every processor before running tasks.
+ */
+pub fn cache_current_id() {
+    tsc_aux::init();
+    if tsc_aux::is_readable() {
+        tsc_aux::write(lapic::get_id() | CACHED_ID_BIT);
+    }
 }
 
+/*
+ * LAPIC id of the current processor, from the cache (see "cache_current_id") since reading
+ * the LAPIC is an uncached MMIO access. Reads the LAPIC until the processor has cached it.
+ */
+#[inline]
+pub fn current_id() -> u32 {
+    match tsc_aux::try_read() {
+        Some(value) if value & CACHED_ID_BIT != 0 => value & !CACHED_ID_BIT,
+        _ => lapic::get_id()
+    }
+}
 
 pub fn register_bsp() {
     BSP_LAPIC_ID.init(lapic::get_id());
-    unsafe { PROCESSORS.insert(*BSP_LAPIC_ID, Processor::new()); }
+    insert(*BSP_LAPIC_ID);
 }
 pub fn register(lapic_id: u32) {
     assert!(BSP_LAPIC_ID.is_init(), "Attempted to register processor before registering BSP");
-    assert_eq!(lapic::get_id(), *BSP_LAPIC_ID, "Can't call register_processor from non BSP");
-    // safe since only BSP will be reaching this
-    unsafe { PROCESSORS.insert(lapic_id, Processor::new()); }
+    assert_eq!(current_id(), *BSP_LAPIC_ID, "Can't call register_processor from non BSP");
+    insert(lapic_id);
 }
+// Only for processors that never ran, nothing can be holding a reference to them
 pub fn unregister(lapic_id: u32) {
     assert!(BSP_LAPIC_ID.is_init(), "Attempted to unregister processor before registering BSP");
-    assert_eq!(lapic::get_id(), *BSP_LAPIC_ID, "Can't call unregister_processor from non BSP");
-    // safe since only BSP will be reaching this
-    unsafe { PROCESSORS.remove(&lapic_id); }
+    assert_eq!(current_id(), *BSP_LAPIC_ID, "Can't call unregister_processor from non BSP");
+
+    let processor_ptr = PROCESSORS[lapic_id as usize].swap(ptr::null_mut(), Ordering::AcqRel);
+    assert!(!processor_ptr.is_null(), "Attempted to unregister processor that isn't registered");
+    PROCESSOR_COUNT.fetch_sub(1, Ordering::Relaxed);
+    drop(unsafe { Box::from_raw(processor_ptr) });
+}
+// Processors can be registered while others are running, so the struct is published with release
+fn insert(lapic_id: u32) {
+    assert!((lapic_id as usize) < MAX_PROCESSORS, "LAPIC id out of range");
+
+    let processor_ptr = Box::into_raw(Box::new(Processor::new(Topology::from_lapic_id(lapic_id))));
+    let prev_ptr = PROCESSORS[lapic_id as usize].swap(processor_ptr, Ordering::AcqRel);
+    assert!(prev_ptr.is_null(), "Attempted to register processor more than once");
+    PROCESSOR_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn bsp_id() -> u32 {
+    *BSP_LAPIC_ID
+}
+
+// Topology of a registered processor
+pub fn topology(lapic_id: u32) -> Option<Topology> {
+    lookup(lapic_id).map(|processor| processor.topology())
+}
+
+// Number of processors registered, APs that failed to start are unregistered
+pub fn count() -> usize {
+    PROCESSOR_COUNT.load(Ordering::Relaxed)
+}
+
+/*
+ * Iterates over the registered processors and their LAPIC ids, only the fields
+ * meant to be shared between processors should be accessed through them
+ */
+pub fn iter() -> impl Iterator<Item = (u32, &'static Processor)> {
+    (0..MAX_PROCESSORS as u32).filter_map(|lapic_id| lookup(lapic_id).map(|processor| (lapic_id, processor)))
+}
+
+// Same restrictions as "iter", for processors other than the current one
+pub fn get_by_id(lapic_id: u32) -> Option<&'static Processor> {
+    lookup(lapic_id)
 }
 
 /*
@@ -79,11 +228,24 @@ pub fn unregister(lapic_id: u32) {
  */
 pub unsafe fn get_bsp() -> &'static Processor {
     // should never fail
-    unsafe { PROCESSORS.get(&*BSP_LAPIC_ID).unwrap() }
+    lookup(*BSP_LAPIC_ID).unwrap()
 }
 
 // Retrieves the processor struct for the processor currently executing
 pub fn get() -> &'static Processor {
     // should never fail
-    unsafe { PROCESSORS.get_mut(&lapic::get_id()).unwrap() }
+    lookup(current_id()).unwrap()
+}
+// Same as "get" but doesn't assume the processor is registered
+pub fn try_get() -> Option<&'static Processor> {
+    if !BSP_LAPIC_ID.is_init() {
+        return None;
+    }
+    lookup(current_id())
+}
+
+#[inline]
+fn lookup(lapic_id: u32) -> Option<&'static Processor> {
+    let processor_ptr = PROCESSORS.get(lapic_id as usize)?.load(Ordering::Acquire);
+    unsafe { processor_ptr.as_ref() }
 }
@@ -0,0 +1,106 @@
+use crate::{utils::lazy_static::LazyStatic, x86_64::cpu::instructions::{cpuid, cpuid_subleaf}};
+
+
+const CPUID_FUNC_GET_MAX: u32 = 0;
+const CPUID_FUNC_GET_FEATURES: u32 = 1;
+const CPUID_FUNC_GET_CACHE_PARAMS: u32 = 4;
+const CPUID_FUNC_GET_TOPOLOGY: u32 = 0xB;
+const CPUID_GET_FEATURES_EDX_HTT_BIT: u32 = 1<<28;
+const TOPOLOGY_LEVEL_TYPE_SMT: u32 = 1;
+const TOPOLOGY_LEVEL_TYPE_CORE: u32 = 2;
+
+
+// Same on every processor so it's read once, by the BSP when registering itself
+static SHIFT_WIDTHS: LazyStatic<ShiftWidths> = LazyStatic::new();
+
+
+// Position of a processor, identified by its LAPIC id, in the physical packages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Topology {
+    pub package: u32,
+    pub core: u32,  // within the package
+    pub thread: u32 // within the core
+}
+impl Topology {
+    pub fn from_lapic_id(lapic_id: u32) -> Topology {
+        let shift_widths = SHIFT_WIDTHS.get_or_init(ShiftWidths::detect);
+
+        let thread_mask = (1 << shift_widths.smt) - 1;
+        let core_mask = (1 << (shift_widths.package - shift_widths.smt)) - 1;
+        Topology {
+            package: lapic_id >> shift_widths.package,
+            core: (lapic_id >> shift_widths.smt) & core_mask,
+            thread: lapic_id & thread_mask
+        }
+    }
+
+    // Whether both are hardware threads of the same core, sharing its execution units
+    pub fn is_sibling_of(&self, other: &Topology) -> bool {
+        self.package == other.package && self.core == other.core && self.thread != other.thread
+    }
+}
+
+
+/**
+ * Bits of the LAPIC id taken by the thread id (smt) and by the thread and core ids together
+ * (package), from the extended topology leaf or estimated from the logical processor counts
+ * in leaves 1 and 4 if it isn't supported.
+ */
+struct ShiftWidths {
+    smt: u32,
+    package: u32
+}
+impl ShiftWidths {
+    fn detect() -> ShiftWidths {
+        let max_function = cpuid(CPUID_FUNC_GET_MAX).eax;
+
+        if max_function >= CPUID_FUNC_GET_TOPOLOGY && cpuid_subleaf(CPUID_FUNC_GET_TOPOLOGY, 0).ebx != 0 {
+            return Self::from_topology_leaf();
+        }
+
+        let features = cpuid(CPUID_FUNC_GET_FEATURES);
+        if features.edx & CPUID_GET_FEATURES_EDX_HTT_BIT == 0 {
+            return ShiftWidths { smt: 0, package: 0 };
+        }
+        let package = Self::count_to_width((features.ebx >> 16) & 0xFF);
+
+        // leaf 4 is Intel only, without it every logical processor is taken as its own core
+        let cores_per_package = if max_function >= CPUID_FUNC_GET_CACHE_PARAMS {
+            let cache_params = cpuid_subleaf(CPUID_FUNC_GET_CACHE_PARAMS, 0);
+            if cache_params.eax & 0x1F != 0 { (cache_params.eax >> 26) + 1 } else { 0 }
+        }
+        else {
+            0
+        };
+        let smt = if cores_per_package == 0 { 0 }
+                  else { package.saturating_sub(Self::count_to_width(cores_per_package)) };
+
+        ShiftWidths { smt, package }
+    }
+
+    fn from_topology_leaf() -> ShiftWidths {
+        let mut smt = 0;
+        let mut package = None;
+
+        // each subleaf is a level, the shift is how far right the id has to go to get to the next one
+        for subleaf in 0.. {
+            let regs = cpuid_subleaf(CPUID_FUNC_GET_TOPOLOGY, subleaf);
+            let level_type = (regs.ecx >> 8) & 0xFF;
+            let shift = regs.eax & 0x1F;
+            match level_type {
+                0 => break,
+                TOPOLOGY_LEVEL_TYPE_SMT => smt = shift,
+                TOPOLOGY_LEVEL_TYPE_CORE => package = Some(shift),
+                _ => {}
+            }
+        }
+
+        let package = package.unwrap_or(smt);
+        ShiftWidths { smt: smt.min(package), package }
+    }
+
+    // Bits needed to tell count different ids apart
+    fn count_to_width(count: u32) -> u32 {
+        if count <= 1 { 0 } else { u32::BITS - (count - 1).leading_zeros() }
+    }
+}
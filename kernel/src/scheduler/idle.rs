@@ -0,0 +1,93 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{processor, x86_64::cpu::{self, instructions::cpuid}};
+
+
+const CPUID_MWAIT_LEAF: u32 = 5;
+const CPUID_POWER_MANAGEMENT_LEAF: u32 = 6;
+const CPUID_MWAIT_BIT: u32 = 1<<3;               // leaf 1 ECX
+const CPUID_MWAIT_ENUMERATION_BIT: u32 = 1<<0;   // leaf 5 ECX, sub C-states are listed in EDX
+const CPUID_ALWAYS_RUNNING_TIMER_BIT: u32 = 1<<2; // leaf 6 EAX (ARAT)
+const MWAIT_HINT_C1: u32 = 0;
+
+
+/**
+ * What the idle task of a processor waits on, in its own cache line since it's what MONITOR
+ * watches. Writing it with "notify" wakes the processor from MWAIT without an interrupt,
+ * without MWAIT only interrupts wake it up from HLT.
+ */
+#[repr(align(64))]
+pub struct IdleWakeup {
+    sequence: AtomicU64,
+    mwait_hints: Option<u32> // None if MWAIT isn't supported
+}
+impl IdleWakeup {
+    pub fn new() -> IdleWakeup {
+        IdleWakeup { sequence: AtomicU64::new(0), mwait_hints: mwait_hints() }
+    }
+
+    // Called after making work available to the processor, e.g. enqueuing a task
+    #[inline]
+    pub fn notify(&self) {
+        self.sequence.fetch_add(1, Ordering::Release);
+    }
+
+    /*
+     * Enables interrupts and waits until one comes in or, with MWAIT, "notify" is called
+     * after sequence was read. Interrupts have to be disabled so one that makes work
+     * available can't be missed in between checking for work and waiting.
+     */
+    fn wait(&self, sequence: u64) {
+        let Some(hints) = self.mwait_hints else {
+            cpu::instructions::sti_hlt();
+            return;
+        };
+
+        cpu::instructions::monitor(&self.sequence as *const _ as usize);
+        if self.sequence.load(Ordering::Acquire) != sequence {
+            cpu::instructions::sti();
+            return;
+        }
+        cpu::instructions::sti_mwait(hints);
+    }
+}
+
+
+// Loop of the idle task, switches to a task as soon as one is ready instead of at the next tick
+pub(super) fn run() -> ! {
+    let processor = processor::get();
+    let idle_wakeup = processor.idle_wakeup();
+
+    loop {
+        let sequence = idle_wakeup.sequence.load(Ordering::Acquire);
+
+        cpu::instructions::cli();
+        if processor.scheduler().has_ready_tasks() {
+            // the idle task doesn't keep its state, it starts over when switched to again
+            processor.scheduler().schedule();
+        }
+        idle_wakeup.wait(sequence);
+    }
+}
+
+/*
+ * EAX hints for MWAIT, the deepest C-state enumerated if the LAPIC timer keeps running in it
+ * (ARAT) and C1 otherwise, since the timer interrupt waking the processor up would never come
+ */
+fn mwait_hints() -> Option<u32> {
+    let max_leaf = cpuid(0).eax;
+    if cpuid(1).ecx & CPUID_MWAIT_BIT == 0 || max_leaf < CPUID_MWAIT_LEAF {
+        return None;
+    }
+
+    let mwait_leaf = cpuid(CPUID_MWAIT_LEAF);
+    let has_always_running_timer = max_leaf >= CPUID_POWER_MANAGEMENT_LEAF
+        && cpuid(CPUID_POWER_MANAGEMENT_LEAF).eax & CPUID_ALWAYS_RUNNING_TIMER_BIT != 0;
+    if mwait_leaf.ecx & CPUID_MWAIT_ENUMERATION_BIT == 0 || !has_always_running_timer {
+        return Some(MWAIT_HINT_C1);
+    }
+
+    // EDX has the number of sub C-states of C0 to C7 in 4 bits each, the hint is C-state - 1
+    let deepest_c_state = (1..8).rev().find(|c_state| (mwait_leaf.edx >> (c_state*4)) & 0xF != 0);
+    Some(deepest_c_state.map_or(MWAIT_HINT_C1, |c_state| (c_state - 1) << 4))
+}
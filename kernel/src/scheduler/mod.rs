@@ -1,35 +1,99 @@
 /* TODO: priority, how much time a task had the cpu for                       */
 
+pub mod idle;
 pub mod task;
+pub mod watchdog;
 
 
-use core::ptr;
+use core::{ptr, sync::atomic::{AtomicU64, Ordering}};
 use alloc::collections::{BTreeMap, VecDeque};
 
 use crate::{
-    ms, processor, time::{Time, timer::{self, stop_schedule_timer}},
+    cmdline, memory::address_space, ms, processor, us, trace::{self, Event}, time::{Time, timer::{self, stop_schedule_timer}},
     x86_64::interrupts::{interrupts_disabled, handler::SavedState as InterruptSavedState},
 };
 use self::task::{Task, TaskId};
 
 
 const TASK_QUEUE_DEFAULT_CAPACITY: usize = 10;
-const DEFAULT_PRREMPT_FREQUENCY: Time = ms!(100);
+const DEFAULT_TIMESLICE: Time = ms!(100);
+// shorter slices would have processors spend most of their time switching tasks
+const MIN_TIMESLICE: Time = us!(100);
+
+
+// timeslice schedulers start with, see init
+static INIT_TIMESLICE_NS: AtomicU64 = AtomicU64::new(0);
+
+
+/**
+ * Sets the timeslice every processor starts with from "timeslice=<ms>" in the kernel command
+ * line, has to be called before any processor is registered
+ */
+pub fn init() {
+    let timeslice = match cmdline::get("timeslice") {
+        Some(value) => match value.parse::<u64>().map(Time::from_ms) {
+            Ok(timeslice) if timeslice >= MIN_TIMESLICE => timeslice,
+            _ => {
+                crate::warn!("WARNING: Invalid timeslice \"{}\" in kernel command line, ignoring it.", value);
+                DEFAULT_TIMESLICE
+            }
+        },
+        None => DEFAULT_TIMESLICE
+    };
+    INIT_TIMESLICE_NS.store(timeslice.total_ns(), Ordering::Relaxed);
+}
 
 
 pub fn schedule() {
     processor::get().scheduler().schedule();
 }
 
+/**
+ * Adds the task to the processor it's pinned to, the current one if it isn't pinned.
+ * A task pinned to another processor is picked up the next time that one schedules,
+ * at the latest once its timeslice is up (right away if it idles with MWAIT).
+ */
 pub fn add_task(task: Task) {
-    processor::get().scheduler().add_task(task);
+    match task.affinity() {
+        Some(lapic_id) if lapic_id != processor::current_id() => {
+            let processor = processor::get_by_id(lapic_id).expect("Task pinned to a processor that isn't registered");
+            interrupts_disabled(|| processor.incoming_tasks().lock().push_back(task));
+            processor.idle_wakeup().notify();
+        }
+        _ => processor::get().scheduler().add_task(task)
+    }
+}
+
+// Runs closure in a new task that only ever executes on the processor with lapic_id
+pub fn spawn_pinned<F>(lapic_id: u32, stack_len: usize, closure: F) -> Result<TaskId, &'static str>
+    where F: FnOnce() + Send + 'static
+{
+    if processor::get_by_id(lapic_id).is_none() {
+        return Err("Processor isn't registered");
+    }
+    let mut task = Task::spawn_closure(stack_len, closure);
+    task.set_affinity(Some(lapic_id));
+    let task_id = task.id;
+    add_task(task);
+    Ok(task_id)
 }
 
+// Gives the other ready tasks a turn, the current task stays ready and runs again after them
+pub fn yield_now() {
+    processor::get().scheduler().yield_now();
+}
+
+// Blocks the currently running task until it's woken up with "wake_up_task"
 pub fn yield_task() {
     processor::get().scheduler().yield_task();
 }
 
-// Yields the currently running task if condition closure returns true
+// Ends the currently running task, its stack and TLS are freed after switching away from it
+pub fn exit_task() -> ! {
+    processor::get().scheduler().exit_task()
+}
+
+// Blocks the currently running task if condition closure returns true
 pub fn yield_on_condition<F>(condition: F)
     where F: FnOnce() -> bool
 {
@@ -55,48 +119,100 @@ pub fn disable_preemption() {
     processor::get().scheduler().disable_preemption();
 }
 
+// Sets how long tasks run on the current processor before being preempted
+pub fn set_timeslice(timeslice: Time) -> Result<(), &'static str> {
+    processor::get().scheduler().set_timeslice(timeslice)
+}
+
 
 pub struct Scheduler {
     is_preemption_enabled: bool,
+    timeslice: Time,
     is_idle: bool,
     idle_task: Task,
     curr_task: Option<Task>,
     task_queue: VecDeque<Task>,
-    blocked_task_map: BTreeMap<TaskId, Task>
+    blocked_task_map: BTreeMap<TaskId, Task>,
+    exited_task: Option<Task> // kept until the next schedule, its stack can be the one in use
 }
 impl Scheduler {
     pub fn new() -> Scheduler {
         Scheduler {
             is_preemption_enabled: false, is_idle: false,
+            timeslice: match INIT_TIMESLICE_NS.load(Ordering::Relaxed) {
+                0 => DEFAULT_TIMESLICE,
+                ns => Time::from_ns(ns)
+            },
             idle_task: Task::idle_task(),
             curr_task: None,
             task_queue: VecDeque::with_capacity(TASK_QUEUE_DEFAULT_CAPACITY),
-            blocked_task_map: BTreeMap::new()
+            blocked_task_map: BTreeMap::new(),
+            exited_task: None
         }
     }
 
     pub fn enable_preemption(&mut self) {
         self.is_preemption_enabled = true;
-        timer::start_schedule_timer(DEFAULT_PRREMPT_FREQUENCY);
+        timer::start_schedule_timer(self.timeslice);
+        processor::get().watchdog().arm();
+        watchdog::start();
     }
     pub fn disable_preemption(&mut self) {
         self.is_preemption_enabled = false;
         stop_schedule_timer();
+        processor::get().watchdog().disarm();
+    }
+
+    // Restarts the schedule timer with the new timeslice if preemption is enabled
+    pub fn set_timeslice(&mut self, timeslice: Time) -> Result<(), &'static str> {
+        if timeslice < MIN_TIMESLICE {
+            return Err("Timeslice is shorter than the minimum");
+        }
+        interrupts_disabled(|| {
+            self.timeslice = timeslice;
+            if self.is_preemption_enabled {
+                timer::start_schedule_timer(timeslice);
+            }
+        });
+        Ok(())
+    }
+    pub fn timeslice(&self) -> Time {
+        self.timeslice
     }
 
     pub fn add_task(&mut self, task: Task) {
+        crate::kassert!(task.affinity().map_or(true, |lapic_id| lapic_id == processor::current_id()),
+            "Attempted to add task to a processor it isn't pinned to");
         self.task_queue.push_back(task);
+        processor::get().idle_wakeup().notify();
+    }
+    pub fn has_ready_tasks(&self) -> bool {
+        !self.task_queue.is_empty() || !processor::get().incoming_tasks().lock().is_empty()
     }
 
     pub fn schedule(&mut self) {
         interrupts_disabled(|| {
             if self.is_preemption_enabled {
-                timer::start_schedule_timer(DEFAULT_PRREMPT_FREQUENCY);
+                timer::start_schedule_timer(self.timeslice);
             }
 
+            // the processor is responsive even if the same task keeps running
+            processor::get().watchdog().touch();
+
             if self.is_idle { return; }
 
-            // in case current task was blocked push it to blocked task map
+            // the last task that exited was switched away from since, its stack is no longer in use
+            self.exited_task = None;
+
+            // tasks pinned to this processor by others
+            self.task_queue.extend(processor::get().incoming_tasks().lock().drain(..));
+
+            /*
+             * in case current task was blocked push it to blocked task map, its state is saved
+             * there on the switch below and nothing else touches the map until then, so the
+             * reference stays valid even when switching to the idle task (once woken up it's
+             * moved out of the map along with the state)
+             */
             let mut curr_task_ref = None;
             if let Some(curr_task) = self.curr_task.as_ref() {
                 let curr_task_id = curr_task.id;
@@ -106,6 +222,10 @@ impl Scheduler {
                     self.blocked_task_map.insert(curr_task_id, curr_task);
                     curr_task_ref = Some(self.blocked_task_map.get_mut(&curr_task_id).unwrap());
                 }
+                // its state isn't saved, it never runs again
+                else if curr_task.is_exited {
+                    self.exited_task = self.curr_task.take();
+                }
             }
 
             // retrieve next task to the queue and switch to it
@@ -134,6 +254,11 @@ impl Scheduler {
         });
     }
 
+    // The current task is moved to the back of the queue by "schedule" if there are others ready
+    pub fn yield_now(&mut self) {
+        self.schedule();
+    }
+
     pub fn yield_task(&mut self) {
         interrupts_disabled(|| {
             if let Some(curr_task) = self.curr_task.as_mut() {
@@ -143,36 +268,86 @@ impl Scheduler {
         });
     }
 
-    pub fn wake_up_task(&mut self, task_id: TaskId) {
-        if let Some(mut task) = self.blocked_task_map.remove(&task_id) {
-            task.is_blocked = false;
-            self.task_queue.push_front(task);
+    pub fn exit_task(&mut self) -> ! {
+        interrupts_disabled(|| {
+            let curr_task = self.curr_task.as_mut().expect("Attempted to exit the idle task");
+            curr_task.is_exited = true;
             self.schedule();
-        }
+        });
+        unreachable!("Exited task was switched back to");
+    }
+
+    // Also called by deferred work, which runs with interrupts enabled
+    pub fn wake_up_task(&mut self, task_id: TaskId) {
+        interrupts_disabled(|| {
+            if let Some(mut task) = self.blocked_task_map.remove(&task_id) {
+                task.is_blocked = false;
+                self.task_queue.push_front(task);
+                self.schedule();
+            }
+        });
     }
 
     pub fn get_executing_task_id(&self) -> TaskId {
-        debug_assert!(self.curr_task.is_none() == false);
+        crate::kassert!(self.curr_task.is_none() == false);
         self.curr_task.as_ref().unwrap().id
     }
+    // None while the idle task is executing
+    pub fn try_get_executing_task_id(&self) -> Option<TaskId> {
+        self.curr_task.as_ref().map(|task| task.id)
+    }
+    // None while the idle task is executing
+    pub fn get_executing_task(&self) -> Option<&Task> {
+        self.curr_task.as_ref()
+    }
+}
+
+/*
+ * Loads the executing task's TLS in the FS base again, has to be done on every entry from ring 3
+ * since a user task can set it to anything with wrfsbase (see registers::fs_base::init)
+ */
+pub fn reload_tls() {
+    use crate::x86_64::cpu::registers;
+
+    if let Some(task) = processor::get().scheduler().get_executing_task() {
+        registers::fs_base::write(task.tls_addr());
+    }
 }
 
 
 fn switch_task(curr_task: Option<&mut Task>, next_task: &Task) {
+    use crate::x86_64::cpu::registers;
+
+    // the TLS of each task is fixed so it's only loaded, when switching from an interrupt
+    // it takes effect on the rest of the handler too (which doesn't access TLS)
+    registers::fs_base::write(next_task.tls_addr());
+
+    if let Some(curr_task) = curr_task.as_ref() {
+        trace::record(Event::TaskSwitchOut(curr_task.id));
+    }
+    trace::record(Event::TaskSwitchIn(next_task.id));
+
     let processor = processor::get();
+    processor.watchdog().set_task(next_task.id);
+    if let Some(kernel_stack_top) = next_task.kernel_stack_top() {
+        processor.set_kernel_stack(kernel_stack_top);
+    }
+    // kernel mappings are the same in every address space, the stack and code in use included
+    address_space::switch_to(next_task.cr3());
+
     let is_handling_interrupt = *processor.active_interrupt_count() > 0;
 
     if is_handling_interrupt {
         let interrupt_saved_state = *processor.curr_interrupt_saved_state();
-        debug_assert!(interrupt_saved_state.is_null() == false);
+        crate::kassert!(interrupt_saved_state.is_null() == false);
         switch_task_from_interrupt(interrupt_saved_state, curr_task, next_task);
     }
     else {
-        switch_task_far_ret(curr_task, next_task);
+        switch_task_iret(curr_task, next_task);
     }
 }
 
-fn switch_task_far_ret(curr_task: Option<&mut Task>, next_task: &Task) {
+fn switch_task_iret(curr_task: Option<&mut Task>, next_task: &Task) {
     use core::arch::asm;
 
     let mut curr_task_state_ptr = ptr::null_mut();
@@ -205,6 +380,12 @@ fn switch_task_far_ret(curr_task: Option<&mut Task>, next_task: &Task) {
                 mov [rax+0x70], rbp
                 mov [rax+0x90], rsp
 
+                # user tasks switch from the kernel too
+                mov rdx, cs
+                mov [rax+0x80], rdx
+                mov rdx, ss
+                mov [rax+0x98], rdx
+
                 # save end of asm block on rip
                 lea rdx, 1f
                 mov [rax+0x78], rdx
@@ -215,16 +396,14 @@ fn switch_task_far_ret(curr_task: Option<&mut Task>, next_task: &Task) {
                 mov [rax+0x88], rdx
 
                 0:
-                # load next task state
-                mov rsp, [rcx+0x90]
-                mov ss , [rcx+0x98]
-
-                # push cs and rip for retf
-                mov rax, [rcx+0x80]
-                push rax
-                mov rax, [rcx+0x78]
-                push rax
+                # push the iretq frame, which can go to ring 3 as well
+                push [rcx+0x98] # ss
+                push [rcx+0x90] # rsp
+                push [rcx+0x88] # RFLAGS
+                push [rcx+0x80] # cs
+                push [rcx+0x78] # rip
 
+                # load next task state
                 mov rax, [rcx]
                 mov rbx, [rcx+0x8]
                 # load rcx later
@@ -241,18 +420,15 @@ fn switch_task_far_ret(curr_task: Option<&mut Task>, next_task: &Task) {
                 mov r15, [rcx+0x68]
                 mov rbp, [rcx+0x70]
 
-                push [rcx+0x88] # push RFLAGS
-
-                mov rcx, [rcx+0x18]
-
-                popfq # restore RFLAGS
+                mov rcx, [rcx+0x10]
 
-                retfq # retf to next task
+                iretq # to next task, restoring RFLAGS
 
                 1:
             "#,
-            in("rax") curr_task_state_ptr,
-            in("rcx") next_task_state_ptr
+            // rax and rcx aren't saved so they hold something else when switching back
+            inout("rax") curr_task_state_ptr => _,
+            inout("rcx") next_task_state_ptr => _
         );
     }
 }
@@ -1,11 +1,77 @@
-use core::{alloc::Layout, mem, ptr, sync::atomic::{AtomicU64, Ordering}};
-use alloc::alloc::{alloc, dealloc};
+use core::{alloc::Layout, arch::asm, mem, ptr, sync::atomic::{AtomicU64, AtomicUsize, Ordering}};
+use alloc::{alloc::{alloc, dealloc}, boxed::Box, sync::Arc};
 
-use crate::{memory::address::VirtAddr, x86_64::interrupts::handler::SavedState as InterruptSavedState};
+use crate::{
+    memory::{
+        MemoryRegion, FrameSize, address::VirtAddr, address_space::{self, AddressSpace},
+        paging::{self, Flags}, slab::{SlabCache, SlabBox}
+    },
+    x86_64::{cpu::registers, interrupts::handler::SavedState as InterruptSavedState, structures::gdt}
+};
 
 
 const IDLE_TASK_ID: TaskId = TaskId { 0: 0 };
 const IDLE_TASK_STACK_LEN: usize = 128;
+// Stack user tasks run on in the kernel
+const USER_TASK_KERNEL_STACK_LEN: usize = 0x4000;
+pub const TLS_NUM_OF_SLOTS: usize = 32;
+
+// Stacks this big or bigger get their own pages with a guard page, smaller ones go on the heap
+const MAPPED_STACK_MIN_LEN: usize = 0x4000;
+// Virtual region of mapped stacks, each gets a slot starting with its guard page
+pub const STACKS_BASE: usize = 0x1200_00000000;
+const STACK_SLOT_LENGTH: usize = 0x100000;
+
+
+// Every task but the idle ones has its own TLS block
+static TLS_CACHE: SlabCache<[u64; TLS_NUM_OF_SLOTS]> = SlabCache::new();
+
+
+// Keys of the task local storage slots used by the kernel
+pub struct TlsKey {}
+impl TlsKey {
+    pub const RNG: usize = 0;
+}
+
+
+/**
+ * Task local storage of the executing task, values are stored in 8 byte slots (zeroed when
+ * the task is created) indexed by key and addressed from the FS base, which the scheduler
+ * points at the storage of the task it switches to. The idle task doesn't have one.
+ */
+pub fn tls_get<T: Copy>(key: usize) -> T {
+    check_tls_access::<T>(key);
+
+    let value: u64;
+    unsafe {
+        asm!(
+            "mov {}, qword ptr fs:[{}]",
+            out(reg) value,
+            in(reg) key*mem::size_of::<u64>()
+        );
+        mem::transmute_copy(&value)
+    }
+}
+pub fn tls_set<T: Copy>(key: usize, value: T) {
+    check_tls_access::<T>(key);
+
+    let mut slot_value: u64 = 0;
+    unsafe {
+        ptr::write(&mut slot_value as *mut u64 as *mut T, value);
+        asm!(
+            "mov qword ptr fs:[{}], {}",
+            in(reg) key*mem::size_of::<u64>(),
+            in(reg) slot_value
+        );
+    }
+}
+fn check_tls_access<T>(key: usize) {
+    assert!(key < TLS_NUM_OF_SLOTS, "TLS key out of range");
+    assert!(mem::size_of::<T>() <= mem::size_of::<u64>() && mem::align_of::<T>() <= mem::align_of::<u64>(),
+        "TLS value doesn't fit in a slot");
+    crate::kassert!(registers::fs_base::read() != 0, "Attempted to access TLS from the idle task");
+}
+
 
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -15,6 +81,13 @@ impl TaskId {
         static NEXT_ID: AtomicU64 = AtomicU64::new(IDLE_TASK_ID.0 + 1);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+    pub const fn idle() -> TaskId {
+        IDLE_TASK_ID
+    }
+
+    pub const fn as_u64(&self) -> u64 {
+        self.0
+    }
 }
 // Use the same setup saved during interrupts since it contains all the registers
 pub struct SavedState(pub InterruptSavedState);
@@ -27,63 +100,209 @@ pub struct Task {
     pub id: TaskId,
     _stack: Stack,
     pub saved_state: SavedState,
-    pub is_blocked: bool
+    pub is_blocked: bool,
+    pub is_exited: bool, // set by "exit_task", the scheduler drops it once it switched away from it
+    is_user: bool, // runs in ring 3, entering the kernel on its stack
+    affinity: Option<u32>, // LAPIC id of the only processor it runs on, None if it isn't pinned
+    address_space: Option<Arc<AddressSpace>>, // None runs in the kernel's, like every kernel task
+    tls: Option<SlabBox<[u64; TLS_NUM_OF_SLOTS]>>
 }
 impl Task {
+    /**
+     * The task starts in "init_task_fn_wrapper" as if it had just been called: RSP is 8 bytes
+     * below a 16 byte boundary (the SysV ABI alignment at function entry, which SSE code relies
+     * on) and that slot holds a null return address. RBP is null so backtraces stop there.
+     * Every other register starts zeroed and RFLAGS only keeps the interrupt flag of the caller.
+     */
     pub fn new<T>(stack_len: usize, init_task_fn: fn(*const T), args: Option<*const T>) -> Task {
-        use crate::x86_64::cpu::registers;
-
         let stack = Stack::new(stack_len);
 
         let mut saved_state = SavedState::new();
         let state = &mut saved_state.0;
 
+        let stack_top = crate::memory::align_down(stack.get_top_addr().as_usize(), 16);
+        let rsp = stack_top - mem::size_of::<u64>();
+        unsafe { (rsp as *mut u64).write(0); }
+
         state.stack_frame.cs  = registers::cs::read() as u64;
         state.stack_frame.rip = init_task_fn_wrapper as u64;
         state.stack_frame.ss  = registers::ss::read() as u64;
-        state.stack_frame.rsp = stack.get_top_addr().as_usize() as u64;
-        state.stack_frame.rflags = registers::rflags::read();
+        state.stack_frame.rsp = rsp as u64;
+        state.stack_frame.rflags = registers::rflags::FLAG_RESERVED
+            | registers::rflags::read() & registers::rflags::FLAG_INTERRUPT_ENABLED;
 
+        state.rbp = 0;
         state.rdi = init_task_fn as u64; // 1st param
         if let Some(args) = args {
             state.rsi = args as u64; // 2nd param
         }
 
-        Task { id: TaskId::new(), _stack: stack, saved_state, is_blocked: false }
+        let tls = Some(TLS_CACHE.alloc([0; TLS_NUM_OF_SLOTS]).expect("Insufficient memory for task local storage"));
+
+        Task {
+            id: TaskId::new(), _stack: stack, saved_state, is_blocked: false, is_exited: false, is_user: false,
+            affinity: None, address_space: None, tls
+        }
+    }
+
+    /**
+     * The task starts at entry in ring 3 with RSP at the 16 byte aligned user_stack_top and
+     * interrupts enabled, both have to be mapped for user access (see memory::user).
+     * Its own stack is only used when it enters the kernel through an interrupt or syscall.
+     */
+    pub fn new_user(entry: VirtAddr, user_stack_top: VirtAddr) -> Task {
+        let stack = Stack::new(USER_TASK_KERNEL_STACK_LEN);
+
+        let mut saved_state = SavedState::new();
+        let state = &mut saved_state.0;
+
+        state.stack_frame.cs  = gdt::USER_CODE_SELECTOR as u64;
+        state.stack_frame.rip = entry.as_usize() as u64;
+        state.stack_frame.ss  = gdt::USER_DATA_SELECTOR as u64;
+        state.stack_frame.rsp = crate::memory::align_down(user_stack_top.as_usize(), 16) as u64;
+        state.stack_frame.rflags = registers::rflags::FLAG_RESERVED | registers::rflags::FLAG_INTERRUPT_ENABLED;
+
+        let tls = Some(TLS_CACHE.alloc([0; TLS_NUM_OF_SLOTS]).expect("Insufficient memory for task local storage"));
+
+        Task {
+            id: TaskId::new(), _stack: stack, saved_state, is_blocked: false, is_exited: false, is_user: true,
+            affinity: None, address_space: None, tls
+        }
+    }
+
+    // Same as "new_user" but the task runs in address_space, which entry and the stack are mapped in
+    pub fn new_user_in(entry: VirtAddr, user_stack_top: VirtAddr, address_space: Arc<AddressSpace>) -> Task {
+        let mut task = Self::new_user(entry, user_stack_top);
+        task.address_space = Some(address_space);
+        task
+    }
+
+    /*
+     * Runs closure in a new task, it's boxed and dropped by the task once it returns.
+     * The task then exits and the scheduler frees it at its next schedule (see exit_task).
+     */
+    pub fn spawn_closure<F>(stack_len: usize, closure: F) -> Task
+        where F: FnOnce() + Send + 'static
+    {
+        let closure: Box<ClosureTaskFn> = Box::new(Box::new(closure));
+        Self::new(stack_len, closure_task_fn, Some(Box::into_raw(closure) as *const ClosureTaskFn))
     }
 
     pub fn idle_task() -> Task {
         let mut idle_task = Self::new(IDLE_TASK_STACK_LEN, idle_task_fn, None);
         idle_task.id = IDLE_TASK_ID;
+        idle_task.tls = None;
         idle_task
     }
+
+    // Has to be set before the task is added to a scheduler, tasks don't move between processors
+    pub fn set_affinity(&mut self, lapic_id: Option<u32>) {
+        self.affinity = lapic_id;
+    }
+    pub fn affinity(&self) -> Option<u32> {
+        self.affinity
+    }
+
+    // Top of the stack the task enters the kernel on, only user tasks need one set
+    pub fn kernel_stack_top(&self) -> Option<VirtAddr> {
+        if self.is_user { Some(self._stack.get_top_addr()) } else { None }
+    }
+
+    // Value CR3 is loaded with while the task executes, the kernel's unless it has its own address space
+    pub fn cr3(&self) -> u64 {
+        match &self.address_space {
+            Some(address_space) => address_space.cr3(),
+            None => address_space::kernel_cr3()
+        }
+    }
+
+    // Address the FS base is set to while the task executes, 0 if it has no TLS
+    pub fn tls_addr(&self) -> u64 {
+        self.tls.as_ref().map_or(0, |tls| tls.as_ptr() as u64)
+    }
+}
+impl core::fmt::Debug for Task {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let stack_bottom = self._stack.buffer as usize;
+        f.debug_struct("Task")
+            .field("id", &self.id)
+            .field("is_blocked", &self.is_blocked)
+            .field("is_exited", &self.is_exited)
+            .field("is_user", &self.is_user)
+            .field("affinity", &self.affinity)
+            .field("address_space", &self.address_space)
+            .field("stack", &format_args!("{:#x}-{:#x}", stack_bottom, self._stack.get_top_addr().as_usize()))
+            .field("tls", &format_args!("{:#x}", self.tls_addr()))
+            .finish()
+    }
 }
 #[allow(improper_ctypes_definitions)]
 extern "sysv64" fn init_task_fn_wrapper(init_task_fn: fn(*const ()), args: *const ()) {
     init_task_fn(args);
 }
+type ClosureTaskFn = Box<dyn FnOnce() + Send>;
+fn closure_task_fn(closure: *const ClosureTaskFn) {
+    let closure = unsafe { Box::from_raw(closure as *mut ClosureTaskFn) };
+    closure();
+    crate::scheduler::exit_task();
+}
 fn idle_task_fn(_args: *const ()) {
-    use crate::x86_64::cpu;
-
-    loop {
-        cpu::instructions::sti();
-        cpu::instructions::hlt();
-    }
+    super::idle::run();
 }
 
 pub struct Stack {
     pub buffer: *mut u8,
-    pub length: usize
+    pub length: usize,
+    is_mapped: bool // whether it was mapped with "new_at" instead of allocated on the heap
 }
 impl Stack {
     pub fn new(length: usize) -> Stack {
+        if length >= MAPPED_STACK_MIN_LEN {
+            return Self::new_mapped(length);
+        }
+
         // allocate the buffer
         let layout = Layout::from_size_align(
             mem::size_of::<u8>()*length, mem::align_of::<u8>()
         ).unwrap();
         let buffer = unsafe { alloc(layout) as *mut u8 };
         assert_ne!(buffer, ptr::null_mut(), "Unsufficient memory to allocate stack");
-        Stack { buffer, length }
+        Stack { buffer, length, is_mapped: false }
+    }
+
+    /*
+     * Maps the stack in the next free slot of the stacks region, virtual slots aren't
+     * reused but the region is big enough to never run out
+     */
+    pub fn new_mapped(length: usize) -> Stack {
+        static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+
+        assert!(length + FrameSize::FourKb.to_bytes() <= STACK_SLOT_LENGTH, "Stack doesn't fit in a stack slot");
+
+        let slot_addr = STACKS_BASE + NEXT_SLOT.fetch_add(1, Ordering::Relaxed)*STACK_SLOT_LENGTH;
+        Self::new_at(VirtAddr::new(slot_addr), length).expect("Unsufficient memory to map stack")
+    }
+
+    /**
+     * Maps a stack of length (rounded up to 4KB pages) above the page at guard_page_addr,
+     * which is left unmapped so overflowing the stack page faults instead of silently
+     * corrupting whatever is below it. Frames come from the global frame allocator.
+     */
+    pub fn new_at(guard_page_addr: VirtAddr, length: usize) -> Result<Stack, &'static str> {
+        use crate::memory::{address::VirtualAddress, align_up, is_aligned};
+
+        let page_size = FrameSize::FourKb.to_bytes();
+        assert!(is_aligned(guard_page_addr.as_usize(), page_size), "Stack guard page isn't aligned");
+
+        if guard_page_addr.to_phys().is_some() {
+            return Err("Stack guard page is mapped");
+        }
+
+        let length = align_up(length, page_size);
+        let body_region = MemoryRegion::new(guard_page_addr.as_usize() + page_size, length);
+        paging::map_region(&body_region, Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE)?;
+
+        Ok(Stack { buffer: body_region.base() as *mut u8, length, is_mapped: true })
     }
 
     pub fn get_top_addr(&self) -> VirtAddr {
@@ -92,6 +311,11 @@ impl Stack {
 }
 impl Drop for Stack {
     fn drop(&mut self) {
+        if self.is_mapped {
+            paging::unmap_region(&MemoryRegion::new(self.buffer as usize, self.length));
+            return;
+        }
+
         let layout = Layout::from_size_align(
             mem::size_of::<u8>()*self.length, mem::align_of::<u8>()
         ).unwrap();
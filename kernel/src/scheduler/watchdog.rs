@@ -0,0 +1,163 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::{
+    cmdline, def_interrupt_handler, ms, processor, scheduler, time::{self, Time, timer},
+    x86_64::interrupts::{self, apic::lapic}
+};
+use super::task::TaskId;
+
+
+// Several preempt periods so legitimately long interrupts disabled sections don't trigger it
+const DEFAULT_THRESHOLD: Time = ms!(1000);
+
+
+static THRESHOLD_NS: AtomicU64 = AtomicU64::new(0);
+static SHOULD_FORCE_PREEMPT: AtomicBool = AtomicBool::new(false);
+
+
+/**
+ * Sets up the watchdog from the kernel command line: "watchdog=<ms>" sets the threshold,
+ * "nowatchdog" (or a threshold of 0) disables it and "watchdog_preempt" makes it send a
+ * preempt IPI to the processor of a task over the threshold besides logging a warning.
+ */
+pub fn init() {
+    let threshold_ns = match cmdline::get("watchdog") {
+        Some(value) => value.parse::<u64>().map(|ms| Time::from_ms(ms).total_ns()).unwrap_or_else(|_| {
+            crate::warn!("WARNING: Invalid watchdog threshold \"{}\" in kernel command line, ignoring it.", value);
+            DEFAULT_THRESHOLD.total_ns()
+        }),
+        None => DEFAULT_THRESHOLD.total_ns()
+    };
+    let threshold_ns = if cmdline::has("nowatchdog") { 0 } else { threshold_ns };
+
+    THRESHOLD_NS.store(threshold_ns, Ordering::Relaxed);
+    SHOULD_FORCE_PREEMPT.store(cmdline::has("watchdog_preempt"), Ordering::Relaxed);
+}
+
+/*
+ * Starts the periodic check on the current processor, which looks at every processor
+ * so one that is stuck with interrupts disabled is still caught by the others.
+ * Called once the timer is initialized, does nothing if the watchdog is disabled.
+ */
+pub fn start() {
+    use crate::x86_64::structures::idt::{Index, Flags};
+
+    let threshold_ns = THRESHOLD_NS.load(Ordering::Relaxed);
+    if threshold_ns == 0 {
+        return;
+    }
+
+    let watchdog = processor::get().watchdog();
+    if watchdog.is_started.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    interrupts::set_idt_entry(Index::PREEMPT, preempt_handler.get_addr(), 0x8, Flags::BASE, 0);
+
+    // checking twice per threshold catches a stall at most 1.5 thresholds in
+    timer::start_watchdog_timer(Time::from_ns(threshold_ns / 2));
+}
+
+/*
+ * Resets the time the current processor has been running the same task, for code that
+ * knowingly keeps interrupts disabled for longer than the threshold.
+ */
+pub fn touch() {
+    processor::get().watchdog().touch();
+}
+
+/**
+ * Warns about every processor that hasn't run the scheduler for longer than the threshold
+ * while preemption is enabled, once per stall. Since the idle task only runs when there's
+ * nothing else it's never considered stuck. Called from the timer interrupt, returns
+ * whether the current processor should be preempted.
+ */
+pub(crate) fn check() -> bool {
+    let threshold_ns = THRESHOLD_NS.load(Ordering::Relaxed);
+    let should_force_preempt = SHOULD_FORCE_PREEMPT.load(Ordering::Relaxed);
+    let now_ns = time::now_ns();
+    let curr_lapic_id = processor::current_id();
+
+    let mut should_preempt_self = false;
+    for (lapic_id, processor) in processor::iter() {
+        let watchdog = processor.watchdog();
+
+        if !watchdog.is_armed.load(Ordering::Acquire) {
+            continue;
+        }
+        let task_id = watchdog.task_id.load(Ordering::Acquire);
+        if task_id == TaskId::idle().as_u64() {
+            continue;
+        }
+
+        // clocks of different processors can be slightly apart
+        let held_ns = now_ns.saturating_sub(watchdog.last_schedule_ns.load(Ordering::Acquire));
+        if held_ns <= threshold_ns || watchdog.is_stall_reported.swap(true, Ordering::AcqRel) {
+            continue;
+        }
+
+        crate::warn!("WATCHDOG: Task {} has held processor {} for {}ms without being preempted",
+            task_id, lapic_id, held_ns / 1_000_000);
+
+        if should_force_preempt {
+            if lapic_id == curr_lapic_id {
+                should_preempt_self = true;
+            }
+            else {
+                // only delivered once the task reenables interrupts
+                if let Err(err) = lapic::send_ipi(lapic_id, crate::x86_64::structures::idt::Index::PREEMPT) {
+                    crate::warn!("WATCHDOG: Couldn't preempt processor {}: {}", lapic_id, err);
+                }
+            }
+        }
+    }
+
+    should_preempt_self
+}
+
+
+/**
+ * Per processor state of the watchdog, written by its own processor whenever the scheduler
+ * runs and read by every processor when checking.
+ */
+pub struct Watchdog {
+    is_started: AtomicBool,
+    is_armed: AtomicBool, // only while preemption is enabled
+    is_stall_reported: AtomicBool,
+    task_id: AtomicU64, // task that was switched to last
+    last_schedule_ns: AtomicU64
+}
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog {
+            is_started: AtomicBool::new(false), is_armed: AtomicBool::new(false),
+            is_stall_reported: AtomicBool::new(false),
+            task_id: AtomicU64::new(TaskId::idle().as_u64()), last_schedule_ns: AtomicU64::new(0)
+        }
+    }
+
+    pub fn arm(&self) {
+        self.touch();
+        self.is_armed.store(true, Ordering::Release);
+    }
+    pub fn disarm(&self) {
+        self.is_armed.store(false, Ordering::Release);
+    }
+
+    pub fn touch(&self) {
+        self.last_schedule_ns.store(time::now_ns(), Ordering::Release);
+        self.is_stall_reported.store(false, Ordering::Release);
+    }
+
+    pub fn set_task(&self, task_id: TaskId) {
+        self.task_id.store(task_id.as_u64(), Ordering::Release);
+    }
+}
+
+
+def_interrupt_handler!(preempt_handler, crate::x86_64::structures::idt::Index::PREEMPT,
+    fn preempt_handler_fn(_stack_frame: &StackFrame) {
+        scheduler::schedule();
+        lapic::eoi();
+    }
+);
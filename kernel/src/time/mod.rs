@@ -1,4 +1,24 @@
+use core::{sync::atomic::{AtomicU64, Ordering}, time::Duration};
+
+use crate::processor;
+
+
 pub mod timer;
+mod timer_wheel;
+
+
+/**
+ * Returns nanoseconds since the BSP timer was initialized without locking or disabling
+ * interrupts. The clock is per processor but APs are offset to the BSP when initializing
+ * their timer, so values read on different processors only differ by calibration error.
+ * Monotonic on the same processor.
+ */
+pub fn now_ns() -> u64 {
+    processor::get().timer().now_ns()
+}
+pub fn uptime() -> Time {
+    Time::from_ns(now_ns())
+}
 
 
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -9,11 +29,18 @@ pub struct Time {
     ns: u16
 }
 impl Time {
+    // Panics if a field is out of range, use "try_new" for times computed at runtime
     #[inline]
     pub const fn new(secs: u64, ms: u16, us: u16, ns: u16) -> Time {
         assert!(ms < 1000 && us < 1000 && ns < 1000);
         Time { secs, ms, us, ns }
     }
+    pub const fn try_new(secs: u64, ms: u16, us: u16, ns: u16) -> Result<Time, TimeError> {
+        if ms >= 1000 || us >= 1000 || ns >= 1000 {
+            return Err(TimeError::FieldOutOfRange);
+        }
+        Ok(Time { secs, ms, us, ns })
+    }
     #[inline]
     pub const fn from_ms(ms: u64) -> Time {
         let secs = ms/1000;
@@ -49,7 +76,40 @@ impl Time {
         *self = *self + Self::from_ns(ns);
     }
 
-    // Converts to timestamp of lowest precision required
+    // Same as the "add_*" functions but leave self unchanged and report overflow instead of saturating
+    pub fn checked_add_secs(&mut self, secs: u64) -> Result<(), TimeError> {
+        *self = self.checked_add(Self::new(secs, 0, 0, 0))?;
+        Ok(())
+    }
+    pub fn checked_add_ms(&mut self, ms: u64) -> Result<(), TimeError> {
+        *self = self.checked_add(Self::from_ms(ms))?;
+        Ok(())
+    }
+    pub fn checked_add_us(&mut self, us: u64) -> Result<(), TimeError> {
+        *self = self.checked_add(Self::from_us(us))?;
+        Ok(())
+    }
+    pub fn checked_add_ns(&mut self, ns: u64) -> Result<(), TimeError> {
+        *self = self.checked_add(Self::from_ns(ns))?;
+        Ok(())
+    }
+    pub fn checked_add(self, rhs: Time) -> Result<Time, TimeError> {
+        // the sum of the sub-second fields carries at most 1 second
+        let carry = (self.subsec_ns() + rhs.subsec_ns() >= 1_000_000_000) as u64;
+        self.secs.checked_add(rhs.secs).and_then(|secs| secs.checked_add(carry))
+            .ok_or(TimeError::Overflow)?;
+        Ok(self + rhs)
+    }
+
+    #[inline]
+    fn subsec_ns(&self) -> u32 {
+        self.ms as u32 * 1_000_000 + self.us as u32 * 1000 + self.ns as u32
+    }
+
+    /*
+     * Converts to timestamp of lowest precision required, which is exact. The "to_*_ts"
+     * conversions truncate the fields finer than their unit.
+     */
     pub fn to_ts(&self) -> Timestamp {
         if self.ns > 0 {
             self.to_ns_ts()
@@ -85,6 +145,39 @@ impl Time {
         timestamp.ts = timestamp.ts.saturating_add(self.ns as u64);
         timestamp
     }
+
+    // Whole time in nanoseconds, saturates at around 584 years
+    #[inline]
+    pub fn total_ns(&self) -> u64 {
+        self.to_ns_ts().ts
+    }
+
+    // Displays compactly in the largest unit that isn't 0, e.g. "1.234s", "5ms" or "800us"
+    pub fn pretty(&self) -> PrettyTime {
+        PrettyTime(*self)
+    }
+}
+impl From<Timestamp> for Time {
+    fn from(timestamp: Timestamp) -> Self {
+        match timestamp.ts_type {
+            TimestampType::Seconds      => Time::new(timestamp.ts, 0, 0, 0),
+            TimestampType::Miliseconds  => Time::from_ms(timestamp.ts),
+            TimestampType::Microseconds => Time::from_us(timestamp.ts),
+            TimestampType::Nanoseconds  => Time::from_ns(timestamp.ts)
+        }
+    }
+}
+impl From<Duration> for Time {
+    fn from(duration: Duration) -> Self {
+        let mut time = Time::from_ns(duration.subsec_nanos() as u64);
+        time.secs = duration.as_secs();
+        time
+    }
+}
+impl From<Time> for Duration {
+    fn from(time: Time) -> Self {
+        Duration::new(time.secs, time.subsec_ns())
+    }
 }
 impl core::ops::Add for Time {
     type Output = Time;
@@ -156,6 +249,65 @@ impl core::fmt::Display for Time {
     }
 }
 
+pub struct PrettyTime(Time);
+impl core::fmt::Display for PrettyTime {
+    /*
+     * The next unit down is the fraction, with up to 3 digits and no trailing zeros.
+     * Microseconds are "µs", which the consoles draw with the VGA font's CP437 glyph.
+     */
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let Time { secs, ms, us, ns } = self.0;
+        let (whole, fraction, unit) = if secs > 0 { (secs, ms, "s") }
+                                      else if ms > 0 { (ms as u64, us, "ms") }
+                                      else if us > 0 { (us as u64, ns, "µs") }
+                                      else { (ns as u64, 0, "ns") };
+
+        write!(f, "{}", whole)?;
+        if fraction > 0 {
+            let mut digits = 3;
+            let mut fraction = fraction;
+            while fraction % 10 == 0 {
+                fraction /= 10;
+                digits -= 1;
+            }
+            write!(f, ".{:0width$}", fraction, width = digits)?;
+        }
+        write!(f, "{}", unit)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimeError {
+    FieldOutOfRange, // ms, us or ns not under 1000
+    Overflow
+}
+impl core::fmt::Display for TimeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TimeError::FieldOutOfRange => write!(f, "Sub-second field of time out of range"),
+            TimeError::Overflow => write!(f, "Time overflowed")
+        }
+    }
+}
+
+// Time stored as nanoseconds, saturates at around 584 years
+pub struct AtomicTime(AtomicU64);
+impl AtomicTime {
+    pub const fn new() -> AtomicTime {
+        AtomicTime(AtomicU64::new(0))
+    }
+
+    pub fn load(&self) -> Time {
+        Time::from_ns(self.load_ns())
+    }
+    pub fn load_ns(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+    pub fn store(&self, time: Time) {
+        self.0.store(time.total_ns(), Ordering::Release);
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum TimestampType { Seconds, Miliseconds, Microseconds, Nanoseconds }
 impl core::fmt::Display for TimestampType {
@@ -179,16 +331,36 @@ impl Timestamp {
         Timestamp { ts, ts_type }
     }
 
+    /**
+     * Converting to a finer unit is exact (saturating), to a coarser one truncates the
+     * remainder (1500ms is 1s), see "to_ts_type_round" to round to the nearest instead.
+     */
     pub fn to_ts_type(&self, ts_type: TimestampType) -> Timestamp {
-        let ts_diff = ts_type as i8 - self.ts_type as i8;
-        let time_mult = if ts_diff == 0 { 1 }
-                             else            { (10 as u64).pow(3*(ts_diff.abs() as u32)) };
+        let (ts_diff, time_mult) = self.ts_type_diff_and_mult(ts_type);
 
         let ts = if ts_diff < 0 { self.ts.saturating_div(time_mult) }
                       else           { self.ts.saturating_mul(time_mult) };
 
         Timestamp::new(ts, ts_type)
     }
+    // Same as "to_ts_type" but rounds to the nearest when converting to a coarser unit (half up)
+    pub fn to_ts_type_round(&self, ts_type: TimestampType) -> Timestamp {
+        let (ts_diff, time_mult) = self.ts_type_diff_and_mult(ts_type);
+
+        if ts_diff >= 0 {
+            return self.to_ts_type(ts_type);
+        }
+
+        let ts = self.ts / time_mult + (self.ts % time_mult >= time_mult - time_mult/2) as u64;
+        Timestamp::new(ts, ts_type)
+    }
+    #[inline]
+    fn ts_type_diff_and_mult(&self, ts_type: TimestampType) -> (i8, u64) {
+        let ts_diff = ts_type as i8 - self.ts_type as i8;
+        let time_mult = if ts_diff == 0 { 1 }
+                             else            { (10 as u64).pow(3*(ts_diff.abs() as u32)) };
+        (ts_diff, time_mult)
+    }
 }
 impl core::fmt::Display for Timestamp {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
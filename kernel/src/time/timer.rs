@@ -1,16 +1,19 @@
-use core::{cmp::{self, Reverse}, sync::atomic::{AtomicBool, Ordering}};
-use alloc::{collections::BinaryHeap, sync::Arc};
+use core::{cmp, sync::atomic::{AtomicBool, AtomicU64, Ordering}};
+use alloc::{sync::Arc, vec::Vec};
 
 use crate::{
-    def_interrupt_handler, processor, scheduler, secs,
-    x86_64::{cpu::tsc, interrupts::{self, apic::lapic::Lapic}}
+    cmdline, def_interrupt_handler, processor, scheduler, secs, trace::{self, Event}, utils::lazy_static::LazyStatic,
+    x86_64::{cpu::tsc, interrupts::{self, apic::{self, lapic::Lapic}}, pit}
 };
-use super::Time;
+use super::{AtomicTime, Time, timer_wheel::TimerWheel};
 
 
-const TIMER_DEFAULT_QUEUE_CAPACITY: usize = 50;
+const TIMER_DEFAULT_EXPIRED_CAPACITY: usize = 50;
 const TIMER_DEFAULT_FREQUENCY: Time = secs!(1);
 
+// Set by the BSP when initializing its timer, which happens before any AP
+static BSP_TIMER_REFERENCE: LazyStatic<TimerReference> = LazyStatic::new();
+
 
 // Halts execution for the duration of time_to_wait
 pub fn wait(time_to_wait: Time) {
@@ -31,12 +34,37 @@ pub fn stop_schedule_timer() {
 pub fn add_schedule_alarm(time_to_wait: Time) {
     processor::get().timer().add_schedule_alarm(time_to_wait);
 }
+// Adds an alarm that runs the watchdog check every period
+pub fn start_watchdog_timer(period: Time) {
+    processor::get().timer().start_watchdog_timer(period);
+}
+// Calibrated TSC frequency, only known if the timers use an invariant TSC
+pub fn tsc_cycles_per_ms() -> Option<u64> {
+    BSP_TIMER_REFERENCE.try_get().and_then(|reference| reference.tsc_cycles_per_ms)
+}
+
+
+// Calibration of the BSP timer used by the APs so all processors share the same clock
+struct TimerReference {
+    tsc_cycles_per_ms: Option<u64>, // only if using the TSC
+    init_tsc: u64 // TSC value when the BSP runtime was 0
+}
 
 
 enum AlarmType {
     Wait { was_triggered: Arc<AtomicBool> },
     // Sleep    {  },
-    Schedule
+    Schedule,
+    Watchdog { period: Time } // added back to the queue every time it's triggered
+}
+impl AlarmType {
+    fn name(&self) -> &'static str {
+        match self {
+            AlarmType::Wait { .. } => "wait",
+            AlarmType::Schedule => "schedule",
+            AlarmType::Watchdog { .. } => "watchdog"
+        }
+    }
 }
 struct Alarm {
     trigger_runtime: Time,
@@ -47,98 +75,190 @@ impl Alarm {
         Alarm { trigger_runtime, alarm_type }
     }
 
-    fn notify(&self) {
+    /**
+     * Returns whether a schedule is required, the timer does it after being restarted
+     * since switching tasks from here would leave it stopped.
+     */
+    fn notify(&self) -> bool {
+        trace::record(Event::AlarmFired(self.alarm_type.name()));
+
         match &self.alarm_type {
-            AlarmType::Wait { was_triggered } =>
-                was_triggered.store(true, Ordering::Release),
-            AlarmType::Schedule => {
-                scheduler::schedule();
-            }
-        };
-    }
-}
-impl PartialEq for Alarm {
-    fn eq(&self, other: &Self) -> bool {
-        self.trigger_runtime.eq(&other.trigger_runtime)
-    }
-}
-impl Eq for Alarm {}
-impl PartialOrd for Alarm {
-    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
-        self.trigger_runtime.partial_cmp(&other.trigger_runtime)
-    }
-}
-impl Ord for Alarm {
-    fn cmp(&self, other: &Self) -> cmp::Ordering {
-        self.trigger_runtime.cmp(&other.trigger_runtime)
+            AlarmType::Wait { was_triggered } => {
+                was_triggered.store(true, Ordering::Release);
+                false
+            },
+            AlarmType::Schedule => true,
+            AlarmType::Watchdog { .. } => scheduler::watchdog::check()
+        }
     }
 }
 
 
 pub struct Timer {
     is_timer_init: bool,
-    alarm_queue: BinaryHeap<Reverse<Alarm>>,
+    alarm_queue: TimerWheel<Alarm>,
+    expired_alarms: Vec<Alarm>, // kept around to avoid allocating on every interrupt
     runtime: Time,
     curr_frequency: Time,
 
+    // copy of runtime for lock-free reads, see now_ns
+    clock: AtomicTime,
+    clock_tsc: AtomicU64, // tsc value runtime was last updated at
+    clock_seq: AtomicU64, // incremented on every update
+    last_now_ns: AtomicU64,
+
     last_lapic_timer_tick_count: u32,
 
     is_using_tsc: bool,
     last_tsc_read: u64,
 
+    // the PIT in periodic mode updates the runtime instead of the LAPIC timer, see init
+    is_using_pit: bool,
+
     schedule_alarm: Option<Alarm>,
 
     should_ignore_interrupt: bool,
-    is_updating_queue: bool,
+    is_schedule_pending: bool,
 
-    ticks_per_ns: u64,
-    ticks_per_us: u64,
-    ticks_per_ms: u64,
-    ticks_per_sec: u64
+    ticks_per_ms: u64
 }
 impl Timer {
     pub fn new() -> Timer {
         Timer {
-            is_timer_init: false, alarm_queue: BinaryHeap::with_capacity(TIMER_DEFAULT_QUEUE_CAPACITY),
+            is_timer_init: false, alarm_queue: TimerWheel::new(),
+            expired_alarms: Vec::with_capacity(TIMER_DEFAULT_EXPIRED_CAPACITY),
             runtime: secs!(0), curr_frequency: TIMER_DEFAULT_FREQUENCY, last_lapic_timer_tick_count: 0,
-            schedule_alarm: None, is_using_tsc: false, last_tsc_read: 0,
-            should_ignore_interrupt: false, is_updating_queue: false,
-            ticks_per_sec: 0, ticks_per_ms: 0, ticks_per_us: 0, ticks_per_ns: 0
+            clock: AtomicTime::new(), clock_tsc: AtomicU64::new(0), clock_seq: AtomicU64::new(0),
+            last_now_ns: AtomicU64::new(0),
+            schedule_alarm: None, is_using_tsc: false, last_tsc_read: 0, is_using_pit: false,
+            should_ignore_interrupt: false, is_schedule_pending: false,
+            ticks_per_ms: 0
         }
     }
 
-    pub fn init(&mut self) {
+    /**
+     * Sets up the LAPIC timer, if it can't be used (no APIC or "nolapictimer" is in the kernel
+     * command line) the BSP falls back to the PIT in periodic mode, which has the resolution of its
+     * period. Since the PIT interrupt goes to a single processor APs can't fall back to it.
+     */
+    pub fn init(&mut self) -> Result<(), &'static str> {
         use crate::x86_64::structures::idt::{Index, Flags};
 
         assert!(self.is_timer_init == false, "Attempted to initialize timer more than once");
 
         let lapic = processor::get().lapic();
-        lapic.setup_timer(Index::LAPIC_TIMER);
+        let reference = BSP_TIMER_REFERENCE.try_get();
+        let lapic_timer_result = if !apic::is_enabled() {
+            Err("No APIC")
+        }
+        else if reference.is_none() && cmdline::has("nolapictimer") {
+            Err("LAPIC timer disabled in kernel command line")
+        }
+        else {
+            lapic.setup_timer(Index::LAPIC_TIMER, reference.and_then(|r| r.tsc_cycles_per_ms))
+        };
+
+        if let Err(err) = lapic_timer_result {
+            if reference.is_some() {
+                return Err(err);
+            }
+            crate::warn!("WARNING: {}, falling back to the PIT.", err);
+            self.init_pit();
+            return Ok(());
+        }
 
         // set timer handler
         interrupts::set_idt_entry(
             Index::LAPIC_TIMER, timer_handler.get_addr(), 0x8, Flags::BASE, 0
         );
 
-        let calc_ticks_per_time = |timer: &mut Timer| {
-            timer.ticks_per_sec = timer.ticks_per_ms.saturating_mul(1000);
-            timer.ticks_per_us = cmp::max(timer.ticks_per_ms/1000, 1);
-            timer.ticks_per_ns = cmp::max(timer.ticks_per_us/1000, 1);
-        };
-
         if lapic.is_tsc_deadline_supported() {
             self.is_using_tsc = true;
             self.ticks_per_ms = lapic.get_tsc_cycles_per_ms();
-            calc_ticks_per_time(self);
             lapic.enable_tsc_deadline();
         }
         else {
             self.ticks_per_ms = lapic.get_timer_ticks_per_ms() as u64;
-            calc_ticks_per_time(self);
         }
         self.start_timer(lapic, TIMER_DEFAULT_FREQUENCY);
 
+        // offset runtime of APs to match the BSP
+        if let Some(reference) = reference {
+            if self.is_using_tsc && reference.tsc_cycles_per_ms.is_some() {
+                // invariant TSCs are synchronized between processors
+                self.runtime = self.ticks_to_time(self.last_tsc_read.saturating_sub(reference.init_tsc));
+            }
+            else {
+                // only as precise as the BSP timer interrupt
+                let bsp_timer = unsafe { processor::get_bsp() }.timer();
+                self.runtime = bsp_timer.clock.load();
+            }
+        }
+        else {
+            BSP_TIMER_REFERENCE.init(TimerReference {
+                tsc_cycles_per_ms: if self.is_using_tsc { Some(self.ticks_per_ms) } else { None },
+                init_tsc: self.last_tsc_read
+            });
+        }
+        self.publish_runtime(self.last_tsc_read);
+
+        self.is_timer_init = true;
+        Ok(())
+    }
+
+    // Only for the BSP, its runtime is the reference for the APs
+    fn init_pit(&mut self) {
+        self.is_using_pit = true;
+        self.ticks_per_ms = (pit::PERIODIC_HZ / 1000) as u64;
+        self.curr_frequency = Self::pit_period();
+
+        BSP_TIMER_REFERENCE.init(TimerReference { tsc_cycles_per_ms: None, init_tsc: 0 });
+        self.publish_runtime(0);
         self.is_timer_init = true;
+
+        pit::start_periodic();
+    }
+
+    #[inline]
+    fn pit_period() -> Time {
+        Time::from_ns(1_000_000_000 / pit::PERIODIC_HZ as u64)
+    }
+
+    /**
+     * Called by the PIT interrupt every period when it's used instead of the LAPIC timer,
+     * alarms are checked on every tick since the period can't be changed.
+     */
+    pub(crate) fn periodic_tick(&mut self) {
+        if !self.is_using_pit {
+            return;
+        }
+
+        self.runtime += Self::pit_period();
+        self.publish_runtime(0);
+        self.update_queue();
+        self.run_pending_schedule();
+    }
+
+    /**
+     * Returns nanoseconds since the timer was initialized without disabling interrupts.
+     * In TSC deadline mode the cycles elapsed since the last runtime update are accounted for,
+     * otherwise the resolution is that of the timer interrupt.
+     */
+    pub fn now_ns(&self) -> u64 {
+        let ns = loop {
+            let seq = self.clock_seq.load(Ordering::Acquire);
+            let mut ns = self.clock.load_ns();
+            if self.is_using_tsc {
+                let cycles_elapsed = tsc::rdtsc().saturating_sub(self.clock_tsc.load(Ordering::Acquire));
+                ns = ns.saturating_add(self.ticks_to_time(cycles_elapsed).total_ns());
+            }
+            // retry if an interrupt updated the runtime in between reads
+            if seq == self.clock_seq.load(Ordering::Acquire) {
+                break ns;
+            }
+        };
+        // runtime updates truncate sub-tick time so make sure it never goes backwards
+        cmp::max(self.last_now_ns.fetch_max(ns, Ordering::AcqRel), ns)
     }
 
     // Halts execution for the duration of time_to_wait
@@ -149,6 +269,7 @@ impl Timer {
         let alarm_type = AlarmType::Wait { was_triggered: was_triggered.clone() };
         self.add_to_queue(time_to_wait, alarm_type);
 
+        // the flag is checked with interrupts disabled before every halt so a wakeup can't be missed
         interrupts::hlt_wait(|| was_triggered.load(Ordering::Acquire) );
     }
 
@@ -157,15 +278,17 @@ impl Timer {
      * and this is called before it has completed it will be reset.
      */
     pub fn start_schedule_timer(&mut self, time_to_wait: Time) {
-        /*
-        * if this was called as result of an alarm triggered while we update
-        * the queue we can simply update it
-        */
-        if self.is_updating_queue {
-            let alarm = Alarm::new(self.runtime + time_to_wait, AlarmType::Schedule);
-            self.schedule_alarm = Some(alarm);
-        }
-        else {
+        use crate::x86_64::interrupts::interrupts_disabled;
+
+        let mut is_alarm_set = false;
+        interrupts_disabled(|| {
+            if let Some(trigger_runtime) = self.trigger_runtime_after_expiry(time_to_wait) {
+                self.schedule_alarm = Some(Alarm::new(trigger_runtime, AlarmType::Schedule));
+                is_alarm_set = true;
+            }
+        });
+
+        if !is_alarm_set {
             self.disable_and_update_timer_run_then_reenable(|timer| {
                 let alarm = Alarm::new(timer.runtime + time_to_wait, AlarmType::Schedule);
                 timer.schedule_alarm = Some(alarm);
@@ -179,25 +302,67 @@ impl Timer {
     pub fn add_schedule_alarm(&mut self, time_to_wait: Time) {
         self.add_to_queue(time_to_wait, AlarmType::Schedule);
     }
+    pub fn start_watchdog_timer(&mut self, period: Time) {
+        self.add_to_queue(period, AlarmType::Watchdog { period });
+    }
 
     // Adds an alarm to the queue
     fn add_to_queue(&mut self, time_to_wait: Time, alarm_type: AlarmType) {
-        /*
-         * if this was called as result of an alarm triggered while we update
-         * the queue we can simply push it
-         */
-        if self.is_updating_queue {
-            let alarm = Alarm::new(self.runtime + time_to_wait, alarm_type);
-            self.alarm_queue.push(Reverse(alarm));
-        }
-        else {
+        use crate::x86_64::interrupts::interrupts_disabled;
+
+        let mut alarm_type = Some(alarm_type);
+        interrupts_disabled(|| {
+            if let Some(trigger_runtime) = self.trigger_runtime_after_expiry(time_to_wait) {
+                let alarm = Alarm::new(trigger_runtime, alarm_type.take().unwrap());
+                self.push_alarm(alarm);
+            }
+        });
+
+        if let Some(alarm_type) = alarm_type {
             self.disable_and_update_timer_run_then_reenable(|timer| {
                 let alarm = Alarm::new(timer.runtime + time_to_wait, alarm_type);
-                timer.alarm_queue.push(Reverse(alarm));
+                timer.push_alarm(alarm);
             });
         }
     }
 
+    #[inline]
+    fn push_alarm(&mut self, alarm: Alarm) {
+        self.alarm_queue.insert(alarm.trigger_runtime.total_ns(), alarm);
+    }
+
+    /**
+     * Returns the runtime after time_to_wait if it is past the expiry of the timer
+     * that is already running, in which case it doesn't have to be restarted.
+     * Interrupts must be disabled.
+     */
+    #[inline]
+    fn trigger_runtime_after_expiry(&self, time_to_wait: Time) -> Option<Time> {
+        let trigger_runtime = self.curr_runtime(processor::get().lapic()) + time_to_wait;
+        if trigger_runtime >= self.runtime + self.curr_frequency {
+            Some(trigger_runtime)
+        }
+        else {
+            None
+        }
+    }
+
+    // Runtime including the time elapsed since it was last updated, without stopping the timer
+    #[inline]
+    fn curr_runtime(&self, lapic: &Lapic) -> Time {
+        if self.is_using_pit {
+            return self.runtime;
+        }
+
+        let ticks_elapsed = if self.is_using_tsc {
+            tsc::rdtsc() - self.last_tsc_read
+        }
+        else {
+            (self.last_lapic_timer_tick_count - lapic.read_curr_timer_tick_count()) as u64
+        };
+        self.runtime + self.ticks_to_time(ticks_elapsed)
+    }
+
     /**
      * Disables and updates the timer runtime, runs closure and then restarts the timer.
      * This must be done for things such as adding a new alarm to the queue to make
@@ -209,6 +374,16 @@ impl Timer {
     {
         use crate::x86_64::interrupts::interrupts_disabled;
 
+        // the PIT keeps running, alarms due before its next tick are triggered on it
+        if self.is_using_pit {
+            interrupts_disabled(|| {
+                closure(self);
+                self.update_queue();
+            });
+            self.run_pending_schedule();
+            return;
+        }
+
         let lapic = processor::get().lapic();
 
         // disable the timers and save the already elapsed ticks
@@ -226,68 +401,96 @@ impl Timer {
             }
         });
 
-        /* Since timer was disabled there should be no concurrency issue      */
+        /*
+         * Any pending timer interrupt was ignored once interrupts were reenabled,
+         * keep them disabled until the timer is restarted so other interrupt
+         * handlers can't see it stopped or half updated.
+         */
+        interrupts_disabled(|| {
+            self.should_ignore_interrupt = false;
 
-        let ticks_elapsed = if let Some(ticks) = curr_lapic_ticks {
-            (self.last_lapic_timer_tick_count - ticks) as u64
-        }
-        else {
-            tsc::rdtsc() - self.last_tsc_read
-        };
-        let time_elapsed = self.ticks_to_time(ticks_elapsed);
-        self.runtime += time_elapsed;
+            let (ticks_elapsed, curr_tsc) = if let Some(ticks) = curr_lapic_ticks {
+                ((self.last_lapic_timer_tick_count - ticks) as u64, 0)
+            }
+            else {
+                let curr_tsc = tsc::rdtsc();
+                (curr_tsc - self.last_tsc_read, curr_tsc)
+            };
+            let time_elapsed = self.ticks_to_time(ticks_elapsed);
+            self.runtime += time_elapsed;
+            self.publish_runtime(curr_tsc);
 
-        closure(self);
+            closure(self);
 
-        self.curr_frequency = self.update_queue();
+            self.curr_frequency = self.update_queue();
 
-        self.should_ignore_interrupt = false;
+            if self.curr_frequency < TIMER_DEFAULT_FREQUENCY {
+                self.start_timer(lapic, self.curr_frequency);
+            }
+            else {
+                self.start_timer(lapic, TIMER_DEFAULT_FREQUENCY);
+            }
+        });
 
-        if self.curr_frequency < TIMER_DEFAULT_FREQUENCY {
-            self.start_timer(lapic, self.curr_frequency);
-        }
-        else {
-            self.start_timer(lapic, TIMER_DEFAULT_FREQUENCY);
+        self.run_pending_schedule();
+    }
+
+    // Schedules required by alarms are deferred until the timer is running again
+    #[inline]
+    fn run_pending_schedule(&mut self) {
+        if self.is_schedule_pending {
+            self.is_schedule_pending = false;
+            scheduler::schedule();
         }
     }
 
+    // Updates the lock-free copy of the runtime, tsc is the value it was calculated at
+    fn publish_runtime(&self, tsc: u64) {
+        use crate::x86_64::interrupts::interrupts_disabled;
+
+        // readers are on the same processor so they can only interleave through an interrupt
+        interrupts_disabled(|| {
+            self.clock.store(self.runtime);
+            self.clock_tsc.store(tsc, Ordering::Release);
+            self.clock_seq.fetch_add(1, Ordering::Release);
+        });
+    }
+
     // Trigger finished alarms and return proper frequency for queue state
     #[inline]
     fn update_queue(&mut self) -> Time {
         let mut timer_required_frequency = TIMER_DEFAULT_FREQUENCY;
-        self.is_updating_queue = true;
 
         if let Some(schedule_alarm_ref) = self.schedule_alarm.as_ref() {
             if schedule_alarm_ref.trigger_runtime <= self.runtime {
-                // must take before call to notify in case alarm gets reset
                 let schedule_alarm = self.schedule_alarm.take().unwrap();
-                schedule_alarm.notify();
-                // in case alarm got reset
-                if let Some(schedule_alarm_ref) = self.schedule_alarm.as_ref() {
-                    if schedule_alarm_ref.trigger_runtime - self.runtime < timer_required_frequency {
-                        timer_required_frequency = schedule_alarm_ref.trigger_runtime - self.runtime;
-                    }
-                }
+                self.is_schedule_pending |= schedule_alarm.notify();
             }
             else if schedule_alarm_ref.trigger_runtime - self.runtime < timer_required_frequency {
                 timer_required_frequency = schedule_alarm_ref.trigger_runtime - self.runtime;
             }
         }
 
-        while let Some(alarm_rev) = self.alarm_queue.peek() {
-            let alarm = &alarm_rev.0;
-            if alarm.trigger_runtime <= self.runtime {
-                alarm.notify();
-                self.alarm_queue.pop();
-                continue;
+        let runtime_ns = self.runtime.total_ns();
+        self.alarm_queue.advance(runtime_ns, &mut self.expired_alarms);
+        let mut watchdog_period = None;
+        for alarm in self.expired_alarms.drain(..) {
+            self.is_schedule_pending |= alarm.notify();
+            if let AlarmType::Watchdog { period } = alarm.alarm_type {
+                watchdog_period = Some(period);
             }
-            else if alarm.trigger_runtime - self.runtime < timer_required_frequency {
-                timer_required_frequency = alarm.trigger_runtime - self.runtime;
+        }
+        if let Some(period) = watchdog_period {
+            self.push_alarm(Alarm::new(self.runtime + period, AlarmType::Watchdog { period }));
+        }
+
+        if let Some(next_expiry_ns) = self.alarm_queue.next_expiry_ns() {
+            let time_until_expiry = Time::from_ns(next_expiry_ns.saturating_sub(runtime_ns));
+            if time_until_expiry < timer_required_frequency {
+                timer_required_frequency = time_until_expiry;
             }
-            break;
         }
 
-        self.is_updating_queue = false;
         timer_required_frequency
     }
 
@@ -306,7 +509,8 @@ impl Timer {
     #[inline]
     fn enable_lapic_timer(&mut self, lapic: &mut Lapic, time_to_wait: Time, is_periodic: bool) {
         let ticks_to_wait = self.time_to_ticks(time_to_wait);
-        let ticks_to_wait = cmp::min(u32::MAX as u64, ticks_to_wait) as u32;
+        // an initial count of 0 would stop the timer instead
+        let ticks_to_wait = cmp::max(cmp::min(u32::MAX as u64, ticks_to_wait) as u32, 1);
         self.last_lapic_timer_tick_count = ticks_to_wait;
         lapic.start_timer(ticks_to_wait, is_periodic);
     }
@@ -317,43 +521,26 @@ impl Timer {
         self.last_tsc_read = lapic.set_tsc_deadline(cycles_to_wait);
     }
 
+    /**
+     * Both conversions scale by the calibrated ticks per ms in 128 bits so nothing overflows
+     * before dividing. Ticks are rounded up so a timer never expires before the time asked for,
+     * while time is truncated so the runtime built from elapsed ticks lags by under 1ns per
+     * update instead of running ahead, either way alarms can only trigger late and never early.
+     */
     #[inline]
     fn time_to_ticks(&self, time: Time) -> u64 {
-        let timestamp = time.to_ts();
-
-        match timestamp.ts_type {
-            super::TimestampType::Seconds =>
-                timestamp.ts.saturating_mul(self.ticks_per_sec),
-            super::TimestampType::Miliseconds =>
-                timestamp.ts.saturating_mul(self.ticks_per_ms),
-            super::TimestampType::Microseconds =>
-                timestamp.ts.saturating_mul(self.ticks_per_us),
-            super::TimestampType::Nanoseconds =>
-                timestamp.ts.saturating_mul(self.ticks_per_ns),
-        }
+        let ticks = (time.total_ns() as u128 * self.ticks_per_ms as u128).div_ceil(1_000_000);
+        cmp::min(ticks, u64::MAX as u128) as u64
     }
-
     #[inline]
     fn ticks_to_time(&self, ticks: u64) -> Time {
-        let div_rem = |dividend: u64, divisor: u64| {
-            (dividend/divisor, dividend%divisor)
-        };
-
-        let (mut secs, ms_ticks) = div_rem(ticks, self.ticks_per_sec);
-        let (mut ms, us_ticks) = div_rem(ms_ticks, self.ticks_per_ms);
-        let (mut us, ns_ticks) = div_rem(us_ticks, self.ticks_per_us);
-        let mut ns = ns_ticks / self.ticks_per_ns;
-
-        if ns >= 1000 { us = us.saturating_add(ns/1000);     ns = ns%1000; }
-        if us >= 1000 { ms = ms.saturating_add(us/1000);     us = us%1000; }
-        if ms >= 1000 { secs = secs.saturating_add(ms/1000); ms = ms%1000; }
-
-        Time::new(secs, ms as u16, us as u16, ns as u16)
+        let ns = ticks as u128 * 1_000_000 / self.ticks_per_ms as u128;
+        Time::from_ns(cmp::min(ns, u64::MAX as u128) as u64)
     }
 }
 
 
-def_interrupt_handler!(timer_handler,
+def_interrupt_handler!(timer_handler, crate::x86_64::structures::idt::Index::LAPIC_TIMER,
     fn timer_handler_fn(_stack_frame: &StackFrame) {
         use crate::x86_64::interrupts::apic::lapic;
 
@@ -369,12 +556,16 @@ def_interrupt_handler!(timer_handler,
 
         // if using tsc update runtime by comparing current tsc with last read
         if timer.is_using_tsc {
-            let cycles_elapsed = tsc::rdtsc() - timer.last_tsc_read;
+            let curr_tsc = tsc::rdtsc();
+            let cycles_elapsed = curr_tsc - timer.last_tsc_read;
             let time_elapsed = timer.ticks_to_time(cycles_elapsed);
             timer.runtime += time_elapsed;
+            timer.publish_runtime(curr_tsc);
         }
         else {
-            timer.runtime += timer.curr_frequency;
+            // the ticks the timer actually ran for, which can be a bit more than asked for
+            timer.runtime += timer.ticks_to_time(timer.last_lapic_timer_tick_count as u64);
+            timer.publish_runtime(0);
         }
 
         timer.curr_frequency = timer.update_queue();
@@ -386,6 +577,8 @@ def_interrupt_handler!(timer_handler,
             timer.start_timer(lapic, TIMER_DEFAULT_FREQUENCY);
         }
 
+        timer.run_pending_schedule();
+
         lapic::eoi();
     }
 );
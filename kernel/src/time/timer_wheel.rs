@@ -0,0 +1,146 @@
+use core::{array, cmp, mem};
+use alloc::vec::Vec;
+
+
+const SLOT_BITS: u32 = 6;
+const SLOTS_PER_LEVEL: usize = 1 << SLOT_BITS;
+const NUM_OF_LEVELS: usize = 6;
+const NS_PER_TICK: u64 = 1000;
+
+
+/**
+ * Hierarchical timer wheel with microsecond ticks, each level has 64 slots and
+ * a slot of a level spans the entire level below it (6 levels cover around 19 hours,
+ * entries further away are placed in the last level and reinserted as it turns).
+ * Insertion is O(1) and entries are moved to a lower level as their expiry gets closer,
+ * so each entry is moved at most NUM_OF_LEVELS times before expiring.
+ * An entry expiring in the current tick is only checked again on the next one.
+ */
+pub struct TimerWheel<T> {
+    levels: [Level<T>; NUM_OF_LEVELS],
+    curr_tick: u64
+}
+struct Level<T> {
+    slots: [Vec<Entry<T>>; SLOTS_PER_LEVEL],
+    occupied: u64 // bitmap of slots that aren't empty
+}
+struct Entry<T> {
+    expiry_ns: u64,
+    value: T
+}
+impl<T> TimerWheel<T> {
+    pub fn new() -> TimerWheel<T> {
+        let levels = array::from_fn(|_| Level { slots: array::from_fn(|_| Vec::new()), occupied: 0 });
+        TimerWheel { levels, curr_tick: 0 }
+    }
+
+    pub fn insert(&mut self, expiry_ns: u64, value: T) {
+        // entries are placed in the tick they expire in, never in the current one
+        let expiry_tick = cmp::max(expiry_ns / NS_PER_TICK, self.curr_tick + 1);
+
+        /*
+         * use the lowest level where the slot is less than a full turn away from the
+         * current one, so a slot of a level only ever holds entries for a single turn
+         */
+        let mut level = 0;
+        let mut placement_tick = expiry_tick;
+        loop {
+            let shift = SLOT_BITS*level as u32;
+            let slots_ahead = (placement_tick >> shift) - (self.curr_tick >> shift);
+            if slots_ahead < SLOTS_PER_LEVEL as u64 {
+                break;
+            }
+            if level == NUM_OF_LEVELS-1 {
+                placement_tick = ((self.curr_tick >> shift) + SLOTS_PER_LEVEL as u64 - 1) << shift;
+                break;
+            }
+            level += 1;
+        }
+
+        let slot = Self::slot_index(placement_tick, level);
+        let level = &mut self.levels[level];
+        level.slots[slot].push(Entry { expiry_ns, value });
+        level.occupied |= 1 << slot;
+    }
+
+    /**
+     * Advances the wheel up to now_ns, pushing expired entries into expired
+     * and moving the ones that got closer to expiring to a lower level.
+     */
+    pub fn advance(&mut self, now_ns: u64, expired: &mut Vec<T>) {
+        let now_tick = now_ns / NS_PER_TICK;
+        if now_tick < self.curr_tick {
+            return;
+        }
+        let prev_tick = self.curr_tick;
+        self.curr_tick = now_tick;
+
+        // entries moved from a level always go to a lower one, so they won't be visited again
+        for level in 0..NUM_OF_LEVELS {
+            let shift = SLOT_BITS*level as u32;
+            let mut slots = Self::slots_between(prev_tick >> shift, now_tick >> shift)
+                & self.levels[level].occupied;
+
+            while slots != 0 {
+                let slot = slots.trailing_zeros() as usize;
+                slots &= slots - 1;
+
+                let entries = mem::take(&mut self.levels[level].slots[slot]);
+                self.levels[level].occupied &= !(1 << slot);
+
+                for entry in entries {
+                    if entry.expiry_ns <= now_ns {
+                        expired.push(entry.value);
+                    }
+                    else {
+                        self.insert(entry.expiry_ns, entry.value);
+                    }
+                }
+            }
+        }
+    }
+
+    /*
+     * Returns the earliest time the wheel has to be advanced at, might be before
+     * the actual earliest expiry if it has to be moved to a lower level first
+     */
+    pub fn next_expiry_ns(&self) -> Option<u64> {
+        let mut next_tick: Option<u64> = None;
+        for (level_index, level) in self.levels.iter().enumerate() {
+            if level.occupied == 0 {
+                continue;
+            }
+            let shift = SLOT_BITS*level_index as u32;
+            let curr_slot = self.curr_tick >> shift;
+
+            // slot of the current tick only has entries that must be moved down
+            let tick = if level.occupied & (1 << Self::slot_index(self.curr_tick, level_index)) != 0 {
+                self.curr_tick + 1
+            }
+            else {
+                let first_slot = (curr_slot + 1) & (SLOTS_PER_LEVEL as u64 - 1);
+                let slots_ahead = level.occupied.rotate_right(first_slot as u32).trailing_zeros() as u64;
+                (curr_slot + 1 + slots_ahead) << shift
+            };
+            next_tick = Some(next_tick.map_or(tick, |next_tick| cmp::min(next_tick, tick)));
+        }
+        next_tick.map(|tick| tick.saturating_mul(NS_PER_TICK))
+    }
+
+    #[inline]
+    fn slot_index(tick: u64, level: usize) -> usize {
+        ((tick >> (SLOT_BITS*level as u32)) & (SLOTS_PER_LEVEL as u64 - 1)) as usize
+    }
+
+    // Bitmap of the slots from first to last (inclusive) wrapping around
+    #[inline]
+    fn slots_between(first: u64, last: u64) -> u64 {
+        let count = last - first + 1;
+        if count >= SLOTS_PER_LEVEL as u64 {
+            u64::MAX
+        }
+        else {
+            (((1 as u64) << count) - 1).rotate_left((first & (SLOTS_PER_LEVEL as u64 - 1)) as u32)
+        }
+    }
+}
@@ -0,0 +1,131 @@
+/*
+ * Per-processor ring buffers of timestamped events, a timeline of what the scheduler, the timer
+ * and interrupt handlers did for when logging would change it too much. Recording neither
+ * blocks nor allocates so it's fine from any context, the oldest events are overwritten.
+ * Without the "trace" feature "record" compiles to nothing and there are no buffers.
+ */
+use crate::scheduler::task::TaskId;
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    TaskSwitchOut(TaskId),
+    TaskSwitchIn(TaskId),
+    InterruptEnter(u8), // vector
+    InterruptExit(u8),
+    AlarmFired(&'static str) // type of alarm
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Record {
+    pub tsc: u64,
+    pub event: Event
+}
+
+
+#[cfg(feature = "trace")]
+pub use buffer::*;
+
+#[cfg(not(feature = "trace"))]
+#[inline(always)]
+pub fn record(_event: Event) {}
+
+#[cfg(not(feature = "trace"))]
+pub fn dump() {
+    crate::println!("Tracing is disabled (kernel built without the \"trace\" feature)");
+}
+
+
+#[cfg(feature = "trace")]
+mod buffer {
+    use core::{cell::UnsafeCell, mem::MaybeUninit, sync::atomic::{self, AtomicUsize, Ordering}};
+    use alloc::{boxed::Box, vec::Vec};
+
+    use super::{Event, Record};
+    use crate::{processor, x86_64::cpu::tsc};
+
+
+    const TRACE_BUFFER_SIZE: usize = 256; // events kept per processor
+
+
+    // Records event in the current processor's buffer, dropped if it isn't registered yet
+    #[inline]
+    pub fn record(event: Event) {
+        if let Some(processor) = processor::try_get() {
+            processor.trace_buffer().push(Record { tsc: tsc::rdtsc(), event });
+        }
+    }
+
+    // Prints the events still in every processor's buffer, merged in TSC order
+    pub fn dump() {
+        let mut records = Vec::new();
+        for (lapic_id, processor) in processor::iter() {
+            processor.trace_buffer().read_recent(|record| records.push((lapic_id, record)));
+        }
+        records.sort_by_key(|(_, record)| record.tsc);
+
+        crate::println!("Trace ({} events):", records.len());
+        for (lapic_id, record) in records {
+            crate::println!("[{:>3}] {:>20} {:?}", lapic_id, record.tsc, record.event);
+        }
+    }
+
+
+    /*
+     * Same slot sequence idea as ArrayQueue but written over instead of waiting to be popped:
+     * the only writer is the processor owning the buffer (or an interrupt nested in it), which
+     * claims a position by incrementing next, marks the slot as being written with 0, writes the
+     * record and stores position + 1 with release. Readers on any processor copy the record out
+     * and only keep it if the sequence was position + 1 both before and after the copy.
+     */
+    pub struct TraceBuffer {
+        slots: Box<[Slot]>,
+        next: AtomicUsize
+    }
+    struct Slot {
+        sequence: AtomicUsize,
+        record: UnsafeCell<MaybeUninit<Record>>
+    }
+    impl TraceBuffer {
+        pub fn new() -> TraceBuffer {
+            let slots = (0..TRACE_BUFFER_SIZE)
+                .map(|_| Slot { sequence: AtomicUsize::new(0), record: UnsafeCell::new(MaybeUninit::uninit()) })
+                .collect();
+            TraceBuffer { slots, next: AtomicUsize::new(0) }
+        }
+
+        fn push(&self, record: Record) {
+            let position = self.next.fetch_add(1, Ordering::Relaxed);
+            let slot = &self.slots[position % self.slots.len()];
+
+            slot.sequence.store(0, Ordering::Relaxed);
+            atomic::fence(Ordering::Release);
+            unsafe { slot.record.get().write_volatile(MaybeUninit::new(record)); }
+            slot.sequence.store(position.wrapping_add(1), Ordering::Release);
+        }
+
+        // Passes the records still in the buffer to f, oldest first, skipping any torn by a write
+        fn read_recent<F>(&self, mut f: F)
+            where F: FnMut(Record)
+        {
+            let end = self.next.load(Ordering::Acquire);
+            let start = end.saturating_sub(self.slots.len());
+
+            for position in start..end {
+                let slot = &self.slots[position % self.slots.len()];
+                let sequence = slot.sequence.load(Ordering::Acquire);
+                if sequence != position.wrapping_add(1) {
+                    continue;
+                }
+
+                let record = unsafe { slot.record.get().read_volatile() };
+                atomic::fence(Ordering::Acquire);
+                if slot.sequence.load(Ordering::Relaxed) == sequence {
+                    f(unsafe { record.assume_init() });
+                }
+            }
+        }
+    }
+    unsafe impl Sync for TraceBuffer {}
+    unsafe impl Send for TraceBuffer {}
+}
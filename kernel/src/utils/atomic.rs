@@ -1,101 +1,145 @@
-use core::{sync::atomic::{AtomicUsize, Ordering}, mem, ptr};
+use core::{cell::UnsafeCell, mem::MaybeUninit, sync::atomic::{AtomicUsize, Ordering}, ptr};
 use alloc::alloc::{alloc, dealloc, Layout};
 
 
-// Lock-free atomic FIFO queue with fixed size
+/**
+ * Lock-free atomic FIFO queue with fixed size, safe with multiple producers and consumers
+ * (bounded MPMC queue by Dmitry Vyukov).
+ *
+ * head and tail are positions that only increase, the slot of a position is position % size.
+ * Each slot has a sequence number telling whose turn it is: equal to the position when it's
+ * free for a push to that position, and to the position + 1 when it holds the value for a pop.
+ * A push or pop claims its position with a CAS on tail or head only if the slot is in the
+ * expected state, writes or reads the value and then hands the slot over by storing the next
+ * sequence number with release, which the acquire load of the next claimer pairs with.
+ */
 pub struct ArrayQueue<T> {
-    buffer_ptr: *mut Option<T>,
+    buffer_ptr: *mut Slot<T>,
     size: usize,
     head: AtomicUsize,
     tail: AtomicUsize
 }
+struct Slot<T> {
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>
+}
 impl<T> ArrayQueue<T> {
     pub fn new(size: usize) -> Option<ArrayQueue<T>> {
+        assert!(size > 0, "ArrayQueue must have a size bigger than 0");
+
         // allocate the buffer
-        let layout = Layout::from_size_align(
-            mem::size_of::<Option<T>>()*size, mem::align_of::<Option<T>>()
-        ).unwrap();
-        let buffer_ptr = unsafe { alloc(layout) as *mut Option<T> };
+        let layout = Layout::array::<Slot<T>>(size).ok()?;
+        let buffer_ptr = unsafe { alloc(layout) as *mut Slot<T> };
 
         if buffer_ptr == ptr::null_mut() {
             return None;
         }
 
-        // set everything to none
-        let buffer = unsafe { core::slice::from_raw_parts_mut(buffer_ptr, layout.size()) };
-        for i in 0..layout.size()/mem::size_of::<Option<T>>() { buffer[i] = None; }
+        // every slot is free for the push to its position in the first turn
+        for i in 0..size {
+            let slot = Slot { sequence: AtomicUsize::new(i), value: UnsafeCell::new(MaybeUninit::uninit()) };
+            unsafe { buffer_ptr.add(i).write(slot); }
+        }
 
         Some(ArrayQueue{ buffer_ptr, size, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) })
     }
 
-    pub fn push(&self, value: T) -> Result<(), ()> {
-        let mut old_tail = self.tail.load(Ordering::Acquire);
-
-        if self.is_full() {
-            return Err(());
-        }
-
-        while let Err(cur_tail) = self.tail.compare_exchange_weak(
-            old_tail, (old_tail+1)%self.size, Ordering::AcqRel, Ordering::Acquire
-        )
-        {
-            old_tail = cur_tail;
-            if self.is_full() {
-                return Err(());
+    // Returns the value back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+
+        loop {
+            let slot = self.slot(tail);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(tail) as isize;
+
+            if diff == 0 {
+                // slot is free, try to claim the position
+                match self.tail.compare_exchange_weak(
+                    tail, tail.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed
+                )
+                {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(value); }
+                        slot.sequence.store(tail.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(curr_tail) => tail = curr_tail
+                }
+            }
+            else if diff < 0 {
+                // slot still holds the value from the previous turn
+                return Err(value);
+            }
+            else {
+                // another push claimed this position
+                tail = self.tail.load(Ordering::Relaxed);
             }
         }
-
-        self.write(old_tail, Some(value));
-
-        Ok(())
     }
 
     pub fn pop(&self) -> Option<T> {
-        let mut old_head = self.head.load(Ordering::Acquire);
-
-        if self.is_empty() { return None; }
-
-        while let Err(cur_head) = self.head.compare_exchange_weak(
-            old_head, (old_head+1)%self.size, Ordering::AcqRel, Ordering::Acquire
-        )
-        {
-            old_head = cur_head;
-            if self.is_empty() { return None; }
+        let mut head = self.head.load(Ordering::Relaxed);
+
+        loop {
+            let slot = self.slot(head);
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(head.wrapping_add(1)) as isize;
+
+            if diff == 0 {
+                // slot holds a value, try to claim the position
+                match self.head.compare_exchange_weak(
+                    head, head.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed
+                )
+                {
+                    Ok(_) => {
+                        let value = unsafe { (*slot.value.get()).assume_init_read() };
+                        // free the slot for the push in the next turn
+                        slot.sequence.store(head.wrapping_add(self.size), Ordering::Release);
+                        return Some(value);
+                    }
+                    Err(curr_head) => head = curr_head
+                }
+            }
+            else if diff < 0 {
+                // no value pushed to this position yet
+                return None;
+            }
+            else {
+                // another pop claimed this position
+                head = self.head.load(Ordering::Relaxed);
+            }
         }
-
-        let value = self.read(old_head).take();
-        self.write(old_head, None);
-
-        value
     }
 
+    // Might already be outdated when returning if others are pushing or popping
     pub fn is_empty(&self) -> bool {
         let head = self.head.load(Ordering::Acquire);
         let tail = self.tail.load(Ordering::Acquire);
-        head == tail && self.read(head).is_none()
+        tail.wrapping_sub(head) as isize <= 0
     }
 
+    // Might already be outdated when returning if others are pushing or popping
     pub fn is_full(&self) -> bool {
         let head = self.head.load(Ordering::Acquire);
         let tail = self.tail.load(Ordering::Acquire);
-        head == tail && !self.read(head).is_none()
+        tail.wrapping_sub(head) as isize >= self.size as isize
     }
 
-    fn write(&self, index: usize, value: Option<T>) {
-        unsafe { core::ptr::write_volatile(self.buffer_ptr.add(index), value) }
-    }
-    fn read(&self, index: usize) -> Option<T> {
-        unsafe { core::ptr::read_volatile(self.buffer_ptr.add(index)) }
+    #[inline]
+    fn slot(&self, position: usize) -> &Slot<T> {
+        unsafe { &*self.buffer_ptr.add(position % self.size) }
     }
 }
 impl<T> Drop for ArrayQueue<T> {
     fn drop(&mut self) {
+        // drop the values still in the queue
+        while let Some(_) = self.pop() {}
+
         let ptr = self.buffer_ptr as *mut u8;
-        let layout = Layout::from_size_align(
-            mem::size_of::<Option<T>>()*self.size, mem::align_of::<Option<T>>()
-        ).unwrap();
+        let layout = Layout::array::<Slot<T>>(self.size).unwrap();
         unsafe { dealloc(ptr, layout); }
     }
 }
-unsafe impl<T> Sync for ArrayQueue<T> {}
-unsafe impl<T> Send for ArrayQueue<T> {}
+unsafe impl<T: Send> Sync for ArrayQueue<T> {}
+unsafe impl<T: Send> Send for ArrayQueue<T> {}
@@ -0,0 +1,70 @@
+use core::hint::spin_loop;
+
+use crate::{processor, scheduler, x86_64::cpu::registers::rflags};
+
+
+// Each step doubles the PAUSEs up to 2^SPIN_LIMIT, "snooze" starts yielding after YIELD_LIMIT steps
+const SPIN_LIMIT: u32 = 6;
+const YIELD_LIMIT: u32 = 10;
+
+
+/*
+ * Exponential backoff for spin loops, the longer a wait takes the more PAUSEs between polls
+ * so contended cache lines (and the bus) are left alone for longer. A new one for each wait:
+ *
+ *     let mut backoff = Backoff::new();
+ *     while !condition() {
+ *         backoff.snooze();
+ *     }
+ */
+pub struct Backoff {
+    step: u32
+}
+impl Backoff {
+    pub const fn new() -> Backoff {
+        Backoff { step: 0 }
+    }
+
+    // For waits that are always short or that can't be left, never yields
+    pub fn spin(&mut self) {
+        for _ in 0..1 << self.step.min(SPIN_LIMIT) {
+            spin_loop();
+        }
+        if self.step <= SPIN_LIMIT {
+            self.step += 1;
+        }
+    }
+
+    /**
+     * Spins like "spin" and once the wait has been long gives the processor to the other ready
+     * tasks instead, whoever is being waited on could be one of them. Keeps spinning where it
+     * can't yield (see "can_yield").
+     */
+    pub fn snooze(&mut self) {
+        if self.step <= YIELD_LIMIT || !can_yield() {
+            for _ in 0..1 << self.step.min(SPIN_LIMIT) {
+                spin_loop();
+            }
+        }
+        else {
+            scheduler::yield_now();
+        }
+        if self.step <= YIELD_LIMIT {
+            self.step += 1;
+        }
+    }
+}
+
+
+/*
+ * Only from a task with interrupts enabled: interrupt handlers can't be switched out and
+ * code that disabled interrupts is in a section that mustn't be left (e.g. holding a lock
+ * taken with "interrupts_disabled"). Before the processor is registered there's no scheduler.
+ */
+fn can_yield() -> bool {
+    if !rflags::is_flag_enabled(rflags::FLAG_INTERRUPT_ENABLED) {
+        return false;
+    }
+    let Some(processor) = processor::try_get() else { return false; };
+    *processor.active_interrupt_count() == 0 && processor.scheduler().get_executing_task().is_some()
+}
@@ -0,0 +1,81 @@
+use crate::{time::{Time, timer}, utils::lazy_static::LazyStatic, x86_64::cpu::{instructions, tsc}};
+
+
+const OVERHEAD_RUNS: usize = 32;
+
+
+// Cycles measured around an empty block, the cost of the measurement itself
+static OVERHEAD_CYCLES: LazyStatic<u64> = LazyStatic::new();
+static IS_RDTSCP_SUPPORTED: LazyStatic<bool> = LazyStatic::new();
+
+
+/**
+ * Runs the block and logs how long it took, evaluates to the value of the block:
+ *
+ *     let frame = bench!("frame allocation", { memory::alloc_frame() });
+ *
+ * Measured in TSC cycles, converted to time if the TSC frequency is known (see "report").
+ * Interrupts and task switches in the middle are measured too, disable them for short blocks.
+ */
+#[macro_export]
+macro_rules! bench {
+    ($name:expr, $body:block) => {{
+        let start = $crate::utils::bench::read_start_tsc();
+        let value = $body;
+        let end = $crate::utils::bench::read_end_tsc();
+        $crate::utils::bench::report($name, end.wrapping_sub(start));
+        value
+    }};
+}
+
+
+/*
+ * The reads are fenced on both sides, otherwise out of order execution moves work in or out
+ * of the measurement: the start is read once every instruction before it has completed and
+ * before the block starts, the end once the block has completed and before anything after.
+ */
+#[inline(always)]
+pub fn read_start_tsc() -> u64 {
+    // checked before the measurement, CPUID is slow and serializing
+    IS_RDTSCP_SUPPORTED.get_or_init(tsc::is_rdtscp_supported);
+    let tsc = tsc::rdtsc_serialized();
+    instructions::lfence();
+    tsc
+}
+#[inline(always)]
+pub fn read_end_tsc() -> u64 {
+    let tsc = if *IS_RDTSCP_SUPPORTED {
+        tsc::rdtscp().0
+    }
+    else {
+        tsc::rdtsc_serialized()
+    };
+    instructions::lfence();
+    tsc
+}
+
+// Logs the cycles of a measurement without the measurement overhead, as time if it can be converted
+pub fn report(name: &str, cycles: u64) {
+    let cycles = cycles.saturating_sub(overhead_cycles());
+    match timer::tsc_cycles_per_ms() {
+        Some(cycles_per_ms) => {
+            let time = Time::from_ns((cycles as u128 * 1_000_000 / cycles_per_ms as u128) as u64);
+            crate::info!("bench {}: {} ({} cycles)", name, time.pretty(), cycles);
+        }
+        None => crate::info!("bench {}: {} cycles", name, cycles)
+    }
+}
+
+// The fastest of a few empty measurements, the others were slowed down by something else
+fn overhead_cycles() -> u64 {
+    *OVERHEAD_CYCLES.get_or_init(|| {
+        (0..OVERHEAD_RUNS)
+            .map(|_| {
+                let start = read_start_tsc();
+                let end = read_end_tsc();
+                end.wrapping_sub(start)
+            })
+            .min()
+            .unwrap()
+    })
+}
@@ -1,7 +1,38 @@
-pub fn eight_bit_modulo(byte_array: &[u8]) -> u64 {
-    let mut sum: u64 = 0;
-    for byte in byte_array {
-        sum += *byte as u64;
+// Sums all bytes wrapping around on overflow, a valid checksummed structure sums to 0
+pub fn sum_bytes(byte_array: &[u8]) -> u8 {
+    byte_array.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::sum_bytes;
+
+    #[test]
+    fn sums_known_bytes() {
+        assert_eq!(sum_bytes(b"ABC"), 0xC6);
+        assert_eq!(sum_bytes(&[0x12, 0x34, 0x56]), 0x9C);
+    }
+
+    #[test]
+    fn sums_nothing_and_zeros_to_zero() {
+        assert_eq!(sum_bytes(&[]), 0);
+        assert_eq!(sum_bytes(&[0; 36]), 0);
+    }
+
+    #[test]
+    fn wraps_around() {
+        assert_eq!(sum_bytes(&[0xFF, 0x01]), 0);
+        assert_eq!(sum_bytes(&[0x80, 0x80, 0x05]), 0x05);
+        assert_eq!(sum_bytes(&[0xFF; 3]), 0xFD);
+    }
+
+    #[test]
+    fn checksummed_bytes_sum_to_zero() {
+        // the last byte is the checksum, 0 until it's set
+        let mut bytes = *b"RSD PTR BOCHS \0";
+        bytes[14] = sum_bytes(&bytes).wrapping_neg();
+        assert_eq!(sum_bytes(&bytes), 0);
     }
-    sum % ((u8::MAX as u64) + 1)
 }
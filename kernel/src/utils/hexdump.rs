@@ -0,0 +1,80 @@
+use core::fmt;
+
+use crate::memory::{FrameSize, MemoryRegion, align_down, align_up, address::{VirtAddr, VirtualAddress}};
+
+
+const BYTES_PER_LINE: usize = 16;
+
+
+// Prints length bytes at addr through the logger, see "lines"
+pub fn hexdump(addr: VirtAddr, length: usize) -> Result<(), &'static str> {
+    for line in lines(addr, length)? {
+        crate::println!("{}", line);
+    }
+    Ok(())
+}
+
+/**
+ * Lines of 16 bytes each formatted as "offset: hex bytes |ascii|", the last one can be shorter.
+ * Fails up front if any page of the range isn't mapped instead of faulting while reading it,
+ * the bytes are read as the lines are formatted (beware of MMIO, reads can have side effects).
+ */
+pub fn lines(addr: VirtAddr, length: usize) -> Result<impl Iterator<Item = HexdumpLine>, &'static str> {
+    let end = addr.as_usize().checked_add(length).ok_or("Range wraps around the address space")?;
+    if length > 0 {
+        // the tables only translate the low 48 bits, the rest has to be a copy of bit 47
+        if !is_canonical(addr.as_usize()) || !is_canonical(end - 1) {
+            return Err("Range isn't canonical");
+        }
+        // from the page the range starts in to the one it ends in
+        let page_size = FrameSize::FourKb.to_bytes();
+        let first_page = align_down(addr.as_usize(), page_size);
+        let region = MemoryRegion::new(first_page, align_up(end, page_size) - first_page);
+        if region.iter(FrameSize::FourKb).any(|page| VirtAddr::new(page).to_phys().is_none()) {
+            return Err("Range isn't mapped");
+        }
+    }
+
+    Ok((0..length).step_by(BYTES_PER_LINE).map(move |offset| {
+        let mut bytes = [0; BYTES_PER_LINE];
+        let line_length = core::cmp::min(BYTES_PER_LINE, length - offset);
+        for (i, byte) in bytes[..line_length].iter_mut().enumerate() {
+            *byte = unsafe { ((addr.as_usize() + offset + i) as *const u8).read_volatile() };
+        }
+        HexdumpLine { offset, bytes, length: line_length }
+    }))
+}
+
+fn is_canonical(addr: usize) -> bool {
+    let upper_bits = addr >> 47;
+    upper_bits == 0 || upper_bits == (1 << 17) - 1
+}
+
+
+pub struct HexdumpLine {
+    offset: usize, // from the start of the dump
+    bytes: [u8; BYTES_PER_LINE],
+    length: usize
+}
+impl fmt::Display for HexdumpLine {
+    // Missing bytes of a short line are padded so the ascii column stays aligned
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}: ", self.offset)?;
+        for i in 0..BYTES_PER_LINE {
+            if i == BYTES_PER_LINE/2 {
+                write!(f, " ")?;
+            }
+            match self.bytes[..self.length].get(i) {
+                Some(byte) => write!(f, "{:02x} ", byte)?,
+                None => write!(f, "   ")?
+            }
+        }
+
+        write!(f, "|")?;
+        for &byte in &self.bytes[..self.length] {
+            let char = if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' };
+            write!(f, "{}", char)?;
+        }
+        write!(f, "|")
+    }
+}
@@ -6,13 +6,13 @@ impl InitOnce {
         InitOnce(AtomicBool::new(false))
     }
 
+    // Only fails if already initialized, strong exchange so it can't fail spuriously
     pub fn init(&self) -> Result<(), ()>{
-        let is_init = self.0.load(Ordering::Acquire);
-        if is_init == true {
+        if self.0.load(Ordering::Acquire) == true {
             return Err(());
         }
-        if let Err(_) = self.0.compare_exchange_weak(
-            is_init, true, Ordering::AcqRel, Ordering::Acquire
+        if let Err(_) = self.0.compare_exchange(
+            false, true, Ordering::AcqRel, Ordering::Acquire
         )
         {
             return Err(());
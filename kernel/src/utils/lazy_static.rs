@@ -1,4 +1,7 @@
-use core::{cell::UnsafeCell, mem::MaybeUninit, ops::{Deref, DerefMut}};
+use core::{
+    cell::UnsafeCell, hint, mem::MaybeUninit, ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, Ordering}
+};
 
 use super::init_once::InitOnce;
 
@@ -25,7 +28,9 @@ pub struct LazyStatic<T>
     where T: Sync
 {
     value: SyncUnsafeCell<MaybeUninit<T>>,
-    is_init: InitOnce
+    is_init: InitOnce,
+    // set after the value is written, is_init is set before
+    is_ready: AtomicBool
 }
 impl<T> LazyStatic<T>
     where T: Sync
@@ -33,17 +38,50 @@ impl<T> LazyStatic<T>
     pub const fn new() -> LazyStatic<T> {
         LazyStatic {
             value: SyncUnsafeCell { value: UnsafeCell::new(MaybeUninit::uninit()) },
-            is_init: InitOnce::new()
+            is_init: InitOnce::new(),
+            is_ready: AtomicBool::new(false)
         }
     }
 
     pub fn init(&self, value: T) {
         self.is_init.init().expect("Attempted to initialize LazyStatic more than once");
         unsafe { (&mut *self.value.get()).write(value); }
+        self.is_ready.store(true, Ordering::Release);
     }
 
+    /*
+     * Initializes with the closure on first access, if another processor is
+     * initializing at the same time waits for it to finish instead.
+     * The closure runs at most once.
+     */
+    pub fn get_or_init<F>(&self, f: F) -> &T
+        where F: FnOnce() -> T
+    {
+        if !self.is_init() {
+            if let Ok(_) = self.is_init.init() {
+                unsafe { (&mut *self.value.get()).write(f()); }
+                self.is_ready.store(true, Ordering::Release);
+            }
+            else {
+                while !self.is_init() {
+                    hint::spin_loop();
+                }
+            }
+        }
+        unsafe { (&*self.value.get()).assume_init_ref() }
+    }
+
+    // Whether the value has been written and can be accessed
     pub fn is_init(&self) -> bool {
-        self.is_init.is_init()
+        self.is_ready.load(Ordering::Acquire)
+    }
+
+    // Returns None instead of panicking if not initialized
+    pub fn try_get(&self) -> Option<&T> {
+        if !self.is_init() {
+            return None;
+        }
+        Some(unsafe { (&*self.value.get()).assume_init_ref() })
     }
 }
 impl<T> Deref for LazyStatic<T>
@@ -51,6 +89,7 @@ impl<T> Deref for LazyStatic<T>
 {
     type Target = T;
     fn deref(&self) -> &Self::Target {
+        assert!(self.is_init(), "Attempted to access LazyStatic before initialization");
         unsafe { (&mut *self.value.get()).assume_init_ref() }
     }
 }
@@ -58,6 +97,7 @@ impl<T> DerefMut for LazyStatic<T>
     where T: Sync
 {
     fn deref_mut(&mut self) -> &mut T {
+        assert!(self.is_init(), "Attempted to access LazyStatic before initialization");
         unsafe { (&mut *self.value.get()).assume_init_mut() }
     }
 }
@@ -2,3 +2,8 @@ pub mod init_once;
 pub mod lazy_static;
 pub mod atomic;
 pub mod checksum;
+pub mod stack_vec;
+pub mod rng;
+pub mod hexdump;
+pub mod backoff;
+pub mod bench;
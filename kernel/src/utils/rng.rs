@@ -0,0 +1,87 @@
+use crate::{
+    locks::spinlock::Spinlock, scheduler::task::{self, TlsKey},
+    x86_64::{cpu::{tsc, rand}, interrupts::interrupts_disabled}
+};
+
+
+// Used while not seeded and in place of 0 (which xorshift can't leave)
+const DEFAULT_SEED: u64 = 0x9E37_79B9_7F4A_7C15;
+
+
+static RNG: Spinlock<Rng> = Spinlock::new(Rng::new(DEFAULT_SEED));
+
+
+// Seeds the global generator from hardware entropy if supported, otherwise from the TSC
+pub fn init() {
+    seed(rand::rdseed().or_else(rand::rdrand).unwrap_or_else(tsc::rdtsc));
+}
+pub fn seed(seed: u64) {
+    interrupts_disabled(|| *RNG.lock() = Rng::new(seed));
+}
+
+// Global generator, shared between processors
+pub fn next_u64() -> u64 {
+    let mut value = 0;
+    interrupts_disabled(|| value = RNG.lock().next_u64());
+    value
+}
+pub fn next_range(lo: u64, hi: u64) -> u64 {
+    let mut value = 0;
+    interrupts_disabled(|| value = RNG.lock().next_range(lo, hi));
+    value
+}
+
+/*
+ * Generator of the executing task, its state is kept in the task's local storage and
+ * seeded from the global generator on first use. Can't be used by the idle task.
+ */
+pub fn task_next_u64() -> u64 {
+    let mut rng = task_rng();
+    let value = rng.next_u64();
+    task::tls_set(TlsKey::RNG, rng.state);
+    value
+}
+pub fn task_next_range(lo: u64, hi: u64) -> u64 {
+    let mut rng = task_rng();
+    let value = rng.next_range(lo, hi);
+    task::tls_set(TlsKey::RNG, rng.state);
+    value
+}
+fn task_rng() -> Rng {
+    let state: u64 = task::tls_get(TlsKey::RNG);
+    // slot is zeroed when the task is created
+    if state == 0 { Rng::new(next_u64()) } else { Rng { state } }
+}
+
+
+// xorshift64* generator, not suitable for cryptography
+pub struct Rng {
+    state: u64
+}
+impl Rng {
+    pub const fn new(seed: u64) -> Rng {
+        Rng { state: if seed == 0 { DEFAULT_SEED } else { seed } }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // Uniformly distributed in [lo, hi)
+    pub fn next_range(&mut self, lo: u64, hi: u64) -> u64 {
+        assert!(lo < hi, "Empty range for random number");
+
+        // reject values from the last incomplete span of range so every value is equally likely
+        let range = hi - lo;
+        let limit = u64::MAX - (u64::MAX % range + 1) % range;
+        loop {
+            let value = self.next_u64();
+            if value <= limit {
+                return lo + value % range;
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use core::{mem::MaybeUninit, ops::{Deref, DerefMut}, ptr, slice};
+
+
+// Vector with fixed capacity stored inline, usable before the heap is initialized
+pub struct StackVec<T, const N: usize> {
+    buffer: [MaybeUninit<T>; N],
+    length: usize
+}
+impl<T, const N: usize> StackVec<T, N> {
+    pub const fn new() -> StackVec<T, N> {
+        // an array of MaybeUninit doesn't require initialization
+        let buffer = unsafe { MaybeUninit::<[MaybeUninit<T>; N]>::uninit().assume_init() };
+        StackVec { buffer, length: 0 }
+    }
+
+    pub const fn len(&self) -> usize {
+        self.length
+    }
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+    pub const fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+    pub const fn is_full(&self) -> bool {
+        self.length == N
+    }
+
+    // Returns the value back if at capacity
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.buffer[self.length].write(value);
+        self.length += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.length -= 1;
+        // length was decremented so the value won't be read (or dropped) again
+        Some(unsafe { self.buffer[self.length].assume_init_read() })
+    }
+
+    // Shifts the values after index to keep the order
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.length, "Attempted to remove from StackVec out of bounds");
+        unsafe {
+            let value = self.buffer[index].assume_init_read();
+            let ptr = self.buffer.as_mut_ptr().add(index);
+            ptr::copy(ptr.add(1), ptr, self.length - index - 1);
+            self.length -= 1;
+            value
+        }
+    }
+
+    pub fn clear(&mut self) {
+        let elements = self.as_mut_slice() as *mut [T];
+        // set length first in case a drop panics
+        self.length = 0;
+        unsafe { ptr::drop_in_place(elements); }
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.buffer.as_ptr() as *const T, self.length) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.buffer.as_mut_ptr() as *mut T, self.length) }
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, T> {
+        self.as_slice().iter()
+    }
+    pub fn iter_mut(&mut self) -> slice::IterMut<'_, T> {
+        self.as_mut_slice().iter_mut()
+    }
+}
+impl<T, const N: usize> Deref for StackVec<T, N> {
+    type Target = [T];
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+impl<T, const N: usize> DerefMut for StackVec<T, N> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.as_mut_slice()
+    }
+}
+impl<'a, T, const N: usize> IntoIterator for &'a StackVec<T, N> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+impl<T, const N: usize> Drop for StackVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
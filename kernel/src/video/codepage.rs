@@ -0,0 +1,30 @@
+// Drawn for characters the VGA font doesn't have, "■"
+pub const REPLACEMENT_GLYPH: u8 = 0xFE;
+
+
+// Characters of glyphs 0x80 to 0xFF of the VGA font, which is in code page 437
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+
+/*
+ * Index of the glyph of c in the VGA font, ASCII is indexed as is (control characters
+ * included, whoever draws them handles the ones it knows) and the rest through CP437_HIGH
+ */
+#[inline]
+pub fn glyph(c: char) -> u8 {
+    if c.is_ascii() {
+        return c as u8;
+    }
+    CP437_HIGH.iter()
+        .position(|high_char| *high_char == c)
+        .map_or(REPLACEMENT_GLYPH, |index| 0x80 + index as u8)
+}
@@ -0,0 +1,62 @@
+use core::{fmt, ptr};
+
+use crate::{locks::spinlock::Spinlock, utils::stack_vec::StackVec, x86_64::interrupts::interrupts_disabled};
+use super::color::Color;
+
+
+const MAX_LOG_CONSOLES: usize = 4;
+
+
+// Where the print macros write, each console has its own lock (always taken after this one)
+static LOG_CONSOLES: Spinlock<StackVec<&'static Spinlock<dyn Console>, MAX_LOG_CONSOLES>> = Spinlock::new(StackVec::new());
+
+
+// A surface text is written to, through fmt::Write
+pub trait Console: fmt::Write + Send {
+    // Clears everything and moves the cursor to the start
+    fn clear(&mut self);
+    fn color(&self) -> Color;
+    fn set_color(&mut self, color: Color);
+    // Moves every line up by one, leaving the last one empty
+    fn scroll(&mut self);
+}
+
+
+/**
+ * Makes the print macros also write to console (see logger::_print), e.g. the screen and a
+ * serial port. Consoles that log themselves while locked would deadlock, like the terminal.
+ */
+pub fn add_log_console(console: &'static Spinlock<dyn Console>) -> Result<(), &'static str> {
+    let mut result = Ok(());
+    // locked with interrupts disabled like in "logger::_print", handlers print
+    interrupts_disabled(|| {
+        let mut consoles = LOG_CONSOLES.lock();
+        result = if consoles.iter().any(|c| ptr::addr_eq(*c, console)) {
+            Err("Console is already a log console")
+        }
+        else {
+            consoles.push(console).map_err(|_| "Too many log consoles")
+        };
+    });
+    result
+}
+pub fn remove_log_console(console: &'static Spinlock<dyn Console>) -> Result<(), &'static str> {
+    let mut result = Err("Console isn't a log console");
+    interrupts_disabled(|| {
+        let mut consoles = LOG_CONSOLES.lock();
+        if let Some(index) = consoles.iter().position(|c| ptr::addr_eq(*c, console)) {
+            consoles.remove(index);
+            result = Ok(());
+        }
+    });
+    result
+}
+
+// Calls closure with each log console locked in turn, interrupts have to be disabled
+pub fn for_each_log_console<F>(mut closure: F)
+    where F: FnMut(&mut dyn Console)
+{
+    for console in LOG_CONSOLES.lock().iter() {
+        closure(&mut *console.lock());
+    }
+}
@@ -5,123 +5,61 @@ use crate::{
     memory::address::VirtAddr, utils::lazy_static::LazyStatic,
 };
 use super::{
-    vesa::{Framebuffer, VBEModeInfo},
-    color::{self, Color, COLOR_BUILDER}
+    console::{self, Console}, text_grid::{self, TextGrid}, vesa::{self, VBEModeInfo},
+    color::{self, Color}
 };
 
 
-const PIXELS_PER_COLUMN: u16 = 9; // 8 bytes per char plus 1 byte for space
-const PIXELS_PER_LINE: u16 = 17;  // 16 bytes per char plus 1 byte for space
+// How long crash paths wait for the locks before stealing them, lets other processors finish a print
+const EMERGENCY_LOCK_TRIES: usize = 10_000_000;
 
 pub static LOGGER: LazyStatic<Spinlock<Logger>> = LazyStatic::new();
 
 pub fn init(vbe_mode_info: &'static VBEModeInfo, vga_bitmap_font_addr: VirtAddr, color: Color) {
     LOGGER.init(Spinlock::new(Logger::new(vbe_mode_info, vga_bitmap_font_addr, color)));
-    LOGGER.lock().clear_screen();
+    LOGGER.lock().clear();
+    console::add_log_console(&*LOGGER).expect("Failed to make the logger a log console");
+}
+// The logger is up before the font and its scale are set (see text_grid::init), so they're applied afterwards
+pub fn init_font() {
+    // warns through the logger, so not while it's locked
+    let (font_result, scale_result) = {
+        let mut logger = LOGGER.lock();
+        (logger.grid.set_font(text_grid::font()), logger.grid.set_scale(text_grid::font_scale()))
+    };
+    if let Err(err) = font_result {
+        crate::warn!("WARNING: {}, keeping the logger's font.", err);
+    }
+    if let Err(err) = scale_result {
+        crate::warn!("WARNING: {}, keeping the logger's font scale.", err);
+    }
 }
 
 pub struct Logger {
-    framebuffer: Framebuffer,
-    vga_bitmap_font: &'static [[u8; 16]; 256],
-    width: u16,
-    column: u16,
-    line: u16,
-    max_column: u16,
-    max_line: u16,
-    color: u32
+    grid: TextGrid
 }
 impl Logger {
     fn new(vbe_mode_info: &'static VBEModeInfo, vga_bitmap_font_addr: VirtAddr, color: Color) -> Logger {
-        let framebuffer = Framebuffer::new(vbe_mode_info);
-        let vga_bitmap_font = unsafe { &*vga_bitmap_font_addr.as_ptr::<[[u8; 16]; 256]>() };
-        let width = vbe_mode_info.width();
-        let max_column = vbe_mode_info.width()/PIXELS_PER_COLUMN;
-        let max_line = vbe_mode_info.height()/PIXELS_PER_LINE;
-        let color = COLOR_BUILDER.build(color);
-        Logger { framebuffer, vga_bitmap_font, width, column: 0, line: 0, max_column, max_line, color }
-    }
-
-    fn write_string(&mut self, input: &str) {
-        for i in input.as_bytes() {
-            if *i == b'\n' {
-                self.new_line();
-            }
-            else {
-                if self.column+1 > self.max_column {
-                    self.wrap_line();
-                }
-                self.draw_char(*i as usize);
-                self.column += 1;
-            }
-        }
+        Logger { grid: TextGrid::new(vbe_mode_info, vga_bitmap_font_addr, color) }
     }
-
-    pub fn get_color(&self) -> Color {
-        COLOR_BUILDER.reverse(self.color)
-    }
-    pub fn set_color(&mut self, color: Color) {
-        self.color = COLOR_BUILDER.build(color);
-    }
-
-    fn new_line(&mut self) {
-        if self.line+1 >= self.max_line {
-            self.scroll_down();
-        }
-        else {
-            self.line += 1;
-        }
-        self.column = 0;
+}
+impl fmt::Write for Logger {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.grid.write_str(s)
     }
-    fn wrap_line(&mut self) {
-        if self.line+1 >= self.max_line {
-            self.scroll_down();
-        }
-        else {
-            self.line += 1;
-        }
-        self.column = 0;
+}
+impl Console for Logger {
+    fn clear(&mut self) {
+        self.grid.clear();
     }
-
-    // Moves every line up by one
-    pub fn scroll_down(&mut self) {
-        // copy 2nd line below one line up
-        let src = self.width as usize * PIXELS_PER_LINE as usize;
-        let length = self.width as usize * ((self.max_line-1)*PIXELS_PER_LINE) as usize;
-        unsafe { self.framebuffer.copy(src, 0, length); }
-        // clear last line
-        let start = self.width as usize * ((self.max_line-1)*PIXELS_PER_LINE) as usize;
-        let length = self.width as usize * PIXELS_PER_LINE as usize;
-        unsafe { self.framebuffer.clear(start, length); }
+    fn color(&self) -> Color {
+        self.grid.color()
     }
-
-    #[inline]
-    fn draw_char(&mut self, i: usize) {
-        let x = self.column*PIXELS_PER_COLUMN;
-        let mut y = self.line*PIXELS_PER_LINE;
-
-        for bitmap_row in self.vga_bitmap_font[i] {
-            let mut x_pos = x;
-            for i in (0..u8::BITS).rev() {
-                if (bitmap_row & (1 << i)) != 0 {
-                    unsafe {
-                        self.framebuffer.put_pixel(x_pos as usize, y as usize, self.color);
-                    }
-                }
-                x_pos += 1;
-            }
-            y += 1;
-        }
+    fn set_color(&mut self, color: Color) {
+        self.grid.set_color(color);
     }
-
-    pub fn clear_screen(&mut self) {
-        self.column = 0; self.line = 0;
-        self.framebuffer.clear_screen();
-    }
-}
-impl fmt::Write for Logger {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        self.write_string(s);
-        Ok(())
+    fn scroll(&mut self) {
+        self.grid.scroll();
     }
 }
 
@@ -154,6 +92,14 @@ macro_rules! println_color {
     ($c:expr,$($arg:tt)*) => ($crate::print_color!($c, "{}\n", format_args!($($arg)*)));
 }
 #[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::video::logger::_emergency_print(None, format_args!($($arg)*)));
+}
+#[macro_export]
+macro_rules! emergency_print_color {
+    ($c:expr,$($arg:tt)*) => ($crate::video::logger::_emergency_print(Some($c), format_args!($($arg)*)));
+}
+#[macro_export]
 macro_rules! eprint {
     ($($arg:tt)*) => ($crate::video::logger::_eprint(format_args!($($arg)*)));
 }
@@ -163,42 +109,63 @@ macro_rules! eprintln {
     ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
 }
 
+// The print macros write to every log console (see console::add_log_console)
 pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
     use crate::x86_64::interrupts::interrupts_disabled;
 
     // execute with interrupts disabled to avoid deadlock
-    interrupts_disabled(|| {
-        LOGGER.lock().write_fmt(args).unwrap();
-    });
+    interrupts_disabled(|| write_to_log_consoles(None, args));
 }
 pub fn _no_enable_irq_print(args: fmt::Arguments) {
-    use core::fmt::Write;
-
-    LOGGER.lock().write_fmt(args).unwrap();
+    write_to_log_consoles(None, args);
 }
 pub fn _print_color(color: Color, args: fmt::Arguments) {
-    use core::fmt::Write;
     use crate::x86_64::interrupts::interrupts_disabled;
 
     // execute with interrupts disabled to avoid deadlock
-    interrupts_disabled(|| {
-        let mut logger = LOGGER.lock();
-        let prev_color = logger.get_color();
-        logger.set_color(color);
-        logger.write_fmt(args).unwrap();
-        logger.set_color(prev_color);
-    });
+    interrupts_disabled(|| write_to_log_consoles(Some(color), args));
 }
 pub fn _no_enable_irq_print_color(color: Color, args: fmt::Arguments) {
+    write_to_log_consoles(Some(color), args);
+}
+fn write_to_log_consoles(color: Option<Color>, args: fmt::Arguments) {
+    console::for_each_log_console(|console| {
+        let prev_color = console.color();
+        if let Some(color) = color {
+            console.set_color(color);
+        }
+        console.write_fmt(args).unwrap();
+        console.set_color(prev_color);
+    });
+}
+pub fn _eprint(args: fmt::Arguments) {
+    print_color!(color::RED, "{args}");
+}
+
+/*
+ * Prints for the panic and exception handlers without deadlocking if they interrupted a print
+ * on this processor, the logger and framebuffer locks are stolen if they can't be taken (see
+ * Spinlock::lock_or_steal). Only to the screen's logger, not the other log consoles.
+ * Interrupts have to be disabled already. Does nothing before the logger is initialized.
+ */
+pub fn _emergency_print(color: Option<Color>, args: fmt::Arguments) {
     use core::fmt::Write;
 
-    let mut logger = LOGGER.lock();
-    let prev_color = logger.get_color();
-    logger.set_color(color);
-    logger.write_fmt(args).unwrap();
+    let Some(logger) = LOGGER.try_get() else { return; };
+    // in the same order as a print takes them, both are held until the end
+    let mut logger = unsafe { logger.lock_or_steal(EMERGENCY_LOCK_TRIES) };
+    let _framebuffer = vesa::lock_for_emergency(EMERGENCY_LOCK_TRIES);
+
+    let prev_color = logger.color();
+    if let Some(color) = color {
+        logger.set_color(color);
+    }
+    let _ = logger.write_fmt(args);
     logger.set_color(prev_color);
 }
-pub fn _eprint(args: fmt::Arguments) {
-    print_color!(color::RED, "{args}");
+pub fn emergency_clear_screen() {
+    let Some(logger) = LOGGER.try_get() else { return; };
+    let mut logger = unsafe { logger.lock_or_steal(EMERGENCY_LOCK_TRIES) };
+    let _framebuffer = vesa::lock_for_emergency(EMERGENCY_LOCK_TRIES);
+    logger.clear();
 }
@@ -1,4 +1,8 @@
 pub mod vesa;
+pub mod codepage;
 pub mod color;
+pub mod console;
+pub mod fonts;
 pub mod logger;
 pub mod terminal;
+pub mod text_grid;
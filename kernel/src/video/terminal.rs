@@ -1,50 +1,60 @@
-use alloc::string::String;
-use alloc::vec::Vec;
+use core::fmt;
+use alloc::{collections::VecDeque, format, string::String};
 
 use crate::{
     drivers::keyboard, locks::spinlock::Spinlock,
-    memory::address::VirtAddr, utils::{init_once::InitOnce, lazy_static::LazyStatic}
+    memory::address::VirtAddr, time, utils::{hexdump, init_once::InitOnce, lazy_static::LazyStatic},
+    x86_64::interrupts::{self, interrupts_disabled}
 };
 use super::{
-    vesa::{Framebuffer, VBEModeInfo},
-    color::{self, COLOR_BUILDER}
+    console::Console, text_grid::{self, TextGrid}, vesa::VBEModeInfo,
+    color::{self, Color}
 };
 
 
-const PIXELS_PER_COLUMN: u16 = 9; // 8 bytes per char plus 1 byte for space
-const PIXELS_PER_LINE: u16 = 17;  // 16 bytes per char plus 1 byte for space
 const INIT_STRING_CAPACITY: usize = 128;
+const HEXDUMP_DEFAULT_LENGTH: usize = 256;
 
 static TERMINAL: LazyStatic<Spinlock<Terminal>> = LazyStatic::new();
-static HAS_FIRST_CHARACTER_BEEN_TYPED: InitOnce = InitOnce::new();
+static HAS_TAKEN_SCREEN: InitOnce = InitOnce::new();
 
 
-pub fn init(vbe_mode_info: &'static VBEModeInfo, vga_bitmap_font_addr: VirtAddr, buffer_capacity: usize) {
-    TERMINAL.init(Spinlock::new(Terminal::new(vbe_mode_info, vga_bitmap_font_addr, buffer_capacity)));
+// Keeps up to max_lines of the lines entered, room for buffer_capacity of them is allocated up front
+pub fn init(vbe_mode_info: &'static VBEModeInfo, vga_bitmap_font_addr: VirtAddr, buffer_capacity: usize, max_lines: usize) {
+    TERMINAL.init(Spinlock::new(Terminal::new(vbe_mode_info, vga_bitmap_font_addr, buffer_capacity, max_lines)));
 }
 
 pub fn terminal_task(_args: *const ()) {
-    use keyboard::scancode::IbmXt;
+    use keyboard::scancode::{IbmXt, Key, ScancodeDecoder};
 
-    let mut terminal = TERMINAL.lock_hlt();
+    let mut decoder = ScancodeDecoder::new();
 
     loop {
         let scancode = keyboard::retrieve_scancode(); // halts until a key is pressed
-        if let Ok(key) = TryInto::<IbmXt>::try_into(scancode) {
+        if let Some(Key::Standard(key)) = decoder.feed(scancode) {
             if let Some(char) = key.to_char() {
-                if let Ok(()) = HAS_FIRST_CHARACTER_BEEN_TYPED.init() {
-                    terminal.clear_screen();
-                }
-
-                terminal.write_string(char);
-
-                if char != "\n" {
-                    terminal.cur_string.push(char.chars().next().unwrap());
-                }
-                else {
-                    terminal.cur_string.shrink_to_fit();
-                    let prev_string = core::mem::replace(&mut terminal.cur_string, String::with_capacity(INIT_STRING_CAPACITY));
-                    terminal.buffer.push(prev_string);
+                let mut command = None;
+                // locked with interrupts disabled like in "_print", handlers can print to it
+                interrupts_disabled(|| {
+                    let mut terminal = TERMINAL.lock();
+                    terminal.take_screen();
+                    terminal.grid.write_string(char);
+
+                    if char != "\n" {
+                        terminal.cur_string.push(char.chars().next().unwrap());
+                    }
+                    else {
+                        command = Some(core::mem::take(&mut terminal.cur_string));
+                    }
+                });
+
+                // commands can print a lot, they only lock for each line they write
+                if let Some(command) = command {
+                    run_command(&command);
+                    interrupts_disabled(|| {
+                        let mut terminal = TERMINAL.lock();
+                        terminal.cur_string = terminal.push_line(command);
+                    });
                 }
             }
             else {
@@ -59,105 +69,147 @@ pub fn terminal_task(_args: *const ()) {
 }
 
 struct Terminal {
-    framebuffer: Framebuffer,
-    vga_bitmap_font: &'static [[u8; 16]; 256],
-    width: u16,
-    column: u16,
-    line: u16,
-    max_column: u16,
-    max_line: u16,
-    color: u32,
-    buffer: Vec<String>,
+    grid: TextGrid,
+    buffer: VecDeque<String>, // lines entered, oldest first
+    max_lines: usize,
     cur_string: String
 }
 impl Terminal {
-    fn new(vbe_mode_info: &'static VBEModeInfo, vga_bitmap_font_addr: VirtAddr, buffer_capacity: usize) -> Terminal {
+    fn new(vbe_mode_info: &'static VBEModeInfo, vga_bitmap_font_addr: VirtAddr, buffer_capacity: usize, max_lines: usize) -> Terminal {
+        let mut grid = TextGrid::new(vbe_mode_info, vga_bitmap_font_addr, color::GREY);
+        if let Err(err) = grid.set_font(text_grid::font()) {
+            crate::warn!("WARNING: {}, keeping the terminal's font.", err);
+        }
+        if let Err(err) = grid.set_scale(text_grid::font_scale()) {
+            crate::warn!("WARNING: {}, keeping the terminal's font scale.", err);
+        }
         Terminal {
-            framebuffer: Framebuffer::new(vbe_mode_info),
-            vga_bitmap_font: unsafe { &*vga_bitmap_font_addr.as_ptr::<[[u8; 16]; 256]>() },
-            width: vbe_mode_info.width(),
-            column: 0, line: 0,
-            max_column: vbe_mode_info.width()/PIXELS_PER_COLUMN,
-            max_line: vbe_mode_info.height()/PIXELS_PER_LINE,
-            color: COLOR_BUILDER.build(color::GREY),
-            buffer: Vec::with_capacity(buffer_capacity),
+            grid,
+            buffer: VecDeque::with_capacity(buffer_capacity.min(max_lines)),
+            max_lines,
             cur_string: String::with_capacity(INIT_STRING_CAPACITY)
         }
     }
 
-    fn write_string(&mut self, input: &str) {
-        for i in input.as_bytes() {
-            if *i == b'\n' {
-                self.new_line();
-            }
-            else {
-                if self.column+1 > self.max_column {
-                    self.wrap_line();
-                }
-                self.draw_char(*i as usize);
-                self.column += 1;
+    /*
+     * Adds line to the buffer, dropping the oldest ones past max_lines, and returns an empty
+     * string for the next line: the last one dropped if any so its allocation is reused
+     */
+    fn push_line(&mut self, line: String) -> String {
+        self.buffer.push_back(line);
+
+        let mut dropped_line = None;
+        while self.buffer.len() > self.max_lines {
+            dropped_line = self.buffer.pop_front();
+        }
+        match dropped_line {
+            Some(mut line) => {
+                line.clear();
+                line
             }
+            None => String::with_capacity(INIT_STRING_CAPACITY)
         }
     }
 
-    fn new_line(&mut self) {
-        if self.line+1 >= self.max_line {
-            self.scroll_down();
-        }
-        else {
-            self.line += 1;
+    /*
+     * The logger has the screen until the terminal is first written to, which clears it.
+     * Both keep their own cursor so anything logged after that is drawn over the terminal.
+     */
+    fn take_screen(&mut self) {
+        if let Ok(()) = HAS_TAKEN_SCREEN.init() {
+            self.grid.clear();
         }
-        self.column = 0;
     }
-    fn wrap_line(&mut self) {
-        if self.line+1 >= self.max_line {
-            self.scroll_down();
-        }
-        else {
-            self.line += 1;
-        }
-        self.column = 0;
+}
+impl fmt::Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.grid.write_str(s)
     }
-
-    // Moves every line up by one
-    fn scroll_down(&mut self) {
-        // copy 2nd line below one line up
-        let src = self.width as usize * PIXELS_PER_LINE as usize;
-        let length = self.width as usize * ((self.max_line-1)*PIXELS_PER_LINE) as usize;
-        unsafe { self.framebuffer.copy(src, 0, length); }
-        // clear last line
-        let start = self.width as usize * ((self.max_line-1)*PIXELS_PER_LINE) as usize;
-        let length = self.width as usize * PIXELS_PER_LINE as usize;
-        unsafe { self.framebuffer.clear(start, length); }
+}
+impl Console for Terminal {
+    fn clear(&mut self) {
+        self.grid.clear();
+    }
+    fn color(&self) -> Color {
+        self.grid.color()
+    }
+    fn set_color(&mut self, color: Color) {
+        self.grid.set_color(color);
     }
+    fn scroll(&mut self) {
+        self.grid.scroll();
+    }
+}
 
-    // fn get_color(&self) -> Color {
-    //     COLOR_BUILDER.reverse(self.color)
-    // }
-    // fn set_color(&mut self, color: Color) {
-    //     self.color = COLOR_BUILDER.build(color);
-    // }
-
-    #[inline]
-    fn draw_char(&mut self, i: usize) {
-        let x = self.column*PIXELS_PER_COLUMN;
-        let mut y = self.line*PIXELS_PER_LINE;
-
-        for bitmap_row in self.vga_bitmap_font[i] {
-            let mut x_pos = x;
-            for i in (0..u8::BITS).rev() {
-                if (bitmap_row & (1 << i)) != 0 {
-                    unsafe {
-                        self.framebuffer.put_pixel(x_pos as usize, y as usize, self.color);
-                    }
+
+// Print macros, for program output as opposed to the logger's:
+#[macro_export]
+macro_rules! tprint {
+    ($($arg:tt)*) => ($crate::video::terminal::_print(format_args!($($arg)*)));
+}
+#[macro_export]
+macro_rules! tprintln {
+    () => ($crate::tprint!("\n"));
+    ($($arg:tt)*) => ($crate::tprint!("{}\n", format_args!($($arg)*)));
+}
+
+// Does nothing before the terminal is initialized
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    let Some(terminal) = TERMINAL.try_get() else { return; };
+    // execute with interrupts disabled to avoid deadlock
+    interrupts_disabled(|| {
+        let mut terminal = terminal.lock();
+        terminal.take_screen();
+        terminal.write_fmt(args).unwrap();
+    });
+}
+
+
+fn run_command(command: &str) {
+    match command.trim() {
+        // dumps the vectors handled by this processor
+        "interrupts" => {
+            for vector in 0..=u8::MAX {
+                let count = interrupts::stats(vector);
+                if count > 0 {
+                    write_locked(&format!("{:#04x}: {}\n", vector, count));
                 }
-                x_pos += 1;
             }
-            y += 1;
+            write_locked(&format!("spurious: {}\n", interrupts::spurious_stats()));
+        }
+        "uptime" => {
+            write_locked(&format!("{}\n", time::uptime().pretty()));
         }
+        // dumps memory, "x <address> [length]" (numbers in hex with 0x or decimal)
+        command if command.starts_with("x ") || command.starts_with("hexdump ") => {
+            let mut args = command.split_whitespace().skip(1);
+            let addr = args.next().and_then(parse_number);
+            let length = args.next().map_or(Some(HEXDUMP_DEFAULT_LENGTH), parse_number);
+
+            let (Some(addr), Some(length)) = (addr, length) else {
+                write_locked("Usage: x <address> [length]\n");
+                return;
+            };
+            match hexdump::lines(VirtAddr::new(addr), length) {
+                Ok(lines) => for line in lines {
+                    write_locked(&format!("{}\n", line));
+                },
+                Err(err) => write_locked(&format!("{}\n", err))
+            }
+        }
+        _ => {}
     }
+}
+// Writes string with the terminal locked and interrupts disabled, the caller formats it beforehand
+fn write_locked(string: &str) {
+    interrupts_disabled(|| TERMINAL.lock().grid.write_string(string));
+}
 
-    fn clear_screen(&mut self) {
-        self.framebuffer.clear_screen();
+fn parse_number(string: &str) -> Option<usize> {
+    match string.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => string.parse().ok()
     }
 }
@@ -0,0 +1,237 @@
+use core::{fmt, sync::atomic::{AtomicU8, AtomicU16, Ordering}};
+
+use crate::{cmdline, memory::address::VirtAddr};
+use super::{
+    codepage, console::Console, fonts, vesa::{self, Framebuffer, VBEModeInfo},
+    color::{Color, COLOR_BUILDER}
+};
+
+
+const PIXELS_PER_COLUMN: u16 = 9; // 8 bytes per char plus 1 byte for space
+const PIXELS_PER_LINE: u16 = 17;  // 16 bytes per char plus 1 byte for space
+const MAX_SCALE: u16 = 8;
+
+
+// font and scale grids are drawn with, see init
+static FONT: AtomicU8 = AtomicU8::new(Font::VGA);
+static FONT_SCALE: AtomicU16 = AtomicU16::new(1);
+
+
+// Fonts a grid can draw with, all of them are 8x16 and in code page 437
+pub struct Font;
+impl Font {
+    pub const VGA: u8 = 0; // passed by the bootloader
+    pub const DEJAVU_SANS_MONO: u8 = 1; // embedded, see fonts.rs
+}
+
+
+/*
+ * Sets the font from "font=<vga|dejavu>" and its scale from "fontscale=<n>" in the kernel
+ * command line, each pixel of the font is drawn as an n*n block (the VGA font at 1 for what
+ * isn't there or is invalid)
+ */
+pub fn init() {
+    let font = match cmdline::get("font") {
+        Some("vga") | None => Font::VGA,
+        Some("dejavu") => Font::DEJAVU_SANS_MONO,
+        Some(value) => {
+            crate::warn!("WARNING: Invalid font \"{}\" in kernel command line, ignoring it.", value);
+            Font::VGA
+        }
+    };
+    FONT.store(font, Ordering::Relaxed);
+
+    let scale = match cmdline::get("fontscale") {
+        Some(value) => match value.parse::<u16>() {
+            Ok(scale) if (1..=MAX_SCALE).contains(&scale) => scale,
+            _ => {
+                crate::warn!("WARNING: Invalid fontscale \"{}\" in kernel command line, ignoring it.", value);
+                1
+            }
+        },
+        None => 1
+    };
+    FONT_SCALE.store(scale, Ordering::Relaxed);
+}
+pub fn font() -> u8 {
+    FONT.load(Ordering::Relaxed)
+}
+pub fn font_scale() -> u16 {
+    FONT_SCALE.load(Ordering::Relaxed)
+}
+
+
+/**
+ * Text drawn on the framebuffer in cells of its font, scale times its size, with a cursor
+ * that wraps at the end of a line and scrolls at the bottom. Each one has its own cursor and
+ * color, the framebuffer is shared (see vesa::with_framebuffer).
+ */
+pub struct TextGrid {
+    vga_bitmap_font: &'static [[u8; 16]; 256],
+    font: &'static [[u8; 16]; 256],
+    width: u16,
+    height: u16,
+    scale: u16,
+    column: u16,
+    line: u16,
+    max_column: u16,
+    max_line: u16,
+    color: u32
+}
+impl TextGrid {
+    pub fn new(vbe_mode_info: &'static VBEModeInfo, vga_bitmap_font_addr: VirtAddr, color: Color) -> TextGrid {
+        let vga_bitmap_font = unsafe { &*vga_bitmap_font_addr.as_ptr::<[[u8; 16]; 256]>() };
+        TextGrid {
+            vga_bitmap_font,
+            font: vga_bitmap_font,
+            width: vbe_mode_info.width(),
+            height: vbe_mode_info.height(),
+            scale: 1,
+            column: 0, line: 0,
+            max_column: vbe_mode_info.width()/PIXELS_PER_COLUMN,
+            max_line: vbe_mode_info.height()/PIXELS_PER_LINE,
+            color: COLOR_BUILDER.build(color)
+        }
+    }
+
+    // Draws from now on with font (see Font), it's the same size so the cursor stays
+    pub fn set_font(&mut self, font: u8) -> Result<(), &'static str> {
+        self.font = match font {
+            Font::VGA => self.vga_bitmap_font,
+            Font::DEJAVU_SANS_MONO => &fonts::DEJAVU_SANS_MONO,
+            _ => return Err("Invalid font")
+        };
+        Ok(())
+    }
+
+    /*
+     * Draws from now on with each pixel of the font as a scale*scale block, text already drawn
+     * stays as is and the cursor moves to the first line below it (to the top if it doesn't fit)
+     */
+    pub fn set_scale(&mut self, scale: u16) -> Result<(), &'static str> {
+        if !(1..=MAX_SCALE).contains(&scale) {
+            return Err("Invalid font scale");
+        }
+        let (max_column, max_line) = (self.width/(PIXELS_PER_COLUMN*scale), self.height/(PIXELS_PER_LINE*scale));
+        if max_column == 0 || max_line == 0 {
+            return Err("Font scale too big for the screen");
+        }
+
+        // end of what's been drawn, the cursor's line only has something on it past column 0
+        let drawn_lines = if self.column > 0 { self.line+1 } else { self.line };
+        let line = (drawn_lines*self.line_height()).div_ceil(PIXELS_PER_LINE*scale);
+
+        self.scale = scale;
+        self.max_column = max_column;
+        self.max_line = max_line;
+        if line < max_line {
+            self.column = 0; self.line = line;
+        }
+        else {
+            self.clear_screen();
+        }
+        Ok(())
+    }
+
+    pub fn write_string(&mut self, input: &str) {
+        vesa::with_framebuffer(|framebuffer| {
+            for c in input.chars() {
+                if c == '\n' {
+                    self.new_line(framebuffer);
+                }
+                else {
+                    if self.column+1 > self.max_column {
+                        self.wrap_line(framebuffer);
+                    }
+                    self.draw_char(framebuffer, codepage::glyph(c) as usize);
+                    self.column += 1;
+                }
+            }
+        });
+    }
+
+    pub fn clear_screen(&mut self) {
+        self.column = 0; self.line = 0;
+        vesa::with_framebuffer(|framebuffer| framebuffer.clear_screen());
+    }
+
+    fn new_line(&mut self, framebuffer: &mut Framebuffer) {
+        if self.line+1 >= self.max_line {
+            self.scroll_down(framebuffer);
+        }
+        else {
+            self.line += 1;
+        }
+        self.column = 0;
+    }
+    fn wrap_line(&mut self, framebuffer: &mut Framebuffer) {
+        if self.line+1 >= self.max_line {
+            self.scroll_down(framebuffer);
+        }
+        else {
+            self.line += 1;
+        }
+        self.column = 0;
+    }
+
+    // Moves every line up by one
+    fn scroll_down(&mut self, framebuffer: &mut Framebuffer) {
+        let line_height = self.line_height();
+        // copy 2nd line below one line up
+        let src = self.width as usize * line_height as usize;
+        let length = self.width as usize * ((self.max_line-1)*line_height) as usize;
+        unsafe { framebuffer.copy(src, 0, length); }
+        // clear last line
+        let start = self.width as usize * ((self.max_line-1)*line_height) as usize;
+        let length = self.width as usize * line_height as usize;
+        unsafe { framebuffer.clear(start, length); }
+    }
+
+    #[inline]
+    fn line_height(&self) -> u16 {
+        PIXELS_PER_LINE*self.scale
+    }
+
+    #[inline]
+    fn draw_char(&mut self, framebuffer: &mut Framebuffer, i: usize) {
+        let scale = self.scale as usize;
+        let x = (self.column*PIXELS_PER_COLUMN) as usize * scale;
+        let mut y = (self.line*PIXELS_PER_LINE) as usize * scale;
+
+        for bitmap_row in self.font[i] {
+            let mut x_pos = x;
+            for i in (0..u8::BITS).rev() {
+                if (bitmap_row & (1 << i)) != 0 {
+                    if scale == 1 {
+                        unsafe { framebuffer.put_pixel(x_pos, y, self.color); }
+                    }
+                    else {
+                        framebuffer.draw_rect(x_pos, y, scale, scale, self.color);
+                    }
+                }
+                x_pos += scale;
+            }
+            y += scale;
+        }
+    }
+}
+impl fmt::Write for TextGrid {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+impl Console for TextGrid {
+    fn clear(&mut self) {
+        self.clear_screen();
+    }
+    fn color(&self) -> Color {
+        COLOR_BUILDER.reverse(self.color)
+    }
+    fn set_color(&mut self, color: Color) {
+        self.color = COLOR_BUILDER.build(color);
+    }
+    fn scroll(&mut self) {
+        vesa::with_framebuffer(|framebuffer| self.scroll_down(framebuffer));
+    }
+}
@@ -1,15 +1,102 @@
-use core::intrinsics::{volatile_copy_memory, volatile_set_memory};
+use core::{
+    cmp, sync::atomic::{AtomicU32, Ordering},
+    intrinsics::{volatile_copy_memory, volatile_copy_nonoverlapping_memory, volatile_set_memory}
+};
 
-use crate::memory::address::{PhysAddr, MutVirtAddr};
+use crate::{
+    locks::spinlock::{Spinlock, SpinlockGuard}, processor, utils::lazy_static::LazyStatic,
+    memory::address::{PhysAddr, VirtAddr, MutVirtAddr}
+};
+
+
+const NO_EMERGENCY_OWNER: u32 = u32::MAX;
+
+
+/*
+ * Single owner of the screen, the logger and the terminal don't hold their own framebuffer
+ * but draw through this one so their writes are serialized and glyphs never get torn.
+ * The logger owns the screen until "terminal::init", after that the terminal owns it
+ * (clearing it on the first key typed) and prints from the logger are drawn on top of it.
+ */
+pub static FRAMEBUFFER: LazyStatic<Spinlock<Framebuffer>> = LazyStatic::new();
+// Processor holding the lock through an EmergencyGuard, it draws without taking the lock again
+static EMERGENCY_OWNER: AtomicU32 = AtomicU32::new(NO_EMERGENCY_OWNER);
+
+pub fn init(vbe_mode_info: &'static VBEModeInfo) {
+    FRAMEBUFFER.init(Spinlock::new(Framebuffer::new(vbe_mode_info)));
+}
+
+/*
+ * Locks the framebuffer for a crash path, stealing the lock after max_tries (see Spinlock::lock_or_steal).
+ * Until the guard is dropped "with_framebuffer" draws through it on this processor, the others wait.
+ * Interrupts have to be disabled already. None before the framebuffer is initialized.
+ */
+pub fn lock_for_emergency(max_tries: usize) -> Option<EmergencyGuard> {
+    let framebuffer = FRAMEBUFFER.try_get()?;
+    let guard = unsafe { framebuffer.lock_or_steal(max_tries) };
+    EMERGENCY_OWNER.store(processor::current_id(), Ordering::Release);
+    Some(EmergencyGuard { _guard: guard })
+}
+pub struct EmergencyGuard {
+    _guard: SpinlockGuard<'static, Framebuffer>
+}
+// The owner is cleared before the lock is released, fields are dropped after "drop"
+impl Drop for EmergencyGuard {
+    fn drop(&mut self) {
+        EMERGENCY_OWNER.store(NO_EMERGENCY_OWNER, Ordering::Release);
+    }
+}
+
+// Executes given closure with the framebuffer locked and interrupts disabled to avoid deadlock
+pub fn with_framebuffer<F>(closure: F)
+    where F: FnOnce(&mut Framebuffer)
+{
+    use crate::x86_64::interrupts::interrupts_disabled;
+
+    interrupts_disabled(|| {
+        if EMERGENCY_OWNER.load(Ordering::Acquire) == processor::current_id() {
+            // the lock is already held by this processor's EmergencyGuard, which isn't used to draw
+            closure(unsafe { &mut *FRAMEBUFFER.as_ptr() });
+        }
+        else {
+            closure(&mut FRAMEBUFFER.lock());
+        }
+    });
+}
 
 
 pub struct VBEModeInfo {
     values: [u8; 256]
 }
 impl VBEModeInfo {
+    pub const MEMORY_MODEL_PACKED_PIXEL: u8 = 4;
+    pub const MEMORY_MODEL_DIRECT_COLOR: u8 = 6;
+
+    // PhysBasePtr is a full dword, so the high word (bytes 42 and 43) is part of the address
     pub fn framebuffer_addr(&self) -> PhysAddr {
-        (self.values[40] as usize | (self.values[41] as usize) << 8 |
-        (self.values[42] as usize) << 16 | (self.values[43] as usize) << 24).into()
+        (self.read_u32(40) as usize).into()
+    }
+
+    pub fn attributes(&self) -> u16 {
+        self.read_u16(0)
+    }
+    pub fn x_char_size(&self) -> u8 {
+        self.values[22]
+    }
+    pub fn y_char_size(&self) -> u8 {
+        self.values[23]
+    }
+    pub fn number_of_planes(&self) -> u8 {
+        self.values[24]
+    }
+    pub fn memory_model(&self) -> u8 {
+        self.values[27]
+    }
+    pub fn number_of_image_pages(&self) -> u8 {
+        self.values[29]
+    }
+    pub fn direct_color_attributes(&self) -> u8 {
+        self.values[39]
     }
 
     pub fn red_mask(&self) -> u8 {
@@ -41,17 +128,110 @@ impl VBEModeInfo {
     }
 
     pub fn pitch(&self) -> u16 {
-        self.values[16] as u16 | (self.values[17] as u16) << 8
+        self.read_u16(16)
     }
     pub fn width(&self) -> u16 {
-        self.values[18] as u16 | (self.values[19] as u16) << 8
+        self.read_u16(18)
     }
     pub fn height(&self) -> u16 {
-        self.values[20] as u16 | (self.values[21] as u16) << 8
+        self.read_u16(20)
     }
     pub fn length(&self) -> usize {
         self.pitch() as usize * self.height() as usize
     }
+
+    // Returns a copy of the mode's fields that can be logged
+    pub fn describe(&self) -> VBEModeDescription {
+        VBEModeDescription {
+            width: self.width(), height: self.height(), pitch: self.pitch(), bpp: self.bpp(),
+            memory_model: self.memory_model(), number_of_planes: self.number_of_planes(),
+            x_char_size: self.x_char_size(), y_char_size: self.y_char_size(),
+            red_mask: self.red_mask(), red_position: self.red_position(),
+            green_mask: self.green_mask(), green_position: self.green_position(),
+            blue_mask: self.blue_mask(), blue_position: self.blue_position(),
+            reserved_mask: self.reserved_mask(), reserved_position: self.reserved_position(),
+            framebuffer_addr: self.framebuffer_addr()
+        }
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.values[offset], self.values[offset+1]])
+    }
+    fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes([
+            self.values[offset], self.values[offset+1], self.values[offset+2], self.values[offset+3]
+        ])
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VBEModeDescription {
+    pub width: u16,
+    pub height: u16,
+    pub pitch: u16,
+    pub bpp: u8,
+    pub memory_model: u8,
+    pub number_of_planes: u8,
+    pub x_char_size: u8,
+    pub y_char_size: u8,
+    pub red_mask: u8,
+    pub red_position: u8,
+    pub green_mask: u8,
+    pub green_position: u8,
+    pub blue_mask: u8,
+    pub blue_position: u8,
+    pub reserved_mask: u8,
+    pub reserved_position: u8,
+    pub framebuffer_addr: PhysAddr
+}
+impl core::fmt::Display for VBEModeDescription {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let memory_model = match self.memory_model {
+            VBEModeInfo::MEMORY_MODEL_PACKED_PIXEL => "Packed Pixel",
+            VBEModeInfo::MEMORY_MODEL_DIRECT_COLOR => "Direct Color",
+            _ => "Other"
+        };
+        write!(
+            f,
+            "{}x{} {}bpp, Pitch: {}, Memory Model: {}, RGB Masks/Positions: {}:{} {}:{} {}:{}, Framebuffer: {:?}",
+            self.width, self.height, self.bpp, self.pitch, memory_model,
+            self.red_mask, self.red_position, self.green_mask, self.green_position,
+            self.blue_mask, self.blue_position, self.framebuffer_addr
+        )
+    }
+}
+
+// Iterates over the list of mode numbers supported by the VBE controller
+pub struct VBEModeListIterator {
+    address: VirtAddr,
+    index: usize
+}
+impl VBEModeListIterator {
+    const END_OF_LIST: u16 = 0xFFFF;
+    const MAX_NUM_OF_MODES: usize = 0x100; // in case the list is malformed
+
+    pub fn new(list_addr: PhysAddr) -> VBEModeListIterator {
+        // no list if bootloader didn't pass one
+        let index = if list_addr == 0 { Self::MAX_NUM_OF_MODES } else { 0 };
+        VBEModeListIterator { address: list_addr.to_virtual(), index }
+    }
+}
+impl Iterator for VBEModeListIterator {
+    type Item = u16;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= Self::MAX_NUM_OF_MODES {
+            return None;
+        }
+
+        let mode = unsafe { self.address.offset::<u16>(self.index).as_ptr::<u16>().read_unaligned() };
+        if mode == Self::END_OF_LIST {
+            self.index = Self::MAX_NUM_OF_MODES;
+            return None;
+        }
+
+        self.index += 1;
+        Some(mode)
+    }
 }
 
 
@@ -59,6 +239,8 @@ pub struct Framebuffer {
     address: MutVirtAddr,
     length: usize,
     pitch: u16,
+    width: u16,
+    height: u16,
     bpp: u8,
 }
 impl Framebuffer {
@@ -66,10 +248,18 @@ impl Framebuffer {
         Framebuffer {
             address: vbe_mode_info.framebuffer_addr().to_mut_virtual(),
             length: vbe_mode_info.length(), pitch: vbe_mode_info.pitch(),
+            width: vbe_mode_info.width(), height: vbe_mode_info.height(),
             bpp: vbe_mode_info.bpp()
         }
     }
 
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
     pub unsafe fn copy(&mut self, src: usize, dst: usize, length: usize) {
         let src = self.address.as_ptr::<u8>().add(src * (self.bpp/8) as usize);
         let dst = self.address.as_ptr::<u8>().add(dst * (self.bpp/8) as usize);
@@ -86,6 +276,57 @@ impl Framebuffer {
         unsafe { volatile_set_memory(self.address.as_ptr::<u8>(), 0, self.length); }
     }
 
+    // Copies raw bytes to the framebuffer starting at byte offset, panics if out of bounds
+    pub fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        assert!(offset.saturating_add(bytes.len()) <= self.length, "Attempted to write past end of framebuffer");
+        unsafe {
+            let dst = self.address.as_ptr::<u8>().add(offset);
+            volatile_copy_nonoverlapping_memory(dst, bytes.as_ptr(), bytes.len());
+        }
+    }
+
+    // Fills a rectangle with color, parts of it outside the framebuffer are clipped
+    pub fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        let x_end = cmp::min(x.saturating_add(width), self.width as usize);
+        let y_end = cmp::min(y.saturating_add(height), self.height as usize);
+
+        for y_pos in y..y_end {
+            for x_pos in x..x_end {
+                unsafe { self.put_pixel(x_pos, y_pos, color); }
+            }
+        }
+    }
+
+    /*
+     * Draws a width*height bitmap of colors (already built for the color depth) stored row by row,
+     * parts of it outside the framebuffer are clipped
+     */
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[u32]) {
+        assert!(pixels.len() >= width*height, "Bitmap smaller than the given dimensions");
+
+        let x_end = cmp::min(x.saturating_add(width), self.width as usize);
+        let y_end = cmp::min(y.saturating_add(height), self.height as usize);
+
+        for y_pos in y..y_end {
+            let row = &pixels[(y_pos-y)*width..];
+            for x_pos in x..x_end {
+                unsafe { self.put_pixel(x_pos, y_pos, row[x_pos-x]); }
+            }
+        }
+    }
+
+    // Returns the color of the pixel at x and y or None if out of bounds
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<u32> {
+        if x >= self.width as usize || y >= self.height as usize {
+            return None;
+        }
+
+        let location = x*(self.bpp/8) as usize + y*self.pitch as usize;
+        let pixel_ptr = (self.address + location).as_ptr::<u32>();
+        let mask = if self.bpp as u32 >= u32::BITS { u32::MAX } else { (1 << self.bpp) - 1 };
+        Some(unsafe { pixel_ptr.read_volatile() } & mask)
+    }
+
     // Caller must check framebuffer bounds
     #[inline]
     pub unsafe fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
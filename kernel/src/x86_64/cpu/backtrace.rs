@@ -0,0 +1,100 @@
+use core::{arch::asm, fmt};
+
+use crate::memory::address::{VirtAddr, VirtualAddress};
+
+
+const MAX_NUM_OF_FRAMES: usize = 32;
+
+
+// General purpose registers (and a few control ones) at the moment they were captured
+#[repr(C)]
+#[derive(Default)]
+pub struct Registers {
+    pub rax: u64, pub rbx: u64, pub rcx: u64, pub rdx: u64,
+    pub rsi: u64, pub rdi: u64, pub rbp: u64, pub rsp: u64,
+    pub r8: u64,  pub r9: u64,  pub r10: u64, pub r11: u64,
+    pub r12: u64, pub r13: u64, pub r14: u64, pub r15: u64,
+    pub rflags: u64, pub cr2: u64, pub cr3: u64
+}
+impl Registers {
+    /*
+     * Inlined so the registers are the caller's, the one holding the
+     * address of the structure while storing them is overwritten
+     */
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let mut registers = Registers::default();
+        unsafe {
+            asm!(
+                "mov [{0}], rax",
+                "mov [{0}+0x8], rbx",
+                "mov [{0}+0x10], rcx",
+                "mov [{0}+0x18], rdx",
+                "mov [{0}+0x20], rsi",
+                "mov [{0}+0x28], rdi",
+                "mov [{0}+0x30], rbp",
+                "mov [{0}+0x38], rsp",
+                "mov [{0}+0x40], r8",
+                "mov [{0}+0x48], r9",
+                "mov [{0}+0x50], r10",
+                "mov [{0}+0x58], r11",
+                "mov [{0}+0x60], r12",
+                "mov [{0}+0x68], r13",
+                "mov [{0}+0x70], r14",
+                "mov [{0}+0x78], r15",
+                in(reg) &mut registers as *mut Registers
+            );
+        }
+        registers.rflags = super::registers::rflags::read();
+        registers.cr2 = super::registers::cr2::read();
+        registers.cr3 = super::registers::cr3::read();
+        registers
+    }
+}
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x} RDX={:016x}", self.rax, self.rbx, self.rcx, self.rdx)?;
+        writeln!(f, "RSI={:016x} RDI={:016x} RBP={:016x} RSP={:016x}", self.rsi, self.rdi, self.rbp, self.rsp)?;
+        writeln!(f, "R8 ={:016x} R9 ={:016x} R10={:016x} R11={:016x}", self.r8, self.r9, self.r10, self.r11)?;
+        writeln!(f, "R12={:016x} R13={:016x} R14={:016x} R15={:016x}", self.r12, self.r13, self.r14, self.r15)?;
+        write!(f, "RFLAGS={:016x} CR2={:016x} CR3={:016x}", self.rflags, self.cr2, self.cr3)
+    }
+}
+
+/**
+ * Walks the frames from rbp (the kernel is built with frame pointers) calling f with each
+ * return address, the innermost first. Stops at a frame pointer that isn't mapped, isn't
+ * aligned or doesn't move up the stack, and after MAX_NUM_OF_FRAMES frames.
+ * Debug symbols are stripped so addresses must be symbolized offline from the unstripped elf.
+ */
+pub fn walk_stack<F>(mut rbp: u64, mut f: F)
+    where F: FnMut(u64)
+{
+    for _ in 0..MAX_NUM_OF_FRAMES {
+        if !is_frame_readable(rbp) {
+            break;
+        }
+
+        // saved rbp followed by the return address
+        let frame = rbp as *const [u64; 2];
+        let [prev_rbp, return_addr] = unsafe { frame.read() };
+        if return_addr == 0 {
+            break;
+        }
+        f(return_addr);
+
+        if prev_rbp <= rbp {
+            break;
+        }
+        rbp = prev_rbp;
+    }
+}
+
+fn is_frame_readable(rbp: u64) -> bool {
+    // canonical (bits 48 to 63 copies of bit 47)
+    let is_canonical = ((rbp as i64) << 16 >> 16) as u64 == rbp;
+    is_canonical && rbp != 0 && rbp % 8 == 0
+        // both words might not be on the same page
+        && VirtAddr::new(rbp as usize).to_phys().is_some()
+        && VirtAddr::new(rbp as usize + 8).to_phys().is_some()
+}
@@ -7,24 +7,60 @@ pub struct CpuidRegs {
     pub ecx: u32,
     pub edx: u32
 }
+// Queries subleaf 0, which is what leaves without subleaves ignore ECX for
 pub fn cpuid(function: u32) -> CpuidRegs {
-    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    cpuid_subleaf(function, 0)
+}
+/*
+ * RBX is reserved by LLVM so it can't be an operand, it's swapped with a register
+ * picked by the compiler instead of being pushed so the stack isn't touched
+ */
+pub fn cpuid_subleaf(function: u32, subleaf: u32) -> CpuidRegs {
+    let (eax, ecx, edx): (u32, u32, u32);
+    let rbx: u64;
     unsafe {
         asm!(
-            "push rbx",
-            "mov eax, {0:e}",
-            "xor rbx, rbx",
+            "mov {0:r}, rbx",
             "cpuid",
-            "mov r8, rbx",
-            "pop rbx",
-            in(reg) function,
-            out("eax") eax,
-            out("r8") ebx,
-            out("ecx") ecx,
-            out("edx") edx
+            "xchg {0:r}, rbx",
+            out(reg) rbx,
+            inout("eax") function => eax,
+            inout("ecx") subleaf => ecx,
+            out("edx") edx,
+            options(nomem, nostack, preserves_flags)
         );
     }
-    CpuidRegs { eax, ebx, ecx, edx }
+    CpuidRegs { eax, ebx: rbx as u32, ecx, edx }
+}
+
+// FS/GS base instructions, only usable once CR4.FSGSBASE is set (see registers::fs_base)
+#[inline]
+pub fn rdfsbase() -> u64 {
+    let value: u64;
+    unsafe { asm!("rdfsbase {}", out(reg) value, options(nomem, nostack, preserves_flags)); }
+    value
+}
+#[inline]
+pub fn wrfsbase(value: u64) {
+    unsafe { asm!("wrfsbase {}", in(reg) value, options(nostack, preserves_flags)); }
+}
+#[inline]
+pub fn rdgsbase() -> u64 {
+    let value: u64;
+    unsafe { asm!("rdgsbase {}", out(reg) value, options(nomem, nostack, preserves_flags)); }
+    value
+}
+#[inline]
+pub fn wrgsbase(value: u64) {
+    unsafe { asm!("wrgsbase {}", in(reg) value, options(nostack, preserves_flags)); }
+}
+
+// Reads IA32_TSC_AUX, if supported (see registers::tsc_aux)
+#[inline]
+pub fn rdpid() -> u64 {
+    let value: u64;
+    unsafe { asm!("rdpid {}", out(reg) value, options(nomem, nostack, preserves_flags)); }
+    value
 }
 
 #[inline]
@@ -52,12 +88,34 @@ pub fn rdmsr(ecx: u32) -> (u32, u32) {
     (edx, eax)
 }
 
+/*
+ * x86 only reorders stores with later loads to other addresses (and accesses to WC memory
+ * or non temporal stores), so lfence and sfence are only needed for those or to serialize
+ * instruction execution (lfence), most code only needs to stop the compiler reordering
+ */
 #[inline]
 pub fn mfence() {
     unsafe {
         asm!("mfence");
     }
 }
+#[inline]
+pub fn lfence() {
+    unsafe {
+        asm!("lfence");
+    }
+}
+#[inline]
+pub fn sfence() {
+    unsafe {
+        asm!("sfence");
+    }
+}
+// prevents the compiler from moving memory accesses across it, emits no instruction
+#[inline]
+pub fn compiler_fence() {
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
 
 #[inline]
 pub fn inb(port: u16) -> u8 {
@@ -130,6 +188,59 @@ pub fn outl(port: u16, value: u32) {
     inl(port); // wait for completion
 }
 
+// reads buffer.len() bytes from port into buffer
+#[inline]
+pub fn insb(port: u16, buffer: &mut [u8]) {
+    unsafe {
+        asm!(
+            "rep insb",
+            inout("rdi") buffer.as_mut_ptr() => _,
+            inout("rcx") buffer.len() => _,
+            in("dx") port,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+// reads buffer.len() words from port into buffer
+#[inline]
+pub fn insw(port: u16, buffer: &mut [u16]) {
+    unsafe {
+        asm!(
+            "rep insw",
+            inout("rdi") buffer.as_mut_ptr() => _,
+            inout("rcx") buffer.len() => _,
+            in("dx") port,
+            options(nostack, preserves_flags)
+        );
+    }
+}
+// writes every byte in buffer to port
+#[inline]
+pub fn outsb(port: u16, buffer: &[u8]) {
+    unsafe {
+        asm!(
+            "rep outsb",
+            inout("rsi") buffer.as_ptr() => _,
+            inout("rcx") buffer.len() => _,
+            in("dx") port,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+}
+// writes every word in buffer to port
+#[inline]
+pub fn outsw(port: u16, buffer: &[u16]) {
+    unsafe {
+        asm!(
+            "rep outsw",
+            inout("rsi") buffer.as_ptr() => _,
+            inout("rcx") buffer.len() => _,
+            in("dx") port,
+            options(nostack, readonly, preserves_flags)
+        );
+    }
+}
+
 #[inline]
 pub fn hlt() { unsafe { asm!("hlt"); } }
 
@@ -139,15 +250,52 @@ pub fn sti() { unsafe { asm!("sti"); } }
 // clear interrupt flag
 #[inline]
 pub fn cli() { unsafe { asm!("cli"); } }
+// invalidate TLB entry of the page containing address, only on the current processor
+#[inline]
+pub fn invlpg(address: usize) {
+    unsafe { asm!("invlpg [{}]", in(reg) address, options(nostack, preserves_flags)); }
+}
+// invalidate TLB entries tagged with PCIDs, which ones depends on kind, only on the current processor
+pub struct InvpcidKind;
+impl InvpcidKind {
+    pub const INDIVIDUAL_ADDRESS: u64 = 0; // of the PCID
+    pub const SINGLE_CONTEXT: u64 = 1; // everything of the PCID
+    pub const ALL_CONTEXTS_AND_GLOBAL: u64 = 2;
+    pub const ALL_CONTEXTS: u64 = 3;
+}
+#[inline]
+pub fn invpcid(kind: u64, pcid: u16, address: usize) {
+    let descriptor: [u64; 2] = [pcid as u64, address as u64];
+    unsafe { asm!("invpcid {}, [{}]", in(reg) kind, in(reg) &descriptor, options(nostack, preserves_flags)); }
+}
 // sti and hlt one after the other, since sti only enables interrupts
 // after the next instruction no interrupts can be fired inbetween the instructions
 #[inline]
 pub fn sti_hlt() { unsafe { asm!("sti", "hlt"); } }
+// arms address monitoring for the cache line containing address, written to it wakes up "mwait"
+#[inline]
+pub fn monitor(address: usize) {
+    unsafe { asm!("monitor", in("rax") address, in("ecx") 0, in("edx") 0, options(nostack, preserves_flags)); }
+}
+// sti and mwait one after the other like "sti_hlt", waits in the C-state hinted until the
+// monitored line is written to or an interrupt comes in
+#[inline]
+pub fn sti_mwait(hints: u32) {
+    unsafe { asm!("sti", "mwait", in("eax") hints, in("ecx") 0, options(nostack)); }
+}
 
 // breakpoint interrupt
 #[inline]
 pub fn int3() { unsafe { asm!("int3"); } }
 
+/*
+ * Software interrupt on VECTOR, its handler runs as if the interrupt was delivered by hardware
+ * except that the CPU never pushes an error code (exceptions that have one would read the
+ * frame wrong) and the LAPIC/PIC don't have it in service (an EOI would end another interrupt)
+ */
+#[inline]
+pub fn int_n<const VECTOR: u8>() { unsafe { asm!("int {}", const VECTOR); } }
+
 // loads gdt descriptor stored at address
 #[inline]
 pub fn lgdt(address: u64) {
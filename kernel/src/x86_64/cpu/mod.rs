@@ -1,4 +1,6 @@
 pub mod registers;
 pub mod instructions;
 pub mod tsc;
+pub mod rand;
+pub mod backtrace;
 pub mod smp;
@@ -0,0 +1,78 @@
+use core::{arch::asm, hint};
+
+use crate::utils::lazy_static::LazyStatic;
+use super::instructions::cpuid;
+
+
+const CPUID_FUNC_GET_FEATURES: u32           = 1;
+const CPUID_GET_FEATURES_ECX_RDRAND_BIT: u32 = 1 << 30;
+const CPUID_FUNC_GET_EXTENDED_FEATURES: u32  = 7;
+const CPUID_EXTENDED_FEATURES_EBX_RDSEED_BIT: u32 = 1 << 18;
+
+// both can fail (CF=0) when the entropy source is exhausted, Intel recommends 10 tries for RDRAND
+const RDRAND_MAX_TRIES: u32 = 10;
+const RDSEED_MAX_TRIES: u32 = 100;
+
+
+static IS_RDRAND_SUPPORTED: LazyStatic<bool> = LazyStatic::new();
+static IS_RDSEED_SUPPORTED: LazyStatic<bool> = LazyStatic::new();
+
+
+pub fn is_rdrand_supported() -> bool {
+    *IS_RDRAND_SUPPORTED.get_or_init(|| {
+        cpuid(CPUID_FUNC_GET_FEATURES).ecx & CPUID_GET_FEATURES_ECX_RDRAND_BIT != 0
+    })
+}
+pub fn is_rdseed_supported() -> bool {
+    *IS_RDSEED_SUPPORTED.get_or_init(|| {
+        cpuid(0).eax >= CPUID_FUNC_GET_EXTENDED_FEATURES
+            && cpuid(CPUID_FUNC_GET_EXTENDED_FEATURES).ebx & CPUID_EXTENDED_FEATURES_EBX_RDSEED_BIT != 0
+    })
+}
+
+// Random number from the processor's DRBG, None if unsupported or it keeps failing
+pub fn rdrand() -> Option<u64> {
+    if !is_rdrand_supported() {
+        return None;
+    }
+
+    for _ in 0..RDRAND_MAX_TRIES {
+        let (value, is_valid): (u64, u8);
+        unsafe {
+            asm!(
+                "rdrand {}",
+                "setc {}",
+                out(reg) value,
+                out(reg_byte) is_valid
+            );
+        }
+        if is_valid != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// Random number straight from the processor's entropy source, None if unsupported or it keeps failing
+pub fn rdseed() -> Option<u64> {
+    if !is_rdseed_supported() {
+        return None;
+    }
+
+    for _ in 0..RDSEED_MAX_TRIES {
+        let (value, is_valid): (u64, u8);
+        unsafe {
+            asm!(
+                "rdseed {}",
+                "setc {}",
+                out(reg) value,
+                out(reg_byte) is_valid
+            );
+        }
+        if is_valid != 0 {
+            return Some(value);
+        }
+        hint::spin_loop();
+    }
+    None
+}
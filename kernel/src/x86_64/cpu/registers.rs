@@ -142,7 +142,11 @@ pub mod ss {
 pub mod rflags {
     use core::arch::asm;
 
+    pub const FLAG_RESERVED: u64 = 1<<1; // always set
+    pub const FLAG_TRAP: u64 = 1<<8;
     pub const FLAG_INTERRUPT_ENABLED: u64 = 1<<9;
+    pub const FLAG_DIRECTION: u64 = 1<<10;
+    pub const FLAG_ALIGNMENT_CHECK: u64 = 1<<18;
 
     pub fn read() -> u64 {
         let value: u64;
@@ -179,6 +183,9 @@ pub mod cr2 {
 pub mod cr3 {
     use core::arch::asm;
 
+    // keeps the TLB entries of the PCID written along with the table (bits 0-11) when CR4.PCIDE is set
+    pub const FLAG_NO_FLUSH: u64 = 1<<63;
+
     pub fn read() -> u64 {
         let value: u64;
         unsafe {
@@ -207,9 +214,46 @@ pub mod cr3 {
     }
 }
 
+pub mod cr4 {
+    use core::arch::asm;
+
+    pub const FLAG_FSGSBASE: u64 = 1<<16;
+    pub const FLAG_PCID: u64 = 1<<17;
+
+    pub fn read() -> u64 {
+        let value: u64;
+        unsafe {
+            asm!(
+                "mov {}, cr4",
+                out(reg) value
+            );
+        }
+        value
+    }
+    pub fn write(value: u64) {
+        unsafe {
+            asm!(
+                "mov cr4, {}",
+                in(reg) value
+            );
+        }
+    }
+}
+
 pub mod cr8 {
     use core::arch::asm;
 
+    pub fn read() -> u64 {
+        let value: u64;
+        unsafe {
+            asm!(
+                "mov {}, cr8",
+                out(reg) value
+            );
+        }
+        value
+    }
+
     pub fn write(value: u64) {
         unsafe {
             asm!(
@@ -219,3 +263,178 @@ pub mod cr8 {
         }
     }
 }
+
+// Model specific registers read and written whole instead of as the EDX:EAX halves
+pub mod msr {
+    use super::super::instructions;
+
+    pub const EFER: u32 = 0xC0000080;
+    pub const STAR: u32 = 0xC0000081;
+    pub const LSTAR: u32 = 0xC0000082;
+    pub const SFMASK: u32 = 0xC0000084;
+
+    pub fn read(index: u32) -> u64 {
+        let (edx, eax) = instructions::rdmsr(index);
+        (edx as u64) << 32 | eax as u64
+    }
+    pub fn write(index: u32, value: u64) {
+        instructions::wrmsr(index, (value >> 32) as u32, value as u32);
+    }
+}
+
+/**
+ * Base address used for fs relative accesses, through rdfsbase/wrfsbase when the CPU supports
+ * them (far cheaper, it's written on every task switch) or the IA32_FS_BASE MSR otherwise.
+ */
+pub mod fs_base {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::{super::instructions, cr4, msr};
+
+    const FS_BASE_MSR_INDEX: u32 = 0xC0000100;
+    const CPUID_FUNC_GET_MAX: u32 = 0;
+    const CPUID_FUNC_GET_EXTENDED_FEATURES: u32 = 7;
+    const CPUID_GET_EXTENDED_FEATURES_EBX_FSGSBASE_BIT: u32 = 1<<0;
+
+    // every processor enables the instructions before its first task switch
+    static IS_FSGSBASE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+    pub fn is_fsgsbase_supported() -> bool {
+        use instructions::{cpuid, cpuid_subleaf};
+
+        if cpuid(CPUID_FUNC_GET_MAX).eax < CPUID_FUNC_GET_EXTENDED_FEATURES {
+            return false;
+        }
+        cpuid_subleaf(CPUID_FUNC_GET_EXTENDED_FEATURES, 0).ebx & CPUID_GET_EXTENDED_FEATURES_EBX_FSGSBASE_BIT != 0
+    }
+
+    /*
+     * Enables the FS/GS base instructions on the current processor if supported, which user
+     * tasks can use too so the FS base is loaded again on entry (see scheduler::reload_tls)
+     */
+    pub fn init() {
+        if is_fsgsbase_supported() {
+            cr4::write(cr4::read() | cr4::FLAG_FSGSBASE);
+            IS_FSGSBASE_ENABLED.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub fn read() -> u64 {
+        if IS_FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+            instructions::rdfsbase()
+        }
+        else {
+            read_msr()
+        }
+    }
+    #[inline]
+    pub fn write(value: u64) {
+        if IS_FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+            instructions::wrfsbase(value);
+        }
+        else {
+            write_msr(value);
+        }
+    }
+
+    pub fn read_msr() -> u64 {
+        msr::read(FS_BASE_MSR_INDEX)
+    }
+    pub fn write_msr(value: u64) {
+        msr::write(FS_BASE_MSR_INDEX, value);
+    }
+}
+
+/**
+ * IA32_TSC_AUX MSR, 32 bits chosen by the OS for each processor that RDPID (or RDTSCP, along
+ * with the TSC) reads far more cheaply than the MSR itself. It's 0 until written.
+ */
+pub mod tsc_aux {
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    use super::{super::{instructions, tsc}, msr};
+
+    const TSC_AUX_MSR_INDEX: u32 = 0xC0000103;
+    const CPUID_FUNC_GET_MAX: u32 = 0;
+    const CPUID_FUNC_GET_EXTENDED_FEATURES: u32 = 7;
+    const CPUID_GET_EXTENDED_FEATURES_ECX_RDPID_BIT: u32 = 1<<22;
+
+    const READ_WITH_NOTHING: u8 = 0;
+    const READ_WITH_RDTSCP: u8 = 1;
+    const READ_WITH_RDPID: u8 = 2;
+
+    // every processor has the same instructions, set by the first one to call "init"
+    static READ_WITH: AtomicU8 = AtomicU8::new(READ_WITH_NOTHING);
+
+    pub fn is_rdpid_supported() -> bool {
+        use instructions::{cpuid, cpuid_subleaf};
+
+        if cpuid(CPUID_FUNC_GET_MAX).eax < CPUID_FUNC_GET_EXTENDED_FEATURES {
+            return false;
+        }
+        cpuid_subleaf(CPUID_FUNC_GET_EXTENDED_FEATURES, 0).ecx & CPUID_GET_EXTENDED_FEATURES_ECX_RDPID_BIT != 0
+    }
+
+    // Picks the instruction "try_read" uses, the MSR can't be read cheaply without one
+    pub fn init() {
+        if READ_WITH.load(Ordering::Relaxed) != READ_WITH_NOTHING {
+            return;
+        }
+        let read_with = if is_rdpid_supported() { READ_WITH_RDPID }
+            else if tsc::is_rdtscp_supported() { READ_WITH_RDTSCP }
+            else { READ_WITH_NOTHING };
+        READ_WITH.store(read_with, Ordering::Relaxed);
+    }
+
+    // None if neither instruction is supported or "init" wasn't called yet
+    #[inline]
+    pub fn try_read() -> Option<u32> {
+        match READ_WITH.load(Ordering::Relaxed) {
+            READ_WITH_RDPID => Some(instructions::rdpid() as u32),
+            READ_WITH_RDTSCP => Some(tsc::rdtscp().1),
+            _ => None
+        }
+    }
+    pub fn is_readable() -> bool {
+        READ_WITH.load(Ordering::Relaxed) != READ_WITH_NOTHING
+    }
+    pub fn write(value: u32) {
+        msr::write(TSC_AUX_MSR_INDEX, value as u64);
+    }
+}
+
+/**
+ * IA32_PAT MSR, memory types selected by the PAT, NO_CACHE and WRITE_THROUGH page flags.
+ * Entries 0-3 are left as the reset values (write back, write through, UC-, uncached) so
+ * the flags keep their usual meaning, entry 4 (only the PAT flag) is made write combining.
+ * Without a PAT the PAT flag is reserved, write combining mappings have to be uncached instead.
+ */
+pub mod pat {
+    use super::{super::instructions, msr};
+
+    const PAT_MSR_INDEX: u32 = 0x277;
+    const MEMORY_TYPE_WRITE_COMBINING: u64 = 0x01;
+    const ENTRY_WRITE_COMBINING: u32 = 4;
+    const CPUID_FUNC_GET_FEATURES: u32 = 1;
+    const CPUID_GET_FEATURES_EDX_PAT_BIT: u32 = 1<<16;
+
+    pub fn is_supported() -> bool {
+        instructions::cpuid(CPUID_FUNC_GET_FEATURES).edx & CPUID_GET_FEATURES_EDX_PAT_BIT != 0
+    }
+
+    // Has to be called on every processor before write combining pages are mapped
+    pub fn init() {
+        if !is_supported() {
+            return;
+        }
+
+        let mut value = msr::read(PAT_MSR_INDEX);
+
+        let entry_shift = ENTRY_WRITE_COMBINING * 8;
+        value &= !(0xFF << entry_shift);
+        value |= MEMORY_TYPE_WRITE_COMBINING << entry_shift;
+
+        msr::write(PAT_MSR_INDEX, value);
+    }
+}
@@ -97,13 +97,22 @@ trampoline_spin_loop: # wait for bsp
     cmp byte ptr [rax], 0
     jnz trampoline_spin_loop
 
-    # setup stack
+    # setup stack, taking it so no other AP can (xchg with memory is atomic)
     mov rax, [0x8090]
-    mov rsp, [rax]
+    xor rsp, rsp
+    xchg rsp, [rax]
+    test rsp, rsp
+    jz trampoline_halt
 
     # jump to "init_ap" in smp/mod.rs with stack top addr as 1st param (rdi)
     mov rdi, rsp
     mov rax, [0x8088]
     jmp rax
 
+    # the BSP gave up on this AP and took the stack back
+trampoline_halt:
+    cli
+    hlt
+    jmp trampoline_halt
+
 trampoline_end:
@@ -1,17 +1,18 @@
 use core::{
     arch::global_asm, intrinsics::volatile_copy_memory,
-    sync::atomic::{AtomicBool, Ordering}, mem, ptr
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering}, mem, ptr
 };
 use alloc::alloc::{alloc, dealloc, Layout};
 
 use crate::{
-    memory::{address::VirtualAddress, paging}, ms, us, processor, scheduler::task::Task,
-    time::{Time, timer}, utils::init_once::InitOnce,
+    memory::{address::{PhysAddr, VirtualAddress}, paging}, ms, us, processor, scheduler::task::Task,
+    time::{Time, timer}, utils::{backoff::Backoff, init_once::InitOnce},
     x86_64::{structures::acpi, interrupts::{self, apic::lapic}, cpu}
 };
 
 
-const TRAMPOLINE_ADDR: u32  = 0x8000;
+pub const TRAMPOLINE_ADDR: u32 = 0x8000;
+pub const TRAMPOLINE_FRAME_SIZE: usize = 0x1000;
 const AP_TEMP_STACK_LENGTH: usize = 4096;
 const INIT_AP_STACK_LENGTH: usize = 32768;
 
@@ -27,80 +28,189 @@ extern {
     // from trampoline.s
     static trampoline_start: ();
     static trampoline_end: ();
-    static mut pml4_addr_0x8080: u64;
-    static mut init_ap_fn_addr_0x8088: u64;
-    static mut stack_top_addr_ptr_0x8090: u64;
-    static mut trampoline_lock_addr_0x8098: u64;
+    static pml4_addr_0x8080: u64;
+    static init_ap_fn_addr_0x8088: u64;
+    static stack_top_addr_ptr_0x8090: u64;
+    static trampoline_lock_addr_0x8098: u64;
 }
 
 static IS_SMP_INIT: InitOnce = InitOnce::new();
+static IS_TRAMPOLINE_RELEASED: InitOnce = InitOnce::new();
+static IS_STARTING_AP: AtomicBool = AtomicBool::new(false);
+// read by the trampoline through the pointers filled in it
+static CURR_AP_STACK_TOP_ADDR: AtomicUsize = AtomicUsize::new(0);
+static TRAMPOLINE_LOCK: AtomicU8 = AtomicU8::new(1);
 static BSP_LOCK: AtomicBool = AtomicBool::new(true);
 static INIT_AP_LOCK: AtomicBool = AtomicBool::new(true);
 
 
+// Whether any AP has been started
 pub fn is_init() -> bool {
-    IS_SMP_INIT.is_init()
+    processor::count() > 1
 }
 
-#[allow(unused_assignments)]
-pub fn init() {
-    IS_SMP_INIT.init().expect("Attempted to initialize SMP more than once");
-
-    let mut curr_ap_stack_top_addr: usize = 0;
-    let mut trampoline_lock: u8 = 1;
+/*
+ * Zeroes the trampoline frame once the APs are online and returns it so it can be reused,
+ * the caller has to make sure nothing else left by the bootloader lives in it.
+ * No AP can be started after this.
+ */
+pub fn release_trampoline() -> PhysAddr {
+    use core::intrinsics::volatile_set_memory;
 
-    unsafe {
-        // fill values to be used in trampoline code
-        let table4 = paging::Table::table4();
-        pml4_addr_0x8080 = table4.address.to_phys().unwrap().as_usize() as u64;
-        init_ap_fn_addr_0x8088 = init_ap as u64;
-        stack_top_addr_ptr_0x8090 = &curr_ap_stack_top_addr as *const _ as u64;
-        trampoline_lock_addr_0x8098 = &trampoline_lock as *const _ as u64;
+    IS_TRAMPOLINE_RELEASED.init().expect("Attempted to release the AP trampoline more than once");
 
-        let trampoline_dst = TRAMPOLINE_ADDR as *mut u8;
-        let trampoline_src = &trampoline_start as *const _ as usize as *const u8;
-        let trampoline_len = &trampoline_end as *const _ as usize - trampoline_src as usize;
+    let trampoline_frame = PhysAddr::new(TRAMPOLINE_ADDR as usize);
+    unsafe { volatile_set_memory(trampoline_frame.to_mut_virtual().as_ptr::<u8>(), 0, TRAMPOLINE_FRAME_SIZE); }
+    trampoline_frame
+}
 
-        volatile_copy_memory(trampoline_dst, trampoline_src, trampoline_len);
-    }
+// Starts every processor in the MADT until max_cpus processors (counting the BSP) are online
+pub fn init(max_cpus: usize) {
+    IS_SMP_INIT.init().expect("Attempted to initialize SMP more than once");
 
     let bsp_id = lapic::get_id();
     let madt = acpi::get_madt();
     for entry in madt.processor_lapic_iter()
         .filter(|e| e.get_id() != bsp_id)
     {
-        curr_ap_stack_top_addr = unsafe { alloc_temp_stack() } + AP_TEMP_STACK_LENGTH;
-
-        let lapic_id = entry.get_id();
-        processor::register(lapic_id);
-
-        trampoline_lock = 1;
-        // send IPIs to init AP
-        lapic::send_init_ipi(lapic_id);
-        timer::wait(ms!(10));
-        lapic::send_startup_ipi(lapic_id, TRAMPOLINE_ADDR);
-        timer::wait(us!(200));
-        lapic::send_startup_ipi(lapic_id, TRAMPOLINE_ADDR);
-        trampoline_lock = 0;
-
-        // wait for AP to unlock BSP
-        let mut was_ap_init = false;
-        for _ in 0..WAS_TRAMPOLINE_EXECUTED_MAX_TRIES {
-            if BSP_LOCK.load(Ordering::Acquire) == false {
-                was_ap_init = true;
-                break;
-            }
-            timer::wait(WAS_TRAMPOLINE_EXECUTED_TIME_PER_TRY);
+        if processor::count() >= max_cpus {
+            break;
         }
-        BSP_LOCK.store(true, Ordering::SeqCst);
 
-        // if AP wasn't initialized unregister it
-        if was_ap_init == false {
+        if let Err(err) = start_ap(entry.get_id()) {
+            crate::warn!("WARNING: Processor {} wasn't started: {}", entry.get_id(), err);
+        }
+    }
+}
+
+/**
+ * Brings up a single AP, returns once it's running kernel code on its own stack.
+ * Only the BSP can start APs and only one at a time.
+ *
+ * The trampoline reads CURR_AP_STACK_TOP_ADDR and spins on TRAMPOLINE_LOCK through the
+ * pointers filled in it, so they're atomics to keep the compiler from dropping or moving
+ * the stores to them. x86 doesn't reorder stores, so with the compiler fence the AP sees
+ * them updated before the IPIs are sent (which are stores to the LAPIC) and the stack top
+ * before the lock release.
+ * The AP takes its stack by atomically swapping the stack top with 0, so a stack is never
+ * handed to more than one AP. If an AP doesn't release BSP_LOCK in time the BSP relocks the
+ * trampoline and takes the stack back the same way: if it's already gone the AP is running
+ * kernel code and only has to release the lock, otherwise it's reset with an INIT IPI so a
+ * late AP can't run with the stack or processor struct meant for the next one.
+ * The AP's release of BSP_LOCK and the BSP's release of INIT_AP_LOCK (which the AP takes
+ * back before going on, so it's ready for the next one) make its registered processor and
+ * everything else set up by the BSP visible to it.
+ */
+pub fn start_ap(lapic_id: u32) -> Result<(), &'static str> {
+    if !interrupts::apic::is_enabled() {
+        return Err("APs can't be started without the APIC");
+    }
+    if IS_TRAMPOLINE_RELEASED.is_init() {
+        return Err("AP trampoline was already released");
+    }
+    if processor::iter().any(|(id, _)| id == lapic_id) {
+        return Err("Processor is already online");
+    }
+    if IS_STARTING_AP.swap(true, Ordering::Acquire) {
+        return Err("Another AP is being started");
+    }
+
+    setup_trampoline();
+
+    CURR_AP_STACK_TOP_ADDR.store(unsafe { alloc_temp_stack() } + AP_TEMP_STACK_LENGTH, Ordering::Relaxed);
+
+    processor::register(lapic_id);
+
+    TRAMPOLINE_LOCK.store(1, Ordering::Relaxed);
+    cpu::instructions::compiler_fence();
+    // send IPIs to init AP
+    let ipi_result = send_startup_ipis(lapic_id);
+    TRAMPOLINE_LOCK.store(0, Ordering::Release);
+
+    // wait for AP to unlock BSP, it could have gotten some of the IPIs before one failed
+    let mut was_ap_init = false;
+    let tries = if ipi_result.is_ok() { WAS_TRAMPOLINE_EXECUTED_MAX_TRIES } else { 1 };
+    for _ in 0..tries {
+        if BSP_LOCK.load(Ordering::Acquire) == false {
+            was_ap_init = true;
+            break;
+        }
+        timer::wait(WAS_TRAMPOLINE_EXECUTED_TIME_PER_TRY);
+    }
+
+    if was_ap_init == false {
+        TRAMPOLINE_LOCK.store(1, Ordering::Relaxed);
+        let stack_top_addr = CURR_AP_STACK_TOP_ADDR.swap(0, Ordering::AcqRel);
+
+        if stack_top_addr == 0 {
+            // AP took its stack before the timeout so it's only running late
+            let mut backoff = Backoff::new();
+            while BSP_LOCK.load(Ordering::Acquire) == true {
+                backoff.snooze();
+            }
+            was_ap_init = true;
+        }
+        else {
+            // park the AP wherever it got to and unregister it
+            match lapic::send_init_ipi(lapic_id) {
+                Ok(()) => unsafe { dealloc_temp_stack(stack_top_addr - AP_TEMP_STACK_LENGTH); },
+                // the AP could still be starting, leak the stack rather than free it under it
+                Err(err) => crate::warn!("WARNING: Processor {} couldn't be parked: {}", lapic_id, err)
+            }
             processor::unregister(lapic_id);
         }
     }
+    BSP_LOCK.store(true, Ordering::SeqCst);
+
+    if was_ap_init {
+        // let the AP go on and wait for it to take the lock back
+        INIT_AP_LOCK.store(false, Ordering::Release);
+        let mut backoff = Backoff::new();
+        while INIT_AP_LOCK.load(Ordering::Acquire) == false {
+            backoff.snooze();
+        }
+    }
+
+    IS_STARTING_AP.store(false, Ordering::Release);
 
-    INIT_AP_LOCK.store(false, Ordering::Release);
+    match ipi_result {
+        _ if was_ap_init => Ok(()),
+        Err(err) => Err(err.as_str()),
+        Ok(()) => Err("AP didn't respond to the startup IPIs")
+    }
+}
+
+// INIT-SIPI-SIPI sequence
+fn send_startup_ipis(lapic_id: u32) -> Result<(), lapic::IpiError> {
+    lapic::send_init_ipi(lapic_id)?;
+    timer::wait(ms!(10));
+    lapic::send_startup_ipi(lapic_id, TRAMPOLINE_ADDR)?;
+    timer::wait(us!(200));
+    lapic::send_startup_ipi(lapic_id, TRAMPOLINE_ADDR)
+}
+
+/*
+ * Copies the trampoline code to where the APs start and fills the values it uses,
+ * in the copy since the original is in the kernel's read-only code
+ */
+fn setup_trampoline() {
+    unsafe {
+        let trampoline_dst = PhysAddr::new(TRAMPOLINE_ADDR as usize).to_mut_virtual();
+        let trampoline_src = &trampoline_start as *const _ as usize;
+        let trampoline_len = &trampoline_end as *const _ as usize - trampoline_src;
+
+        volatile_copy_memory(trampoline_dst.as_ptr::<u8>(), trampoline_src as *const u8, trampoline_len);
+
+        let fill = |field: &u64, value: u64| {
+            let field_offset = field as *const u64 as usize - trampoline_src;
+            trampoline_dst.offset::<u8>(field_offset).as_ptr::<u64>().write_volatile(value);
+        };
+        let table4 = paging::Table::table4();
+        fill(&pml4_addr_0x8080, table4.address.to_phys().unwrap().as_usize() as u64);
+        fill(&init_ap_fn_addr_0x8088, init_ap as u64);
+        fill(&stack_top_addr_ptr_0x8090, &CURR_AP_STACK_TOP_ADDR as *const _ as u64);
+        fill(&trampoline_lock_addr_0x8098, &TRAMPOLINE_LOCK as *const _ as u64);
+    }
 }
 
 // Allocates the temp stack and returns its address
@@ -124,26 +234,32 @@ unsafe fn dealloc_temp_stack(stack_buf_addr: usize) {
 extern "sysv64" fn init_ap(stack_top_addr: usize) {
     BSP_LOCK.store(false, Ordering::Release);
 
-    // Wait for all APs to get to this point
-    while INIT_AP_LOCK.load(Ordering::Acquire) == true {
-        core::hint::spin_loop();
+    // wait for the BSP to finish starting this AP and take the lock back for the next one
+    let mut backoff = Backoff::new();
+    while INIT_AP_LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        backoff.snooze();
     }
 
-    crate::x86_64::structures::gdt::load();
+    // the AP was registered by the BSP, so it can load its own GDT right away
+    processor::cache_current_id();
+    processor::get().load_gdt_and_tss();
+    cpu::registers::fs_base::init();
+    cpu::registers::pat::init();
+    crate::memory::address_space::init_pcid();
+    crate::x86_64::syscall::init();
 
-    let stack_buf =
-        (stack_top_addr - AP_TEMP_STACK_LENGTH) as *const [u8; AP_TEMP_STACK_LENGTH];
+    let stack_buf_addr = stack_top_addr - AP_TEMP_STACK_LENGTH;
 
     let scheduler = processor::get().scheduler();
     scheduler.add_task(
-        Task::new(INIT_AP_STACK_LENGTH, init_ap_task, Some(stack_buf))
+        Task::spawn_closure(INIT_AP_STACK_LENGTH, move || init_ap_task(stack_buf_addr))
     );
     scheduler.schedule();
 }
 
 // AP initialization task FIXME
-fn init_ap_task(args: *const [u8; AP_TEMP_STACK_LENGTH]) {
-    let stack_buf_addr = args as usize;
+fn init_ap_task(stack_buf_addr: usize) {
+    // the temp stack isn't used anymore once running on the task's stack
     unsafe { dealloc_temp_stack(stack_buf_addr); }
 
     interrupts::fill_and_load_idt();
@@ -154,14 +270,16 @@ fn init_ap_task(args: *const [u8; AP_TEMP_STACK_LENGTH]) {
     cpu::instructions::sti();
 
     let timer = processor.timer();
-    timer.init();
+    if let Err(err) = timer.init() {
+        // without a timer the AP only runs tasks that yield
+        crate::warn!("WARNING: Processor {} has no timer, it won't preempt tasks: {}", lapic::get_id(), err);
+        return;
+    }
 
     processor.scheduler().enable_preemption();
 
-    crate::println!("PROC ID: {}: INITIALIZED", lapic::get_id());
+    crate::info!("PROC ID: {}: INITIALIZED", lapic::get_id());
 
-    loop {
-        cpu::instructions::cli();
-        cpu::instructions::hlt();
-    }
+    // returning exits the task, which is freed, leaving the AP to its idle task and the
+    // tasks added to its scheduler (driven by the timer)
 }
@@ -1,9 +1,11 @@
 const CPUID_FUNC_GET_FEATURES: u32        = 1;
 const CPUID_GET_FEATURES_ECX_TSC_BIT: u32 = 1 << 24;
 
-const CPUID_FUNC_8_BASE: u32                   = 0x80000000;
-const CPUID_FUNC_GET_CAPABILITIES: u32         = CPUID_FUNC_8_BASE | 0x7;
-const CPUID_GET_CAPABILITIES_EDX_ITSC_BIT: u32 = 1 << 8;
+const CPUID_FUNC_8_BASE: u32                     = 0x80000000;
+const CPUID_FUNC_GET_EXT_FEATURES: u32           = CPUID_FUNC_8_BASE | 0x1;
+const CPUID_GET_EXT_FEATURES_EDX_RDTSCP_BIT: u32 = 1 << 27;
+const CPUID_FUNC_GET_CAPABILITIES: u32           = CPUID_FUNC_8_BASE | 0x7;
+const CPUID_GET_CAPABILITIES_EDX_ITSC_BIT: u32   = 1 << 8;
 
 
 pub fn is_invariant_tsc_supported() -> bool {
@@ -21,6 +23,15 @@ pub fn is_invariant_tsc_supported() -> bool {
     true
 }
 
+pub fn is_rdtscp_supported() -> bool {
+    use super::instructions::cpuid;
+
+    let cpuid_regs = cpuid(CPUID_FUNC_8_BASE);
+    if cpuid_regs.eax < CPUID_FUNC_GET_EXT_FEATURES { return false; }
+
+    cpuid(CPUID_FUNC_GET_EXT_FEATURES).edx & CPUID_GET_EXT_FEATURES_EDX_RDTSCP_BIT != 0
+}
+
 #[inline]
 pub fn rdtsc() -> u64 {
     let (high, low): (u64, u64);
@@ -35,3 +46,34 @@ pub fn rdtsc() -> u64 {
 
     low | (high << 32)
 }
+
+/*
+ * Reads the TSC after every instruction before it has executed, unlike "rdtsc" it can't
+ * be moved before earlier loads (LFENCE only lets instructions start once the previous
+ * ones are done). Instructions after it can still start before it's read.
+ */
+#[inline]
+pub fn rdtsc_serialized() -> u64 {
+    super::instructions::lfence();
+    rdtsc()
+}
+
+/*
+ * Reads the TSC and IA32_TSC_AUX (the processor id set by the OS) once every instruction
+ * before it has executed, later ones can still start before it. Check "is_rdtscp_supported".
+ */
+#[inline]
+pub fn rdtscp() -> (u64, u32) {
+    let (high, low, aux): (u64, u64, u32);
+
+    unsafe {
+        core::arch::asm!(
+            "rdtscp",
+            out("rax") low,
+            out("rdx") high,
+            out("ecx") aux,
+        );
+    }
+
+    (low | (high << 32), aux)
+}
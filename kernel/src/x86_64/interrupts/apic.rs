@@ -1,27 +1,33 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use crate::x86_64::{structures::acpi::madt::MADT, cpu};
 
 
-const PIC1_DATA: u16 = 0x21;
-const PIC2_DATA: u16 = 0xA1;
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+
 
 // uses cpuid to determine whether cpu supports apic
-fn supports_apic() -> bool {
+pub fn is_supported() -> bool {
     let cpuid_regs = cpu::instructions::cpuid(1);
     cpuid_regs.edx & 0x200 != 0
 }
 
+// Whether "init_apic" was called, otherwise IRQs go through the PICs (see interrupts::enable_irq)
+#[inline]
+pub fn is_enabled() -> bool {
+    IS_ENABLED.load(Ordering::Acquire)
+}
+
+// The PICs have to be masked already
 pub fn init_apic(madt: &'static MADT) -> Result<(), &'static str> {
-    if !supports_apic() {
+    if !is_supported() {
         return Err("APIC not supported by CPU.");
     }
 
-    // disable PIC
-    cpu::instructions::outb(PIC1_DATA, 0xFF);
-    cpu::instructions::outb(PIC2_DATA, 0xFF);
-
     lapic::init_base_addr(madt.get_lapic_addr());
     io_apic::init(madt)?;
 
+    IS_ENABLED.store(true, Ordering::Release);
     Ok(())
 }
 
@@ -56,48 +62,81 @@ pub mod lapic {
     const ICR_ASSERT_BIT: u32 = 1<<14;
     const ICR_DELIVERY_STATUS_PENDING_BIT: u32 = 1<<12;
     const ICR_DESTINATION_BROADCAST_EXCLUDING_SELF_BITS: u32 = 0b11<<18;
+    // polls of the delivery status before giving up, each up to 64 PAUSEs (see Backoff::spin)
+    const IPI_DELIVERY_MAX_POLLS: u32 = 100_000;
 
 
     static BASE_ADDR: LazyStatic<PhysAddr> = LazyStatic::new();
 
 
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum IpiError {
+        DeliveryTimeout // the LAPIC never finished sending it, e.g. the target didn't accept it
+    }
+    impl IpiError {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                IpiError::DeliveryTimeout => "IPI delivery timed out"
+            }
+        }
+    }
+    impl core::fmt::Display for IpiError {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            write!(f, "{}", self.as_str())
+        }
+    }
+
+
     pub fn init_base_addr(base_addr: PhysAddr) {
         BASE_ADDR.init(base_addr);
     }
 
+    // Without an APIC there's only the BSP, which gets id 0
     pub fn get_id() -> u32 {
+        if !super::is_enabled() {
+            return 0;
+        }
         read(LAPIC_ID_OFFSET) >> 24 // id stored in the highest 8 bitsS
     }
 
     // Sends IPI to all LAPICS excluding self
-    pub fn broadcast_ipi(vector: u8) {
+    pub fn broadcast_ipi(vector: u8) -> Result<(), IpiError> {
         let value_with_vec = (read(ICR_OFFSET1) & ICR_OFFSET1_BITMASK)
             & ICR_FIXED_BITMASK | ICR_DESTINATION_BROADCAST_EXCLUDING_SELF_BITS | vector as u32;
         write(ICR_OFFSET1, value_with_vec);
-        wait_for_ipi_delivery();
+        wait_for_ipi_delivery()
+    }
+
+    // Sends fixed IPI with given vector to a single LAPIC
+    pub fn send_ipi(receiver_lapic_id: u32, vector: u8) -> Result<(), IpiError> {
+        write_id_to_icr(receiver_lapic_id);
+
+        let value_with_vec = (read(ICR_OFFSET1) & ICR_OFFSET1_BITMASK) & ICR_FIXED_BITMASK | vector as u32;
+        write(ICR_OFFSET1, value_with_vec);
+        wait_for_ipi_delivery()
     }
 
-    pub fn send_init_ipi(receiver_lapic_id: u32) {
+    pub fn send_init_ipi(receiver_lapic_id: u32) -> Result<(), IpiError> {
         write_id_to_icr(receiver_lapic_id);
 
         // assert init IPI
         let value_with_init = (read(ICR_OFFSET1) & ICR_OFFSET1_BITMASK) | ICR_INIT_BITS | ICR_ASSERT_BIT;
         write(ICR_OFFSET1, value_with_init);
-        wait_for_ipi_delivery();
+        wait_for_ipi_delivery()?;
 
         // deassert init IPI
         let value_with_deassert = (read(ICR_OFFSET1) & ICR_OFFSET1_BITMASK) | ICR_INIT_BITS & !ICR_ASSERT_BIT;
         write(ICR_OFFSET1, value_with_deassert);
-        wait_for_ipi_delivery();
+        wait_for_ipi_delivery()
     }
 
-    pub fn send_startup_ipi(receiver_lapic_id: u32, address: u32) {
+    pub fn send_startup_ipi(receiver_lapic_id: u32, address: u32) -> Result<(), IpiError> {
         write_id_to_icr(receiver_lapic_id);
 
         let startup_flags: u32 = ICR_STARTUP_BITS | (address/0x1000);
         let value_with_startup = (read(ICR_OFFSET1) & ICR_OFFSET1_BITMASK) | startup_flags;
         write(ICR_OFFSET1, value_with_startup);
-        wait_for_ipi_delivery();
+        wait_for_ipi_delivery()
     }
 
     fn write_id_to_icr(receiver_lapic_id: u32) {
@@ -105,10 +144,19 @@ pub mod lapic {
         write(ICR_OFFSET2, value_with_id);
     }
 
-    fn wait_for_ipi_delivery() {
-        while read(ICR_OFFSET1) & ICR_DELIVERY_STATUS_PENDING_BIT != 0 {
-            core::hint::spin_loop();
+    /*
+     * Bounded since a wedged LAPIC would otherwise hang the sender forever, the IPI can still
+     * be delivered after it times out. Doesn't use the timer, IPIs are also sent when panicking.
+     */
+    fn wait_for_ipi_delivery() -> Result<(), IpiError> {
+        let mut backoff = crate::utils::backoff::Backoff::new();
+        for _ in 0..IPI_DELIVERY_MAX_POLLS {
+            if read(ICR_OFFSET1) & ICR_DELIVERY_STATUS_PENDING_BIT == 0 {
+                return Ok(());
+            }
+            backoff.spin();
         }
+        Err(IpiError::DeliveryTimeout)
     }
 
     #[inline]
@@ -129,10 +177,12 @@ pub mod lapic {
         unsafe { ptr.read_volatile() }
     }
 
-    def_interrupt_handler!(spurious_handler,
-        fn spurious_handler_fn(_stack_frame: &StackFrame) {
-            x86_64::interrupts::apic::lapic::eoi();
-        }
+    /*
+     * Not acknowledged, a spurious interrupt never gets in service so an EOI would end the
+     * highest priority one that is (counted by the handler wrapper, see interrupts::spurious_stats)
+     */
+    def_interrupt_handler!(spurious_handler, Index::SPURIOUS,
+        fn spurious_handler_fn(_stack_frame: &StackFrame) {}
     );
 
 
@@ -154,6 +204,11 @@ pub mod lapic {
         const INITIAL_COUNT_OFFSET: usize = 0x380;
         const CURRENT_COUNT_OFFSET: usize = 0x390;
         const DIVISOR_CONFIG_OFFSET: usize = 0x3E0;
+        /*
+         * EOI broadcast suppression (bit 12) isn't set, the LAPIC only broadcasts EOIs of
+         * level triggered interrupts to the IOAPICs and the ones routed through it (timer
+         * and keyboard, see ioapic) are ISA edge triggered ones, so nothing would be saved
+         */
         const SIVR_VALUE: u32 = (1<<8) | Index::SPURIOUS as u32;
         const MASK_BIT: u32 = 1<<16;
 
@@ -162,6 +217,8 @@ pub mod lapic {
         const TIMER_TSC_DEADLINE_MODE_BIT: u32 = 1<<18;
         const TIMER_TSC_DEADLINE_MSR_ADDR: u32 = 0x6E0;
         const TIMER_DIVISOR: u32 = 0x3; // 16
+        // how far the TSC can be off from the LAPIC timer after calibrating, 1/20 (5%)
+        const TSC_MAX_DIVERGENCE_DIVISOR: u64 = 20;
 
         pub fn new() -> Lapic {
             Lapic {
@@ -178,7 +235,7 @@ pub mod lapic {
                 Index::SPURIOUS, spurious_handler.get_addr(), 0x8, Flags::BASE, 0
             );
 
-            x86_64::interrupts::set_task_priority_level(0);
+            x86_64::interrupts::set_task_priority_level(x86_64::interrupts::PriorityClass::NONE);
 
             // make sure the APIC is enabled and not in x2APIC mode (not implemented yet)
             let (edx, mut eax) = cpu::instructions::rdmsr(Self::APIC_MSR_INDEX);
@@ -192,33 +249,70 @@ pub mod lapic {
             self.is_enabled = true;
         }
 
-        pub fn setup_timer(&mut self, interrupt_vector: u8) {
+        /**
+         * Calibrates the timer with the PIT, unless the TSC is invariant and its frequency
+         * is already known (calibrated by another processor) in which case the timer
+         * is calibrated against it so all processors share the same reference.
+         * Fails if the timer didn't count while calibrating, in which case it can't be used.
+         */
+        pub fn setup_timer(&mut self, interrupt_vector: u8, tsc_cycles_per_ms: Option<u64>) -> Result<(), &'static str> {
             use crate::x86_64::{interrupts, pit, cpu::tsc};
 
             assert!(self.is_enabled, "Attempted to setup lapic timer before enabling it");
             assert!(self.is_timer_setup == false, "Attempt to setup lapic timer more than once");
             write(Self::DIVISOR_CONFIG_OFFSET, Self::TIMER_DIVISOR);
 
-            // setup wait of 1ms
-            let mut pit = pit::lock();
-            pit.prepare_wait(1000);
+            let tsc_cycles_per_ms = tsc_cycles_per_ms.filter(|_| tsc::is_invariant_tsc_supported());
+            if let Some(tsc_cycles_per_ms) = tsc_cycles_per_ms {
+                // set initial counter to -1 and wait 1ms with the TSC
+                write(Self::INITIAL_COUNT_OFFSET, 0xFFFFFFFF);
+                let tsc_start = tsc::rdtsc_serialized();
+                while tsc::rdtsc_serialized() - tsc_start < tsc_cycles_per_ms {
+                    core::hint::spin_loop();
+                }
+                // get number of ticks in 1ms
+                self.timer_ticks_per_ms = 0xFFFFFFFF - read(Self::CURRENT_COUNT_OFFSET);
 
-            // set initial counter to -1
-            write(Self::INITIAL_COUNT_OFFSET, 0xFFFFFFFF);
-            pit.wait();
-            // get number of ticks in 1ms
-            self.timer_ticks_per_ms = 0xFFFFFFFF - read(Self::CURRENT_COUNT_OFFSET);
+                self.is_timer_tsc_mode_supported = true;
+                self.tsc_cycles_per_ms = tsc_cycles_per_ms;
+            }
+            else {
+                // setup wait of 1ms
+                let mut pit = pit::lock();
+                pit.prepare_wait(1000);
 
-            if tsc::is_invariant_tsc_supported() {
-                let tsc_start = tsc::rdtsc();
+                // set initial counter to -1
+                write(Self::INITIAL_COUNT_OFFSET, 0xFFFFFFFF);
                 pit.wait();
-                let tsc_end = tsc::rdtsc();
+                // get number of ticks in 1ms
+                self.timer_ticks_per_ms = 0xFFFFFFFF - read(Self::CURRENT_COUNT_OFFSET);
 
-                self.is_timer_tsc_mode_supported = true;
-                self.tsc_cycles_per_ms = tsc_end - tsc_start;
+                if tsc::is_invariant_tsc_supported() {
+                    let tsc_start = tsc::rdtsc_serialized();
+                    pit.wait();
+                    let tsc_end = tsc::rdtsc_serialized();
+
+                    self.is_timer_tsc_mode_supported = true;
+                    self.tsc_cycles_per_ms = tsc_end - tsc_start;
+                }
+
+                pit::unlock(pit);
             }
 
-            pit::unlock(pit);
+            if self.timer_ticks_per_ms == 0 {
+                write(Self::INITIAL_COUNT_OFFSET, 0);
+                return Err("LAPIC timer didn't count during calibration");
+            }
+
+            if self.is_timer_tsc_mode_supported {
+                let tsc_cycles = self.measure_tsc_cycles_per_timer_ms();
+                if !Self::is_tsc_consistent(tsc_cycles, self.tsc_cycles_per_ms) {
+                    crate::warn!("WARNING: TSC counted {} cycles in 1ms of LAPIC timer instead of {}, not using TSC-deadline mode.",
+                        tsc_cycles, self.tsc_cycles_per_ms);
+                    self.is_timer_tsc_mode_supported = false;
+                    self.tsc_cycles_per_ms = 0;
+                }
+            }
 
             // set apic timer interrupt vector and make sure its masked
             write(Self::LVT_TIMER_OFFSET, read(Self::LVT_TIMER_OFFSET) | Self::MASK_BIT | interrupt_vector as u32);
@@ -228,6 +322,30 @@ pub mod lapic {
             interrupts::remove_idt_entry(interrupt_vector);
 
             self.is_timer_setup = true;
+            Ok(())
+        }
+
+        /*
+         * Counts the TSC cycles in a new window of 1ms measured by the LAPIC timer, CPUID can
+         * report an invariant TSC that doesn't tick at a constant rate (e.g. under some VMs
+         * without invtsc) or stops in deep C-states, calibrating with it would then be wrong
+         */
+        fn measure_tsc_cycles_per_timer_ms(&self) -> u64 {
+            use crate::x86_64::cpu::tsc;
+
+            write(Self::INITIAL_COUNT_OFFSET, 0xFFFFFFFF);
+            let tsc_start = tsc::rdtsc_serialized();
+            while 0xFFFFFFFF - read(Self::CURRENT_COUNT_OFFSET) < self.timer_ticks_per_ms {
+                core::hint::spin_loop();
+            }
+            let tsc_end = tsc::rdtsc_serialized();
+            write(Self::INITIAL_COUNT_OFFSET, 0);
+
+            tsc_end - tsc_start
+        }
+        // Whether the cycles measured are within TSC_MAX_DIVERGENCE_DIVISOR of the calibrated ones
+        fn is_tsc_consistent(tsc_cycles: u64, tsc_cycles_per_ms: u64) -> bool {
+            tsc_cycles.abs_diff(tsc_cycles_per_ms) <= tsc_cycles_per_ms / Self::TSC_MAX_DIVERGENCE_DIVISOR
         }
 
         pub fn get_timer_ticks_per_ms(&self) -> u32 {
@@ -0,0 +1,57 @@
+use crate::{processor, scheduler::{self, task::TaskId}, x86_64::cpu::{self, registers::rflags}};
+
+
+pub const DEFERRED_WORK_QUEUE_SIZE: usize = 64;
+
+
+// Work an interrupt handler leaves to run after it returns, with interrupts enabled
+pub enum DeferredWork {
+    WakeUpTask(TaskId),
+    Call(fn())
+}
+impl DeferredWork {
+    fn run(self) {
+        match self {
+            DeferredWork::WakeUpTask(task_id) => scheduler::wake_up_task(task_id),
+            DeferredWork::Call(function) => function()
+        }
+    }
+}
+
+
+// Queues work on the current processor, runs it right away if the queue is full
+pub fn defer(work: DeferredWork) {
+    if let Err(work) = processor::get().deferred_work().push(work) {
+        work.run();
+    }
+}
+
+/**
+ * Runs the work queued on the current processor, called by the outermost interrupt
+ * handler after it returns (so after its EOI) with interrupts enabled in the meantime.
+ * Task switches done by the work take place when returning from the interrupt.
+ * If interrupts were disabled before the interrupt (e.g. an exception) the work
+ * is left for the next one, so the interrupted code isn't interrupted further.
+ */
+pub(super) fn run_pending(interrupted_rflags: u64) {
+    if interrupted_rflags & rflags::FLAG_INTERRUPT_ENABLED == 0 {
+        return;
+    }
+
+    let queue = processor::get().deferred_work();
+    if queue.is_empty() {
+        return;
+    }
+
+    loop {
+        cpu::instructions::sti();
+        while let Some(work) = queue.pop() {
+            work.run();
+        }
+        cpu::instructions::cli();
+        // a nested interrupt might have queued work after the last pop
+        if queue.is_empty() {
+            break;
+        }
+    }
+}
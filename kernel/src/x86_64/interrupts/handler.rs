@@ -1,4 +1,4 @@
-use crate::processor;
+use crate::{processor, trace::{self, Event}};
 
 
 pub struct InterruptHandler();
@@ -38,14 +38,47 @@ pub struct SavedState {
     pub rbp: u64,
     pub stack_frame: StackFrame
 }
+// Fields are copied out since references to fields of a packed struct can be unaligned
+impl core::fmt::Debug for SavedState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let (rax, rbx, rcx, rdx) = (self.rax, self.rbx, self.rcx, self.rdx);
+        let (rsi, rdi, rbp) = (self.rsi, self.rdi, self.rbp);
+        let (r8, r9, r10, r11) = (self.r8, self.r9, self.r10, self.r11);
+        let (r12, r13, r14, r15) = (self.r12, self.r13, self.r14, self.r15);
+        let stack_frame = self.stack_frame;
+
+        f.debug_struct("SavedState")
+            .field("rax", &format_args!("{:#018x}", rax))
+            .field("rbx", &format_args!("{:#018x}", rbx))
+            .field("rcx", &format_args!("{:#018x}", rcx))
+            .field("rdx", &format_args!("{:#018x}", rdx))
+            .field("rsi", &format_args!("{:#018x}", rsi))
+            .field("rdi", &format_args!("{:#018x}", rdi))
+            .field("r8",  &format_args!("{:#018x}", r8))
+            .field("r9",  &format_args!("{:#018x}", r9))
+            .field("r10", &format_args!("{:#018x}", r10))
+            .field("r11", &format_args!("{:#018x}", r11))
+            .field("r12", &format_args!("{:#018x}", r12))
+            .field("r13", &format_args!("{:#018x}", r13))
+            .field("r14", &format_args!("{:#018x}", r14))
+            .field("r15", &format_args!("{:#018x}", r15))
+            .field("rbp", &format_args!("{:#018x}", rbp))
+            .field("stack_frame", &stack_frame)
+            .finish()
+    }
+}
 
 
 /**
  * Increments the processor's active interrupt count, if it's not a nested interrupt saves the
- * current task state in the scheduler in case of a task switch
+ * current task state in the scheduler in case of a task switch and, after the handler returns,
+ * runs the work deferred by handlers
  */
-pub unsafe extern "sysv64" fn handler_wrapper(handler_addr: usize, saved_state_addr: usize) {
+pub unsafe extern "sysv64" fn handler_wrapper(handler_addr: usize, saved_state_addr: usize, vector: u64) {
+    reload_tls_if_from_user(saved_state_addr);
     let processor = processor::get();
+    processor.count_interrupt(vector as u8);
+    trace::record(Event::InterruptEnter(vector as u8));
     let active_interrupt_count = processor.active_interrupt_count();
     *active_interrupt_count += 1;
 
@@ -59,11 +92,19 @@ pub unsafe extern "sysv64" fn handler_wrapper(handler_addr: usize, saved_state_a
     let handler_fn: fn(&StackFrame) = core::mem::transmute(handler_addr);
     handler_fn(stack_frame);
 
-    debug_assert!(*active_interrupt_count > 0);
+    if *active_interrupt_count == 1 {
+        super::deferred::run_pending((*saved_state_ptr).stack_frame.rflags);
+    }
+
+    crate::kassert!(*active_interrupt_count > 0);
     *active_interrupt_count -= 1;
+    trace::record(Event::InterruptExit(vector as u8));
 }
-pub unsafe extern "sysv64" fn handler_with_err_wrapper(handler_addr: usize, saved_state_addr: usize, error: u64) {
+pub unsafe extern "sysv64" fn handler_with_err_wrapper(handler_addr: usize, saved_state_addr: usize, error: u64, vector: u64) {
+    reload_tls_if_from_user(saved_state_addr);
     let processor = processor::get();
+    processor.count_interrupt(vector as u8);
+    trace::record(Event::InterruptEnter(vector as u8));
     let active_interrupt_count = processor.active_interrupt_count();
     *active_interrupt_count += 1;
 
@@ -77,8 +118,47 @@ pub unsafe extern "sysv64" fn handler_with_err_wrapper(handler_addr: usize, save
     let handler_fn: fn(&StackFrame, u64) = core::mem::transmute(handler_addr);
     handler_fn(stack_frame, error);
 
-    debug_assert!(*active_interrupt_count > 0);
+    if *active_interrupt_count == 1 {
+        super::deferred::run_pending((*saved_state_ptr).stack_frame.rflags);
+    }
+
+    crate::kassert!(*active_interrupt_count > 0);
     *active_interrupt_count -= 1;
+    trace::record(Event::InterruptExit(vector as u8));
+}
+pub unsafe extern "sysv64" fn handler_with_vector_wrapper(handler_addr: usize, saved_state_addr: usize, vector: u64) {
+    reload_tls_if_from_user(saved_state_addr);
+    let processor = processor::get();
+    processor.count_interrupt(vector as u8);
+    trace::record(Event::InterruptEnter(vector as u8));
+    let active_interrupt_count = processor.active_interrupt_count();
+    *active_interrupt_count += 1;
+
+    let saved_state_ptr = saved_state_addr as *mut SavedState;
+
+    if *active_interrupt_count == 1 {
+        *processor.curr_interrupt_saved_state() = saved_state_addr as *mut SavedState;
+    }
+
+    let stack_frame = &(*saved_state_ptr).stack_frame;
+    let handler_fn: fn(&StackFrame, u8) = core::mem::transmute(handler_addr);
+    handler_fn(stack_frame, vector as u8);
+
+    if *active_interrupt_count == 1 {
+        super::deferred::run_pending((*saved_state_ptr).stack_frame.rflags);
+    }
+
+    crate::kassert!(*active_interrupt_count > 0);
+    *active_interrupt_count -= 1;
+    trace::record(Event::InterruptExit(vector as u8));
+}
+// The FS base is whatever the task left in it if the interrupt came in while in ring 3
+#[inline]
+unsafe fn reload_tls_if_from_user(saved_state_addr: usize) {
+    let cs = (*(saved_state_addr as *const SavedState)).stack_frame.cs;
+    if cs & 0b11 != 0 {
+        crate::scheduler::reload_tls();
+    }
 }
 
 /*
@@ -86,11 +166,18 @@ pub unsafe extern "sysv64" fn handler_with_err_wrapper(handler_addr: usize, save
  * which saves the register state previous to the interrupt and calls either "handler_wrapper" or
  * "handler_with_err_wrapper" which, in turn, will then call the actual handler function.
  *
- * Function passed must receive either &StackFrame or &StackFrame and u64
+ * The second argument is the vector the handler is set on, used to keep interrupt statistics.
+ * Function passed must receive either &StackFrame, &StackFrame and u64 (error)
+ * or &StackFrame and u8 (vector).
+ *
+ * A function receiving the vector can be shared between vectors by defining
+ * the other handlers with "use" followed by the path to the function, e.g.:
+ *     def_interrupt_handler!(irq0_handler, 0x20, fn irq_handler_fn(stack_frame: &StackFrame, vector: u8) {...});
+ *     def_interrupt_handler!(irq1_handler, 0x21, use irq_handler_fn);
  */
 #[macro_export]
 macro_rules! def_interrupt_handler {
-    ($handler_name:ident, fn $handler_fn_name:ident($param:ident: &StackFrame) $handler_fn_body:block) => {
+    ($handler_name:ident, $vector:expr, fn $handler_fn_name:ident($param:ident: &StackFrame) $handler_fn_body:block) => {
         fn $handler_fn_name($param: &crate::x86_64::interrupts::handler::StackFrame)
             $handler_fn_body
 
@@ -126,6 +213,7 @@ macro_rules! def_interrupt_handler {
 
                     lea rdi, {}  # 1st param, address to handler function
                     mov rsi, rsp # 2nd param, address to saved state
+                    mov edx, {}  # 3rd param, vector number
                     call {}
 
                     pop rax
@@ -147,13 +235,14 @@ macro_rules! def_interrupt_handler {
                 "#,
                 sym [<$handler_name _isr_entry_point>],
                 sym $handler_fn_name,
+                const $vector,
                 sym crate::x86_64::interrupts::handler::handler_wrapper
             );
         }
     };
 
     // Interrupt handler with error
-    ($handler_name:ident, fn $handler_fn_name:ident($param:ident: &StackFrame, $param2:ident: u64) $handler_fn_body:block) => {
+    ($handler_name:ident, $vector:expr, fn $handler_fn_name:ident($param:ident: &StackFrame, $param2:ident: u64) $handler_fn_body:block) => {
         fn $handler_fn_name($param: &crate::x86_64::interrupts::handler::StackFrame, $param2: u64)
             $handler_fn_body
 
@@ -197,6 +286,7 @@ macro_rules! def_interrupt_handler {
                     lea rdi, {}  # 1st param, address to handler function
                     mov rsi, rsp # 2nd param, address to saved state
                     mov rdx, rbp # 3rd param, error code
+                    mov ecx, {}  # 4th param, vector number
                     call {}
 
                     pop rax
@@ -218,8 +308,79 @@ macro_rules! def_interrupt_handler {
                 "#,
                 sym [<$handler_name _isr_entry_point>],
                 sym $handler_fn_name,
+                const $vector,
                 sym crate::x86_64::interrupts::handler::handler_with_err_wrapper
             );
         }
     };
+
+    // Interrupt handler that receives the vector number
+    ($handler_name:ident, $vector:expr, fn $handler_fn_name:ident($param:ident: &StackFrame, $param2:ident: u8) $handler_fn_body:block) => {
+        fn $handler_fn_name($param: &crate::x86_64::interrupts::handler::StackFrame, $param2: u8)
+            $handler_fn_body
+
+        crate::def_interrupt_handler!($handler_name, $vector, use $handler_fn_name);
+    };
+
+    // Interrupt handler sharing an already defined function that receives the vector number
+    ($handler_name:ident, $vector:expr, use $handler_fn:path) => {
+        #[allow(improper_ctypes)]
+        extern {
+            paste::paste! {
+                static [<$handler_name _isr_entry_point>]: crate::x86_64::interrupts::handler::InterruptHandler;
+            }
+        }
+        #[allow(non_upper_case_globals)]
+        static $handler_name: &crate::x86_64::interrupts::handler::InterruptHandler =
+            unsafe { paste::paste! { &[<$handler_name _isr_entry_point>] } };
+
+        paste::paste! {
+            core::arch::global_asm!(
+                r#"
+                {}:
+                    push rbp
+                    push r15
+                    push r14
+                    push r13
+                    push r12
+                    push r11
+                    push r10
+                    push r9
+                    push r8
+                    push rdi
+                    push rsi
+                    push rdx
+                    push rcx
+                    push rbx
+                    push rax
+
+                    lea rdi, {}  # 1st param, address to handler function
+                    mov rsi, rsp # 2nd param, address to saved state
+                    mov edx, {}  # 3rd param, vector number
+                    call {}
+
+                    pop rax
+                    pop rbx
+                    pop rcx
+                    pop rdx
+                    pop rsi
+                    pop rdi
+                    pop r8
+                    pop r9
+                    pop r10
+                    pop r11
+                    pop r12
+                    pop r13
+                    pop r14
+                    pop r15
+                    pop rbp
+                    iretq
+                "#,
+                sym [<$handler_name _isr_entry_point>],
+                sym $handler_fn,
+                const $vector,
+                sym crate::x86_64::interrupts::handler::handler_with_vector_wrapper
+            );
+        }
+    };
 }
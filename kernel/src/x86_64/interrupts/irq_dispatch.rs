@@ -0,0 +1,89 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{def_interrupt_handler, x86_64::structures::idt::Flags};
+use super::{handler::StackFrame, pic};
+
+
+// Vectors whose handlers are registered at runtime, the same as the PICs' so IRQs from them fit
+pub const FIRST_VECTOR: u8 = pic::MASTER_VECTOR_BASE;
+pub const VECTOR_COUNT: usize = 16;
+
+
+// Address of the fn(&StackFrame) registered for each vector, 0 if there's none
+static HANDLERS: [AtomicUsize; VECTOR_COUNT] = [const { AtomicUsize::new(0) }; VECTOR_COUNT];
+
+
+/**
+ * Registers the handler called when vector comes in, on any processor. A handler for an IRQ
+ * has to acknowledge it (see interrupts::end_of_irq) like the ones set with "enable_irq".
+ * The PIC's spurious vector is taken, as is any vector with a handler set directly in the IDT.
+ */
+pub fn register_irq(vector: u8, handler: fn(&StackFrame)) -> Result<(), &'static str> {
+    let index = index(vector)?;
+    if vector == pic::vector(pic::SPURIOUS_IRQ) {
+        return Err("Vector is reserved for spurious IRQs");
+    }
+    HANDLERS[index].compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map(|_| ())
+        .map_err(|_| "Vector already has a handler registered")
+}
+
+// A handler could still be running on another processor when it returns
+pub fn unregister_irq(vector: u8) -> Result<(), &'static str> {
+    let index = index(vector)?;
+    if HANDLERS[index].swap(0, Ordering::AcqRel) == 0 {
+        return Err("Vector has no handler registered");
+    }
+    Ok(())
+}
+
+// Sets the dispatching handlers on the current processor, before any set for a single vector
+pub fn set_handlers() {
+    let handlers = [
+        irq_0x20_handler, irq_0x21_handler, irq_0x22_handler, irq_0x23_handler,
+        irq_0x24_handler, irq_0x25_handler, irq_0x26_handler, irq_0x27_handler,
+        irq_0x28_handler, irq_0x29_handler, irq_0x2a_handler, irq_0x2b_handler,
+        irq_0x2c_handler, irq_0x2d_handler, irq_0x2e_handler, irq_0x2f_handler,
+    ];
+    for (vector, handler) in (FIRST_VECTOR..).zip(handlers) {
+        super::set_idt_entry(vector, handler.get_addr(), 0x8, Flags::BASE, 0);
+    }
+}
+
+fn index(vector: u8) -> Result<usize, &'static str> {
+    let index = vector.wrapping_sub(FIRST_VECTOR) as usize;
+    if index >= VECTOR_COUNT {
+        return Err("Vector isn't one of the dispatched ones");
+    }
+    Ok(index)
+}
+
+
+def_interrupt_handler!(irq_0x20_handler, 0x20,
+    fn dispatch(stack_frame: &StackFrame, vector: u8) {
+        let handler_addr = HANDLERS[vector as usize - FIRST_VECTOR as usize].load(Ordering::Acquire);
+        if handler_addr == 0 {
+            crate::warn!("WARNING: Unexpected interrupt on vector {:#x}, no handler registered", vector);
+            // left in service it would hold off every IRQ of the same or lower priority
+            super::end_of_irq(vector - FIRST_VECTOR);
+            return;
+        }
+        let handler: fn(&StackFrame) = unsafe { core::mem::transmute(handler_addr) };
+        handler(stack_frame);
+    }
+);
+def_interrupt_handler!(irq_0x21_handler, 0x21, use dispatch);
+def_interrupt_handler!(irq_0x22_handler, 0x22, use dispatch);
+def_interrupt_handler!(irq_0x23_handler, 0x23, use dispatch);
+def_interrupt_handler!(irq_0x24_handler, 0x24, use dispatch);
+def_interrupt_handler!(irq_0x25_handler, 0x25, use dispatch);
+def_interrupt_handler!(irq_0x26_handler, 0x26, use dispatch);
+def_interrupt_handler!(irq_0x27_handler, 0x27, use dispatch);
+def_interrupt_handler!(irq_0x28_handler, 0x28, use dispatch);
+def_interrupt_handler!(irq_0x29_handler, 0x29, use dispatch);
+def_interrupt_handler!(irq_0x2a_handler, 0x2A, use dispatch);
+def_interrupt_handler!(irq_0x2b_handler, 0x2B, use dispatch);
+def_interrupt_handler!(irq_0x2c_handler, 0x2C, use dispatch);
+def_interrupt_handler!(irq_0x2d_handler, 0x2D, use dispatch);
+def_interrupt_handler!(irq_0x2e_handler, 0x2E, use dispatch);
+def_interrupt_handler!(irq_0x2f_handler, 0x2F, use dispatch);
@@ -1,7 +1,12 @@
-use crate::{def_interrupt_handler, processor, x86_64::{cpu, structures::{acpi, idt}}};
+use crate::{cmdline, def_interrupt_handler, processor, video::color::RED, x86_64::{cpu, structures::{acpi, idt}}};
 
 pub mod apic;
+pub mod deferred;
 pub mod handler;
+pub mod irq_dispatch;
+pub mod pic;
+
+pub use irq_dispatch::{register_irq, unregister_irq};
 
 
 #[inline(never)]
@@ -11,6 +16,9 @@ pub fn fill_and_load_idt() {
 
     let idt_descriptor = processor::get().idt_descriptor();
 
+    // handlers set for any of the dispatched vectors later replace the dispatching one
+    irq_dispatch::set_handlers();
+
     // fill up IDT for exceptions
     idt_descriptor.set_entry(
         Index::BREAKPOINT, breakpoint_handler.get_addr(), 0x8, Flags::BASE | Flags::TRAP_GATE, 0
@@ -27,32 +35,43 @@ pub fn fill_and_load_idt() {
     idt_descriptor.set_entry(
         Index::HALT, halt_handler.get_addr(), 0x8, Flags::BASE | Flags::TRAP_GATE, 0
     );
+    pic::set_spurious_handler();
 
     idt_descriptor.load();
 }
 
-def_interrupt_handler!(breakpoint_handler,
+def_interrupt_handler!(breakpoint_handler, idt::Index::BREAKPOINT,
     fn breakpoint_handler_fn(stack_frame: &StackFrame) {
-        crate::println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame); // FIXME
+        crate::warn!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
     }
 );
-def_interrupt_handler!(double_fault_handler,
+def_interrupt_handler!(double_fault_handler, idt::Index::DOUBLE_FAULT,
     fn double_fault_handler_fn(stack_frame: &StackFrame, _error: u64) {
+        dump_interrupted_task();
         panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
     }
 );
-def_interrupt_handler!(general_protection_fault_handler,
+def_interrupt_handler!(general_protection_fault_handler, idt::Index::GENERAL_PROTECTION_FAULT,
     fn general_protection_fault_handler_fn(stack_frame: &StackFrame, error: u64) {
+        dump_interrupted_task();
         panic!("EXCEPTION: GENERAL PROTECTION FAULT - ERROR: {:#x}\n{:#?}", error, stack_frame);
     }
 );
-def_interrupt_handler!(page_fault_handler,
+def_interrupt_handler!(page_fault_handler, idt::Index::PAGE_FAULT,
     fn page_fault_handler_fn(stack_frame: &StackFrame, error: u64) {
+        use crate::memory::address::{VirtAddr, VirtualAddress};
+
         let cr2 = cpu::registers::cr2::read();
+        // a bad pointer passed in by a user task makes the copy fail instead
+        if crate::memory::usercopy::fixup_page_fault(cr2 as usize) {
+            return;
+        }
+
+        crate::emergency_print_color!(RED, "PAGE FAULT ADDRESS TRANSLATION:\n{}\n", VirtAddr::new(cr2 as usize).translate());
         panic!("EXCEPTION: PAGE FAULT - ERROR: {:#x} - CR2: {:#x}\n{:#?}", error, cr2, stack_frame);
     }
 );
-def_interrupt_handler!(halt_handler,
+def_interrupt_handler!(halt_handler, idt::Index::HALT,
     fn halt_handler_fn(_stack_frame: &StackFrame) {
         cpu::instructions::cli();
         cpu::instructions::hlt();
@@ -60,19 +79,127 @@ def_interrupt_handler!(halt_handler,
 );
 
 
+/*
+ * Prints the task executing when the exception happened and its registers at that point.
+ * Like the translation printed on page faults it goes through the emergency print path since
+ * the exception could have come from inside a print, whose locks would still be held.
+ */
+fn dump_interrupted_task() {
+    let Some(processor) = processor::try_get() else { return; };
+
+    match processor.scheduler().get_executing_task() {
+        Some(task) => crate::emergency_print_color!(RED, "TASK: {:#?}\n", task),
+        None => crate::emergency_print_color!(RED, "TASK: idle\n")
+    }
+    let saved_state = *processor.curr_interrupt_saved_state();
+    if !saved_state.is_null() {
+        crate::emergency_print_color!(RED, "{:#?}\n", unsafe { *saved_state });
+    }
+}
+
+
+// Number of times the vector was handled by the current processor
+pub fn stats(vector: u8) -> u64 {
+    processor::get().interrupt_count(vector)
+}
+// Number of spurious interrupts from the LAPIC and the PICs the current processor got
+pub fn spurious_stats() -> u64 {
+    stats(idt::Index::SPURIOUS) + stats(pic::vector(pic::SPURIOUS_IRQ))
+}
+
+
+/*
+ * Sets up the APIC unless the CPU doesn't have one or "noapic" is in the kernel command line,
+ * in which case IRQs are left to the PICs and there's only the BSP. The PICs are remapped and
+ * masked either way, the MADT is only needed for the APIC.
+ */
 pub fn init_hardware_interrupts() -> Result<(), &'static str> {
-    // initialize APIC
-    let madt = acpi::get_madt();
-    apic::init_apic(madt)?;
+    pic::init();
+
+    if should_use_apic() {
+        let madt = acpi::get_madt();
+        apic::init_apic(madt)?;
+    }
 
     Ok(())
 }
 
+pub fn should_use_apic() -> bool {
+    apic::is_supported() && !cmdline::has("noapic")
+}
+
+
+// Legacy ISA IRQs, through the IO APIC (which can have them overridden in the MADT) or the PICs
+pub struct Irq {}
+impl Irq {
+    pub const SYSTEM_TIMER: u8 = 0;
+    pub const KEYBOARD: u8 = 1;
+}
+
+/*
+ * Sets handler for irq and unmasks it, sent to the current processor. With the APIC it comes
+ * in at vector, the PICs have a fixed vector for each IRQ (see pic::vector).
+ */
+pub fn enable_irq(irq: u8, vector: u8, handler_addr: usize) {
+    use idt::Flags;
+
+    if !apic::is_enabled() {
+        set_idt_entry(pic::vector(irq), handler_addr, 0x8, Flags::BASE, 0);
+        pic::unmask(irq);
+        return;
+    }
+
+    set_idt_entry(vector, handler_addr, 0x8, Flags::BASE, 0);
+    match irq {
+        Irq::SYSTEM_TIMER => apic::io_apic::enable_system_timer(vector),
+        Irq::KEYBOARD => apic::io_apic::enable_keyboard(vector),
+        _ => panic!("IRQ {} isn't routed through the IO APIC", irq)
+    }
+}
+
+// Has to be called by the handler of an IRQ enabled with "enable_irq" once it's done
+#[inline]
+pub fn end_of_irq(irq: u8) {
+    if apic::is_enabled() {
+        apic::lapic::eoi();
+    }
+    else {
+        pic::eoi(irq);
+    }
+}
+
+
+/**
+ * Interrupt priority classes, the class of a vector is its high nibble.
+ * While the task priority level (CR8) is set to a class, interrupts of that class
+ * or lower are held pending by the LAPIC until it is lowered. Since the LAPIC also
+ * won't deliver an interrupt with a class that isn't higher than the one in service,
+ * a handler that enables interrupts can only be preempted by a higher class:
+ *   0x0..0x1 exceptions (not affected by the TPR)
+ *   0x2      IRQs dispatched to registered handlers (0x20..0x2F, see irq_dispatch)
+ *   0xE      keyboard (0xE9)
+ *   0xF      system timer (0xF6), LAPIC timer (0xF7), preempt IPI (0xFD), halt IPI (0xFE)
+ *            and spurious (0xFF)
+ * So the timers can preempt the keyboard handler but not the other way around.
+ */
+pub struct PriorityClass {}
+impl PriorityClass {
+    pub const NONE: u8 = 0x0;
+    pub const KEYBOARD: u8 = priority_class(idt::Index::KEYBOARD);
+    pub const TIMER: u8 = priority_class(idt::Index::LAPIC_TIMER);
+}
+
+pub const fn priority_class(vector: u8) -> u8 {
+    vector >> 4
+}
 
 pub fn set_task_priority_level(level: u8) {
     assert!(level <= 0xF);
     cpu::registers::cr8::write(level as u64);
 }
+pub fn get_task_priority_level() -> u8 {
+    cpu::registers::cr8::read() as u8
+}
 
 pub fn set_idt_entry(index: u8, fn_ptr: usize, selector: u16, flags: u8, ist_index: u8) {
     let idt_descriptor = processor::get().idt_descriptor();
@@ -0,0 +1,88 @@
+use crate::{def_interrupt_handler, x86_64::{port, structures::idt::Flags}};
+
+
+// Vectors of IRQ 0 on each PIC, right after the exceptions
+pub const MASTER_VECTOR_BASE: u8 = 0x20;
+pub const SLAVE_VECTOR_BASE: u8 = 0x28;
+// raised by the master when an IRQ goes away before it's acknowledged, never in service
+pub const SPURIOUS_IRQ: u8 = 7;
+
+const SLAVE_CASCADE_IRQ: u8 = 2;
+
+const ICW1_INIT: u8 = 0x10;
+const ICW1_ICW4: u8 = 0x01;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+
+
+/**
+ * Remaps both PICs to MASTER_VECTOR_BASE and SLAVE_VECTOR_BASE with every IRQ masked, by default
+ * they overlap the exception vectors. Done even when the APIC is used so a spurious IRQ from a
+ * masked PIC can't look like an exception.
+ */
+pub fn init() {
+    port::PIC1_COMMAND.write(ICW1_INIT | ICW1_ICW4);
+    io_wait();
+    port::PIC2_COMMAND.write(ICW1_INIT | ICW1_ICW4);
+    io_wait();
+    // ICW2, vector offsets
+    port::PIC1_DATA.write(MASTER_VECTOR_BASE);
+    io_wait();
+    port::PIC2_DATA.write(SLAVE_VECTOR_BASE);
+    io_wait();
+    // ICW3, the slave is wired to the master's IRQ 2
+    port::PIC1_DATA.write(1 << SLAVE_CASCADE_IRQ);
+    io_wait();
+    port::PIC2_DATA.write(SLAVE_CASCADE_IRQ);
+    io_wait();
+    // ICW4
+    port::PIC1_DATA.write(ICW4_8086);
+    io_wait();
+    port::PIC2_DATA.write(ICW4_8086);
+    io_wait();
+
+    port::PIC1_DATA.write(0xFF);
+    port::PIC2_DATA.write(0xFF);
+}
+
+pub const fn vector(irq: u8) -> u8 {
+    if irq < 8 { MASTER_VECTOR_BASE + irq } else { SLAVE_VECTOR_BASE + irq - 8 }
+}
+
+pub fn unmask(irq: u8) {
+    assert!(irq < 16);
+    if irq < 8 {
+        port::PIC1_DATA.write(port::PIC1_DATA.read() & !(1 << irq));
+    }
+    else {
+        port::PIC2_DATA.write(port::PIC2_DATA.read() & !(1 << (irq - 8)));
+        port::PIC1_DATA.write(port::PIC1_DATA.read() & !(1 << SLAVE_CASCADE_IRQ));
+    }
+}
+
+// IRQs from the slave have to be acknowledged on both
+pub fn eoi(irq: u8) {
+    if irq >= 8 {
+        port::PIC2_COMMAND.write(OCW2_EOI);
+    }
+    port::PIC1_COMMAND.write(OCW2_EOI);
+}
+
+// Sets the handler of the spurious IRQ on the current processor
+pub fn set_spurious_handler() {
+    super::set_idt_entry(vector(SPURIOUS_IRQ), spurious_handler.get_addr(), 0x8, Flags::BASE, 0);
+}
+
+// The ICW sequence needs a short delay between writes on older PICs, port 0x80 is unused
+#[inline]
+fn io_wait() {
+    port::POST_CODE.write(0);
+}
+
+
+// IRQ 7 is never unmasked, whenever the master raises it it's spurious
+def_interrupt_handler!(spurious_handler, vector(SPURIOUS_IRQ),
+    fn spurious_handler_fn(_stack_frame: &StackFrame) {
+        // not acknowledged, it was never in service
+    }
+);
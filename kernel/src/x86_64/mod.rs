@@ -2,3 +2,5 @@ pub mod cpu;
 pub mod structures;
 pub mod interrupts;
 pub mod pit;
+pub mod port;
+pub mod syscall;
@@ -1,45 +1,93 @@
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
-use crate::{def_interrupt_handler, locks::spinlock::{Spinlock, SpinlockGuard}};
-use super::{cpu::instructions, interrupts};
+use crate::{def_interrupt_handler, locks::spinlock::{Spinlock, SpinlockGuard}, processor};
+use super::{interrupts, port};
 
 
 const FREQUENCY: u32 = 1193180;
-const COMMAND_PORT: u16 = 0x43;
-const CHANNEL_O_PORT: u16 = 0x40;
 const COMMAND_CHANNEL0_ACCESSLOHI_MODE0: u8 = 0b00110000;
+const COMMAND_CHANNEL0_ACCESSLOHI_MODE2: u8 = 0b00110100;
 
+// Frequency of the periodic mode driving the timer when the LAPIC timer can't be used
+pub const PERIODIC_HZ: u32 = 1000;
 
-static PIT: Spinlock<Pit> = Spinlock::new(Pit { divisor: 0 });
+
+static PIT: Spinlock<Pit> = Spinlock::new(Pit { divisor: 0, periodic_ticks_to_wait: 0 });
 static IS_WAIT_OVER: AtomicBool = AtomicBool::new(false);
+static IS_PERIODIC: AtomicBool = AtomicBool::new(false);
+static PERIODIC_TICK_COUNT: AtomicU64 = AtomicU64::new(0);
+
+
+/**
+ * Starts channel 0 in periodic mode at PERIODIC_HZ, from then on the interrupt
+ * updates the timer of the current processor (the only one the IRQ is sent to).
+ * Waits are still possible but are counted in periodic ticks.
+ */
+pub fn start_periodic() {
+    use super::{interrupts::Irq, structures::idt::Index};
+
+    let pit = lock();
+    assert!(IS_PERIODIC.load(Ordering::Relaxed) == false, "Attempted to start PIT periodic mode more than once");
+
+    let divisor = (FREQUENCY / PERIODIC_HZ) as u16;
+    // channel 0, access lobyte and hibyte, mode 2 (rate generator)
+    port::PIT_COMMAND.write(COMMAND_CHANNEL0_ACCESSLOHI_MODE2);
+    port::PIT_CHANNEL_0.write(divisor as u8);        // low byte
+    port::PIT_CHANNEL_0.write((divisor >> 8) as u8); // high byte
+
+    interrupts::enable_irq(Irq::SYSTEM_TIMER, Index::SYS_TIMER, pit_periodic_handler.get_addr());
+
+    IS_PERIODIC.store(true, Ordering::Release);
+    unlock(pit);
+}
 
 
 pub struct Pit {
-    divisor: u16
+    divisor: u16,
+    periodic_ticks_to_wait: u64 // used instead of the divisor once in periodic mode
 }
 impl Pit {
     pub fn prepare_wait(&mut self, hz: u32) {
-        use super::{interrupts::{set_idt_entry, apic::io_apic}, structures::idt::{Index, Flags}};
+        use super::{interrupts::Irq, structures::idt::Index};
 
         assert!(hz <= FREQUENCY);
 
+        // the channel and IRQ now belong to the timer of another processor
+        if IS_PERIODIC.load(Ordering::Acquire) {
+            assert!(hz <= PERIODIC_HZ, "Attempted to wait less than a PIT periodic tick");
+            self.periodic_ticks_to_wait = PERIODIC_HZ.div_ceil(hz) as u64;
+            return;
+        }
+
         // channel 0, access lobyte and hibyte, mode 0
-        instructions::outb(COMMAND_PORT, COMMAND_CHANNEL0_ACCESSLOHI_MODE0);
+        port::PIT_COMMAND.write(COMMAND_CHANNEL0_ACCESSLOHI_MODE0);
 
         // 0 divisor is lowest possible frequency
         self.divisor = if FREQUENCY/hz > u16::MAX as u32 { 0 }
                        else { (FREQUENCY/hz) as u16 };
 
-        // set pit handler on IDT
-        set_idt_entry(Index::SYS_TIMER, pit_handler.get_addr(), 0x8, Flags::BASE, 0);
-        // direct system timer irq to be sent to current processor's lapic
-        io_apic::enable_system_timer(Index::SYS_TIMER);
+        // set pit handler and direct the system timer irq to the current processor
+        interrupts::enable_irq(Irq::SYSTEM_TIMER, Index::SYS_TIMER, pit_handler.get_addr());
     }
 
     pub fn wait(&self) {
+        if IS_PERIODIC.load(Ordering::Acquire) {
+            // interrupts go to another processor so only the tick count can be watched,
+            // waiting for a tick first so the wait starts on a tick boundary
+            let tick_count = PERIODIC_TICK_COUNT.load(Ordering::Acquire);
+            while PERIODIC_TICK_COUNT.load(Ordering::Acquire) == tick_count {
+                core::hint::spin_loop();
+            }
+            let end_tick_count = tick_count + 1 + self.periodic_ticks_to_wait;
+            while PERIODIC_TICK_COUNT.load(Ordering::Acquire) < end_tick_count {
+                core::hint::spin_loop();
+            }
+            return;
+        }
+
         // set divisor so PIT frequency
-        instructions::outb(CHANNEL_O_PORT, self.divisor as u8);        // low byte
-        instructions::outb(CHANNEL_O_PORT, (self.divisor >> 8) as u8); // high byte
+        port::PIT_CHANNEL_0.write(self.divisor as u8);        // low byte
+        port::PIT_CHANNEL_0.write((self.divisor >> 8) as u8); // high byte
 
         interrupts::hlt_wait(
             || { IS_WAIT_OVER.load(Ordering::Acquire) }
@@ -56,10 +104,16 @@ pub fn unlock(pit: SpinlockGuard<'static, Pit>) {
     pit.unlock();
 }
 
-def_interrupt_handler!(pit_handler,
+def_interrupt_handler!(pit_handler, super::structures::idt::Index::SYS_TIMER,
     fn pit_handler_fn(_stack_frame: &StackFrame) {
-        use interrupts::apic::lapic;
         IS_WAIT_OVER.store(true, Ordering::Release);
-        lapic::eoi();
+        interrupts::end_of_irq(interrupts::Irq::SYSTEM_TIMER);
+    }
+);
+def_interrupt_handler!(pit_periodic_handler, super::structures::idt::Index::SYS_TIMER,
+    fn pit_periodic_handler_fn(_stack_frame: &StackFrame) {
+        PERIODIC_TICK_COUNT.fetch_add(1, Ordering::Release);
+        processor::get().timer().periodic_tick();
+        interrupts::end_of_irq(interrupts::Irq::SYSTEM_TIMER);
     }
 );
@@ -0,0 +1,73 @@
+use core::marker::PhantomData;
+
+use super::cpu::instructions;
+
+
+// PIC
+pub const PIC1_COMMAND: Port<u8> = Port::new(0x20);
+pub const PIC1_DATA: Port<u8> = Port::new(0x21);
+pub const PIC2_COMMAND: Port<u8> = Port::new(0xA0);
+pub const PIC2_DATA: Port<u8> = Port::new(0xA1);
+
+// POST code, nothing listens to it so writes only take time
+pub const POST_CODE: Port<u8> = Port::new(0x80);
+
+// PIT
+pub const PIT_CHANNEL_0: Port<u8> = Port::new(0x40);
+pub const PIT_COMMAND: Port<u8> = Port::new(0x43);
+
+// PS/2 controller
+pub const PS2_CONTROLLER_DATA: Port<u8> = Port::new(0x60);
+pub const PS2_CONTROLLER_STATUS: Port<u8> = Port::new(0x64);
+
+
+// Values that can be read from and written to an I/O port, each using its own sized instruction
+pub trait PortValue: Copy {
+    fn read_from(port: u16) -> Self;
+    fn write_to(port: u16, value: Self);
+}
+impl PortValue for u8 {
+    #[inline]
+    fn read_from(port: u16) -> u8 { instructions::inb(port) }
+    #[inline]
+    fn write_to(port: u16, value: u8) { instructions::outb(port, value) }
+}
+impl PortValue for u16 {
+    #[inline]
+    fn read_from(port: u16) -> u16 { instructions::inw(port) }
+    #[inline]
+    fn write_to(port: u16, value: u16) { instructions::outw(port, value) }
+}
+impl PortValue for u32 {
+    #[inline]
+    fn read_from(port: u16) -> u32 { instructions::inl(port) }
+    #[inline]
+    fn write_to(port: u16, value: u32) { instructions::outl(port, value) }
+}
+
+
+// I/O port accessed with the width of T
+#[derive(Clone, Copy)]
+pub struct Port<T: PortValue> {
+    number: u16,
+    phantom: PhantomData<T>
+}
+impl<T: PortValue> Port<T> {
+    pub const fn new(number: u16) -> Port<T> {
+        Port { number, phantom: PhantomData }
+    }
+
+    #[inline]
+    pub fn number(&self) -> u16 {
+        self.number
+    }
+
+    #[inline]
+    pub fn read(&self) -> T {
+        T::read_from(self.number)
+    }
+    #[inline]
+    pub fn write(&self, value: T) {
+        T::write_to(self.number, value)
+    }
+}
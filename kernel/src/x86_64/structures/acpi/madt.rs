@@ -1,9 +1,12 @@
 use core::mem;
 
-use crate::memory::address::{PhysAddr, VirtAddr};
+use crate::{memory::address::{PhysAddr, VirtAddr}, utils::stack_vec::StackVec};
 use super::SDTHeader;
 
 
+pub const MAX_NUM_OF_IO_APICS: usize = 8;
+
+
 #[repr(C, packed)]
 pub struct MADT {
     header: SDTHeader,
@@ -29,6 +32,20 @@ impl MADT {
         Err("IO APIC not found in MADT")
     }
 
+    // Returns MMIO addresses of all IO APICs, doesn't require the heap
+    pub fn get_io_apic_addrs(&self) -> StackVec<PhysAddr, MAX_NUM_OF_IO_APICS> {
+        let mut io_apic_addrs = StackVec::new();
+        for entry in self.iter()
+            .filter(|h| h.entry_type == EntryType::IO_APIC_ENTRY)
+            .map(|h| h.to_entry::<IOApicEntry>())
+        {
+            if let Err(_) = io_apic_addrs.push(PhysAddr::new(entry.io_apic_addr as usize)) {
+                break;
+            }
+        }
+        io_apic_addrs
+    }
+
     // Returns interrupt source override for the given interrupt source
     pub fn get_interrupt_source_override(&self, irq_source: u8) -> Option<&'static IOInterruptSourceOverride> {
         for entry in self.iter()
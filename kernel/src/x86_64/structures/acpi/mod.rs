@@ -67,14 +67,14 @@ impl RSDP {
     pub fn validate(&self) -> Result<(), &'static str> {
         // validate first part
         let byte_array = unsafe { &*(self as *const _ as usize as *const [u8; mem::size_of::<RSDP1>()]) };
-        let remainder = checksum::eight_bit_modulo(byte_array);
+        let remainder = checksum::sum_bytes(byte_array);
 
         // if ACPI version 2.0 or higher validate rest
-        let mut remainder2: u64 = 0;
+        let mut remainder2: u8 = 0;
         if self.first_part.revision != 0 {
             let addr = (self as *const _ as usize) + mem::size_of::<RSDP1>();
             let byte_array = unsafe { &*(addr as *const [u8; mem::size_of::<RSDP>() - mem::size_of::<RSDP1>()]) };
-            remainder2 = checksum::eight_bit_modulo(byte_array);
+            remainder2 = checksum::sum_bytes(byte_array);
         }
 
         if remainder != 0 || remainder2 != 0 {
@@ -109,6 +109,14 @@ struct SDTHeader {
     pub creator_id: u32,
     pub creator_revision: u32
 }
+impl SDTHeader {
+    // Sums every byte of the table this header starts (header and entries) as they're stored in memory
+    fn sum_table_bytes(&self) -> u8 {
+        let length = self.length as usize;
+        let byte_array = unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, length) };
+        checksum::sum_bytes(byte_array)
+    }
+}
 
 trait RootSystemDescriptionTable: Sync {
     fn validate(&self) -> Result<(), &'static str>;
@@ -131,15 +139,7 @@ impl RSDT {
 }
 impl RootSystemDescriptionTable for RSDT {
     fn validate(&self) -> Result<(), &'static str> {
-        let byte_array = unsafe { &*(self as *const _ as usize as *const [u8; mem::size_of::<SDTHeader>()]) };
-        let mut remainder = checksum::eight_bit_modulo(byte_array);
-        for addr in self.iter() {
-            let byte_array = unsafe { &*(&addr as *const _ as *const [u8; 4]) };
-            remainder += checksum::eight_bit_modulo(byte_array);
-        }
-        remainder %= (u8::MAX as u64) + 1;
-
-        if remainder != 0 {
+        if self.header.sum_table_bytes() != 0 {
             return Err("RSDT checksum invalid");
         }
 
@@ -180,7 +180,8 @@ impl Iterator for RSDTIterator {
         }
         let cur_addr = self.start_addr.offset::<u32>(self.index);
         self.index += 1;
-        Some(unsafe{ *cur_addr.as_ptr::<u32>() })
+        // entries aren't guaranteed to be aligned
+        Some(unsafe{ cur_addr.as_ptr::<u32>().read_unaligned() })
     }
 }
 
@@ -200,17 +201,7 @@ impl XSDT {
 }
 impl RootSystemDescriptionTable for XSDT {
     fn validate(&self) -> Result<(), &'static str> {
-        // validate first part
-        let byte_array = unsafe { &*(self as *const _ as usize as *const [u8; mem::size_of::<SDTHeader>()]) };
-        let mut remainder = checksum::eight_bit_modulo(byte_array);
-        for addr in self.iter() {
-            let addr = addr.as_usize();
-            let byte_array = unsafe { &*(&addr as *const _ as *const [u8; 8]) };
-            remainder += checksum::eight_bit_modulo(byte_array);
-        }
-        remainder %= (u8::MAX as u64) + 1;
-
-        if remainder != 0 {
+        if self.header.sum_table_bytes() != 0 {
             return Err("XSDT checksum invalid");
         }
 
@@ -251,6 +242,59 @@ impl Iterator for XSDTIterator {
         }
         let cur_addr = self.start_addr.offset::<PhysAddr>(self.index);
         self.index += 1;
-        Some(unsafe{ *cur_addr.as_ptr::<PhysAddr>() })
+        // entries aren't guaranteed to be aligned (XSDT entries start at offset 36)
+        Some(unsafe{ cur_addr.as_ptr::<PhysAddr>().read_unaligned() })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use core::slice;
+    use super::*;
+
+    fn bytes_of<T>(value: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    // Both checksums are set so the bytes they cover sum to 0
+    fn rsdp(revision: u8) -> RSDP {
+        let mut rsdp = RSDP {
+            first_part: RSDP1 { signature: *b"RSD PTR ", checksum: 0, oemid: *b"BOCHS ", revision, rsdt_addr: 0x7FE14D2 },
+            length: mem::size_of::<RSDP>() as u32,
+            xsdt_addr: 0x7FE1512,
+            extended_checksum: 0,
+            reserved: [0; 3]
+        };
+        rsdp.first_part.checksum = checksum::sum_bytes(&bytes_of(&rsdp)[..mem::size_of::<RSDP1>()]).wrapping_neg();
+        rsdp.extended_checksum = checksum::sum_bytes(&bytes_of(&rsdp)[mem::size_of::<RSDP1>()..]).wrapping_neg();
+        rsdp
+    }
+
+    #[test]
+    fn accepts_valid_rsdp() {
+        assert!(rsdp(0).validate().is_ok());
+        assert!(rsdp(2).validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_corrupted_first_part() {
+        for revision in [0, 2] {
+            let mut rsdp = rsdp(revision);
+            rsdp.first_part.oemid[0] ^= 1;
+            assert!(rsdp.validate().is_err());
+        }
+    }
+
+    // only checked from ACPI 2.0
+    #[test]
+    fn checks_extended_part_by_revision() {
+        let mut rsdp1 = rsdp(0);
+        rsdp1.reserved[0] = 1;
+        assert!(rsdp1.validate().is_ok());
+
+        let mut rsdp2 = rsdp(2);
+        rsdp2.reserved[0] = 1;
+        assert!(rsdp2.validate().is_err());
     }
 }
@@ -1,29 +1,25 @@
 use core::mem;
 
 use crate::{x86_64, utils::lazy_static::LazyStatic};
+use super::tss::Tss;
 
 
+// Segment selectors, user ones in the order SYSRET expects (data and then code after STAR's base)
+pub const KERNEL_CODE_SELECTOR: u16 = 0x8;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+pub const TSS_SELECTOR: u16 = 0x28;
+
+
+// Used until each processor loads its own copy with a TSS
 static GDT_DESCRIPTOR: LazyStatic<GdtDescriptor> = LazyStatic::new();
 static GDT: LazyStatic<Gdt> = LazyStatic::new();
 
 
 pub fn init() {
-    use EntryAccess as Access;
-    use EntryFlags as Flags;
-
-    // code segment
-    let code_entry = Entry::new(
-        Access::RW | Access::EXECUTABLE | Access::CODE_OR_DATA | Access::PRESENT,
-        Flags::LONG_MODE | Flags::GRANULARITY
-    );
-    // data segment
-    let data_entry = Entry::new(
-        Access::RW | Access::CODE_OR_DATA | Access::PRESENT,
-        Flags::SIZE | Flags::GRANULARITY
-    );
-
     // init GDT and GDT_DESCRIPTOR
-    GDT.init(Gdt::new(code_entry, data_entry));
+    GDT.init(Gdt::new());
     GDT_DESCRIPTOR.init(GdtDescriptor::new(&GDT));
 }
 
@@ -32,6 +28,16 @@ pub fn load() {
     GDT_DESCRIPTOR.load();
 }
 
+/*
+ * Loads gdt (a processor's own, neither can move after) with an entry for tss and loads
+ * the TSS, has to be called on the processor they belong to
+ */
+pub fn load_with_tss(gdt: &'static mut Gdt, tss: &'static Tss) {
+    gdt.tss_entry = TssEntry::new(tss);
+    GdtDescriptor::new(gdt).load();
+    x86_64::cpu::instructions::ltr(TSS_SELECTOR);
+}
+
 
 #[repr(C, packed)]
 pub struct GdtDescriptor {
@@ -44,21 +50,38 @@ impl GdtDescriptor {
         GdtDescriptor { limit, address: gdt }
     }
 
-    fn load(&'static self) {
+    fn load(&self) {
         x86_64::cpu::instructions::lgdt(self as *const _ as u64);
     }
 }
 
 #[repr(C, packed)]
-struct Gdt {
+pub struct Gdt {
     null: u64,
-    // code, data
-    code_entry: Entry,
-    data_entry: Entry,
+    kernel_code_entry: Entry,
+    kernel_data_entry: Entry,
+    user_data_entry: Entry,
+    user_code_entry: Entry,
+    tss_entry: TssEntry
 }
 impl Gdt {
-    fn new(code_entry: Entry, data_entry: Entry) -> Gdt {
-        Gdt { null: 0, code_entry, data_entry }
+    pub fn new() -> Gdt {
+        use EntryAccess as Access;
+        use EntryFlags as Flags;
+
+        let code_access = Access::RW | Access::EXECUTABLE | Access::CODE_OR_DATA | Access::PRESENT;
+        let data_access = Access::RW | Access::CODE_OR_DATA | Access::PRESENT;
+        let code_flags = Flags::LONG_MODE | Flags::GRANULARITY;
+        let data_flags = Flags::SIZE | Flags::GRANULARITY;
+
+        Gdt {
+            null: 0,
+            kernel_code_entry: Entry::new(code_access, code_flags),
+            kernel_data_entry: Entry::new(data_access, data_flags),
+            user_data_entry: Entry::new(data_access | Access::USER, data_flags),
+            user_code_entry: Entry::new(code_access | Access::USER, code_flags),
+            tss_entry: TssEntry::null()
+        }
     }
 }
 
@@ -83,6 +106,7 @@ impl EntryAccess {
     const RW: u8 = 0x2;
     const EXECUTABLE: u8 = 0x8;
     const CODE_OR_DATA: u8 = 0x10;
+    const USER: u8 = 0x60; // descriptor privilege level 3
     const PRESENT: u8 = 0x80;
 }
 struct EntryFlags;
@@ -92,28 +116,36 @@ impl EntryFlags {
     const GRANULARITY: u8 = 0x80;
 }
 
-// FIXME: Implement TSS
-// #[repr(C, packed)]
-// struct TssEntry {
-//     lower_half: Entry,
-//     null: u32,
-//     base4: u32,
-// }
-// impl TssEntry {
-//     const TSS_ENTRY_ACCESS_TYPE: u8 = 0x9;
-
-//     fn new(tss: &'static Tss) -> TssEntry {
-//         let tss_addr = tss as *const _ as usize;
-
-//         let limit = (mem::size_of::<Tss>()-1) as u16;
-//         let base3 = (tss_addr >> 24) as u8;
-//         let base2 = (tss_addr >> 16) as u8;
-//         let base1 = tss_addr as u16;
-//         let access = TssEntry::TSS_ENTRY_ACCESS_TYPE | EntryAccess::PRESENT;
-//         let flagslimit = 0;
-//         let entry = Entry { limit, base1, base2, access, flagslimit, base3 };
-
-//         let base4 = (tss_addr >> 32) as u32;
-//         TssEntry { lower_half: entry, null: 0, base4 }
-//     }
-// }
+// System segment entries take twice the space in long mode
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct TssEntry {
+    lower_half: Entry,
+    base4: u32,
+    reserved: u32
+}
+impl TssEntry {
+    const ACCESS_TYPE_AVAILABLE_TSS: u8 = 0x9;
+
+    const fn null() -> TssEntry {
+        TssEntry {
+            lower_half: Entry { limit: 0, base1: 0, base2: 0, access: 0, flagslimit: 0, base3: 0 },
+            base4: 0, reserved: 0
+        }
+    }
+
+    fn new(tss: &'static Tss) -> TssEntry {
+        let tss_addr = tss as *const _ as usize;
+
+        let limit = (mem::size_of::<Tss>()-1) as u16;
+        let base3 = (tss_addr >> 24) as u8;
+        let base2 = (tss_addr >> 16) as u8;
+        let base1 = tss_addr as u16;
+        let access = TssEntry::ACCESS_TYPE_AVAILABLE_TSS | EntryAccess::PRESENT;
+        let flagslimit = 0;
+        let lower_half = Entry { limit, base1, base2, access, flagslimit, base3 };
+
+        let base4 = (tss_addr >> 32) as u32;
+        TssEntry { lower_half, base4, reserved: 0 }
+    }
+}
@@ -107,6 +107,7 @@ impl Index {
     pub const KEYBOARD: u8 = 0xE9;
     pub const SYS_TIMER: u8 = 0xF6;
     pub const LAPIC_TIMER: u8 = 0xF7;
+    pub const PREEMPT: u8 = 0xFD;
     pub const HALT: u8 = 0xFE;
     pub const SPURIOUS: u8 = 0xFF;
 }
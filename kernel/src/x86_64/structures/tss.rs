@@ -1,3 +1,5 @@
+use core::mem;
+
 use crate::memory::address::VirtAddr;
 
 
@@ -12,12 +14,21 @@ pub struct Tss {
     io_map_base_addr: u16,
 }
 impl Tss {
+    // The I/O map base points past the end of the TSS so there's no I/O permission bitmap
     pub const fn new() -> Tss {
-        Tss{ reserved0: 0, pst: [0; 3], reserved1: 0, ist: [0; 7], reserved2: 0, reserved3: 0, io_map_base_addr: 0 }
+        Tss{
+            reserved0: 0, pst: [0; 3], reserved1: 0, ist: [0; 7], reserved2: 0, reserved3: 0,
+            io_map_base_addr: mem::size_of::<Tss>() as u16
+        }
     }
 
     pub fn set_ist_entry(&mut self, index: usize, stack_end_addr: VirtAddr) {
         assert!(index < 7);
         self.ist[index] = stack_end_addr.as_usize();
     }
+
+    // Stack switched to when an interrupt comes in while running in ring 3 (RSP0)
+    pub fn set_kernel_stack(&mut self, stack_end_addr: VirtAddr) {
+        self.pst[0] = stack_end_addr.as_usize();
+    }
 }
@@ -0,0 +1,179 @@
+use core::arch::global_asm;
+use alloc::vec::Vec;
+
+use crate::{
+    memory::{address::VirtAddr, usercopy},
+    processor, scheduler,
+    x86_64::{cpu::{instructions, registers::{msr, rflags}}, structures::gdt}
+};
+
+
+const EFER_SYSCALL_ENABLE: u64 = 1<<0;
+const KERNEL_GS_BASE_MSR_INDEX: u32 = 0xC0000102;
+// cleared on entry, interrupts are enabled again by "dispatch" if the caller had them
+const ENTRY_RFLAGS_MASK: u64 = rflags::FLAG_TRAP | rflags::FLAG_INTERRUPT_ENABLED
+    | rflags::FLAG_DIRECTION | rflags::FLAG_ALIGNMENT_CHECK;
+
+
+/*
+ * Entered through the syscall instruction from ring 3, which leaves the return address in RCX
+ * and the caller's RFLAGS in R11. The number is in RAX and up to 6 arguments in RDI, RSI, RDX,
+ * R10, R8 and R9, the result is returned in RAX and every other register but RCX and R11 is kept.
+ *
+ * The kernel GS base points to the processor's EntryState, swapgs switches to it only for as
+ * long as it takes to move to the task's kernel stack (nothing else in the kernel uses GS).
+ * The user RSP is kept on that stack since the task can be switched out during the call.
+ */
+global_asm!(r#"
+syscall_entry:
+    swapgs
+    mov gs:[8], rsp
+    mov rsp, gs:[0]
+    push qword ptr gs:[8]
+    swapgs
+
+    push rbp
+    mov rbp, rsp
+    and rsp, -16
+    sub rsp, 8 # keeps the stack aligned for the call
+
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+
+    mov rdi, rsp # 1st param, address to the saved registers
+    call {}
+
+    add rsp, 8 # number, RAX has the result
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+
+    mov rsp, rbp
+    pop rbp
+
+    # nothing can interrupt while on the user stack in ring 0
+    cli
+    pop rsp
+    sysretq
+"#, sym dispatch);
+
+#[allow(improper_ctypes)]
+extern {
+    static syscall_entry: ();
+}
+
+
+// Numbers of the system calls, indexes to HANDLERS
+pub struct Syscall;
+impl Syscall {
+    pub const WRITE: u64 = 0; // (buffer, length) prints a UTF-8 string, returns the length
+    pub const EXIT: u64 = 1;  // (code) ends the task, which is freed by the scheduler
+    pub const YIELD: u64 = 2; // () gives the other ready tasks a turn
+}
+// Returned instead of a result
+pub struct SyscallError;
+impl SyscallError {
+    pub const NO_SUCH_SYSCALL: i64 = -1;
+    pub const INVALID_ARGUMENT: i64 = -2;
+}
+
+const HANDLERS: [fn(&[u64; 6]) -> i64; 3] = [sys_write, sys_exit, sys_yield];
+
+
+/*
+ * Makes syscall enter "syscall_entry" on the current processor, which has to be registered.
+ * SYSCALL loads CS from STAR bits 32-47 (and SS from the entry after it), SYSRET loads SS and
+ * CS from the entries 1 and 2 after the selector in bits 48-63, with RPL 3.
+ */
+pub fn init() {
+    let sysret_base_selector = (gdt::USER_DATA_SELECTOR as u64 & !3) - 8;
+    msr::write(msr::STAR, sysret_base_selector << 48 | (gdt::KERNEL_CODE_SELECTOR as u64) << 32);
+    msr::write(msr::LSTAR, unsafe { &syscall_entry as *const _ as u64 });
+    msr::write(msr::SFMASK, ENTRY_RFLAGS_MASK);
+    msr::write(KERNEL_GS_BASE_MSR_INDEX, processor::get().syscall_entry_state() as *const _ as u64);
+    msr::write(msr::EFER, msr::read(msr::EFER) | EFER_SYSCALL_ENABLE);
+}
+
+
+// Read by "syscall_entry" through the kernel GS base, one per processor
+#[repr(C)]
+pub struct EntryState {
+    kernel_stack_top: u64, // of the user task running on the processor
+    user_rsp: u64 // only until it's pushed on the kernel stack
+}
+impl EntryState {
+    pub const fn new() -> EntryState {
+        EntryState { kernel_stack_top: 0, user_rsp: 0 }
+    }
+
+    pub fn set_kernel_stack(&mut self, stack_top: VirtAddr) {
+        self.kernel_stack_top = stack_top.as_usize() as u64;
+    }
+}
+
+
+// In the reverse order as it is pushed to the stack
+#[repr(C)]
+struct SavedRegisters {
+    number: u64,
+    args: [u64; 6],
+    rflags: u64,
+    _rip: u64
+}
+
+extern "sysv64" fn dispatch(saved_registers: &SavedRegisters) -> i64 {
+    // before anything uses TLS, the caller could have changed the FS base
+    scheduler::reload_tls();
+    if saved_registers.rflags & rflags::FLAG_INTERRUPT_ENABLED != 0 {
+        instructions::sti();
+    }
+
+    match HANDLERS.get(saved_registers.number as usize) {
+        Some(handler) => handler(&saved_registers.args),
+        None => SyscallError::NO_SUCH_SYSCALL
+    }
+}
+
+fn sys_write(args: &[u64; 6]) -> i64 {
+    let (buffer_addr, length) = (args[0] as usize, args[1] as usize);
+
+    let mut bytes = Vec::new();
+    if bytes.try_reserve_exact(length).is_err() {
+        return SyscallError::INVALID_ARGUMENT;
+    }
+    bytes.resize(length, 0);
+    if usercopy::copy_from_user(&mut bytes, buffer_addr, length).is_err() {
+        return SyscallError::INVALID_ARGUMENT;
+    }
+
+    match core::str::from_utf8(&bytes) {
+        Ok(string) => {
+            crate::print!("{}", string);
+            length as i64
+        },
+        Err(_) => SyscallError::INVALID_ARGUMENT
+    }
+}
+
+// Like a returned closure task, freed once the scheduler switched away from it
+fn sys_exit(args: &[u64; 6]) -> i64 {
+    crate::debug!("Task {:?} exited with code {}", scheduler::get_executing_task_id(), args[0] as i64);
+    scheduler::exit_task();
+}
+
+fn sys_yield(_args: &[u64; 6]) -> i64 {
+    scheduler::yield_now();
+    0
+}
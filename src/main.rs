@@ -1,27 +1,42 @@
-use std::{env, path::Path, process::Command};
+use std::{env, fs, path::Path, process::Command};
+
+
+const DISK_SIZE_ALIGNMENT: u64 = 1024*1024; // disk img size is rounded up to MBs
+// BIOSes might not read a disk smaller than a cylinder (with the usual 16 heads and 63 sectors per track)
+const MIN_DISK_SIZE: u64 = 16*63*512;
 
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let binary_path = Path::new("./bootloader/target/x86-bootloader-target/release/bootloader.bin");
 
+    let binary_size = match fs::metadata(binary_path) {
+        Ok(metadata) => metadata.len(),
+        Err(err) => panic!("Couldn't read {} ({}), was it built?", binary_path.to_string_lossy(), err)
+    };
+    let disk_size = std::cmp::max(
+        (binary_size + DISK_SIZE_ALIGNMENT - 1) / DISK_SIZE_ALIGNMENT * DISK_SIZE_ALIGNMENT, MIN_DISK_SIZE
+    );
+
     // create disk img
     let mut qemu_img = Command::new("qemu-img");
     let if_path_arg = format!("if={}", binary_path.to_string_lossy());
     qemu_img.args(["dd", "-f", "raw", "-O", "raw", &if_path_arg, "of=disk.img", "bs=512"]);
     assert!(qemu_img.status().unwrap().success(), "Failed to create disk img");
 
-    // resize disk img (FIXME: would need to be updated if binary is bigger than 1M)
+    // resize disk img to fit the binary
     let mut qemu_img = Command::new("qemu-img");
-    qemu_img.args(["resize", "disk.img", "1M"]);
+    let disk_size_arg = disk_size.to_string();
+    qemu_img.args(["resize", "-f", "raw", "disk.img", &disk_size_arg]);
     assert!(qemu_img.status().unwrap().success(), "Failed to resize disk img");
 
     // setup qemu command
     let mut qemu = Command::new("qemu-system-x86_64");
-    qemu.args(["-hda", "disk.img", "-monitor", "stdio"]);
+    qemu.args(["-hda", "disk.img"]);
     let mut machine_args = vec!["-machine", "q35"];
 
     let mut was_kvm_found = false;
+    let (mut is_gdb, mut is_debug, mut is_serial, mut is_test) = (false, false, false, false);
     for (i, arg) in args.iter().enumerate().skip(1) {
         match arg.to_lowercase().as_str() {
             "m" => {
@@ -64,12 +79,50 @@ fn main() {
                 }
                 qemu.args(["-cpu", "host,+invtsc"]);
             }
+            // GDB stub on tcp::1234, halted until a debugger connects
+            "gdb" => {
+                qemu.args(["-s", "-S"]);
+                is_gdb = true;
+            }
+            // log interrupts and CPU resets instead of rebooting on a triple fault
+            "debug" => {
+                qemu.args(["-d", "int,cpu_reset", "-no-reboot"]);
+                is_debug = true;
+            }
+            "serial" => {
+                is_serial = true;
+            }
+            // kernel can exit QEMU by writing to port 0xf4, the value written determines the exit code
+            "test" => {
+                qemu.args(["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]);
+                is_test = true;
+            }
             _ => { continue; }
         }
     }
 
+    if was_kvm_found && is_gdb {
+        eprintln!("WARNING: breakpoints might not work with gdb arg when running with KVM");
+    }
+    if was_kvm_found && is_debug {
+        eprintln!("WARNING: interrupts aren't logged by debug arg when running with KVM");
+    }
+
+    // serial and monitor both on stdio (switch with Ctrl-a c)
+    if is_serial {
+        qemu.args(["-serial", "mon:stdio"]);
+    }
+    else {
+        qemu.args(["-monitor", "stdio"]);
+    }
+
     qemu.args(machine_args);
 
     // run with qemu
-    assert!(qemu.status().unwrap().success(), "Failed to run QEMU");
+    let status = qemu.status().expect("Failed to run QEMU");
+    if is_test {
+        // exit code is (value written to isa-debug-exit << 1) | 1
+        std::process::exit(status.code().unwrap_or(1));
+    }
+    assert!(status.success(), "Failed to run QEMU");
 }